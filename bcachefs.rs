@@ -7,10 +7,15 @@ use std::convert::TryInto;
 
 use clap::{AppSettings, Clap};
 use env_logger::Builder;
-use log::{debug, LevelFilter};
+use log::{debug, error, LevelFilter};
 use uuid::Uuid;
 
-use libbcachefs::{self, format_device, BchError, ErrorAction, Result};
+use libbcachefs::{
+    self, cat, device_add, device_remove, device_resize, device_set_state, format_device,
+    list_devices, list_journal, list_keys, mount, remove_passphrase, restore, set_label,
+    set_options, set_passphrase, set_user_uuid, stat, unlock, upgrade, BchError, Bpos, BtreeId,
+    ErrorAction, Keyring, MemberState, Result,
+};
 
 /// Bcachefs userspace tooling.
 #[derive(Clap)]
@@ -27,9 +32,64 @@ struct Opts {
 
 #[derive(Clap)]
 #[clap(setting = AppSettings::ColoredHelp)]
+#[allow(clippy::large_enum_variant)]
 enum SubCommand {
     /// Format a given device
     Format(FormatArgs),
+    /// Change options of an unmounted filesystem
+    SetOption(SetOptionArgs),
+    /// Change the label of an unmounted filesystem
+    SetLabel(SetLabelArgs),
+    /// Change the user uuid of an unmounted filesystem
+    SetUuid(SetUuidArgs),
+    /// Manage the devices of an unmounted filesystem
+    Device(DeviceArgs),
+    /// Add the key of an encrypted filesystem to the kernel keyring
+    Unlock(UnlockArgs),
+    /// Change the passphrase of an unmounted encrypted filesystem
+    SetPassphrase(SetPassphraseArgs),
+    /// Remove the passphrase of an unmounted encrypted filesystem
+    RemovePassphrase(RemovePassphraseArgs),
+    /// Find the members of a filesystem and mount it
+    Mount(MountArgs),
+    /// List the bcachefs filesystems found and their members
+    ListDevices(ListDevicesArgs),
+    /// Change the metadata version of an unmounted filesystem
+    Upgrade(UpgradeArgs),
+    /// Print the journal of an unmounted filesystem
+    ListJournal(ListJournalArgs),
+    /// Print the keys of a btree of an unmounted filesystem
+    List(ListArgs),
+    /// Print the inode of a file of an unmounted filesystem
+    Stat(StatArgs),
+    /// Write a file of an unmounted filesystem to stdout
+    Cat(CatArgs),
+    /// Restore files of an unmounted filesystem to a directory
+    Restore(RestoreArgs),
+}
+
+/// The arguments that the device subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct DeviceArgs {
+    /// The device command to run
+    #[clap(subcommand)]
+    subcmd: DeviceCommand,
+}
+
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+enum DeviceCommand {
+    /// Add a new device to a filesystem
+    Add(DeviceAddArgs),
+    /// Change the state of a member: rw, ro, failed or spare
+    SetState(DeviceSetStateArgs),
+    /// Mark a member as failed
+    Fail(DeviceFailArgs),
+    /// Remove a member from a filesystem
+    Remove(DeviceRemoveArgs),
+    /// Grow a member to the size of its device
+    Resize(DeviceResizeArgs),
 }
 
 const MIN_BLOCK_SHIFT: u16 = 9;
@@ -52,6 +112,20 @@ fn valid_block_size(s: &str) -> std::result::Result<(), String> {
     }
 }
 
+fn valid_bucket_size(s: &str) -> std::result::Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(size) if size >= 1 << MIN_BLOCK_SHIFT && size % (1 << MIN_BLOCK_SHIFT) == 0 => {
+            if size >> MIN_BLOCK_SHIFT > u64::from(u16::MAX) {
+                Err(format!("bucket size too large: {}", size))
+            } else {
+                Ok(())
+            }
+        }
+        Ok(size) => Err(format!("invalid bucket size: {}", size)),
+        _ => Err(format!("failed to parse integer: {}", s)),
+    }
+}
+
 fn is_gt_0(s: &str) -> std::result::Result<(), String> {
     match s.parse::<u64>() {
         Ok(size) if size > 0 => Ok(()),
@@ -99,15 +173,21 @@ struct FormatArgs {
     /// The disk uuid
     #[clap(short = 'u', long = "uuid")]
     uuid: Option<Uuid>,
-    /// Force creation if a preexisting FS exists
+    /// Force creation if a preexisting FS or partition table exists
     #[clap(short = 'f', long = "force")]
     force: bool,
     /// The size of the filesystem
     #[clap(long = "superblock-size", default_value = "2048")]
     superblock_size: u64,
-    /// The block size of the new FS
-    #[clap(long = "block-size", default_value = "512", validator = valid_block_size)]
-    block_size: u16,
+    /// Format host-managed zoned devices, using only their conventional zones
+    #[clap(long = "host-managed")]
+    host_managed: bool,
+    /// The block size of the new FS, defaults to the largest physical sector size
+    #[clap(long = "block-size", validator = valid_block_size)]
+    block_size: Option<u16>,
+    /// The bucket size in bytes, picked for each device if not given
+    #[clap(long = "bucket-size", validator = valid_bucket_size)]
+    bucket_size: Option<u64>,
     /// The foreground target of the device set
     #[clap(long = "foreground-target")]
     foreground_target: Option<String>,
@@ -128,6 +208,609 @@ struct FormatArgs {
     devices: Vec<String>,
 }
 
+/// The arguments that the set-option subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct SetOptionArgs {
+    /// The action to take on error: continue, ro or panic
+    #[clap(long = "errors")]
+    errors: Option<String>,
+    /// The number of metadata replicas
+    #[clap(long = "metadata-replicas")]
+    metadata_replicas: Option<String>,
+    /// The number of data replicas
+    #[clap(long = "data-replicas")]
+    data_replicas: Option<String>,
+    /// The number of metadata replicas required to write
+    #[clap(long = "metadata-replicas-required")]
+    metadata_replicas_required: Option<String>,
+    /// The number of data replicas required to write
+    #[clap(long = "data-replicas-required")]
+    data_replicas_required: Option<String>,
+    /// The metadata checksum type: none, crc32c, crc64 or xxhash
+    #[clap(long = "metadata-checksum")]
+    metadata_checksum: Option<String>,
+    /// The data checksum type: none, crc32c, crc64 or xxhash
+    #[clap(long = "data-checksum")]
+    data_checksum: Option<String>,
+    /// The compression type: none, lz4, gzip or zstd
+    #[clap(long = "compression")]
+    compression: Option<String>,
+    /// The background compression type: none, lz4, gzip or zstd
+    #[clap(long = "background-compression")]
+    background_compression: Option<String>,
+    /// The hash of directory entries and xattrs: crc32c, crc64 or siphash
+    #[clap(long = "str-hash")]
+    str_hash: Option<String>,
+    /// The foreground target device, or none
+    #[clap(long = "foreground-target")]
+    foreground_target: Option<String>,
+    /// The background target device, or none
+    #[clap(long = "background-target")]
+    background_target: Option<String>,
+    /// The promote target device, or none
+    #[clap(long = "promote-target")]
+    promote_target: Option<String>,
+    /// The metadata target device, or none
+    #[clap(long = "metadata-target")]
+    metadata_target: Option<String>,
+    /// The percentage of space reserved for copygc
+    #[clap(long = "gc-reserve-percent")]
+    gc_reserve_percent: Option<String>,
+    /// The percentage of space reserved for root
+    #[clap(long = "root-reserve-percent")]
+    root_reserve_percent: Option<String>,
+    /// Enable POSIX ACLs
+    #[clap(long = "acl")]
+    acl: Option<String>,
+    /// Enable user quotas
+    #[clap(long = "usrquota")]
+    usrquota: Option<String>,
+    /// Enable group quotas
+    #[clap(long = "grpquota")]
+    grpquota: Option<String>,
+    /// Enable project quotas
+    #[clap(long = "prjquota")]
+    prjquota: Option<String>,
+    /// Limit inode numbers to 32 bits
+    #[clap(long = "inodes-32bit")]
+    inodes_32bit: Option<String>,
+    /// Shard new inode numbers by CPU
+    #[clap(long = "shard-inode-numbers")]
+    shard_inode_numbers: Option<String>,
+    /// Enable erasure coding
+    #[clap(long = "erasure-code")]
+    erasure_code: Option<String>,
+    /// The delay in milliseconds before a journal entry is flushed
+    #[clap(long = "journal-flush-delay")]
+    journal_flush_delay: Option<String>,
+    /// Disable journal flushes on sync
+    #[clap(long = "journal-flush-disabled")]
+    journal_flush_disabled: Option<String>,
+    /// The delay in milliseconds between journal reclaim runs
+    #[clap(long = "journal-reclaim-delay")]
+    journal_reclaim_delay: Option<String>,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<SetOptionArgs> for libbcachefs::SetOptionArgs {
+    fn from(args: SetOptionArgs) -> libbcachefs::SetOptionArgs {
+        let options = vec![
+            ("errors", args.errors),
+            ("metadata_replicas", args.metadata_replicas),
+            ("data_replicas", args.data_replicas),
+            (
+                "metadata_replicas_required",
+                args.metadata_replicas_required,
+            ),
+            ("data_replicas_required", args.data_replicas_required),
+            ("metadata_checksum", args.metadata_checksum),
+            ("data_checksum", args.data_checksum),
+            ("compression", args.compression),
+            ("background_compression", args.background_compression),
+            ("str_hash", args.str_hash),
+            ("foreground_target", args.foreground_target),
+            ("background_target", args.background_target),
+            ("promote_target", args.promote_target),
+            ("metadata_target", args.metadata_target),
+            ("gc_reserve_percent", args.gc_reserve_percent),
+            ("root_reserve_percent", args.root_reserve_percent),
+            ("acl", args.acl),
+            ("usrquota", args.usrquota),
+            ("grpquota", args.grpquota),
+            ("prjquota", args.prjquota),
+            ("inodes_32bit", args.inodes_32bit),
+            ("shard_inode_numbers", args.shard_inode_numbers),
+            ("erasure_code", args.erasure_code),
+            ("journal_flush_delay", args.journal_flush_delay),
+            ("journal_flush_disabled", args.journal_flush_disabled),
+            ("journal_reclaim_delay", args.journal_reclaim_delay),
+        ];
+
+        libbcachefs::SetOptionArgs {
+            options: options
+                .into_iter()
+                .filter_map(|(name, value)| value.map(|value| (name.to_string(), value)))
+                .collect(),
+            devices: args.devices,
+        }
+    }
+}
+
+/// The arguments that the set-label subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct SetLabelArgs {
+    /// The new label, empty to clear it
+    #[clap(validator = valid_label)]
+    label: String,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<SetLabelArgs> for libbcachefs::SetLabelArgs {
+    fn from(args: SetLabelArgs) -> libbcachefs::SetLabelArgs {
+        libbcachefs::SetLabelArgs {
+            label: args.label,
+            devices: args.devices,
+        }
+    }
+}
+
+/// The arguments that the set-uuid subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct SetUuidArgs {
+    /// The new user uuid, a random one if not given
+    #[clap(short = 'u', long = "uuid")]
+    uuid: Option<Uuid>,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<SetUuidArgs> for libbcachefs::SetUuidArgs {
+    fn from(args: SetUuidArgs) -> libbcachefs::SetUuidArgs {
+        libbcachefs::SetUuidArgs {
+            uuid: args.uuid,
+            devices: args.devices,
+        }
+    }
+}
+
+/// The arguments that the unlock subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct UnlockArgs {
+    /// Only check the passphrase, without adding the key
+    #[clap(short = 'c', long = "check")]
+    check: bool,
+    /// Read the passphrase from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// The keyring to add the key to: user or session
+    #[clap(short = 'k', long = "keyring", default_value = "user")]
+    keyring: Keyring,
+    /// A device of the filesystem
+    device: String,
+}
+
+impl From<UnlockArgs> for libbcachefs::UnlockArgs {
+    fn from(args: UnlockArgs) -> libbcachefs::UnlockArgs {
+        libbcachefs::UnlockArgs {
+            device: args.device,
+            keyfile: args.keyfile,
+            check: args.check,
+            keyring: args.keyring,
+        }
+    }
+}
+
+/// The arguments that the set-passphrase subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct SetPassphraseArgs {
+    /// Read the current passphrase from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// Read the new passphrase from a file instead of prompting for it
+    #[clap(short = 'n', long = "new-keyfile")]
+    new_keyfile: Option<String>,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<SetPassphraseArgs> for libbcachefs::PassphraseArgs {
+    fn from(args: SetPassphraseArgs) -> libbcachefs::PassphraseArgs {
+        libbcachefs::PassphraseArgs {
+            devices: args.devices,
+            keyfile: args.keyfile,
+            new_keyfile: args.new_keyfile,
+        }
+    }
+}
+
+/// The arguments that the remove-passphrase subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct RemovePassphraseArgs {
+    /// Read the current passphrase from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<RemovePassphraseArgs> for libbcachefs::PassphraseArgs {
+    fn from(args: RemovePassphraseArgs) -> libbcachefs::PassphraseArgs {
+        libbcachefs::PassphraseArgs {
+            devices: args.devices,
+            keyfile: args.keyfile,
+            new_keyfile: None,
+        }
+    }
+}
+
+/// The arguments that the mount subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct MountArgs {
+    /// Comma separated mount options
+    #[clap(short = 'o', long = "options")]
+    options: Option<String>,
+    /// Mount even if members of the filesystem are missing
+    #[clap(long = "degraded")]
+    degraded: bool,
+    /// Read the passphrase from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// UUID=<uuid> or a device of the filesystem
+    device: String,
+    /// The directory to mount on
+    dir: String,
+}
+
+impl From<MountArgs> for libbcachefs::MountArgs {
+    fn from(args: MountArgs) -> libbcachefs::MountArgs {
+        libbcachefs::MountArgs {
+            device: args.device,
+            dir: args.dir,
+            options: args.options.unwrap_or_default(),
+            degraded: args.degraded,
+            keyfile: args.keyfile,
+        }
+    }
+}
+
+/// The arguments that the list-devices subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct ListDevicesArgs {
+    /// The devices to probe, every block device if none are given
+    devices: Vec<String>,
+}
+
+impl From<ListDevicesArgs> for libbcachefs::ListArgs {
+    fn from(args: ListDevicesArgs) -> libbcachefs::ListArgs {
+        libbcachefs::ListArgs {
+            devices: args.devices,
+        }
+    }
+}
+
+/// The arguments that the upgrade subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct UpgradeArgs {
+    /// The metadata version to move to, the current version if not given
+    #[clap(long = "to")]
+    to: Option<u16>,
+    /// Only show the changes that would be made
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<UpgradeArgs> for libbcachefs::UpgradeArgs {
+    fn from(args: UpgradeArgs) -> libbcachefs::UpgradeArgs {
+        libbcachefs::UpgradeArgs {
+            to: args.to,
+            dry_run: args.dry_run,
+            devices: args.devices,
+        }
+    }
+}
+
+/// The arguments that the list-journal subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct ListJournalArgs {
+    /// The first sequence number to show, the oldest dirty entry if not given
+    #[clap(long = "from")]
+    from: Option<u64>,
+    /// The last sequence number to show
+    #[clap(long = "to")]
+    to: Option<u64>,
+    /// Only show the entries of the given btree
+    #[clap(short = 'b', long = "btree")]
+    btree: Option<BtreeId>,
+    /// Read the passphrase of an encrypted filesystem from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<ListJournalArgs> for libbcachefs::ListJournalArgs {
+    fn from(args: ListJournalArgs) -> libbcachefs::ListJournalArgs {
+        libbcachefs::ListJournalArgs {
+            from: args.from,
+            to: args.to,
+            btree: args.btree,
+            devices: args.devices,
+            keyfile: args.keyfile,
+        }
+    }
+}
+
+/// The arguments that the list subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct ListArgs {
+    /// The btree to list
+    #[clap(short = 'b', long = "btree")]
+    btree: BtreeId,
+    /// The first position to list, as inode:offset[:snapshot]
+    #[clap(long = "start", default_value = "POS_MIN")]
+    start: Bpos,
+    /// The last position to list, as inode:offset[:snapshot]
+    #[clap(long = "end", default_value = "POS_MAX")]
+    end: Bpos,
+    /// Read the passphrase of an encrypted filesystem from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<ListArgs> for libbcachefs::ListKeysArgs {
+    fn from(args: ListArgs) -> libbcachefs::ListKeysArgs {
+        libbcachefs::ListKeysArgs {
+            btree: args.btree,
+            start: args.start,
+            end: args.end,
+            devices: args.devices,
+            keyfile: args.keyfile,
+        }
+    }
+}
+
+/// The arguments that the stat subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct StatArgs {
+    /// Read the passphrase of an encrypted filesystem from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// The absolute path of the file within the filesystem
+    path: String,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<StatArgs> for libbcachefs::StatArgs {
+    fn from(args: StatArgs) -> libbcachefs::StatArgs {
+        libbcachefs::StatArgs {
+            path: args.path,
+            devices: args.devices,
+            keyfile: args.keyfile,
+        }
+    }
+}
+
+/// The arguments that the cat subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct CatArgs {
+    /// Use data even if its checksum is wrong, to salvage what is left
+    #[clap(long = "ignore-csum")]
+    ignore_csum: bool,
+    /// Read the passphrase of an encrypted filesystem from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// The devices of the filesystem, separated by colons
+    devices: String,
+    /// The absolute path of the file within the filesystem
+    path: String,
+}
+
+impl From<CatArgs> for libbcachefs::CatArgs {
+    fn from(args: CatArgs) -> libbcachefs::CatArgs {
+        libbcachefs::CatArgs {
+            devices: args.devices.split(':').map(String::from).collect(),
+            path: args.path,
+            ignore_csum: args.ignore_csum,
+            keyfile: args.keyfile,
+        }
+    }
+}
+
+/// The arguments that the restore subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct RestoreArgs {
+    /// Use data even if its checksum is wrong, to salvage what is left
+    #[clap(long = "ignore-csum")]
+    ignore_csum: bool,
+    /// Read the passphrase of an encrypted filesystem from a file instead of prompting for it
+    #[clap(short = 'f', long = "keyfile")]
+    keyfile: Option<String>,
+    /// The devices of the filesystem, separated by colons
+    devices: String,
+    /// The absolute path of the file or directory within the filesystem
+    path: String,
+    /// The directory to restore to, created if missing, existing files in it are kept
+    outdir: String,
+}
+
+impl From<RestoreArgs> for libbcachefs::RestoreArgs {
+    fn from(args: RestoreArgs) -> libbcachefs::RestoreArgs {
+        libbcachefs::RestoreArgs {
+            devices: args.devices.split(':').map(String::from).collect(),
+            path: args.path,
+            outdir: args.outdir,
+            ignore_csum: args.ignore_csum,
+            keyfile: args.keyfile,
+        }
+    }
+}
+
+/// The arguments that the device add subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct DeviceAddArgs {
+    /// The bucket size in bytes, picked for the device if not given
+    #[clap(long = "bucket-size", validator = valid_bucket_size)]
+    bucket_size: Option<u64>,
+    /// The durability of data stored on the device
+    #[clap(long = "durability", default_value = "1")]
+    durability: u64,
+    /// Force adding the device if a preexisting FS or partition table exists
+    #[clap(short = 'f', long = "force")]
+    force: bool,
+    /// Add a host-managed zoned device, using only its conventional zones
+    #[clap(long = "host-managed")]
+    host_managed: bool,
+    /// The device to add
+    new_device: String,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<DeviceAddArgs> for libbcachefs::DeviceAddArgs {
+    fn from(args: DeviceAddArgs) -> libbcachefs::DeviceAddArgs {
+        libbcachefs::DeviceAddArgs {
+            devices: args.devices,
+            new_device: args.new_device,
+            bucket_size: args.bucket_size,
+            durability: args.durability,
+            force: args.force,
+            host_managed: args.host_managed,
+        }
+    }
+}
+
+/// The arguments that the device set-state subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct DeviceSetStateArgs {
+    /// Change the state even if the required replicas can no longer be written
+    #[clap(short = 'f', long = "force")]
+    force: bool,
+    /// The new state of the member
+    state: MemberState,
+    /// The path or index of the member
+    device: String,
+    /// The devices of the filesystem, the changed member may be missing
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<DeviceSetStateArgs> for libbcachefs::DeviceSetStateArgs {
+    fn from(args: DeviceSetStateArgs) -> libbcachefs::DeviceSetStateArgs {
+        libbcachefs::DeviceSetStateArgs {
+            devices: args.devices,
+            device: args.device,
+            state: args.state,
+            force: args.force,
+        }
+    }
+}
+
+/// The arguments that the device fail subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct DeviceFailArgs {
+    /// Fail the member even if the required replicas can no longer be written
+    #[clap(short = 'f', long = "force")]
+    force: bool,
+    /// The path or index of the member
+    device: String,
+    /// The devices of the filesystem, the failed member may be missing
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<DeviceFailArgs> for libbcachefs::DeviceSetStateArgs {
+    fn from(args: DeviceFailArgs) -> libbcachefs::DeviceSetStateArgs {
+        libbcachefs::DeviceSetStateArgs {
+            devices: args.devices,
+            device: args.device,
+            state: MemberState::Failed,
+            force: args.force,
+        }
+    }
+}
+
+/// The arguments that the device remove subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct DeviceRemoveArgs {
+    /// Remove the member even if the required replicas can no longer be written
+    #[clap(short = 'f', long = "force")]
+    force: bool,
+    /// The path or index of the member
+    device: String,
+    /// The devices of the filesystem, the removed member may be missing
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<DeviceRemoveArgs> for libbcachefs::DeviceRemoveArgs {
+    fn from(args: DeviceRemoveArgs) -> libbcachefs::DeviceRemoveArgs {
+        libbcachefs::DeviceRemoveArgs {
+            devices: args.devices,
+            device: args.device,
+            force: args.force,
+        }
+    }
+}
+
+/// The arguments that the device resize subcommand may be provided.
+#[derive(Debug, Clap)]
+#[clap(setting = AppSettings::ColoredHelp)]
+struct DeviceResizeArgs {
+    /// The new size in bytes, defaults to the size of the device
+    #[clap(long = "size", validator = is_gt_0)]
+    size: Option<u64>,
+    /// The member to resize
+    device: String,
+    /// The devices of the filesystem
+    #[clap(min_values = 1, required = true)]
+    devices: Vec<String>,
+}
+
+impl From<DeviceResizeArgs> for libbcachefs::DeviceResizeArgs {
+    fn from(args: DeviceResizeArgs) -> libbcachefs::DeviceResizeArgs {
+        libbcachefs::DeviceResizeArgs {
+            devices: args.devices,
+            device: args.device,
+            size: args.size,
+        }
+    }
+}
+
 fn validate_target(
     opt: &str,
     target_opt: Option<String>,
@@ -218,7 +901,9 @@ impl TryInto<libbcachefs::FormatArgs> for FormatArgs {
             uuid: self.uuid.unwrap_or(Uuid::new_v4()),
             force: self.force,
             superblock_size: self.superblock_size,
+            host_managed: self.host_managed,
             block_size: self.block_size,
+            bucket_size: self.bucket_size,
             foreground_target,
             background_target,
             promote_target,
@@ -255,5 +940,140 @@ fn main() {
                 }
             }
         }
+        SubCommand::SetOption(args) => {
+            debug!("set-option args={:?}", args);
+            if let Err(e) = set_options(args.into()) {
+                error!("Failed to set options: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::SetLabel(args) => {
+            debug!("set-label args={:?}", args);
+            if let Err(e) = set_label(args.into()) {
+                error!("Failed to set label: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::SetUuid(args) => {
+            debug!("set-uuid args={:?}", args);
+            if let Err(e) = set_user_uuid(args.into()) {
+                error!("Failed to set uuid: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Unlock(args) => {
+            debug!("unlock args={:?}", args);
+            if let Err(e) = unlock(args.into()) {
+                error!("Failed to unlock: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::SetPassphrase(args) => {
+            debug!("set-passphrase args={:?}", args);
+            if let Err(e) = set_passphrase(args.into()) {
+                error!("Failed to set passphrase: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::RemovePassphrase(args) => {
+            debug!("remove-passphrase args={:?}", args);
+            if let Err(e) = remove_passphrase(args.into()) {
+                error!("Failed to remove passphrase: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Mount(args) => {
+            debug!("mount args={:?}", args);
+            if let Err(e) = mount(args.into()) {
+                error!("Failed to mount: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::ListDevices(args) => {
+            debug!("list-devices args={:?}", args);
+            if let Err(e) = list_devices(args.into()) {
+                error!("Failed to list devices: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Upgrade(args) => {
+            debug!("upgrade args={:?}", args);
+            if let Err(e) = upgrade(args.into()) {
+                error!("Failed to change the metadata version: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::ListJournal(args) => {
+            debug!("list-journal args={:?}", args);
+            if let Err(e) = list_journal(args.into()) {
+                error!("Failed to list the journal: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::List(args) => {
+            debug!("list args={:?}", args);
+            if let Err(e) = list_keys(args.into()) {
+                error!("Failed to list the btree: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Stat(args) => {
+            debug!("stat args={:?}", args);
+            if let Err(e) = stat(args.into()) {
+                error!("Failed to stat the file: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Cat(args) => {
+            debug!("cat args={:?}", args);
+            if let Err(e) = cat(args.into()) {
+                error!("Failed to read the file: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Restore(args) => {
+            debug!("restore args={:?}", args);
+            if let Err(e) = restore(args.into()) {
+                error!("Failed to restore: {}", e);
+                std::process::exit(1);
+            }
+        }
+        SubCommand::Device(args) => match args.subcmd {
+            DeviceCommand::Add(args) => {
+                debug!("device add args={:?}", args);
+                if let Err(e) = device_add(args.into()) {
+                    error!("Failed to add device: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            DeviceCommand::SetState(args) => {
+                debug!("device set-state args={:?}", args);
+                if let Err(e) = device_set_state(args.into()) {
+                    error!("Failed to set device state: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            DeviceCommand::Fail(args) => {
+                debug!("device fail args={:?}", args);
+                if let Err(e) = device_set_state(args.into()) {
+                    error!("Failed to fail device: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            DeviceCommand::Remove(args) => {
+                debug!("device remove args={:?}", args);
+                if let Err(e) = device_remove(args.into()) {
+                    error!("Failed to remove device: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            DeviceCommand::Resize(args) => {
+                debug!("device resize args={:?}", args);
+                if let Err(e) = device_resize(args.into()) {
+                    error!("Failed to resize device: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
     }
 }
@@ -0,0 +1,1029 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The size of an unpacked key
+pub const BKEY_BYTES: usize = bkey_offsets::P.end;
+/// The size of an unpacked key in u64s
+pub const BKEY_U64S: usize = BKEY_BYTES / 8;
+/// The size of a key format
+pub const BKEY_FORMAT_BYTES: usize = format_offsets::FIELD_OFFSET.end;
+/// The number of fields of a packed key
+pub const BKEY_NR_FIELDS: usize = 6;
+/// The format of keys packed with the format of their btree node
+pub const KEY_FORMAT_LOCAL_BTREE: u8 = 0;
+/// The format of unpacked keys
+pub const KEY_FORMAT_CURRENT: u8 = 1;
+/// The bits of the first u64 of a packed key taken by the key header
+const KEY_PACKED_BITS_START: u32 = 24;
+
+mod bkey_offsets {
+    use std::ops::Range;
+
+    pub const U64S: usize = 0;
+    pub const FORMAT: usize = 1;
+    pub const TYPE: usize = 2;
+    // 1 byte of padding
+    pub const VERSION_LO: Range<usize> = 4..12;
+    pub const VERSION_HI: Range<usize> = 12..16;
+    pub const SIZE: Range<usize> = 16..20;
+    pub const P: Range<usize> = 20..40;
+}
+
+/// The size of a position
+pub const BPOS_BYTES: usize = bpos_offsets::INODE.end;
+
+mod bpos_offsets {
+    use std::ops::Range;
+
+    pub const SNAPSHOT: Range<usize> = 0..4;
+    pub const OFFSET: Range<usize> = 4..12;
+    pub const INODE: Range<usize> = 12..20;
+}
+
+mod format_offsets {
+    use std::ops::Range;
+
+    pub const KEY_U64S: usize = 0;
+    pub const NR_FIELDS: usize = 1;
+    pub const BITS_PER_FIELD: Range<usize> = 2..8;
+    pub const FIELD_OFFSET: Range<usize> = 8..56;
+}
+
+/// A position in a btree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bpos {
+    /// The inode number
+    pub inode: u64,
+    /// The offset within the inode
+    pub offset: u64,
+    /// The snapshot id
+    pub snapshot: u32,
+}
+
+impl Bpos {
+    /// The smallest position
+    pub const MIN: Bpos = Bpos::new(0, 0, 0);
+    /// The largest position
+    pub const MAX: Bpos = Bpos::new(u64::MAX, u64::MAX, u32::MAX);
+
+    /// Create a position
+    pub const fn new(inode: u64, offset: u64, snapshot: u32) -> Bpos {
+        Bpos {
+            inode,
+            offset,
+            snapshot,
+        }
+    }
+
+    /// Decode a position as stored on disk
+    pub fn decode(buf: &[u8]) -> Result<Bpos> {
+        if buf.len() < BPOS_BYTES {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(Bpos {
+                inode: LittleEndian::read_u64(&buf[bpos_offsets::INODE]),
+                offset: LittleEndian::read_u64(&buf[bpos_offsets::OFFSET]),
+                snapshot: LittleEndian::read_u32(&buf[bpos_offsets::SNAPSHOT]),
+            })
+        }
+    }
+
+    /// Encode the position as stored on disk
+    pub fn encode(&self, buf: &mut [u8]) -> Result<()> {
+        if buf.len() < BPOS_BYTES {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u64(&mut buf[bpos_offsets::INODE], self.inode);
+            LittleEndian::write_u64(&mut buf[bpos_offsets::OFFSET], self.offset);
+            LittleEndian::write_u32(&mut buf[bpos_offsets::SNAPSHOT], self.snapshot);
+            Ok(())
+        }
+    }
+
+    /// The position right after this one, `None` for `Bpos::MAX`
+    pub fn successor(&self) -> Option<Bpos> {
+        if let Some(snapshot) = self.snapshot.checked_add(1) {
+            Some(Bpos { snapshot, ..*self })
+        } else if let Some(offset) = self.offset.checked_add(1) {
+            Some(Bpos::new(self.inode, offset, 0))
+        } else {
+            self.inode
+                .checked_add(1)
+                .map(|inode| Bpos::new(inode, 0, 0))
+        }
+    }
+}
+
+impl fmt::Display for Bpos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Bpos::MAX {
+            write!(f, "POS_MAX")
+        } else {
+            write!(f, "{}:{}:{}", self.inode, self.offset, self.snapshot)
+        }
+    }
+}
+
+impl FromStr for Bpos {
+    type Err = BchError;
+
+    /// Parse `inode:offset[:snapshot]`, `POS_MIN` or `POS_MAX`
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BchError::Einval(format!("invalid position: {}", s));
+        match s {
+            "POS_MIN" => return Ok(Bpos::MIN),
+            "POS_MAX" => return Ok(Bpos::MAX),
+            _ => (),
+        }
+
+        let fields = s
+            .split(':')
+            .map(|field| field.parse::<u64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        match fields[..] {
+            [inode, offset] => Ok(Bpos::new(inode, offset, 0)),
+            [inode, offset, snapshot] if snapshot <= u64::from(u32::MAX) => {
+                Ok(Bpos::new(inode, offset, snapshot as u32))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The version of a key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bversion {
+    /// The high 32 bits of the version
+    pub hi: u32,
+    /// The low 64 bits of the version
+    pub lo: u64,
+}
+
+impl fmt::Display for Bversion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.hi, self.lo)
+    }
+}
+
+/// The types of keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum KeyType {
+    /// A deleted key
+    Deleted = 0,
+    /// A discarded key, the whiteout of a key deleted in a snapshot
+    Discard = 1,
+    /// An error
+    Error = 2,
+    /// A cookie, used for testing
+    Cookie = 3,
+    /// A whiteout in a hashed btree
+    HashWhiteout = 4,
+    /// A pointer to a btree node
+    BtreePtr = 5,
+    /// An extent
+    Extent = 6,
+    /// A reservation of space
+    Reservation = 7,
+    /// An inode
+    Inode = 8,
+    /// The generation of a deleted inode
+    InodeGeneration = 9,
+    /// A directory entry
+    Dirent = 10,
+    /// An extended attribute
+    Xattr = 11,
+    /// Bucket allocation info
+    Alloc = 12,
+    /// A quota
+    Quota = 13,
+    /// An erasure coded stripe
+    Stripe = 14,
+    /// A pointer to a reflinked extent
+    ReflinkP = 15,
+    /// A reflinked extent
+    ReflinkV = 16,
+    /// Data stored inline
+    InlineData = 17,
+    /// A pointer to a btree node, with the node's min key
+    BtreePtrV2 = 18,
+    /// Reflinked data stored inline
+    IndirectInlineData = 19,
+    /// Bucket allocation info, varint encoded
+    AllocV2 = 20,
+    /// A subvolume
+    Subvolume = 21,
+    /// A node of the snapshot tree
+    Snapshot = 22,
+}
+
+const KEY_TYPE_NAMES: [&str; 23] = [
+    "deleted",
+    "discard",
+    "error",
+    "cookie",
+    "hash_whiteout",
+    "btree_ptr",
+    "extent",
+    "reservation",
+    "inode",
+    "inode_generation",
+    "dirent",
+    "xattr",
+    "alloc",
+    "quota",
+    "stripe",
+    "reflink_p",
+    "reflink_v",
+    "inline_data",
+    "btree_ptr_v2",
+    "indirect_inline_data",
+    "alloc_v2",
+    "subvolume",
+    "snapshot",
+];
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", KEY_TYPE_NAMES[*self as usize])
+    }
+}
+
+impl TryFrom<u8> for KeyType {
+    type Error = BchError;
+
+    fn try_from(ty: u8) -> Result<Self> {
+        use KeyType::*;
+
+        const TYPES: [KeyType; 23] = [
+            Deleted,
+            Discard,
+            Error,
+            Cookie,
+            HashWhiteout,
+            BtreePtr,
+            Extent,
+            Reservation,
+            Inode,
+            InodeGeneration,
+            Dirent,
+            Xattr,
+            Alloc,
+            Quota,
+            Stripe,
+            ReflinkP,
+            ReflinkV,
+            InlineData,
+            BtreePtrV2,
+            IndirectInlineData,
+            AllocV2,
+            Subvolume,
+            Snapshot,
+        ];
+        TYPES
+            .get(ty as usize)
+            .copied()
+            .ok_or_else(|| BchError::Einval(format!("unknown key type {}", ty)))
+    }
+}
+
+/// The fields of a packed key, in the order they are packed
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum BkeyField {
+    /// The inode of the position
+    Inode = 0,
+    /// The offset of the position
+    Offset = 1,
+    /// The snapshot of the position
+    Snapshot = 2,
+    /// The size of the key
+    Size = 3,
+    /// The high bits of the version
+    VersionHi = 4,
+    /// The low bits of the version
+    VersionLo = 5,
+}
+
+impl BkeyField {
+    /// All the fields in the order they are packed
+    pub const ALL: [BkeyField; BKEY_NR_FIELDS] = [
+        BkeyField::Inode,
+        BkeyField::Offset,
+        BkeyField::Snapshot,
+        BkeyField::Size,
+        BkeyField::VersionHi,
+        BkeyField::VersionLo,
+    ];
+
+    /// The number of bits of the field in an unpacked key
+    pub fn unpacked_bits(self) -> u32 {
+        match self {
+            BkeyField::Inode | BkeyField::Offset | BkeyField::VersionLo => 64,
+            BkeyField::Snapshot | BkeyField::Size | BkeyField::VersionHi => 32,
+        }
+    }
+}
+
+/// The largest value of `bits` bits
+fn max_value(bits: u32) -> u64 {
+    1u64.checked_shl(bits).unwrap_or(0).wrapping_sub(1)
+}
+
+/// A key, either packed or unpacked
+///
+/// The header of a key is the same whether it is packed or not, the rest of a
+/// packed key has to be unpacked with the format of its btree node first.
+pub struct Bkey<T> {
+    buffer: T,
+}
+
+impl<T> Bkey<T> {
+    /// Create a key view of the given bytes
+    pub fn from(buf: T) -> Bkey<T> {
+        Bkey { buffer: buf }
+    }
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for Bkey<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer.as_ref()
+    }
+}
+
+impl<T: AsRef<[u8]>> Bkey<T> {
+    /// The unpacked fields of the key
+    fn unpacked(&self) -> Result<&[u8]> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < BKEY_BYTES {
+            Err(BchError::Exhausted)
+        } else if self.is_packed()? {
+            Err(BchError::Einval("key is packed".to_string()))
+        } else {
+            Ok(buf)
+        }
+    }
+
+    /// The size of the key and its value in u64s
+    pub fn u64s(&self) -> Result<u8> {
+        let buf = self.buffer.as_ref();
+        if buf.is_empty() {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf[bkey_offsets::U64S])
+        }
+    }
+
+    /// The format of the key
+    pub fn format(&self) -> Result<u8> {
+        let buf = self.buffer.as_ref();
+        if buf.len() <= bkey_offsets::FORMAT {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf[bkey_offsets::FORMAT] & 0x7f)
+        }
+    }
+
+    /// Whether the key needs a whiteout when it is overwritten
+    pub fn needs_whiteout(&self) -> Result<bool> {
+        let buf = self.buffer.as_ref();
+        if buf.len() <= bkey_offsets::FORMAT {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf[bkey_offsets::FORMAT] & 0x80 != 0)
+        }
+    }
+
+    /// Whether the key is packed with the format of its btree node
+    pub fn is_packed(&self) -> Result<bool> {
+        Ok(self.format()? != KEY_FORMAT_CURRENT)
+    }
+
+    /// The raw type of the key, see `KeyType`
+    pub fn ty(&self) -> Result<u8> {
+        let buf = self.buffer.as_ref();
+        if buf.len() <= bkey_offsets::TYPE {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf[bkey_offsets::TYPE])
+        }
+    }
+
+    /// The version of an unpacked key
+    pub fn version(&self) -> Result<Bversion> {
+        let buf = self.unpacked()?;
+        Ok(Bversion {
+            hi: LittleEndian::read_u32(&buf[bkey_offsets::VERSION_HI]),
+            lo: LittleEndian::read_u64(&buf[bkey_offsets::VERSION_LO]),
+        })
+    }
+
+    /// The size of an unpacked key, in sectors for extents
+    pub fn size(&self) -> Result<u32> {
+        Ok(LittleEndian::read_u32(
+            &self.unpacked()?[bkey_offsets::SIZE],
+        ))
+    }
+
+    /// The position of an unpacked key
+    pub fn p(&self) -> Result<Bpos> {
+        Bpos::decode(&self.unpacked()?[bkey_offsets::P])
+    }
+
+    /// The position of the start of an unpacked key
+    ///
+    /// Extents are indexed by their end, other keys have a size of zero.
+    pub fn start(&self) -> Result<Bpos> {
+        let p = self.p()?;
+        Ok(Bpos {
+            offset: p.offset.saturating_sub(self.size()? as u64),
+            ..p
+        })
+    }
+
+    /// Whether the key is deleted, i.e. a whiteout
+    pub fn is_deleted(&self) -> Result<bool> {
+        let ty = self.ty()?;
+        Ok(ty == KeyType::Deleted as u8 || ty == KeyType::Discard as u8)
+    }
+}
+
+impl<T: AsMut<[u8]>> AsMut<[u8]> for Bkey<T> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut()
+    }
+}
+
+impl<T: AsMut<[u8]>> Bkey<T> {
+    /// The unpacked fields of the key
+    fn unpacked_mut(&mut self) -> Result<&mut [u8]> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < BKEY_BYTES {
+            Err(BchError::Exhausted)
+        } else if buf[bkey_offsets::FORMAT] & 0x7f != KEY_FORMAT_CURRENT {
+            Err(BchError::Einval("key is packed".to_string()))
+        } else {
+            Ok(buf)
+        }
+    }
+
+    /// Set the size of the key and its value in u64s
+    pub fn set_u64s(&mut self, u64s: u8) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.is_empty() {
+            Err(BchError::Exhausted)
+        } else {
+            buf[bkey_offsets::U64S] = u64s;
+            Ok(())
+        }
+    }
+
+    /// Set the format and whether the key needs a whiteout
+    pub fn set_format(&mut self, format: u8, needs_whiteout: bool) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() <= bkey_offsets::FORMAT {
+            Err(BchError::Exhausted)
+        } else if format > 0x7f {
+            Err(BchError::Einval(format!("invalid key format {}", format)))
+        } else {
+            buf[bkey_offsets::FORMAT] = format | if needs_whiteout { 0x80 } else { 0 };
+            Ok(())
+        }
+    }
+
+    /// Set the type of the key
+    pub fn set_ty(&mut self, ty: u8) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() <= bkey_offsets::TYPE {
+            Err(BchError::Exhausted)
+        } else {
+            buf[bkey_offsets::TYPE] = ty;
+            Ok(())
+        }
+    }
+
+    /// Set the version of an unpacked key
+    pub fn set_version(&mut self, version: Bversion) -> Result<()> {
+        let buf = self.unpacked_mut()?;
+        LittleEndian::write_u32(&mut buf[bkey_offsets::VERSION_HI], version.hi);
+        LittleEndian::write_u64(&mut buf[bkey_offsets::VERSION_LO], version.lo);
+        Ok(())
+    }
+
+    /// Set the size of an unpacked key
+    pub fn set_size(&mut self, size: u32) -> Result<()> {
+        LittleEndian::write_u32(&mut self.unpacked_mut()?[bkey_offsets::SIZE], size);
+        Ok(())
+    }
+
+    /// Set the position of an unpacked key
+    pub fn set_p(&mut self, p: Bpos) -> Result<()> {
+        p.encode(&mut self.unpacked_mut()?[bkey_offsets::P])
+    }
+}
+
+/// An unpacked key and its value
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedKey {
+    /// The unpacked key
+    pub key: [u8; BKEY_BYTES],
+    /// The value of the key
+    pub val: Vec<u8>,
+}
+
+impl OwnedKey {
+    /// A view of the key
+    pub fn bkey(&self) -> Bkey<&[u8]> {
+        Bkey::from(&self.key[..])
+    }
+
+    /// The raw type of the key
+    pub fn ty(&self) -> u8 {
+        self.key[bkey_offsets::TYPE]
+    }
+
+    /// The position of the key
+    pub fn p(&self) -> Bpos {
+        Bpos::decode(&self.key[bkey_offsets::P]).expect("unpacked key too small")
+    }
+}
+
+impl fmt::Display for OwnedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = self.bkey();
+        let ty = match KeyType::try_from(self.ty()) {
+            Ok(ty) => ty.to_string(),
+            Err(_) => self.ty().to_string(),
+        };
+        write!(
+            f,
+            "u64s {} type {} {} len {} ver {}",
+            key.u64s().map_err(|_| fmt::Error)?,
+            ty,
+            self.p(),
+            key.size().map_err(|_| fmt::Error)?,
+            key.version().map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+/// The format keys of a btree node are packed with
+///
+/// Each field is stored as its difference to the offset of the field, in the
+/// number of bits given for the field. Fields are packed from the most
+/// significant bit of the last u64 of the key down, after the key header in
+/// the least significant bits of the first u64.
+pub struct BkeyFormat<T> {
+    buffer: T,
+}
+
+impl<T> BkeyFormat<T> {
+    /// Create a key format view of the given bytes
+    pub fn from(buf: T) -> BkeyFormat<T> {
+        BkeyFormat { buffer: buf }
+    }
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for BkeyFormat<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer.as_ref()
+    }
+}
+
+/// The format of unpacked keys
+pub fn bkey_format_current() -> [u8; BKEY_FORMAT_BYTES] {
+    let mut buf = [0u8; BKEY_FORMAT_BYTES];
+    let mut format = BkeyFormat::from(&mut buf[..]);
+    format
+        .set_key_u64s(BKEY_U64S as u8)
+        .and_then(|_| format.set_nr_fields(BKEY_NR_FIELDS as u8))
+        .expect("key format buffer too small");
+    for field in BkeyField::ALL.iter() {
+        format
+            .set_field(*field, field.unpacked_bits() as u8, 0)
+            .expect("key format buffer too small");
+    }
+    buf
+}
+
+impl<T: AsRef<[u8]>> BkeyFormat<T> {
+    /// The size of a packed key in u64s
+    pub fn key_u64s(&self) -> Result<u8> {
+        let buf = self.buffer.as_ref();
+        if buf.is_empty() {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf[format_offsets::KEY_U64S])
+        }
+    }
+
+    /// The number of fields of a packed key
+    pub fn nr_fields(&self) -> Result<u8> {
+        let buf = self.buffer.as_ref();
+        if buf.len() <= format_offsets::NR_FIELDS {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf[format_offsets::NR_FIELDS])
+        }
+    }
+
+    /// The number of bits the given field is packed in
+    pub fn bits_per_field(&self, field: BkeyField) -> Result<u8> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < format_offsets::BITS_PER_FIELD.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf[format_offsets::BITS_PER_FIELD.start + field as usize])
+        }
+    }
+
+    /// The value added to the given field when unpacking it
+    pub fn field_offset(&self, field: BkeyField) -> Result<u64> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < format_offsets::FIELD_OFFSET.end {
+            Err(BchError::Exhausted)
+        } else {
+            let start = format_offsets::FIELD_OFFSET.start + (field as usize * 8);
+            Ok(LittleEndian::read_u64(&buf[start..(start + 8)]))
+        }
+    }
+
+    /// Check that every key of the format may be unpacked
+    pub fn validate(&self) -> Result<()> {
+        if self.nr_fields()? as usize != BKEY_NR_FIELDS {
+            return Err(BchError::Einval(format!(
+                "invalid key format: {} fields",
+                self.nr_fields()?
+            )));
+        }
+
+        let mut bits = KEY_PACKED_BITS_START;
+        for field in BkeyField::ALL.iter() {
+            let field_bits = self.bits_per_field(*field)? as u32;
+            let unpacked_max = max_value(field.unpacked_bits());
+            if field_bits > field.unpacked_bits()
+                || self.field_offset(*field)? > unpacked_max - max_value(field_bits)
+            {
+                return Err(BchError::Einval(format!(
+                    "invalid key format: field {:?} overflows",
+                    field
+                )));
+            }
+            bits += field_bits;
+        }
+
+        let key_u64s = self.key_u64s()? as u32;
+        if key_u64s == 0 || bits > key_u64s * 64 {
+            return Err(BchError::Einval(format!(
+                "invalid key format: {} bits in {} u64s",
+                bits, key_u64s
+            )));
+        }
+        Ok(())
+    }
+
+    /// The size in bytes of the given key, packed or not, without its value
+    pub fn key_bytes(&self, key: &[u8]) -> Result<usize> {
+        if Bkey::from(key).is_packed()? {
+            Ok(self.key_u64s()? as usize * 8)
+        } else {
+            Ok(BKEY_BYTES)
+        }
+    }
+
+    /// The value of the given key, packed or not
+    pub fn val<'a>(&self, key: &'a [u8]) -> Result<&'a [u8]> {
+        let start = self.key_bytes(key)?;
+        let end = Bkey::from(key).u64s()? as usize * 8;
+        if end < start {
+            Err(BchError::Einval(format!(
+                "key of {} bytes smaller than its header",
+                end
+            )))
+        } else if key.len() < end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(&key[start..end])
+        }
+    }
+
+    /// Unpack the given key along with its value
+    pub fn unpack_key(&self, key: &[u8]) -> Result<OwnedKey> {
+        Ok(OwnedKey {
+            key: self.unpack(key)?,
+            val: self.val(key)?.to_vec(),
+        })
+    }
+
+    /// Unpack the given key, the key is copied if it is not packed
+    ///
+    /// Only the key is unpacked, the value that follows it is left out but
+    /// still accounted for in the u64s of the unpacked key.
+    pub fn unpack(&self, key: &[u8]) -> Result<[u8; BKEY_BYTES]> {
+        let packed = Bkey::from(key);
+        let mut out = [0u8; BKEY_BYTES];
+
+        if !packed.is_packed()? {
+            if key.len() < BKEY_BYTES {
+                return Err(BchError::Exhausted);
+            }
+            out.copy_from_slice(&key[..BKEY_BYTES]);
+            return Ok(out);
+        }
+        if packed.format()? != KEY_FORMAT_LOCAL_BTREE {
+            return Err(BchError::Einval(format!(
+                "unknown key format {}",
+                packed.format()?
+            )));
+        }
+
+        let key_u64s = self.key_u64s()? as usize;
+        if key.len() < key_u64s * 8 {
+            return Err(BchError::Exhausted);
+        }
+        let val_u64s = (packed.u64s()? as usize)
+            .checked_sub(key_u64s)
+            .ok_or_else(|| BchError::Einval("packed key smaller than its format".to_string()))?;
+
+        let mut state = UnpackState {
+            key,
+            word: key_u64s - 1,
+            w: LittleEndian::read_u64(&key[((key_u64s - 1) * 8)..(key_u64s * 8)]),
+            bits: 64,
+        };
+        let mut fields = [0u64; BKEY_NR_FIELDS];
+        for (i, field) in BkeyField::ALL.iter().enumerate() {
+            let v = state.next(self.bits_per_field(*field)? as u32)?;
+            fields[i] = v.wrapping_add(self.field_offset(*field)?);
+        }
+
+        let mut unpacked = Bkey::from(&mut out[..]);
+        unpacked.set_u64s((BKEY_U64S + val_u64s) as u8)?;
+        unpacked.set_format(KEY_FORMAT_CURRENT, packed.needs_whiteout()?)?;
+        unpacked.set_ty(packed.ty()?)?;
+        unpacked.set_p(Bpos::new(fields[0], fields[1], fields[2] as u32))?;
+        unpacked.set_size(fields[3] as u32)?;
+        unpacked.set_version(Bversion {
+            hi: fields[4] as u32,
+            lo: fields[5],
+        })?;
+        Ok(out)
+    }
+
+    /// Pack the given unpacked key, `None` if it does not fit the format
+    ///
+    /// Only the key is packed, the value has to be appended by the caller.
+    pub fn pack(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let unpacked = Bkey::from(key);
+        let p = unpacked.p()?;
+        let version = unpacked.version()?;
+        let fields = [
+            p.inode,
+            p.offset,
+            p.snapshot as u64,
+            unpacked.size()? as u64,
+            version.hi as u64,
+            version.lo,
+        ];
+
+        let key_u64s = self.key_u64s()? as usize;
+        let mut out = vec![0u8; key_u64s * 8];
+        let mut word = key_u64s - 1;
+        let mut w = 0u64;
+        let mut left = 64;
+        for (field, v) in BkeyField::ALL.iter().zip(fields.iter()) {
+            let bits = self.bits_per_field(*field)? as u32;
+            let v = match v.checked_sub(self.field_offset(*field)?) {
+                Some(v) if v <= max_value(bits) => v,
+                _ => return Ok(None),
+            };
+
+            let mut bits = bits;
+            if bits > left {
+                bits -= left;
+                w |= v.checked_shr(bits).unwrap_or(0);
+                LittleEndian::write_u64(&mut out[(word * 8)..((word + 1) * 8)], w);
+                word = word
+                    .checked_sub(1)
+                    .ok_or_else(|| BchError::Einval("key format too small".to_string()))?;
+                w = 0;
+                left = 64;
+            }
+            left -= bits;
+            w |= v.checked_shl(left).unwrap_or(0);
+        }
+        LittleEndian::write_u64(&mut out[(word * 8)..((word + 1) * 8)], w);
+
+        let val_u64s = (unpacked.u64s()? as usize).saturating_sub(BKEY_U64S);
+        let mut packed = Bkey::from(&mut out[..]);
+        packed.set_u64s((key_u64s + val_u64s) as u8)?;
+        packed.set_format(KEY_FORMAT_LOCAL_BTREE, unpacked.needs_whiteout()?)?;
+        packed.set_ty(unpacked.ty()?)?;
+        Ok(Some(out))
+    }
+}
+
+/// The state of unpacking the fields of a packed key
+struct UnpackState<'a> {
+    key: &'a [u8],
+    /// The index of the current u64
+    word: usize,
+    /// What is left of the current u64, in its most significant bits
+    w: u64,
+    /// The number of bits left in the current u64
+    bits: u32,
+}
+
+impl<'a> UnpackState<'a> {
+    /// Take the next field of the given number of bits
+    fn next(&mut self, bits: u32) -> Result<u64> {
+        let mut bits = bits;
+        let mut v = 0;
+
+        if bits >= self.bits {
+            v = self.w.checked_shr(64 - bits).unwrap_or(0);
+            bits -= self.bits;
+
+            self.word = self
+                .word
+                .checked_sub(1)
+                .ok_or_else(|| BchError::Einval("packed key overflows its format".to_string()))?;
+            self.w = LittleEndian::read_u64(&self.key[(self.word * 8)..((self.word + 1) * 8)]);
+            self.bits = 64;
+        }
+
+        v |= (self.w >> 1) >> (63 - bits);
+        self.w = self.w.checked_shl(bits).unwrap_or(0);
+        self.bits -= bits;
+        Ok(v)
+    }
+}
+
+impl<T: AsMut<[u8]>> AsMut<[u8]> for BkeyFormat<T> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut()
+    }
+}
+
+impl<T: AsMut<[u8]>> BkeyFormat<T> {
+    /// Set the size of a packed key in u64s
+    pub fn set_key_u64s(&mut self, key_u64s: u8) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.is_empty() {
+            Err(BchError::Exhausted)
+        } else {
+            buf[format_offsets::KEY_U64S] = key_u64s;
+            Ok(())
+        }
+    }
+
+    /// Set the number of fields of a packed key
+    pub fn set_nr_fields(&mut self, nr_fields: u8) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() <= format_offsets::NR_FIELDS {
+            Err(BchError::Exhausted)
+        } else {
+            buf[format_offsets::NR_FIELDS] = nr_fields;
+            Ok(())
+        }
+    }
+
+    /// Set the number of bits and the offset of the given field
+    pub fn set_field(&mut self, field: BkeyField, bits: u8, offset: u64) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < format_offsets::FIELD_OFFSET.end {
+            Err(BchError::Exhausted)
+        } else {
+            buf[format_offsets::BITS_PER_FIELD.start + field as usize] = bits;
+            let start = format_offsets::FIELD_OFFSET.start + (field as usize * 8);
+            LittleEndian::write_u64(&mut buf[start..(start + 8)], offset);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_bkey {
+    use super::*;
+
+    fn key(p: Bpos, size: u32, version: Bversion, val_u64s: u8) -> [u8; BKEY_BYTES] {
+        let mut buf = [0u8; BKEY_BYTES];
+        let mut key = Bkey::from(&mut buf[..]);
+        key.set_u64s(BKEY_U64S as u8 + val_u64s).unwrap();
+        key.set_format(KEY_FORMAT_CURRENT, false).unwrap();
+        key.set_ty(KeyType::Extent as u8).unwrap();
+        key.set_p(p).unwrap();
+        key.set_size(size).unwrap();
+        key.set_version(version).unwrap();
+        buf
+    }
+
+    /// A format of one u64: 8 bits of inode, 16 bits of offset, 8 bits of size
+    fn small_format() -> [u8; BKEY_FORMAT_BYTES] {
+        let mut buf = [0u8; BKEY_FORMAT_BYTES];
+        let mut format = BkeyFormat::from(&mut buf[..]);
+        format.set_key_u64s(1).unwrap();
+        format.set_nr_fields(BKEY_NR_FIELDS as u8).unwrap();
+        format.set_field(BkeyField::Inode, 8, 0x1000).unwrap();
+        format.set_field(BkeyField::Offset, 16, 0).unwrap();
+        format.set_field(BkeyField::Size, 8, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn unpack_packed_key() {
+        let format_buf = small_format();
+        let format = BkeyFormat::from(&format_buf[..]);
+        format.validate().unwrap();
+
+        let packed = [0x01, 0x00, 0x06, 0x00, 0x08, 0xcd, 0xab, 0x12];
+        let unpacked = format.unpack(&packed).unwrap();
+        let key = Bkey::from(&unpacked[..]);
+        assert!(!key.is_packed().unwrap());
+        assert_eq!(key.u64s().unwrap(), BKEY_U64S as u8);
+        assert_eq!(
+            KeyType::try_from(key.ty().unwrap()).unwrap(),
+            KeyType::Extent
+        );
+        assert_eq!(key.p().unwrap(), Bpos::new(0x1012, 0xabcd, 0));
+        assert_eq!(key.size().unwrap(), 8);
+        assert_eq!(key.start().unwrap(), Bpos::new(0x1012, 0xabc5, 0));
+        assert_eq!(key.version().unwrap(), Bversion::default());
+        assert!(Bkey::from(&packed[..]).p().is_err());
+
+        let repacked = format.pack(&unpacked).unwrap().unwrap();
+        assert_eq!(repacked, packed);
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let version = Bversion { hi: 7, lo: 1 << 40 };
+        let unpacked = key(Bpos::new(4096, 1 << 33, u32::MAX), 128, version, 3);
+
+        let current = bkey_format_current();
+        let format = BkeyFormat::from(&current[..]);
+        format.validate().unwrap();
+        let packed = format.pack(&unpacked).unwrap().unwrap();
+        assert_eq!(Bkey::from(&packed[..]).u64s().unwrap(), BKEY_U64S as u8 + 3);
+        assert_eq!(format.unpack(&packed).unwrap(), unpacked);
+        assert_eq!(format.unpack(&unpacked).unwrap(), unpacked);
+
+        let small = small_format();
+        let format = BkeyFormat::from(&small[..]);
+        assert!(format.pack(&unpacked).unwrap().is_none());
+        let fits = key(Bpos::new(0x10ff, 3, 0), 1, Bversion::default(), 2);
+        let packed = format.pack(&fits).unwrap().unwrap();
+        assert_eq!(packed.len(), 8);
+        assert_eq!(
+            format
+                .val(&[&packed[..], &[0u8; 16]].concat())
+                .unwrap()
+                .len(),
+            16
+        );
+        assert_eq!(format.unpack(&packed).unwrap(), fits);
+
+        let owned = format
+            .unpack_key(&[&packed[..], &[7u8; 16]].concat())
+            .unwrap();
+        assert_eq!(owned.p(), Bpos::new(0x10ff, 3, 0));
+        assert_eq!(owned.val, vec![7u8; 16]);
+    }
+
+    #[test]
+    fn invalid_formats() {
+        let mut buf = small_format();
+        let mut format = BkeyFormat::from(&mut buf[..]);
+        format.set_field(BkeyField::Snapshot, 33, 0).unwrap();
+        assert!(format.validate().is_err());
+        format
+            .set_field(BkeyField::Snapshot, 8, u32::MAX as u64)
+            .unwrap();
+        assert!(format.validate().is_err());
+        format.set_field(BkeyField::Snapshot, 8, 0).unwrap();
+        format.validate().unwrap();
+        format.set_field(BkeyField::VersionLo, 64, 0).unwrap();
+        assert!(format.validate().is_err());
+    }
+
+    #[test]
+    fn bpos_order() {
+        assert!(Bpos::new(1, 0, 0) > Bpos::new(0, u64::MAX, u32::MAX));
+        assert_eq!(
+            Bpos::new(1, 2, u32::MAX).successor(),
+            Some(Bpos::new(1, 3, 0))
+        );
+        assert_eq!(Bpos::MAX.successor(), None);
+        assert_eq!(Bpos::new(1, 2, 3).to_string(), "1:2:3");
+        assert_eq!("1:2:3".parse::<Bpos>().unwrap(), Bpos::new(1, 2, 3));
+        assert_eq!("4096:0".parse::<Bpos>().unwrap(), Bpos::new(4096, 0, 0));
+        assert_eq!("POS_MAX".parse::<Bpos>().unwrap(), Bpos::MAX);
+        assert!("1:2:3:4".parse::<Bpos>().is_err());
+        assert!("1:2:4294967296".parse::<Bpos>().is_err());
+    }
+}
@@ -0,0 +1,487 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::zoned::{self, Zone, ZoneModel};
+use crate::{BchError, Result};
+
+use log::debug;
+use nix::fcntl::{fallocate, FallocateFlags};
+use nix::{ioctl_read, ioctl_read_bad, ioctl_write_ptr_bad, request_code_none};
+
+const BLK_IOC_MAGIC: u8 = 0x12;
+
+const BLKSSZGET_IOC_TYPE_MODE: u8 = 104;
+const BLKGETSIZE64_IOC_TYPE_MODE: u8 = 114;
+const BLKDISCARD_IOC_TYPE_MODE: u8 = 119;
+const BLKIOMIN_IOC_TYPE_MODE: u8 = 120;
+const BLKIOOPT_IOC_TYPE_MODE: u8 = 121;
+const BLKALIGNOFF_IOC_TYPE_MODE: u8 = 122;
+const BLKPBSZGET_IOC_TYPE_MODE: u8 = 123;
+
+ioctl_read_bad!(
+    blksszget,
+    request_code_none!(BLK_IOC_MAGIC, BLKSSZGET_IOC_TYPE_MODE),
+    libc::c_int
+);
+ioctl_read!(blkgetsize64, BLK_IOC_MAGIC, BLKGETSIZE64_IOC_TYPE_MODE, u64);
+ioctl_write_ptr_bad!(
+    blkdiscard,
+    request_code_none!(BLK_IOC_MAGIC, BLKDISCARD_IOC_TYPE_MODE),
+    [u64; 2]
+);
+ioctl_read_bad!(
+    blkiomin,
+    request_code_none!(BLK_IOC_MAGIC, BLKIOMIN_IOC_TYPE_MODE),
+    libc::c_uint
+);
+ioctl_read_bad!(
+    blkioopt,
+    request_code_none!(BLK_IOC_MAGIC, BLKIOOPT_IOC_TYPE_MODE),
+    libc::c_uint
+);
+ioctl_read_bad!(
+    blkalignoff,
+    request_code_none!(BLK_IOC_MAGIC, BLKALIGNOFF_IOC_TYPE_MODE),
+    libc::c_int
+);
+ioctl_read_bad!(
+    blkpbszget,
+    request_code_none!(BLK_IOC_MAGIC, BLKPBSZGET_IOC_TYPE_MODE),
+    libc::c_uint
+);
+
+/// The I/O topology of a device, all values in bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Topology {
+    /// The smallest unit the device can address
+    pub logical_block_size: u64,
+    /// The smallest unit the device can write without a read-modify-write
+    pub physical_block_size: u64,
+    /// The preferred minimum I/O size
+    pub io_min: u64,
+    /// The optimal I/O size (e.g. the stripe width of a RAID), or zero
+    pub io_opt: u64,
+    /// The offset of the device from its natural alignment
+    pub alignment_offset: u64,
+}
+
+impl Topology {
+    /// The topology of a plain device with the given sector sizes
+    pub fn new(logical_block_size: u64, physical_block_size: u64) -> Topology {
+        Topology {
+            logical_block_size,
+            physical_block_size,
+            io_min: physical_block_size,
+            io_opt: 0,
+            alignment_offset: 0,
+        }
+    }
+}
+
+/// A device a filesystem can be stored on
+///
+/// All offsets and lengths are in bytes.
+pub trait BlockDevice {
+    /// The size of the device
+    fn size(&self) -> Result<u64>;
+
+    /// The smallest unit the device can address
+    fn logical_block_size(&self) -> Result<u64>;
+
+    /// The smallest unit the device can write without a read-modify-write
+    fn physical_block_size(&self) -> Result<u64>;
+
+    /// Fill `buf` with the data at `offset`
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+
+    /// Write all of `buf` at `offset`
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()>;
+
+    /// Make sure all written data reached stable storage
+    fn flush(&self) -> Result<()>;
+
+    /// Tell the device the given range no longer holds any data
+    fn discard(&self, offset: u64, len: u64) -> Result<()>;
+
+    /// The full I/O topology of the device
+    fn topology(&self) -> Result<Topology> {
+        Ok(Topology::new(
+            self.logical_block_size()?,
+            self.physical_block_size()?,
+        ))
+    }
+
+    /// The zone model of the device
+    fn zone_model(&self) -> Result<ZoneModel> {
+        Ok(ZoneModel::None)
+    }
+
+    /// Report every zone of the device, in order
+    fn report_zones(&self) -> Result<Vec<Zone>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Check that an I/O of `len` bytes at `offset` fits on a device of `size` bytes
+fn check_range(size: u64, offset: u64, len: u64) -> Result<()> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(BchError::Einval(format!(
+            "I/O of {} bytes at offset {} past the end of the device ({} bytes)",
+            len, offset, size
+        ))),
+    }
+}
+
+/// A block device node, e.g. `/dev/sda1`
+#[derive(Debug)]
+pub struct BlockDev {
+    file: File,
+}
+
+impl BlockDev {
+    /// Wrap an open block device node
+    pub fn new(file: File) -> BlockDev {
+        BlockDev { file }
+    }
+}
+
+impl BlockDevice for BlockDev {
+    fn size(&self) -> Result<u64> {
+        let mut size = 0u64;
+        unsafe { blkgetsize64(self.file.as_raw_fd(), &mut size)? };
+        Ok(size)
+    }
+
+    fn logical_block_size(&self) -> Result<u64> {
+        let mut size = 0;
+        unsafe { blksszget(self.file.as_raw_fd(), &mut size)? };
+        Ok(size as u64)
+    }
+
+    fn physical_block_size(&self) -> Result<u64> {
+        let mut size = 0;
+        unsafe { blkpbszget(self.file.as_raw_fd(), &mut size)? };
+        Ok(u64::from(size))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        Ok(self.file.read_exact_at(buf, offset)?)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        check_range(self.size()?, offset, buf.len() as u64)?;
+        Ok(self.file.write_all_at(buf, offset)?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> Result<()> {
+        let range = [offset, len];
+        unsafe { blkdiscard(self.file.as_raw_fd(), &range)? };
+        Ok(())
+    }
+
+    fn topology(&self) -> Result<Topology> {
+        let fd = self.file.as_raw_fd();
+        let mut io_min = 0;
+        let mut io_opt = 0;
+        let mut alignment_offset = 0;
+        unsafe {
+            blkiomin(fd, &mut io_min)?;
+            blkioopt(fd, &mut io_opt)?;
+            blkalignoff(fd, &mut alignment_offset)?;
+        }
+        // A negative alignment offset means the device is not aligned at all
+        Ok(Topology {
+            logical_block_size: self.logical_block_size()?,
+            physical_block_size: self.physical_block_size()?,
+            io_min: u64::from(io_min),
+            io_opt: u64::from(io_opt),
+            alignment_offset: cmp::max(alignment_offset, 0) as u64,
+        })
+    }
+
+    fn zone_model(&self) -> Result<ZoneModel> {
+        zoned::zone_model(&self.file)
+    }
+
+    fn report_zones(&self) -> Result<Vec<Zone>> {
+        zoned::report_zones(&self.file)
+    }
+}
+
+/// A regular file holding a filesystem image
+#[derive(Debug)]
+pub struct ImageFile {
+    file: File,
+}
+
+impl ImageFile {
+    /// Wrap an open image file
+    pub fn new(file: File) -> ImageFile {
+        ImageFile { file }
+    }
+}
+
+impl BlockDevice for ImageFile {
+    fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.st_size())
+    }
+
+    fn logical_block_size(&self) -> Result<u64> {
+        Ok(512)
+    }
+
+    fn physical_block_size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.st_blksize())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        Ok(self.file.read_exact_at(buf, offset)?)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        // writing past the end would silently grow the image
+        check_range(self.size()?, offset, buf.len() as u64)?;
+        Ok(self.file.write_all_at(buf, offset)?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> Result<()> {
+        fallocate(
+            self.file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )?;
+        Ok(())
+    }
+}
+
+/// A device backed by memory, mostly useful for testing
+#[derive(Debug)]
+pub struct MemoryDevice {
+    data: RefCell<Vec<u8>>,
+    topology: Topology,
+    zones: Option<(ZoneModel, Vec<Zone>)>,
+}
+
+impl MemoryDevice {
+    /// Create a zeroed device of `size` bytes with 512 byte sectors
+    pub fn new(size: usize) -> MemoryDevice {
+        MemoryDevice {
+            data: RefCell::new(vec![0u8; size]),
+            topology: Topology::new(512, 512),
+            zones: None,
+        }
+    }
+
+    /// Report the given topology for the device
+    pub fn with_topology(mut self, topology: Topology) -> MemoryDevice {
+        self.topology = topology;
+        self
+    }
+
+    /// Emulate a zoned device with the given zones
+    pub fn with_zones(mut self, model: ZoneModel, zones: Vec<Zone>) -> MemoryDevice {
+        self.zones = Some((model, zones));
+        self
+    }
+
+    /// The current contents of the device
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data.into_inner()
+    }
+}
+
+impl BlockDevice for MemoryDevice {
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.borrow().len() as u64)
+    }
+
+    fn logical_block_size(&self) -> Result<u64> {
+        Ok(self.topology.logical_block_size)
+    }
+
+    fn physical_block_size(&self) -> Result<u64> {
+        Ok(self.topology.physical_block_size)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        check_range(self.size()?, offset, buf.len() as u64)?;
+        let start = offset as usize;
+        buf.copy_from_slice(&self.data.borrow()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        check_range(self.size()?, offset, buf.len() as u64)?;
+        let start = offset as usize;
+        self.data.borrow_mut()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> Result<()> {
+        check_range(self.size()?, offset, len)?;
+        let start = offset as usize;
+        for byte in self.data.borrow_mut()[start..start + len as usize].iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    fn topology(&self) -> Result<Topology> {
+        Ok(self.topology)
+    }
+
+    fn zone_model(&self) -> Result<ZoneModel> {
+        Ok(self.zones.as_ref().map_or(ZoneModel::None, |zones| zones.0))
+    }
+
+    fn report_zones(&self) -> Result<Vec<Zone>> {
+        Ok(self
+            .zones
+            .as_ref()
+            .map_or_else(Vec::new, |zones| zones.1.clone()))
+    }
+}
+
+/// Open the device or image file at `path`
+///
+/// Block devices opened for writing are opened exclusively, which fails if
+/// the device is mounted or otherwise claimed by the kernel.
+pub fn open_device(path: &str, writable: bool) -> Result<Box<dyn BlockDevice>> {
+    let is_block_device = fs::metadata(path)?.file_type().is_block_device();
+    let mut options = OpenOptions::new();
+
+    options.read(true).write(writable);
+    if writable && is_block_device {
+        options.custom_flags(libc::O_EXCL);
+    }
+
+    let file = match options.open(path) {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+            return Err(BchError::Str(format!(
+                "{}: device is mounted or in use",
+                path
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if is_block_device {
+        debug!("{}: opened block device", path);
+        Ok(Box::new(BlockDev::new(file)))
+    } else {
+        debug!("{}: opened image file", path);
+        Ok(Box::new(ImageFile::new(file)))
+    }
+}
+
+/// The loop devices backed by the given file
+fn loop_devices(file: &Path) -> Result<Vec<PathBuf>> {
+    let mut devices = Vec::new();
+
+    let entries = match fs::read_dir("/sys/block") {
+        Ok(entries) => entries,
+        Err(_) => return Ok(devices),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let backing = entry.path().join("loop/backing_file");
+        if let Ok(backing) = fs::read_to_string(backing) {
+            if Path::new(backing.trim()) == file {
+                devices.push(Path::new("/dev").join(entry.file_name()));
+            }
+        }
+    }
+
+    Ok(devices)
+}
+
+/// Check if the device or image file at `path` is part of a mounted filesystem
+pub fn is_mounted(path: &str) -> Result<bool> {
+    let path = fs::canonicalize(path)?;
+    let mut candidates = vec![path.clone()];
+
+    if !fs::metadata(&path)?.file_type().is_block_device() {
+        candidates.extend(loop_devices(&path)?);
+    }
+
+    let mounts = fs::read_to_string("/proc/mounts")?;
+    for line in mounts.lines() {
+        let source = match line.split_whitespace().next() {
+            Some(source) => source.replace("\\040", " "),
+            None => continue,
+        };
+        // multi device bcachefs filesystems are mounted as dev1:dev2:...
+        for dev in source.split(':') {
+            if let Ok(dev) = fs::canonicalize(dev) {
+                if candidates.contains(&dev) {
+                    debug!("{}: mounted as part of {}", path.display(), source);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test_block_dev {
+    use super::*;
+
+    #[test]
+    fn memory_read_write() {
+        let dev = MemoryDevice::new(4096);
+        assert_eq!(dev.size().unwrap(), 4096);
+
+        dev.write_at(b"bcachefs", 1020).unwrap();
+        let mut buf = [0u8; 12];
+        dev.read_at(&mut buf, 1018).unwrap();
+        assert_eq!(&buf, b"\0\0bcachefs\0\0");
+
+        dev.discard(1024, 512).unwrap();
+        dev.read_at(&mut buf, 1018).unwrap();
+        assert_eq!(&buf, b"\0\0bcac\0\0\0\0\0\0");
+    }
+
+    #[test]
+    fn memory_out_of_range() {
+        let dev = MemoryDevice::new(4096);
+        let mut buf = [0u8; 512];
+        assert!(dev.read_at(&mut buf, 3840).is_err());
+        assert!(dev.write_at(&buf, 3585).is_err());
+        assert!(dev.write_at(&buf, u64::MAX).is_err());
+        assert!(dev.discard(4096, 1).is_err());
+    }
+
+    #[test]
+    fn memory_topology() {
+        let dev = MemoryDevice::new(4096);
+        assert_eq!(dev.topology().unwrap(), Topology::new(512, 512));
+
+        let dev = dev.with_topology(Topology::new(512, 4096));
+        assert_eq!(dev.logical_block_size().unwrap(), 512);
+        assert_eq!(dev.physical_block_size().unwrap(), 4096);
+        assert_eq!(dev.topology().unwrap().io_min, 4096);
+    }
+}
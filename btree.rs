@@ -0,0 +1,966 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::bkey::{Bkey, BkeyFormat, Bpos, KeyType, OwnedKey, BKEY_FORMAT_BYTES};
+use crate::block_dev::BlockDevice;
+use crate::checksum::{checksum, CsumType, Nonce, NONCE_BTREE};
+use crate::crypt::{chacha20, CHACHA_BLOCK_BYTES, KEY_BYTES};
+use crate::extents::ExtentPtr;
+use crate::super_block::{Field, SuperBlock};
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::warn;
+
+/// The magic of bsets, xored with the start of the filesystem uuid
+const BSET_MAGIC: u64 = 0x9013_5c78_b99e_07f5;
+/// The size of the btree node header, the first bset header included
+pub const BTREE_NODE_BYTES: usize = node_offsets::KEYS.end;
+/// The size of the header of a later bset written to a btree node
+pub const BTREE_NODE_ENTRY_BYTES: usize = entry_offsets::KEYS.end;
+/// The size of a bset header
+pub const BSET_BYTES: usize = bset_offsets::U64S.end;
+
+mod node_offsets {
+    use std::ops::Range;
+
+    pub const CSUM: Range<usize> = 0..16;
+    pub const MAGIC: Range<usize> = 16..24;
+    pub const FLAGS: Range<usize> = 24..32;
+    pub const MIN_KEY: Range<usize> = 32..52;
+    pub const MAX_KEY: Range<usize> = 52..72;
+    // 8 bytes of an obsolete extent pointer
+    pub const FORMAT: Range<usize> = 80..136;
+    pub const KEYS: Range<usize> = 136..160;
+}
+
+mod entry_offsets {
+    use std::ops::Range;
+
+    pub const CSUM: Range<usize> = 0..16;
+    pub const KEYS: Range<usize> = 16..40;
+}
+
+mod bset_offsets {
+    use std::ops::Range;
+
+    pub const SEQ: Range<usize> = 0..8;
+    pub const JOURNAL_SEQ: Range<usize> = 8..16;
+    pub const FLAGS: Range<usize> = 16..20;
+    pub const VERSION: Range<usize> = 20..22;
+    pub const U64S: Range<usize> = 22..24;
+}
+
+/// The btrees of a filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum BtreeId {
+    /// File data extents
+    Extents = 0,
+    /// Inodes
+    Inodes = 1,
+    /// Directory entries
+    Dirents = 2,
+    /// Extended attributes
+    Xattrs = 3,
+    /// Bucket allocation info
+    Alloc = 4,
+    /// Quotas
+    Quotas = 5,
+    /// Erasure coded stripes
+    Stripes = 6,
+    /// Reflinked extents
+    Reflink = 7,
+    /// Subvolumes
+    Subvolumes = 8,
+    /// The snapshot tree
+    Snapshots = 9,
+}
+
+impl BtreeId {
+    /// All the btrees
+    pub const ALL: [BtreeId; 10] = [
+        BtreeId::Extents,
+        BtreeId::Inodes,
+        BtreeId::Dirents,
+        BtreeId::Xattrs,
+        BtreeId::Alloc,
+        BtreeId::Quotas,
+        BtreeId::Stripes,
+        BtreeId::Reflink,
+        BtreeId::Subvolumes,
+        BtreeId::Snapshots,
+    ];
+
+    /// The name of the btree
+    pub fn name(self) -> &'static str {
+        match self {
+            BtreeId::Extents => "extents",
+            BtreeId::Inodes => "inodes",
+            BtreeId::Dirents => "dirents",
+            BtreeId::Xattrs => "xattrs",
+            BtreeId::Alloc => "alloc",
+            BtreeId::Quotas => "quotas",
+            BtreeId::Stripes => "stripes",
+            BtreeId::Reflink => "reflink",
+            BtreeId::Subvolumes => "subvolumes",
+            BtreeId::Snapshots => "snapshots",
+        }
+    }
+}
+
+impl fmt::Display for BtreeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for BtreeId {
+    type Err = BchError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        BtreeId::ALL
+            .iter()
+            .copied()
+            .find(|id| id.name() == s)
+            .ok_or_else(|| BchError::Einval(format!("unknown btree: {}", s)))
+    }
+}
+
+impl TryFrom<u64> for BtreeId {
+    type Error = BchError;
+
+    fn try_from(id: u64) -> Result<Self> {
+        BtreeId::ALL
+            .get(id as usize)
+            .copied()
+            .ok_or_else(|| BchError::Einval(format!("unknown btree id: {}", id)))
+    }
+}
+
+/// The magic of the bsets of a filesystem
+pub fn bset_magic(sb: &[u8]) -> Result<u64> {
+    let uuid = SuperBlock::from(sb).uuid()?;
+    Ok(LittleEndian::read_u64(&uuid.as_bytes()[..8]) ^ BSET_MAGIC)
+}
+
+/// A set of sorted keys written to a btree node at once
+pub struct Bset<T> {
+    buffer: T,
+}
+
+impl<T> Bset<T> {
+    /// Create a bset view of the given bytes
+    pub fn from(buf: T) -> Bset<T> {
+        Bset { buffer: buf }
+    }
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for Bset<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer.as_ref()
+    }
+}
+
+impl<T: AsRef<[u8]>> Bset<T> {
+    /// The sequence number of the btree node the bset was written to
+    pub fn seq(&self) -> Result<u64> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < bset_offsets::SEQ.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u64(&buf[bset_offsets::SEQ]))
+        }
+    }
+
+    /// The newest journal sequence number of the keys of the bset
+    pub fn journal_seq(&self) -> Result<u64> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < bset_offsets::JOURNAL_SEQ.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u64(&buf[bset_offsets::JOURNAL_SEQ]))
+        }
+    }
+
+    /// The raw flags of the bset
+    fn flags(&self) -> Result<u32> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < bset_offsets::FLAGS.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u32(&buf[bset_offsets::FLAGS]))
+        }
+    }
+
+    /// The checksum type of the bset
+    pub fn csum_type(&self) -> Result<CsumType> {
+        CsumType::try_from(u64::from(self.flags()? & 0xf))
+    }
+
+    /// Whether the bset was written on a big endian machine
+    pub fn big_endian(&self) -> Result<bool> {
+        Ok(self.flags()? & (1 << 4) != 0)
+    }
+
+    /// The metadata version the bset was written with
+    pub fn version(&self) -> Result<u16> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < bset_offsets::VERSION.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u16(&buf[bset_offsets::VERSION]))
+        }
+    }
+
+    /// The size of the keys of the bset in u64s
+    pub fn u64s(&self) -> Result<u16> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < bset_offsets::U64S.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u16(&buf[bset_offsets::U64S]))
+        }
+    }
+
+    /// The packed keys following the bset header
+    pub fn keys(&self) -> Result<&[u8]> {
+        let buf = self.buffer.as_ref();
+        let end = BSET_BYTES + self.u64s()? as usize * 8;
+        if buf.len() < end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(&buf[BSET_BYTES..end])
+        }
+    }
+}
+
+impl<T: AsMut<[u8]>> AsMut<[u8]> for Bset<T> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut()
+    }
+}
+
+impl<T: AsMut<[u8]>> Bset<T> {
+    /// Set the sequence number of the btree node
+    pub fn set_seq(&mut self, seq: u64) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < bset_offsets::SEQ.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u64(&mut buf[bset_offsets::SEQ], seq);
+            Ok(())
+        }
+    }
+
+    /// Set the newest journal sequence number of the keys
+    pub fn set_journal_seq(&mut self, seq: u64) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < bset_offsets::JOURNAL_SEQ.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u64(&mut buf[bset_offsets::JOURNAL_SEQ], seq);
+            Ok(())
+        }
+    }
+
+    /// Set the checksum type of the bset
+    pub fn set_csum_type(&mut self, ty: CsumType) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < bset_offsets::FLAGS.end {
+            Err(BchError::Exhausted)
+        } else {
+            let flags = LittleEndian::read_u32(&buf[bset_offsets::FLAGS]);
+            LittleEndian::write_u32(&mut buf[bset_offsets::FLAGS], (flags & !0xf) | ty as u32);
+            Ok(())
+        }
+    }
+
+    /// Set the metadata version the bset was written with
+    pub fn set_version(&mut self, version: u16) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < bset_offsets::VERSION.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u16(&mut buf[bset_offsets::VERSION], version);
+            Ok(())
+        }
+    }
+
+    /// Set the size of the keys of the bset in u64s
+    pub fn set_u64s(&mut self, u64s: u16) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < bset_offsets::U64S.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u16(&mut buf[bset_offsets::U64S], u64s);
+            Ok(())
+        }
+    }
+}
+
+/// The header of a btree node, followed by the keys of its first bset
+pub struct BtreeNodeHeader<T> {
+    buffer: T,
+}
+
+impl<T> BtreeNodeHeader<T> {
+    /// Create a btree node header view of the given bytes
+    pub fn from(buf: T) -> BtreeNodeHeader<T> {
+        BtreeNodeHeader { buffer: buf }
+    }
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for BtreeNodeHeader<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer.as_ref()
+    }
+}
+
+impl<T: AsRef<[u8]>> BtreeNodeHeader<T> {
+    /// The header, checking it is complete
+    fn header(&self) -> Result<&[u8]> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < BTREE_NODE_BYTES {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf)
+        }
+    }
+
+    /// The checksum of the node header and first bset, low and high u64
+    pub fn csum(&self) -> Result<[u64; 2]> {
+        let csum = &self.header()?[node_offsets::CSUM];
+        Ok([
+            LittleEndian::read_u64(&csum[..8]),
+            LittleEndian::read_u64(&csum[8..]),
+        ])
+    }
+
+    /// The magic of the node, see `bset_magic`
+    pub fn magic(&self) -> Result<u64> {
+        Ok(LittleEndian::read_u64(&self.header()?[node_offsets::MAGIC]))
+    }
+
+    /// The raw id of the btree the node belongs to, see `BtreeId`
+    pub fn btree_id(&self) -> Result<u64> {
+        Ok(LittleEndian::read_u64(&self.header()?[node_offsets::FLAGS]) & 0xf)
+    }
+
+    /// The level of the node, leaves are at level 0
+    pub fn level(&self) -> Result<u8> {
+        Ok(((LittleEndian::read_u64(&self.header()?[node_offsets::FLAGS]) >> 4) & 0xf) as u8)
+    }
+
+    /// The smallest position of the keys of the node
+    pub fn min_key(&self) -> Result<Bpos> {
+        Bpos::decode(&self.header()?[node_offsets::MIN_KEY])
+    }
+
+    /// The largest position of the keys of the node
+    pub fn max_key(&self) -> Result<Bpos> {
+        Bpos::decode(&self.header()?[node_offsets::MAX_KEY])
+    }
+
+    /// The format the keys of the node are packed with
+    pub fn format(&self) -> Result<BkeyFormat<&[u8]>> {
+        Ok(BkeyFormat::from(&self.header()?[node_offsets::FORMAT]))
+    }
+
+    /// The first bset of the node
+    pub fn bset(&self) -> Result<Bset<&[u8]>> {
+        Ok(Bset::from(&self.header()?[node_offsets::KEYS.start..]))
+    }
+}
+
+impl<T: AsMut<[u8]>> AsMut<[u8]> for BtreeNodeHeader<T> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut()
+    }
+}
+
+impl<T: AsMut<[u8]>> BtreeNodeHeader<T> {
+    /// The header, checking it is complete
+    fn header_mut(&mut self) -> Result<&mut [u8]> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < BTREE_NODE_BYTES {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(buf)
+        }
+    }
+
+    /// Set the checksum of the node header and first bset
+    pub fn set_csum(&mut self, csum: [u64; 2]) -> Result<()> {
+        let buf = &mut self.header_mut()?[node_offsets::CSUM];
+        LittleEndian::write_u64(&mut buf[..8], csum[0]);
+        LittleEndian::write_u64(&mut buf[8..], csum[1]);
+        Ok(())
+    }
+
+    /// Set the magic of the node
+    pub fn set_magic(&mut self, magic: u64) -> Result<()> {
+        LittleEndian::write_u64(&mut self.header_mut()?[node_offsets::MAGIC], magic);
+        Ok(())
+    }
+
+    /// Set the btree and level of the node
+    pub fn set_btree(&mut self, id: BtreeId, level: u8) -> Result<()> {
+        if level > 0xf {
+            return Err(BchError::Einval(format!("btree level {} too large", level)));
+        }
+        let buf = &mut self.header_mut()?[node_offsets::FLAGS];
+        let flags = LittleEndian::read_u64(buf) & !0xff;
+        LittleEndian::write_u64(buf, flags | id as u64 | (u64::from(level) << 4));
+        Ok(())
+    }
+
+    /// Set the smallest position of the keys of the node
+    pub fn set_min_key(&mut self, p: Bpos) -> Result<()> {
+        p.encode(&mut self.header_mut()?[node_offsets::MIN_KEY])
+    }
+
+    /// Set the largest position of the keys of the node
+    pub fn set_max_key(&mut self, p: Bpos) -> Result<()> {
+        p.encode(&mut self.header_mut()?[node_offsets::MAX_KEY])
+    }
+
+    /// Set the format the keys of the node are packed with
+    pub fn set_format(&mut self, format: &[u8; BKEY_FORMAT_BYTES]) -> Result<()> {
+        self.header_mut()?[node_offsets::FORMAT].copy_from_slice(format);
+        Ok(())
+    }
+
+    /// The first bset of the node
+    pub fn bset_mut(&mut self) -> Result<Bset<&mut [u8]>> {
+        Ok(Bset::from(
+            &mut self.header_mut()?[node_offsets::KEYS.start..],
+        ))
+    }
+}
+
+/// A btree node read from disk, with the keys of all its bsets merged
+#[derive(Debug)]
+pub struct BtreeNode {
+    /// The btree the node belongs to
+    pub btree_id: BtreeId,
+    /// The level of the node, leaves are at level 0
+    pub level: u8,
+    /// The smallest position of the keys of the node
+    pub min_key: Bpos,
+    /// The largest position of the keys of the node
+    pub max_key: Bpos,
+    /// The sequence number of the node
+    pub seq: u64,
+    /// The sectors of the node that were written
+    pub sectors_written: u32,
+    /// The live keys of the node in order
+    pub keys: Vec<OwnedKey>,
+    /// The problems found reading the node
+    pub errors: Vec<String>,
+}
+
+impl BtreeNode {
+    /// An iterator over the live keys of the node in order
+    pub fn keys(&self) -> impl Iterator<Item = &OwnedKey> {
+        self.keys.iter()
+    }
+}
+
+impl IntoIterator for BtreeNode {
+    type Item = OwnedKey;
+    type IntoIter = std::vec::IntoIter<OwnedKey>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.keys.into_iter()
+    }
+}
+
+/// The journal sequence numbers that may not be used, as start and end
+fn journal_seq_denylist(sb: &[u8]) -> Result<Vec<(u64, u64)>> {
+    let view = SuperBlock::from(sb);
+    Ok(match view.field(Field::JournalSeqDenylist)? {
+        Some(field) => field
+            .chunks_exact(16)
+            .map(|entry| {
+                (
+                    LittleEndian::read_u64(&entry[..8]),
+                    LittleEndian::read_u64(&entry[8..]),
+                )
+            })
+            .collect(),
+        None => Vec::new(),
+    })
+}
+
+/// The nonce of the bset at the given byte offset of a btree node
+fn btree_nonce(bset: &Bset<&[u8]>, offset: usize) -> Result<Nonce> {
+    Ok(Nonce([
+        offset as u32,
+        bset.seq()? as u32,
+        bset.journal_seq()? as u32 ^ NONCE_BTREE,
+        0,
+    ]))
+}
+
+/// Verify the checksum of a bset, covering everything after the checksum
+fn verify_csum(
+    key: Option<&[u8; KEY_BYTES]>,
+    bset: &Bset<&[u8]>,
+    offset: usize,
+    stored: [u64; 2],
+    data: &[u8],
+) -> Result<()> {
+    let ty = bset.csum_type()?;
+    let csum = checksum(ty, key, btree_nonce(bset, offset)?, data)?;
+    if csum != stored {
+        Err(BchError::Str(format!(
+            "bad {} checksum: got {:x}:{:x}, expected {:x}:{:x}",
+            ty, csum[1], csum[0], stored[1], stored[0]
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Decrypt the node header and keys of the bset at the given byte offset
+///
+/// `buf` starts at the node header for the first bset, at the keys for
+/// later ones. Only bsets with a MAC are encrypted, their checksum covers the
+/// encrypted data.
+fn decrypt_bset(
+    key: Option<&[u8; KEY_BYTES]>,
+    bset: &Bset<&[u8]>,
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<()> {
+    let key = match key {
+        Some(key) if bset.csum_type()?.is_encryption() => key,
+        _ => return Ok(()),
+    };
+    let mut nonce = btree_nonce(bset, offset)?;
+    let mut keys = &mut buf[..];
+    if offset == 0 {
+        let header = node_offsets::FLAGS.start..node_offsets::KEYS.start;
+        chacha20(key, nonce, &mut keys[header.clone()]);
+        let bytes = header.len() as u64;
+        nonce = nonce.advance(
+            bytes + (CHACHA_BLOCK_BYTES - bytes % CHACHA_BLOCK_BYTES) % CHACHA_BLOCK_BYTES,
+        );
+        keys = &mut keys[BTREE_NODE_BYTES..];
+    }
+    chacha20(key, nonce, keys);
+    Ok(())
+}
+
+/// Unpack the keys of a bset, reporting keys out of order or out of the node
+fn unpack_bset(
+    format: &BkeyFormat<&[u8]>,
+    keys: &[u8],
+    range: (Bpos, Bpos),
+    errors: &mut Vec<String>,
+) -> Vec<OwnedKey> {
+    let mut unpacked: Vec<OwnedKey> = Vec::new();
+    let mut pos = 0;
+
+    while pos < keys.len() {
+        let key = &keys[pos..];
+        let bytes = Bkey::from(key).u64s().unwrap_or(0) as usize * 8;
+        if bytes == 0 || bytes > key.len() {
+            errors.push(format!("key of {} bytes at {} overruns bset", bytes, pos));
+            break;
+        }
+
+        match format.unpack_key(&key[..bytes]) {
+            Ok(key) => {
+                let p = key.p();
+                if let Some(prev) = unpacked.last() {
+                    if prev.p() > p {
+                        errors.push(format!("keys out of order: {} > {}", prev.p(), p));
+                    }
+                }
+                if p < range.0 || p > range.1 {
+                    errors.push(format!(
+                        "key at {} outside of node range {} - {}",
+                        p, range.0, range.1
+                    ));
+                }
+                unpacked.push(key);
+            }
+            Err(e) => errors.push(format!("invalid key at {}: {}", pos, e)),
+        }
+        pos += bytes;
+    }
+
+    unpacked
+}
+
+/// Read the bsets of a btree node and merge their keys
+///
+/// Later bsets are only read while they carry the sequence number of the node,
+/// later bsets failing their checksum or from a denied journal sequence number
+/// are dropped. Keys of newer bsets replace those at the same position in older
+/// ones. Deleted keys are left out, whiteouts are kept. Encrypted nodes need
+/// the filesystem key.
+pub fn parse_btree_node(sb: &[u8], key: Option<&[u8; KEY_BYTES]>, buf: &[u8]) -> Result<BtreeNode> {
+    let view = SuperBlock::from(sb);
+    let block_bytes = view.block_size()? as usize * 512;
+    let denylist = journal_seq_denylist(sb)?;
+    let denied = |seq: u64| {
+        denylist
+            .iter()
+            .any(|(start, end)| seq >= *start && seq < *end)
+    };
+
+    let node = BtreeNodeHeader::from(buf);
+    if node.magic()? != bset_magic(sb)? {
+        return Err(BchError::Str("bad btree node magic".to_string()));
+    }
+    let first = node.bset()?;
+    if first.big_endian()? {
+        return Err(BchError::Str(
+            "big endian btree nodes are not supported".to_string(),
+        ));
+    }
+    let seq = first.seq()?;
+    let end = BTREE_NODE_BYTES + first.u64s()? as usize * 8;
+    if end > buf.len() {
+        return Err(BchError::Str("first bset overruns btree node".to_string()));
+    }
+    verify_csum(
+        key,
+        &first,
+        0,
+        node.csum()?,
+        &buf[node_offsets::MAGIC.start..end],
+    )?;
+
+    let mut header = buf[..end].to_vec();
+    decrypt_bset(key, &first, 0, &mut header)?;
+    let node = BtreeNodeHeader::from(&header[..]);
+    let format = node.format()?;
+    format.validate()?;
+    let range = (node.min_key()?, node.max_key()?);
+
+    // like the kernel the first bset is kept, even from a denied journal seq
+    let mut errors = Vec::new();
+    let first = node.bset()?;
+    let mut bsets = vec![unpack_bset(&format, first.keys()?, range, &mut errors)];
+
+    let mut offset = end + (block_bytes - end % block_bytes) % block_bytes;
+    while offset + BTREE_NODE_ENTRY_BYTES <= buf.len() {
+        let entry = &buf[offset..];
+        let bset = Bset::from(&entry[entry_offsets::KEYS.start..]);
+        if bset.seq()? != seq {
+            break;
+        }
+
+        let end = BTREE_NODE_ENTRY_BYTES + bset.u64s()? as usize * 8;
+        if end > entry.len() {
+            errors.push(format!("bset at {} overruns btree node", offset >> 9));
+            break;
+        }
+
+        let csum = &entry[entry_offsets::CSUM];
+        let stored = [
+            LittleEndian::read_u64(&csum[..8]),
+            LittleEndian::read_u64(&csum[8..]),
+        ];
+        if let Err(e) = verify_csum(
+            key,
+            &bset,
+            offset,
+            stored,
+            &entry[entry_offsets::KEYS.start..end],
+        ) {
+            errors.push(format!("bset at {}: {}", offset >> 9, e));
+        } else if bset.big_endian()? {
+            errors.push(format!("bset at {} is big endian", offset >> 9));
+        } else if denied(bset.journal_seq()?) {
+            errors.push(format!(
+                "bset at {} has denied journal seq {}",
+                offset >> 9,
+                bset.journal_seq()?
+            ));
+        } else {
+            let mut keys = bset.keys()?.to_vec();
+            decrypt_bset(key, &bset, offset, &mut keys)?;
+            bsets.push(unpack_bset(&format, &keys, range, &mut errors));
+        }
+
+        offset += end + (block_bytes - end % block_bytes) % block_bytes;
+    }
+
+    let mut keys = bsets.into_iter().flatten().collect::<Vec<_>>();
+    keys.sort_by_key(|key| key.p());
+    let mut merged: Vec<OwnedKey> = Vec::with_capacity(keys.len());
+    for key in keys {
+        match merged.last_mut() {
+            Some(last) if last.p() == key.p() => *last = key,
+            _ => merged.push(key),
+        }
+    }
+    merged.retain(|key| key.ty() != KeyType::Deleted as u8);
+
+    for e in errors.iter() {
+        warn!("btree node {}: {}", seq, e);
+    }
+
+    Ok(BtreeNode {
+        btree_id: BtreeId::try_from(node.btree_id()?)?,
+        level: node.level()?,
+        min_key: range.0,
+        max_key: range.1,
+        seq,
+        sectors_written: (offset.min(buf.len()) >> 9) as u32,
+        keys: merged,
+        errors,
+    })
+}
+
+/// Read the btree node of the given number of sectors a pointer points to
+///
+/// The device is the member the pointer refers to.
+pub fn read_btree_node(
+    dev: &dyn BlockDevice,
+    sb: &[u8],
+    key: Option<&[u8; KEY_BYTES]>,
+    ptr: &ExtentPtr,
+    sectors: u32,
+) -> Result<BtreeNode> {
+    let mut buf = vec![0u8; sectors as usize * 512];
+    dev.read_at(&mut buf, ptr.offset << 9)?;
+    parse_btree_node(sb, key, &buf)
+        .map_err(|e| BchError::Str(format!("btree node at {}: {}", ptr, e)))
+}
+
+#[cfg(test)]
+pub(crate) mod test_btree {
+    use super::*;
+    use crate::bkey::{bkey_format_current, BKEY_BYTES, BKEY_U64S, KEY_FORMAT_CURRENT};
+    use crate::block_dev::MemoryDevice;
+    use crate::format::test_format::format_members;
+    use crate::super_io::set_field;
+
+    /// An unpacked key of the given type at the given position
+    pub(crate) fn key(ty: KeyType, p: Bpos, val: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; BKEY_BYTES];
+        let mut key = Bkey::from(&mut buf[..]);
+        key.set_u64s((BKEY_U64S + val.len() / 8) as u8).unwrap();
+        key.set_format(KEY_FORMAT_CURRENT, false).unwrap();
+        key.set_ty(ty as u8).unwrap();
+        key.set_p(p).unwrap();
+        buf.extend_from_slice(val);
+        buf
+    }
+
+    /// A btree node of 4 sectors holding a bset of keys for each entry
+    ///
+    /// The entries are the journal seq and keys of each bset, written one
+    /// sector each.
+    pub(crate) fn node(
+        sb: &[u8],
+        id: BtreeId,
+        level: u8,
+        bsets: &[(u64, Vec<Vec<u8>>)],
+    ) -> Vec<u8> {
+        node_with(sb, id, level, bsets, CsumType::Crc32cNonzero, None)
+    }
+
+    /// A btree node of the given checksum type, encrypted with the key if any
+    fn node_with(
+        sb: &[u8],
+        id: BtreeId,
+        level: u8,
+        bsets: &[(u64, Vec<Vec<u8>>)],
+        ty: CsumType,
+        key: Option<&[u8; KEY_BYTES]>,
+    ) -> Vec<u8> {
+        let mut buf = vec![0u8; 4 << 9];
+        let mut header = BtreeNodeHeader::from(&mut buf[..]);
+        header.set_magic(bset_magic(sb).unwrap()).unwrap();
+        header.set_btree(id, level).unwrap();
+        header.set_min_key(Bpos::MIN).unwrap();
+        header.set_max_key(Bpos::MAX).unwrap();
+        header.set_format(&bkey_format_current()).unwrap();
+
+        for (i, (journal_seq, keys)) in bsets.iter().enumerate() {
+            let keys = keys.concat();
+            let (start, csum_start, encrypted) = if i == 0 {
+                (node_offsets::KEYS.start, node_offsets::MAGIC.start, 0)
+            } else {
+                let start = i * 512 + entry_offsets::KEYS.start;
+                (start, start, start + BSET_BYTES)
+            };
+
+            let mut bset = Bset::from(&mut buf[start..]);
+            bset.set_seq(0x5eed).unwrap();
+            bset.set_journal_seq(*journal_seq).unwrap();
+            bset.set_csum_type(ty).unwrap();
+            bset.set_u64s((keys.len() / 8) as u16).unwrap();
+            let end = start + BSET_BYTES + keys.len();
+            buf[(start + BSET_BYTES)..end].copy_from_slice(&keys);
+
+            let offset = if i == 0 { 0 } else { i * 512 };
+            let bset_buf = buf[start..(start + BSET_BYTES)].to_vec();
+            let bset = Bset::from(&bset_buf[..]);
+            decrypt_bset(key, &bset, offset, &mut buf[encrypted..end]).unwrap();
+            let nonce = btree_nonce(&bset, offset).unwrap();
+            let csum = checksum(ty, key, nonce, &buf[csum_start..end]).unwrap();
+            let csum_offset = csum_start - 16;
+            LittleEndian::write_u64(&mut buf[csum_offset..(csum_offset + 8)], csum[0]);
+            LittleEndian::write_u64(&mut buf[(csum_offset + 8)..csum_start], csum[1]);
+        }
+        buf
+    }
+
+    #[test]
+    fn read_bsets() {
+        let member = format_members(&["mem0"]).remove(0);
+        let mut sb = member.sb;
+        let mut denylist = [0u8; 16];
+        LittleEndian::write_u64(&mut denylist[..8], 100);
+        LittleEndian::write_u64(&mut denylist[8..], 200);
+        set_field(&mut sb, Field::JournalSeqDenylist, &denylist).unwrap();
+
+        let at = |offset| Bpos::new(4096, offset, 0);
+        let mut buf = node(
+            &sb,
+            BtreeId::Inodes,
+            0,
+            &[
+                (
+                    1,
+                    vec![
+                        key(KeyType::Inode, at(1), &[1; 8]),
+                        key(KeyType::Inode, at(3), &[]),
+                    ],
+                ),
+                (
+                    2,
+                    vec![
+                        key(KeyType::Inode, at(2), &[]),
+                        key(KeyType::Deleted, at(3), &[]),
+                    ],
+                ),
+                (150, vec![key(KeyType::Inode, at(5), &[])]),
+            ],
+        );
+
+        let dev = MemoryDevice::new(1 << 20);
+        dev.write_at(&buf, 64 << 9).unwrap();
+        let ptr = ExtentPtr {
+            dev: 0,
+            offset: 64,
+            gen: 0,
+            cached: false,
+        };
+        let node = read_btree_node(&dev, &sb, None, &ptr, 4).unwrap();
+        assert_eq!(node.btree_id, BtreeId::Inodes);
+        assert_eq!(node.level, 0);
+        assert_eq!(node.seq, 0x5eed);
+        assert_eq!(node.errors.len(), 1);
+        let keys = node.keys().map(|key| key.p()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![at(1), at(2)]);
+        assert_eq!(node.keys[0].val, vec![1; 8]);
+
+        buf[512 + 40] ^= 1;
+        let node = parse_btree_node(&sb, None, &buf).unwrap();
+        assert_eq!(node.errors.len(), 2);
+        let keys = node.keys().map(|key| key.p()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![at(1), at(3)]);
+
+        buf[node_offsets::KEYS.end] ^= 1;
+        assert!(parse_btree_node(&sb, None, &buf).is_err());
+
+        // a denied first bset is still read
+        let buf = self::node(
+            &sb,
+            BtreeId::Inodes,
+            0,
+            &[
+                (150, vec![key(KeyType::Inode, at(1), &[])]),
+                (160, vec![key(KeyType::Inode, at(2), &[])]),
+                (3, vec![key(KeyType::Inode, at(3), &[])]),
+            ],
+        );
+        let node = parse_btree_node(&sb, None, &buf).unwrap();
+        assert_eq!(node.errors.len(), 1);
+        let keys = node.keys().map(|key| key.p()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![at(1), at(3)]);
+    }
+
+    #[test]
+    fn encrypted_checksum() {
+        let secret = [0x42; KEY_BYTES];
+        let mut bset_buf = [0u8; BSET_BYTES];
+        let mut bset = Bset::from(&mut bset_buf[..]);
+        bset.set_seq(0x0123_4567_89ab_cdef).unwrap();
+        bset.set_journal_seq(0x1122_3344_5566_7788).unwrap();
+        bset.set_csum_type(CsumType::ChaCha20Poly1305_128).unwrap();
+        let bset = Bset::from(&bset_buf[..]);
+
+        assert_eq!(
+            btree_nonce(&bset, 4096).unwrap(),
+            Nonce([4096, 0x89ab_cdef, 0x7566_7788, 0])
+        );
+        let data = (0..64).collect::<Vec<u8>>();
+        let stored = [0x7a7b_143f_cae0_ba48, 0x1c24_9a04_92c4_d33e];
+        verify_csum(Some(&secret), &bset, 4096, stored, &data).unwrap();
+        assert!(verify_csum(Some(&secret), &bset, 0, stored, &data).is_err());
+        assert!(verify_csum(None, &bset, 4096, stored, &data).is_err());
+    }
+
+    #[test]
+    fn read_encrypted() {
+        let member = format_members(&["mem0"]).remove(0);
+        let secret = [0x42; KEY_BYTES];
+        let at = |offset| Bpos::new(4096, offset, 0);
+        let buf = node_with(
+            &member.sb,
+            BtreeId::Inodes,
+            0,
+            &[
+                (1, vec![key(KeyType::Inode, at(1), &[1; 8])]),
+                (2, vec![key(KeyType::Inode, at(2), &[2; 8])]),
+            ],
+            CsumType::ChaCha20Poly1305_80,
+            Some(&secret),
+        );
+
+        assert!(!buf.windows(8).any(|bytes| bytes == [2; 8]));
+        let node = parse_btree_node(&member.sb, Some(&secret), &buf).unwrap();
+        assert_eq!(node.btree_id, BtreeId::Inodes);
+        assert!(node.errors.is_empty());
+        let keys = node.keys().map(|key| key.p()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![at(1), at(2)]);
+        assert_eq!(node.keys[1].val, vec![2; 8]);
+
+        assert!(parse_btree_node(&member.sb, None, &buf).is_err());
+        assert!(parse_btree_node(&member.sb, Some(&[0x24; KEY_BYTES]), &buf).is_err());
+    }
+
+    #[test]
+    fn report_out_of_order() {
+        let member = format_members(&["mem0"]).remove(0);
+        let at = |offset| Bpos::new(1, offset, 0);
+        let buf = node(
+            &member.sb,
+            BtreeId::Extents,
+            0,
+            &[(
+                1,
+                vec![
+                    key(KeyType::Extent, at(8), &[]),
+                    key(KeyType::Extent, at(4), &[]),
+                ],
+            )],
+        );
+        let node = parse_btree_node(&member.sb, None, &buf).unwrap();
+        assert_eq!(node.errors.len(), 1);
+        assert!(node.errors[0].contains("out of order"));
+        assert_eq!(node.keys.len(), 2);
+        assert_eq!("dirents".parse::<BtreeId>().unwrap(), BtreeId::Dirents);
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::bkey::{Bpos, KeyType, OwnedKey};
+use crate::btree::{read_btree_node, BtreeId};
+use crate::crypt::{fs_key, KEY_BYTES};
+use crate::extents::{ExtentPtr, EXTENT_PTR_BYTES};
+use crate::journal::{decode_items, read_journal, JournalItem};
+use crate::super_block::{Field, SuperBlock, SuperBlockFlag};
+use crate::super_io::{open_members_partial, Member};
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, info};
+
+/// The offset of the extent pointers in the value of a btree_ptr_v2 key
+const BTREE_PTR_V2_PTRS: usize = 40;
+
+/// The root of a btree, a pointer to the root node and its level
+type BtreeRoot = (u8, OwnedKey);
+
+/// The btrees of an unmounted filesystem
+///
+/// The roots are taken from the clean section of the superblock after a clean
+/// shutdown, from the journal otherwise. Leaf keys still only in the journal
+/// are laid over the keys of the btree when iterating.
+pub struct Btrees {
+    members: BTreeMap<u8, Member>,
+    sb: Vec<u8>,
+    roots: BTreeMap<BtreeId, BtreeRoot>,
+    journal_keys: BTreeMap<BtreeId, BTreeMap<Bpos, OwnedKey>>,
+    key: Option<[u8; KEY_BYTES]>,
+}
+
+/// The btree roots recorded in the clean section of a superblock
+fn clean_roots(sb: &[u8]) -> Result<Option<BTreeMap<BtreeId, BtreeRoot>>> {
+    let view = SuperBlock::from(sb);
+    if view.flags()?.flag(SuperBlockFlag::CLEAN)? == 0 {
+        return Ok(None);
+    }
+    let clean = match view.field(Field::Clean)? {
+        Some(clean) if clean.len() >= 16 => clean,
+        _ => return Ok(None),
+    };
+
+    let mut roots = BTreeMap::new();
+    for item in decode_items(&clean[16..])? {
+        if let JournalItem::BtreeRoot {
+            btree_id,
+            level,
+            key,
+        } = item
+        {
+            roots.insert(btree_id, (level, key));
+        }
+    }
+    Ok(Some(roots))
+}
+
+/// The extent pointers of a key pointing to a btree node
+fn btree_ptrs(key: &OwnedKey) -> Result<Vec<ExtentPtr>> {
+    let ptrs = match KeyType::try_from(key.ty())? {
+        KeyType::BtreePtr => &key.val[..],
+        KeyType::BtreePtrV2 => key
+            .val
+            .get(BTREE_PTR_V2_PTRS..)
+            .ok_or(BchError::Exhausted)?,
+        ty => {
+            return Err(BchError::Einval(format!(
+                "{} key is not a btree pointer",
+                ty
+            )))
+        }
+    };
+    ptrs.chunks_exact(EXTENT_PTR_BYTES)
+        .map(ExtentPtr::decode)
+        .collect()
+}
+
+impl Btrees {
+    /// Open the btrees of the filesystem the given members belong to
+    ///
+    /// Encrypted journal entries and btree nodes are read with the given
+    /// filesystem key.
+    pub fn open(members: Vec<Member>, key: Option<[u8; KEY_BYTES]>) -> Result<Btrees> {
+        let sb = members
+            .iter()
+            .max_by_key(|member| member.sb().seq().unwrap_or(0))
+            .ok_or_else(|| BchError::Str("no devices given".to_string()))?
+            .sb
+            .clone();
+
+        let mut journal_keys: BTreeMap<BtreeId, BTreeMap<Bpos, OwnedKey>> = BTreeMap::new();
+        let roots = match clean_roots(&sb)? {
+            Some(roots) => {
+                info!("filesystem is clean, using the btree roots of the superblock");
+                roots
+            }
+            None => {
+                let journal = read_journal(&members, key.as_ref())?;
+                for entry in journal.dirty() {
+                    for item in entry.items.iter() {
+                        if let JournalItem::BtreeKeys {
+                            btree_id,
+                            level: 0,
+                            keys,
+                        } = item
+                        {
+                            let overlay = journal_keys.entry(*btree_id).or_default();
+                            for key in keys.iter() {
+                                overlay.insert(key.p(), key.clone());
+                            }
+                        }
+                    }
+                }
+                journal.btree_roots()
+            }
+        };
+
+        let members = members
+            .into_iter()
+            .map(|member| Ok((member.sb().device_index()?, member)))
+            .collect::<Result<_>>()?;
+        Ok(Btrees {
+            members,
+            sb,
+            roots,
+            journal_keys,
+            key,
+        })
+    }
+
+    /// The newest superblock of the members
+    pub fn sb(&self) -> &[u8] {
+        &self.sb
+    }
+
+    /// The key of the filesystem, if it is encrypted
+    pub fn key(&self) -> Option<&[u8; KEY_BYTES]> {
+        self.key.as_ref()
+    }
+
+    /// The member of the given device index, if it is available
+    pub fn member(&self, dev: u8) -> Option<&Member> {
+        self.members.get(&dev)
+    }
+
+    /// The root of the given btree, `None` if the btree has no root
+    pub fn root(&self, id: BtreeId) -> Option<&BtreeRoot> {
+        self.roots.get(&id)
+    }
+
+    /// Read the node a btree pointer points to, trying each of its copies
+    pub fn read_node(&self, key: &OwnedKey) -> Result<Vec<OwnedKey>> {
+        let sectors = SuperBlock::from(&self.sb[..])
+            .flags()?
+            .flag(SuperBlockFlag::BTREE_NODE_SIZE)? as u32;
+        let seq = if key.ty() == KeyType::BtreePtrV2 as u8 {
+            key.val.get(8..16).map(LittleEndian::read_u64)
+        } else {
+            None
+        };
+
+        let mut errors = Vec::new();
+        for ptr in btree_ptrs(key)? {
+            let member = match self.members.get(&ptr.dev) {
+                Some(member) => member,
+                None => {
+                    errors.push(format!("{}: device missing", ptr));
+                    continue;
+                }
+            };
+            let dev = member.dev.as_ref();
+            match read_btree_node(dev, &self.sb, self.key.as_ref(), &ptr, sectors) {
+                Ok(node) if seq.is_some() && seq != Some(node.seq) => errors.push(format!(
+                    "{}: node seq {:x} instead of {:x}",
+                    ptr,
+                    node.seq,
+                    seq.unwrap_or(0)
+                )),
+                Ok(node) => return Ok(node.keys),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        Err(BchError::Str(format!(
+            "no readable copy of btree node {}: {}",
+            key.p(),
+            errors.join(", ")
+        )))
+    }
+
+    /// An iterator over the keys of the given btree
+    pub fn iter(&self, id: BtreeId) -> BtreeIter<'_> {
+        let mut iter = BtreeIter {
+            btrees: self,
+            id,
+            whiteouts: false,
+            path: Vec::new(),
+            peeked: None,
+            pos: Bpos::MIN,
+        };
+        iter.seek(Bpos::MIN);
+        iter
+    }
+}
+
+/// An iterator over the keys of a btree, in order
+///
+/// Deleted keys are skipped, keys in the journal replace those in the btree
+/// at the same position.
+pub struct BtreeIter<'a> {
+    btrees: &'a Btrees,
+    id: BtreeId,
+    /// Whether whiteouts are returned rather than skipped
+    whiteouts: bool,
+    /// The level, keys and index of the next key of the nodes from the root
+    /// down, starting with a node holding only the root pointer
+    path: Vec<(u8, Vec<OwnedKey>, usize)>,
+    /// The next key of the btree, not yet compared to the journal
+    peeked: Option<OwnedKey>,
+    /// Journal keys before this position were returned already
+    pos: Bpos,
+}
+
+impl<'a> BtreeIter<'a> {
+    /// Also return the whiteouts of keys deleted in a snapshot
+    pub fn with_whiteouts(mut self) -> Self {
+        self.whiteouts = true;
+        self
+    }
+
+    /// Position the iterator at the first key at or after `pos`
+    pub fn seek(&mut self, pos: Bpos) {
+        self.path.clear();
+        self.peeked = None;
+        self.pos = pos;
+        if let Some((level, root)) = self.btrees.root(self.id) {
+            self.path.push((level + 1, vec![root.clone()], 0));
+        }
+    }
+
+    /// The next key of the btree itself, descending into nodes as needed
+    fn next_btree_key(&mut self) -> Result<Option<OwnedKey>> {
+        loop {
+            let (level, keys, idx) = match self.path.last_mut() {
+                Some(node) => node,
+                None => return Ok(None),
+            };
+            if *idx >= keys.len() {
+                self.path.pop();
+                continue;
+            }
+
+            let key = keys[*idx].clone();
+            let level = *level;
+            *idx += 1;
+            if level == 0 {
+                return Ok(Some(key));
+            }
+
+            debug!("{}: reading node {}", self.id, key.p());
+            let children = self.btrees.read_node(&key)?;
+            let first = children.iter().position(|child| child.p() >= self.pos);
+            let first = first.unwrap_or(children.len());
+            self.path.push((level - 1, children, first));
+        }
+    }
+
+    /// The next key, merging the btree with the keys in the journal
+    fn next_key(&mut self) -> Result<Option<OwnedKey>> {
+        loop {
+            if self.peeked.is_none() {
+                self.peeked = self.next_btree_key()?;
+            }
+            let journal = self
+                .btrees
+                .journal_keys
+                .get(&self.id)
+                .and_then(|keys| keys.range(self.pos..).next())
+                .map(|(_, key)| key);
+
+            let key = match (self.peeked.take(), journal) {
+                (None, None) => return Ok(None),
+                (Some(key), None) => key,
+                (Some(key), Some(journal)) if key.p() < journal.p() => key,
+                (btree, Some(journal)) => {
+                    if btree.as_ref().map(|key| key.p()) != Some(journal.p()) {
+                        self.peeked = btree;
+                    }
+                    journal.clone()
+                }
+            };
+
+            match key.p().successor() {
+                Some(next) => self.pos = next,
+                None => self.path.clear(),
+            }
+            let whiteout = self.whiteouts && key.ty() == KeyType::Discard as u8;
+            if whiteout || !key.bkey().is_deleted()? {
+                return Ok(Some(key));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for BtreeIter<'a> {
+    type Item = Result<OwnedKey>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_key() {
+            Ok(key) => key.map(Ok),
+            Err(e) => {
+                self.path.clear();
+                self.peeked = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Arguments that the list subcommand may be provided.
+#[derive(Debug)]
+pub struct ListKeysArgs {
+    /// The btree to list
+    pub btree: BtreeId,
+    /// The first position to list
+    pub start: Bpos,
+    /// The last position to list
+    pub end: Bpos,
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// Read the passphrase of an encrypted filesystem from a file
+    pub keyfile: Option<String>,
+}
+
+/// Print the keys of a btree of an unmounted filesystem
+pub fn list_keys(args: ListKeysArgs) -> Result<()> {
+    let members = open_members_partial(&args.devices, false)?;
+    let key = fs_key(&members, args.keyfile.as_deref())?;
+    let btrees = Btrees::open(members, key)?;
+    if btrees.root(args.btree).is_none() {
+        return Err(BchError::Str(format!(
+            "no root found for btree {}",
+            args.btree
+        )));
+    }
+
+    let mut iter = btrees.iter(args.btree);
+    iter.seek(args.start);
+    for key in iter {
+        let key = key?;
+        if key.p() > args.end {
+            break;
+        }
+        println!("{}", key);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_btree_iter {
+    use super::*;
+    use crate::btree::test_btree::{key, node};
+    use crate::format::test_format::format_members;
+    use crate::journal::test_journal::{item, jset, write_journal};
+    use crate::journal::JsetEntryType;
+    use crate::super_block::SuperBlockFlags;
+    use crate::super_io::set_field;
+
+    /// A btree_ptr_v2 value pointing to the given sector of device 0
+    pub(crate) fn ptr_val(offset: u64) -> Vec<u8> {
+        let mut val = vec![0u8; BTREE_PTR_V2_PTRS];
+        LittleEndian::write_u64(&mut val[8..16], 0x5eed);
+        let ptr = ExtentPtr {
+            dev: 0,
+            offset,
+            gen: 0,
+            cached: false,
+        };
+        val.extend_from_slice(&ptr.encode().unwrap());
+        val
+    }
+
+    /// A filesystem with a two level inodes btree, its root in the journal
+    fn filesystem() -> (Vec<Member>, Vec<u8>) {
+        let mut members = format_members(&["mem0"]);
+        let sb = members[0].sb.clone();
+        let at = |inode| Bpos::new(inode, 0, 0);
+        let inode = |inode| key(KeyType::Inode, at(inode), &[0u8; 8]);
+
+        let leaves = [
+            node(
+                &sb,
+                BtreeId::Inodes,
+                0,
+                &[(1, vec![inode(10), inode(11), inode(12)])],
+            ),
+            node(&sb, BtreeId::Inodes, 0, &[(1, vec![inode(20), inode(21)])]),
+        ];
+        let interior = node(
+            &sb,
+            BtreeId::Inodes,
+            1,
+            &[(
+                1,
+                vec![
+                    key(KeyType::BtreePtrV2, at(12), &ptr_val(8192)),
+                    key(KeyType::BtreePtrV2, Bpos::MAX, &ptr_val(8704)),
+                ],
+            )],
+        );
+        members[0].dev.write_at(&leaves[0], 8192 << 9).unwrap();
+        members[0].dev.write_at(&leaves[1], 8704 << 9).unwrap();
+        members[0].dev.write_at(&interior, 9216 << 9).unwrap();
+
+        let root = item(
+            JsetEntryType::BtreeRoot,
+            BtreeId::Inodes,
+            1,
+            &key(KeyType::BtreePtrV2, Bpos::MAX, &ptr_val(9216)),
+        );
+        let updates = item(
+            JsetEntryType::BtreeKeys,
+            BtreeId::Inodes,
+            0,
+            &[inode(15), key(KeyType::Deleted, at(20), &[])].concat(),
+        );
+        write_journal(
+            &mut members,
+            &[20],
+            &[(20, jset(&sb, 5, 5, &[root.clone(), updates]))],
+        );
+        (members, root)
+    }
+
+    /// The members of a filesystem with a single leaf for each btree given
+    pub(crate) fn btree_members(leaves: &[(BtreeId, Vec<Vec<u8>>)]) -> Vec<Member> {
+        let mut members = format_members(&["mem0"]);
+        let sb = members[0].sb.clone();
+        let mut roots = Vec::new();
+        for (i, (id, keys)) in leaves.iter().enumerate() {
+            let offset = 10240 + i as u64 * 512;
+            let leaf = node(&sb, *id, 0, &[(1, keys.clone())]);
+            members[0].dev.write_at(&leaf, offset << 9).unwrap();
+            roots.push(item(
+                JsetEntryType::BtreeRoot,
+                *id,
+                0,
+                &key(KeyType::BtreePtrV2, Bpos::MAX, &ptr_val(offset)),
+            ));
+        }
+        write_journal(&mut members, &[20], &[(20, jset(&sb, 5, 5, &roots))]);
+        members
+    }
+
+    /// The btrees of a filesystem with a single leaf for each btree given
+    pub(crate) fn btrees(leaves: &[(BtreeId, Vec<Vec<u8>>)]) -> Btrees {
+        Btrees::open(btree_members(leaves), None).unwrap()
+    }
+
+    fn inodes(iter: BtreeIter<'_>) -> Vec<u64> {
+        iter.map(|key| key.unwrap().p().inode).collect()
+    }
+
+    #[test]
+    fn iterate_with_journal() {
+        let (members, _) = filesystem();
+        let btrees = Btrees::open(members, None).unwrap();
+        assert_eq!(btrees.root(BtreeId::Inodes).unwrap().0, 1);
+        assert_eq!(
+            inodes(btrees.iter(BtreeId::Inodes)),
+            vec![10, 11, 12, 15, 21]
+        );
+        assert!(btrees.iter(BtreeId::Dirents).next().is_none());
+
+        let mut iter = btrees.iter(BtreeId::Inodes);
+        iter.seek(Bpos::new(12, 1, 0));
+        assert_eq!(inodes(iter), vec![15, 21]);
+        let mut iter = btrees.iter(BtreeId::Inodes);
+        iter.seek(Bpos::new(16, 0, 0));
+        assert_eq!(inodes(iter), vec![21]);
+    }
+
+    #[test]
+    fn iterate_clean() {
+        let (mut members, root) = filesystem();
+        let mut clean = vec![0u8; 16];
+        clean.extend_from_slice(&root);
+        set_field(&mut members[0].sb, Field::Clean, &clean).unwrap();
+        let mut flags_buf = [0u8; 64];
+        flags_buf.copy_from_slice(members[0].sb().flags().unwrap().as_ref());
+        let mut flags = SuperBlockFlags::from(&mut flags_buf[..]);
+        flags.set_flag(SuperBlockFlag::CLEAN, 1).unwrap();
+        members[0].sb_mut().set_flags(&flags).unwrap();
+
+        let btrees = Btrees::open(members, None).unwrap();
+        assert_eq!(
+            inodes(btrees.iter(BtreeId::Inodes)),
+            vec![10, 11, 12, 20, 21]
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hasher;
+
+use crate::crypt::{chacha20, CHACHA_BLOCK_BYTES, KEY_BYTES};
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use poly1305::universal_hash::NewUniversalHash;
+use poly1305::{Key, Poly1305};
+use twox_hash::XxHash64;
+
+/// Reflected crc32c (Castagnoli) polynomial
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+/// crc64 ECMA-182 polynomial, processed most significant bit first
+const CRC64_POLY: u64 = 0x42f0_e1eb_a9ea_3693;
+
+/// The nonce type of data extents
+pub const NONCE_EXTENT: u32 = 1 << 28;
+/// The nonce type of btree nodes
+pub const NONCE_BTREE: u32 = 2 << 28;
+/// The nonce type of journal entries
+pub const NONCE_JOURNAL: u32 = 3 << 28;
+/// The nonce bit the Poly1305 key is generated with
+const NONCE_POLY: u32 = 1 << 31;
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u64) << 56;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & (1 << 63) != 0 {
+                (crc << 1) ^ CRC64_POLY
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+/// Continue a crc32c over `data`
+///
+/// Like the kernel's `crc32c()` the value is neither inverted before nor
+/// after the update, callers pick the seed and finalization.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Continue a big endian crc64 over `data`, as the kernel's `crc64_be()`
+pub fn crc64_be(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, &byte| {
+        CRC64_TABLE[((crc >> 56) ^ u64::from(byte)) as usize] ^ (crc << 8)
+    })
+}
+
+/// The nonce of a MAC or of encrypted data
+///
+/// As the kernel's `struct nonce`, the first word is the ChaCha20 block
+/// counter and the other three are the ChaCha20 nonce.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Nonce(pub [u32; 4]);
+
+impl Nonce {
+    /// The nonce of the data `bytes` further on, a multiple of 64 bytes
+    pub fn advance(self, bytes: u64) -> Nonce {
+        let mut nonce = self;
+        nonce.0[0] = nonce.0[0].wrapping_add((bytes / CHACHA_BLOCK_BYTES) as u32);
+        nonce
+    }
+}
+
+/// The checksum types stored on disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum CsumType {
+    /// No checksum
+    None = 0,
+    /// crc32c seeded and finalized with all ones
+    Crc32cNonzero = 1,
+    /// crc64 seeded and finalized with all ones
+    Crc64Nonzero = 2,
+    /// 80 bit ChaCha20/Poly1305 MAC
+    ChaCha20Poly1305_80 = 3,
+    /// 128 bit ChaCha20/Poly1305 MAC
+    ChaCha20Poly1305_128 = 4,
+    /// crc32c
+    Crc32c = 5,
+    /// crc64
+    Crc64 = 6,
+    /// xxhash64
+    Xxhash = 7,
+}
+
+impl CsumType {
+    /// Whether the checksum is a MAC that needs the filesystem key
+    pub fn is_encryption(self) -> bool {
+        matches!(
+            self,
+            CsumType::ChaCha20Poly1305_80 | CsumType::ChaCha20Poly1305_128
+        )
+    }
+}
+
+impl TryFrom<u64> for CsumType {
+    type Error = BchError;
+
+    fn try_from(ty: u64) -> Result<Self> {
+        match ty {
+            0 => Ok(CsumType::None),
+            1 => Ok(CsumType::Crc32cNonzero),
+            2 => Ok(CsumType::Crc64Nonzero),
+            3 => Ok(CsumType::ChaCha20Poly1305_80),
+            4 => Ok(CsumType::ChaCha20Poly1305_128),
+            5 => Ok(CsumType::Crc32c),
+            6 => Ok(CsumType::Crc64),
+            7 => Ok(CsumType::Xxhash),
+            _ => Err(BchError::Einval(format!("unknown checksum type: {}", ty))),
+        }
+    }
+}
+
+impl fmt::Display for CsumType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsumType::None => write!(f, "none"),
+            CsumType::Crc32cNonzero => write!(f, "crc32c_nonzero"),
+            CsumType::Crc64Nonzero => write!(f, "crc64_nonzero"),
+            CsumType::ChaCha20Poly1305_80 => write!(f, "chacha20_poly1305_80"),
+            CsumType::ChaCha20Poly1305_128 => write!(f, "chacha20_poly1305_128"),
+            CsumType::Crc32c => write!(f, "crc32c"),
+            CsumType::Crc64 => write!(f, "crc64"),
+            CsumType::Xxhash => write!(f, "xxhash"),
+        }
+    }
+}
+
+/// The xxhash64 of `data`, with a zero seed
+pub fn xxhash64(data: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(data);
+    hasher.finish()
+}
+
+/// The Poly1305 MAC of `data`
+///
+/// The Poly1305 key is the first 32 bytes of the ChaCha20 keystream of the
+/// nonce, with the poly bit set.
+fn poly1305_mac(key: &[u8; KEY_BYTES], nonce: Nonce, data: &[u8]) -> [u8; 16] {
+    let mut poly_nonce = nonce;
+    poly_nonce.0[3] ^= NONCE_POLY;
+    let mut poly_key = [0u8; 32];
+    chacha20(key, poly_nonce, &mut poly_key);
+    Poly1305::new(&Key::from(poly_key))
+        .compute_unpadded(data)
+        .into_bytes()
+        .into()
+}
+
+/// Checksum `data` with the given checksum type
+///
+/// The MACs are keyed with the filesystem key and the nonce, the other types
+/// ignore both. Returns the low and high u64 as stored on disk.
+pub fn checksum(
+    ty: CsumType,
+    key: Option<&[u8; KEY_BYTES]>,
+    nonce: Nonce,
+    data: &[u8],
+) -> Result<[u64; 2]> {
+    match ty {
+        CsumType::None => Ok([0, 0]),
+        CsumType::Crc32cNonzero => Ok([u64::from(!crc32c(!0, data)), 0]),
+        CsumType::Crc64Nonzero => Ok([!crc64_be(!0, data), 0]),
+        CsumType::Crc32c => Ok([u64::from(crc32c(0, data)), 0]),
+        CsumType::Crc64 => Ok([crc64_be(0, data), 0]),
+        CsumType::Xxhash => Ok([xxhash64(data), 0]),
+        CsumType::ChaCha20Poly1305_80 | CsumType::ChaCha20Poly1305_128 => {
+            let key = key.ok_or_else(|| {
+                BchError::Str(format!("{} checksums need the filesystem key", ty))
+            })?;
+            let mac = poly1305_mac(key, nonce, data);
+            let hi = if ty == CsumType::ChaCha20Poly1305_80 {
+                u64::from(LittleEndian::read_u16(&mac[8..10]))
+            } else {
+                LittleEndian::read_u64(&mac[8..])
+            };
+            Ok([LittleEndian::read_u64(&mac[..8]), hi])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_checksum {
+    use super::*;
+
+    const CHECK: &[u8] = b"123456789";
+
+    #[test]
+    fn crc_check_values() {
+        // CRC-32C/ISCSI and CRC-64/ECMA-182 check values
+        assert_eq!(!crc32c(!0, CHECK), 0xe306_9283);
+        assert_eq!(crc64_be(0, CHECK), 0x6c40_df5f_0b49_7347);
+        // the update may be split at any point
+        assert_eq!(
+            crc32c(crc32c(!0, &CHECK[..4]), &CHECK[4..]),
+            crc32c(!0, CHECK)
+        );
+        assert_eq!(
+            crc64_be(crc64_be(0, &CHECK[..4]), &CHECK[4..]),
+            crc64_be(0, CHECK)
+        );
+    }
+
+    #[test]
+    fn checksum_types() {
+        let csum = |ty| checksum(ty, None, Nonce::default(), CHECK).unwrap();
+        assert_eq!(csum(CsumType::None), [0, 0]);
+        assert_eq!(csum(CsumType::Crc32cNonzero), [0xe306_9283, 0]);
+        assert_eq!(csum(CsumType::Crc64Nonzero), [0x62ec_59e3_f1a4_f00a, 0]);
+        assert_eq!(
+            checksum(CsumType::Crc32c, None, Nonce::default(), b"").unwrap(),
+            [0, 0]
+        );
+        assert_eq!(csum(CsumType::Crc64), [0x6c40_df5f_0b49_7347, 0]);
+        assert_eq!(csum(CsumType::Xxhash), [0x8cb8_41db_40e6_ae83, 0]);
+        // the nonce only matters to the MACs
+        assert_eq!(
+            checksum(CsumType::Xxhash, None, Nonce([1, 2, 3, 4]), CHECK).unwrap(),
+            csum(CsumType::Xxhash)
+        );
+        assert!(checksum(CsumType::ChaCha20Poly1305_80, None, Nonce::default(), CHECK).is_err());
+    }
+
+    #[test]
+    fn xxhash64_check_values() {
+        assert_eq!(xxhash64(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(xxhash64(b"abc"), 0x44bc_2cf5_ad77_0999);
+        // long enough for the four lane loop
+        let data: Vec<u8> = (0..100).collect();
+        assert_eq!(xxhash64(&data), 0x6ac1_e580_3216_6597);
+    }
+
+    #[test]
+    fn chacha20_poly1305() {
+        let key: [u8; KEY_BYTES] = [
+            0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d,
+            0x8e, 0x8f, 0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b,
+            0x9c, 0x9d, 0x9e, 0x9f,
+        ];
+
+        // the poly bit set gives the Poly1305 key generation nonce of RFC 8439
+        let nonce = Nonce([0, 0, 0x0302_0100, 0x0706_0504 ^ NONCE_POLY]);
+        let mut poly_key = [0u8; 32];
+        chacha20(&key, Nonce([0, 0, 0x0302_0100, 0x0706_0504]), &mut poly_key);
+        assert_eq!(
+            poly_key,
+            [
+                0x8a, 0xd5, 0xa0, 0x8b, 0x90, 0x5f, 0x81, 0xcc, 0x81, 0x50, 0x40, 0x27, 0x4a, 0xb2,
+                0x94, 0x71, 0xa8, 0x33, 0xb6, 0x37, 0xe3, 0xfd, 0x0d, 0xa5, 0x08, 0xdb, 0xb8, 0xe2,
+                0xfd, 0xd1, 0xa6, 0x46,
+            ]
+        );
+        assert_eq!(
+            checksum(CsumType::ChaCha20Poly1305_128, Some(&key), nonce, CHECK).unwrap(),
+            [0x6b78_4726_33b4_dec6, 0x6e7a_0653_04e1_fa72]
+        );
+        assert_eq!(
+            checksum(CsumType::ChaCha20Poly1305_80, Some(&key), nonce, CHECK).unwrap(),
+            [0x6b78_4726_33b4_dec6, 0xfa72]
+        );
+
+        // a nonzero block counter
+        let nonce = Nonce([0, 0x0123_4567, 0x89ab_cdef, NONCE_EXTENT]).advance(64);
+        assert_eq!(nonce.0[0], 1);
+        assert_eq!(
+            checksum(CsumType::ChaCha20Poly1305_128, Some(&key), nonce, CHECK).unwrap(),
+            [0x67c0_d5d1_379b_ac52, 0xc1d1_b1a3_163f_da1a]
+        );
+    }
+}
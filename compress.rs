@@ -0,0 +1,267 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Read;
+
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::read::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression;
+
+/// The size of the length preceding zstd compressed data
+const ZSTD_LEN_BYTES: usize = 4;
+
+/// The compression types of extents
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum CompressionType {
+    /// Stored as is
+    None = 0,
+    /// lz4, as written by old versions
+    Lz4Old = 1,
+    /// Raw deflate
+    Gzip = 2,
+    /// lz4 block
+    Lz4 = 3,
+    /// zstd frame preceded by its length
+    Zstd = 4,
+    /// Stored as is, found not to compress
+    Incompressible = 5,
+}
+
+impl TryFrom<u8> for CompressionType {
+    type Error = BchError;
+
+    fn try_from(ty: u8) -> Result<Self> {
+        match ty {
+            0 => Ok(CompressionType::None),
+            1 => Ok(CompressionType::Lz4Old),
+            2 => Ok(CompressionType::Gzip),
+            3 => Ok(CompressionType::Lz4),
+            4 => Ok(CompressionType::Zstd),
+            5 => Ok(CompressionType::Incompressible),
+            _ => Err(BchError::Einval(format!(
+                "unknown compression type: {}",
+                ty
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CompressionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionType::None => write!(f, "none"),
+            CompressionType::Lz4Old => write!(f, "lz4_old"),
+            CompressionType::Gzip => write!(f, "gzip"),
+            CompressionType::Lz4 => write!(f, "lz4"),
+            CompressionType::Zstd => write!(f, "zstd"),
+            CompressionType::Incompressible => write!(f, "incompressible"),
+        }
+    }
+}
+
+/// Take the next byte of an lz4 block
+fn lz4_byte(src: &mut &[u8]) -> Result<u8> {
+    let (byte, rest) = src.split_first().ok_or(BchError::Exhausted)?;
+    *src = rest;
+    Ok(*byte)
+}
+
+/// Take the length of literals or a match of an lz4 block
+///
+/// The 4 bits of the token are extended by bytes while those are all ones.
+fn lz4_length(src: &mut &[u8], token: u8) -> Result<usize> {
+    let mut len = token as usize;
+    if token == 15 {
+        loop {
+            let byte = lz4_byte(src)?;
+            len += byte as usize;
+            if byte != u8::MAX {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decompress an lz4 block into `len` bytes
+///
+/// Like the kernel this stops once `len` bytes are out, as the block is
+/// followed by the padding to the compressed size.
+fn lz4_decompress(mut src: &[u8], len: usize) -> Result<Vec<u8>> {
+    let overflow = || BchError::Einval(format!("lz4 block longer than {} bytes", len));
+
+    let mut dst = Vec::with_capacity(len);
+    while dst.len() < len {
+        let token = lz4_byte(&mut src)?;
+        let literals = lz4_length(&mut src, token >> 4)?;
+        if dst.len() + literals > len {
+            return Err(overflow());
+        }
+        dst.extend_from_slice(src.get(..literals).ok_or(BchError::Exhausted)?);
+        src = &src[literals..];
+        if dst.len() == len {
+            break;
+        }
+
+        let offset = LittleEndian::read_u16(src.get(..2).ok_or(BchError::Exhausted)?) as usize;
+        src = &src[2..];
+        let match_len = lz4_length(&mut src, token & 15)? + 4;
+        if offset == 0 || offset > dst.len() {
+            return Err(BchError::Einval(format!("invalid lz4 offset {}", offset)));
+        }
+        if dst.len() + match_len > len {
+            return Err(overflow());
+        }
+        let start = dst.len() - offset;
+        for i in start..start + match_len {
+            dst.push(dst[i]);
+        }
+    }
+    Ok(dst)
+}
+
+/// Decompress the data of an extent
+///
+/// `src` is the data as stored, padded to the compressed size, and `len` the
+/// uncompressed size in bytes, which the data must decompress to exactly.
+pub fn decompress(ty: CompressionType, src: &[u8], len: usize) -> Result<Vec<u8>> {
+    let err = |e: &dyn fmt::Display| BchError::Str(format!("{} decompression failed: {}", ty, e));
+
+    let dst = match ty {
+        CompressionType::None | CompressionType::Incompressible => src.to_vec(),
+        CompressionType::Lz4Old | CompressionType::Lz4 => {
+            lz4_decompress(src, len).map_err(|e| err(&e))?
+        }
+        CompressionType::Gzip => {
+            let mut dst = Vec::with_capacity(len);
+            DeflateDecoder::new(src)
+                .take(len as u64 + 1)
+                .read_to_end(&mut dst)
+                .map_err(|e| err(&e))?;
+            dst
+        }
+        CompressionType::Zstd => {
+            let src_len = src
+                .get(..ZSTD_LEN_BYTES)
+                .map(LittleEndian::read_u32)
+                .ok_or(BchError::Exhausted)? as usize;
+            let src = src
+                .get(ZSTD_LEN_BYTES..ZSTD_LEN_BYTES + src_len)
+                .ok_or(BchError::Exhausted)?;
+            zstd::block::decompress(src, len).map_err(|e| err(&e))?
+        }
+    };
+
+    if dst.len() != len {
+        return Err(err(&format!("{} bytes instead of {}", dst.len(), len)));
+    }
+    Ok(dst)
+}
+
+/// Compress data to be written to an extent
+///
+/// The compressed data is padded with zeroes to whole sectors, it is up to
+/// the writer to store it as incompressible if it did not get smaller.
+pub fn compress(ty: CompressionType, src: &[u8]) -> Result<Vec<u8>> {
+    let err = |e: &dyn fmt::Display| BchError::Str(format!("{} compression failed: {}", ty, e));
+
+    let mut dst = match ty {
+        CompressionType::None | CompressionType::Incompressible => src.to_vec(),
+        CompressionType::Lz4Old | CompressionType::Lz4 => lz4_flex::block::compress(src),
+        CompressionType::Gzip => {
+            let mut dst = Vec::new();
+            DeflateEncoder::new(src, Compression::default())
+                .read_to_end(&mut dst)
+                .map_err(|e| err(&e))?;
+            dst
+        }
+        CompressionType::Zstd => {
+            let frame = zstd::block::compress(src, 0).map_err(|e| err(&e))?;
+            let mut dst = (frame.len() as u32).to_le_bytes().to_vec();
+            dst.extend_from_slice(&frame);
+            dst
+        }
+    };
+
+    dst.resize(dst.len().saturating_add(511) >> 9 << 9, 0);
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod test_compress {
+    use super::*;
+
+    /// Two sectors of text that compress well
+    fn text() -> Vec<u8> {
+        b"bcachefs compresses extents. "
+            .iter()
+            .copied()
+            .cycle()
+            .take(1024)
+            .collect()
+    }
+
+    /// Pad data to a sector, as it is stored
+    fn sector(data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data.resize(512, 0);
+        data
+    }
+
+    #[test]
+    fn known_vectors() {
+        // "abc" and a 58 byte match at offset 3, then the 5 final literals
+        let lz4 = [&[0x3f][..], b"abc", &[0x03, 0x00, 0x27, 0x50], b"bcabc"].concat();
+        let abc = b"abc".repeat(22);
+        for ty in [CompressionType::Lz4Old, CompressionType::Lz4].iter() {
+            assert_eq!(decompress(*ty, &sector(&lz4), abc.len()).unwrap(), abc);
+            assert!(decompress(*ty, &lz4[..10], abc.len()).is_err());
+        }
+        let mut bad_offset = sector(&lz4);
+        bad_offset[4] = 4;
+        assert!(decompress(CompressionType::Lz4, &bad_offset, abc.len()).is_err());
+
+        // "hello" in a single stored deflate block
+        let gzip = [&[0x01, 0x05, 0x00, 0xfa, 0xff][..], b"hello"].concat();
+        assert_eq!(
+            decompress(CompressionType::Gzip, &sector(&gzip), 5).unwrap(),
+            b"hello"
+        );
+        assert!(decompress(CompressionType::Gzip, &sector(&gzip), 4).is_err());
+
+        // "hello" in a single raw block frame, preceded by the frame length
+        let zstd = [
+            &[
+                14, 0, 0, 0, 0x28, 0xb5, 0x2f, 0xfd, 0x20, 0x05, 0x29, 0x00, 0x00,
+            ][..],
+            b"hello",
+        ]
+        .concat();
+        assert_eq!(
+            decompress(CompressionType::Zstd, &sector(&zstd), 5).unwrap(),
+            b"hello"
+        );
+        let mut truncated = sector(&zstd);
+        truncated[0] = 13;
+        assert!(decompress(CompressionType::Zstd, &truncated, 5).is_err());
+        assert!(decompress(CompressionType::Zstd, &zstd[..3], 5).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = text();
+        for ty in 0..6 {
+            let ty = CompressionType::try_from(ty).unwrap();
+            let compressed = compress(ty, &text).unwrap();
+            assert_eq!(compressed.len() % 512, 0);
+            if ty != CompressionType::None && ty != CompressionType::Incompressible {
+                assert!(compressed.len() < text.len(), "{}", ty);
+            }
+            assert_eq!(decompress(ty, &compressed, text.len()).unwrap(), text);
+            assert!(decompress(ty, &compressed, text.len() + 512).is_err());
+        }
+        assert!(CompressionType::try_from(6).is_err());
+    }
+}
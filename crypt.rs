@@ -0,0 +1,604 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::block_dev::open_device;
+use crate::checksum::Nonce;
+use crate::super_block::{Field, SuperBlock};
+use crate::super_io::{open_members, read_super, set_field, write_members, Member};
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use chacha20::cipher::{NewCipher, StreamCipher, StreamCipherSeek};
+use chacha20::{ChaCha20, Key, Nonce as ChaChaNonce};
+use log::{debug, info};
+
+/// The magic of a decrypted key, `bch**key`
+const KEY_MAGIC: u64 = 0x7965_6b2a_2a68_6362;
+/// The salt used to derive a key from a passphrase, with its trailing NUL as
+/// bcachefs-tools passes it
+const KDF_SALT: &[u8] = b"bcache\0";
+/// log2 of the scrypt N, r and p parameters used for new passphrases
+const SCRYPT_PARAMS: (u64, u64, u64) = (14, 3, 4);
+/// The size of a key
+pub const KEY_BYTES: usize = 32;
+/// The size of a ChaCha20 block, the unit of the nonce counter
+pub(crate) const CHACHA_BLOCK_BYTES: u64 = 64;
+/// The size of the crypt field body
+pub(crate) const CRYPT_BYTES: usize = crypt_offsets::KEY.end;
+
+mod crypt_offsets {
+    use std::ops::Range;
+
+    pub const FLAGS: Range<usize> = 0..8;
+    pub const KDF_FLAGS: Range<usize> = 8..16;
+    pub const KEY_MAGIC: Range<usize> = 16..24;
+    pub const KEY: Range<usize> = 24..56;
+}
+
+/// The key derivation functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum KdfType {
+    /// scrypt with the parameters stored in the crypt field
+    Scrypt = 0,
+}
+
+/// A crypt flag bitmask of either the flags or the kdf flags
+pub struct CryptFlag(Range<usize>, Range<u64>);
+
+impl CryptFlag {
+    /// Bitmask for the key derivation function
+    pub const KDF_TYPE: CryptFlag = CryptFlag(crypt_offsets::FLAGS, 0..4);
+    /// Bitmask for log2 of the scrypt cost parameter N
+    pub const SCRYPT_N: CryptFlag = CryptFlag(crypt_offsets::KDF_FLAGS, 0..16);
+    /// Bitmask for log2 of the scrypt block size parameter r
+    pub const SCRYPT_R: CryptFlag = CryptFlag(crypt_offsets::KDF_FLAGS, 16..32);
+    /// Bitmask for log2 of the scrypt parallelization parameter p
+    pub const SCRYPT_P: CryptFlag = CryptFlag(crypt_offsets::KDF_FLAGS, 32..48);
+}
+
+/// The crypt field holding the encrypted key of the filesystem
+pub struct CryptField<T> {
+    buffer: T,
+}
+
+impl<T> CryptField<T> {
+    /// Create a crypt field view for the given bytes
+    pub fn from(buf: T) -> CryptField<T> {
+        CryptField { buffer: buf }
+    }
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for CryptField<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer.as_ref()
+    }
+}
+
+impl<T: AsRef<[u8]>> CryptField<T> {
+    /// The value of the given crypt flag
+    pub fn flag(&self, flag: CryptFlag) -> Result<u64> {
+        let max = (1u64 << (flag.1.end - flag.1.start)).wrapping_sub(1);
+        let buf = self.buffer.as_ref();
+        if buf.len() < flag.0.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok((LittleEndian::read_u64(&buf[flag.0]) >> flag.1.start) & max)
+        }
+    }
+
+    /// The key, magic included, which is encrypted unless the magic matches
+    pub fn key(&self) -> Result<&[u8]> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < crypt_offsets::KEY.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(&buf[crypt_offsets::KEY_MAGIC.start..crypt_offsets::KEY.end])
+        }
+    }
+
+    /// Whether the key is encrypted with a passphrase
+    pub fn is_encrypted(&self) -> Result<bool> {
+        Ok(LittleEndian::read_u64(&self.key()?[..8]) != KEY_MAGIC)
+    }
+}
+
+impl<T: AsMut<[u8]>> AsMut<[u8]> for CryptField<T> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut()
+    }
+}
+
+impl<T: AsMut<[u8]>> CryptField<T> {
+    /// Set the crypt flag with the specified value
+    pub fn set_flag(&mut self, flag: CryptFlag, val: u64) -> Result<()> {
+        let max = (1u64 << (flag.1.end - flag.1.start)).wrapping_sub(1);
+        let buf = self.buffer.as_mut();
+        if buf.len() < flag.0.end {
+            Err(BchError::Exhausted)
+        } else if val > max {
+            Err(BchError::Einval(format!("{} > {}", val, max)))
+        } else {
+            let mut flags = LittleEndian::read_u64(&buf[flag.0.clone()]);
+            flags &= !(max << flag.1.start);
+            flags |= val << flag.1.start;
+            LittleEndian::write_u64(&mut buf[flag.0], flags);
+            Ok(())
+        }
+    }
+
+    /// Set the key, magic included
+    pub fn set_key(&mut self, key: &[u8; 8 + KEY_BYTES]) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < crypt_offsets::KEY.end {
+            Err(BchError::Exhausted)
+        } else {
+            buf[crypt_offsets::KEY_MAGIC.start..crypt_offsets::KEY.end].copy_from_slice(key);
+            Ok(())
+        }
+    }
+}
+
+/// Derive the key wrapping the filesystem key from a passphrase
+pub fn derive_key<T: AsRef<[u8]>>(
+    crypt: &CryptField<T>,
+    passphrase: &str,
+) -> Result<[u8; KEY_BYTES]> {
+    if crypt.flag(CryptFlag::KDF_TYPE)? != KdfType::Scrypt as u64 {
+        return Err(BchError::Str(format!(
+            "unknown key derivation function: {}",
+            crypt.flag(CryptFlag::KDF_TYPE)?
+        )));
+    }
+
+    let log_n = crypt.flag(CryptFlag::SCRYPT_N)?;
+    let log_r = crypt.flag(CryptFlag::SCRYPT_R)?;
+    let log_p = crypt.flag(CryptFlag::SCRYPT_P)?;
+    if log_n > 63 || log_r > 31 || log_p > 31 {
+        return Err(BchError::Einval(format!(
+            "invalid scrypt parameters: N=2^{} r=2^{} p=2^{}",
+            log_n, log_r, log_p
+        )));
+    }
+    debug!(
+        "deriving key with scrypt N=2^{} r=2^{} p=2^{}",
+        log_n, log_r, log_p
+    );
+
+    let params = scrypt::Params::new(log_n as u8, 1 << log_r, 1 << log_p)
+        .map_err(|e| BchError::Einval(format!("invalid scrypt parameters: {}", e)))?;
+    let mut key = [0u8; KEY_BYTES];
+    scrypt::scrypt(passphrase.as_bytes(), KDF_SALT, &params, &mut key)
+        .map_err(|e| BchError::Str(format!("scrypt failed: {}", e)))?;
+    Ok(key)
+}
+
+/// The nonce the filesystem key is encrypted with, taken from the internal uuid
+fn key_nonce(sb: &[u8]) -> Result<Nonce> {
+    let uuid = SuperBlock::from(sb).uuid()?;
+    let magic = &uuid.as_bytes()[..8];
+    Ok(Nonce([
+        0,
+        0,
+        LittleEndian::read_u32(&magic[..4]),
+        LittleEndian::read_u32(&magic[4..]),
+    ]))
+}
+
+/// Encrypt or decrypt `buf` in place with ChaCha20
+///
+/// The keystream starts at the block the nonce counts.
+pub(crate) fn chacha20(key: &[u8; KEY_BYTES], nonce: Nonce, buf: &mut [u8]) {
+    let mut iv = [0u8; 12];
+    LittleEndian::write_u32_into(&nonce.0[1..], &mut iv);
+    let mut cipher = ChaCha20::new(&Key::from(*key), &ChaChaNonce::from(iv));
+    cipher.seek(u64::from(nonce.0[0]) * CHACHA_BLOCK_BYTES);
+    cipher.apply_keystream(buf);
+}
+
+/// The crypt field of a superblock
+pub fn crypt_field(sb: &[u8]) -> Result<CryptField<&[u8]>> {
+    match SuperBlock::from(sb).field_range(Field::Crypt)? {
+        Some(range) if range.end - range.start - 8 >= CRYPT_BYTES => {
+            Ok(CryptField::from(&sb[(range.start + 8)..range.end]))
+        }
+        Some(_) => Err(BchError::Exhausted),
+        None => Err(BchError::Str("filesystem is not encrypted".to_string())),
+    }
+}
+
+/// Decrypt the filesystem key with the key derived from the passphrase
+pub fn decrypt_key(sb: &[u8], passphrase_key: &[u8; KEY_BYTES]) -> Result<[u8; KEY_BYTES]> {
+    let crypt = crypt_field(sb)?;
+    let mut key = [0u8; 8 + KEY_BYTES];
+    key.copy_from_slice(crypt.key()?);
+
+    if crypt.is_encrypted()? {
+        chacha20(passphrase_key, key_nonce(sb)?, &mut key);
+        if LittleEndian::read_u64(&key[..8]) != KEY_MAGIC {
+            return Err(BchError::Str("incorrect passphrase".to_string()));
+        }
+    }
+
+    let mut master = [0u8; KEY_BYTES];
+    master.copy_from_slice(&key[8..]);
+    Ok(master)
+}
+
+/// Build a crypt field holding the filesystem key
+///
+/// The key is encrypted with a key derived from the passphrase with the given
+/// log2 scrypt parameters, or stored unencrypted without a passphrase.
+fn wrap_key(
+    sb: &[u8],
+    master: &[u8; KEY_BYTES],
+    passphrase: Option<&str>,
+    (log_n, log_r, log_p): (u64, u64, u64),
+) -> Result<Vec<u8>> {
+    let mut body = crypt_field(sb)?.as_ref().to_vec();
+    let mut key = [0u8; 8 + KEY_BYTES];
+    LittleEndian::write_u64(&mut key[..8], KEY_MAGIC);
+    key[8..].copy_from_slice(master);
+
+    let mut crypt = CryptField::from(&mut body[..]);
+    if let Some(passphrase) = passphrase {
+        crypt.set_flag(CryptFlag::KDF_TYPE, KdfType::Scrypt as u64)?;
+        crypt.set_flag(CryptFlag::SCRYPT_N, log_n)?;
+        crypt.set_flag(CryptFlag::SCRYPT_R, log_r)?;
+        crypt.set_flag(CryptFlag::SCRYPT_P, log_p)?;
+
+        let passphrase_key = derive_key(&crypt, passphrase)?;
+        chacha20(&passphrase_key, key_nonce(sb)?, &mut key);
+    }
+    crypt.set_key(&key)?;
+
+    Ok(body)
+}
+
+/// Read a passphrase from a key file or prompt for it
+pub(crate) fn read_passphrase(keyfile: Option<&str>, prompt: &str) -> Result<String> {
+    match keyfile {
+        Some(path) => {
+            let passphrase = fs::read_to_string(path)?;
+            Ok(passphrase
+                .strip_suffix('\n')
+                .unwrap_or(&passphrase)
+                .to_string())
+        }
+        None => Ok(rpassword::read_password_from_tty(Some(prompt))?),
+    }
+}
+
+/// The kernel keyrings a key may be added to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Keyring {
+    /// The keyring of the user
+    User,
+    /// The keyring of the session
+    Session,
+}
+
+impl Keyring {
+    /// The special keyring id of the keyring
+    fn id(self) -> libc::c_long {
+        match self {
+            Keyring::User => -4,
+            Keyring::Session => -3,
+        }
+    }
+}
+
+impl FromStr for Keyring {
+    type Err = BchError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(Keyring::User),
+            "session" => Ok(Keyring::Session),
+            _ => Err(BchError::Einval(format!("unknown keyring: {}", s))),
+        }
+    }
+}
+
+/// Add a user key to the given keyring
+fn add_key(description: &str, payload: &[u8], keyring: Keyring) -> Result<()> {
+    let ty = CString::new("user").expect("static string");
+    let description = CString::new(description)
+        .map_err(|_| BchError::Einval(format!("invalid key description: {}", description)))?;
+
+    // SAFETY: the strings are nul terminated and the payload outlives the call
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            ty.as_ptr(),
+            description.as_ptr(),
+            payload.as_ptr(),
+            payload.len(),
+            keyring.id(),
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error().into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Arguments that the unlock subcommand may be provided.
+#[derive(Debug)]
+pub struct UnlockArgs {
+    /// A device of the filesystem
+    pub device: String,
+    /// Read the passphrase from a file instead of prompting for it
+    pub keyfile: Option<String>,
+    /// Only check the passphrase, without adding the key
+    pub check: bool,
+    /// The keyring the key is added to
+    pub keyring: Keyring,
+}
+
+/// Prompt for the passphrase of an encrypted superblock and check it
+///
+/// Returns the key derived from the passphrase.
+fn passphrase_key(sb: &[u8], keyfile: Option<&str>) -> Result<[u8; KEY_BYTES]> {
+    let crypt = crypt_field(sb)?;
+    let passphrase = read_passphrase(keyfile, "Enter passphrase: ")?;
+    let passphrase_key = derive_key(&crypt, &passphrase)?;
+    decrypt_key(sb, &passphrase_key)?;
+    Ok(passphrase_key)
+}
+
+/// Prompt for the passphrase of an encrypted superblock and add its key
+///
+/// The key derived from the passphrase is added with the description the
+/// kernel looks up on mount, `bcachefs:<user uuid>`. Nothing is done when the
+/// key is not encrypted with a passphrase.
+pub(crate) fn unlock_sb(sb: &[u8], keyfile: Option<&str>, keyring: Keyring) -> Result<()> {
+    if !crypt_field(sb)?.is_encrypted()? {
+        info!("key is not encrypted with a passphrase");
+        return Ok(());
+    }
+
+    let passphrase_key = passphrase_key(sb, keyfile)?;
+    let description = format!("bcachefs:{}", SuperBlock::from(sb).user_uuid()?);
+    debug!("adding key {} to the {:?} keyring", description, keyring);
+    add_key(&description, &passphrase_key, keyring)
+}
+
+/// Unlock an encrypted filesystem by adding its key to the kernel keyring
+pub fn unlock(args: UnlockArgs) -> Result<()> {
+    let dev = open_device(&args.device, false)?;
+    let sb = read_super(dev.as_ref())?;
+
+    if !args.check {
+        return unlock_sb(&sb, args.keyfile.as_deref(), args.keyring);
+    }
+
+    if crypt_field(&sb)?.is_encrypted()? {
+        passphrase_key(&sb, args.keyfile.as_deref())?;
+        println!("{}: passphrase is correct", args.device);
+    } else {
+        info!("{}: key is not encrypted with a passphrase", args.device);
+    }
+    Ok(())
+}
+
+/// Arguments that the set-passphrase and remove-passphrase subcommands may be
+/// provided.
+#[derive(Debug)]
+pub struct PassphraseArgs {
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// Read the current passphrase from a file instead of prompting for it
+    pub keyfile: Option<String>,
+    /// Read the new passphrase from a file instead of prompting for it
+    pub new_keyfile: Option<String>,
+}
+
+/// Decrypt the filesystem key of the members, prompting for the passphrase
+fn unwrap_key(members: &[Member], keyfile: Option<&str>) -> Result<[u8; KEY_BYTES]> {
+    let sb = match members.first() {
+        Some(first) => &first.sb,
+        None => return Err(BchError::Str("no devices given".to_string())),
+    };
+    let crypt = crypt_field(sb)?;
+
+    if crypt.is_encrypted()? {
+        let passphrase = read_passphrase(keyfile, "Enter current passphrase: ")?;
+        decrypt_key(sb, &derive_key(&crypt, &passphrase)?)
+    } else {
+        decrypt_key(sb, &[0u8; KEY_BYTES])
+    }
+}
+
+/// The key of the filesystem if it is encrypted, prompting for the passphrase
+pub(crate) fn fs_key(members: &[Member], keyfile: Option<&str>) -> Result<Option<[u8; KEY_BYTES]>> {
+    match members.first() {
+        Some(first) if first.sb().field(Field::Crypt)?.is_some() => {
+            Ok(Some(unwrap_key(members, keyfile)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Replace the crypt field of every member
+fn rewrap_members(
+    members: &mut [Member],
+    master: &[u8; KEY_BYTES],
+    passphrase: Option<&str>,
+    params: (u64, u64, u64),
+) -> Result<()> {
+    let body = wrap_key(&members[0].sb, master, passphrase, params)?;
+    for member in members.iter_mut() {
+        set_field(&mut member.sb, Field::Crypt, &body)?;
+    }
+    Ok(())
+}
+
+/// Change the passphrase of an unmounted encrypted filesystem
+///
+/// The filesystem key itself is unchanged, only the key wrapping it is.
+pub fn set_passphrase(args: PassphraseArgs) -> Result<()> {
+    let mut members = open_members(&args.devices, true)?;
+    let master = unwrap_key(&members, args.keyfile.as_deref())?;
+
+    let passphrase = match args.new_keyfile {
+        Some(ref path) => read_passphrase(Some(path), "")?,
+        None => {
+            let passphrase = read_passphrase(None, "Enter new passphrase: ")?;
+            if passphrase != read_passphrase(None, "Enter same passphrase again: ")? {
+                return Err(BchError::Str("passphrases do not match".to_string()));
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        return Err(BchError::Einval("empty passphrase".to_string()));
+    }
+
+    rewrap_members(&mut members, &master, Some(&passphrase), SCRYPT_PARAMS)?;
+    write_members(&mut members)
+}
+
+/// Store the key of an unmounted encrypted filesystem without a passphrase
+pub fn remove_passphrase(args: PassphraseArgs) -> Result<()> {
+    let mut members = open_members(&args.devices, true)?;
+    let master = unwrap_key(&members, args.keyfile.as_deref())?;
+
+    rewrap_members(&mut members, &master, None, SCRYPT_PARAMS)?;
+    write_members(&mut members)
+}
+
+#[cfg(test)]
+mod test_crypt {
+    use super::*;
+    use crate::format::test_format::format_members;
+    use uuid::Uuid;
+
+    /// Small scrypt parameters to keep the tests fast
+    const TEST_PARAMS: (u64, u64, u64) = (10, 3, 0);
+
+    /// Add a crypt field with a key encrypted with the given passphrase
+    fn encrypt(sb: &mut Vec<u8>, key: &[u8; KEY_BYTES], passphrase: &str) {
+        set_field(sb, Field::Crypt, &[0u8; CRYPT_BYTES]).unwrap();
+        let body = wrap_key(sb, key, Some(passphrase), TEST_PARAMS).unwrap();
+        set_field(sb, Field::Crypt, &body).unwrap();
+    }
+
+    /// A crypt field laid out as bcachefs-tools writes it for a passphrase
+    ///
+    /// The default N=2^14, r=8 and p=16 are in the kdf flags, the key is
+    /// 00 01 .. 1f encrypted for the internal uuid 2f6a4b55-0cf0-4f4d-9d6e-8a3b1c0e7d21.
+    const TOOLS_CRYPT: [u8; CRYPT_BYTES] = [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x0e, 0x00, 0x03, 0x00, 0x04, 0x00, 0x00, 0x00, //
+        0x25, 0xb7, 0x6d, 0xe7, 0xab, 0x2d, 0x79, 0xee, //
+        0xb3, 0x12, 0xe1, 0xc1, 0xd7, 0xf4, 0x71, 0xdc, //
+        0xc0, 0x5a, 0x50, 0xa0, 0x22, 0xf9, 0x6a, 0x89, //
+        0x88, 0x35, 0x99, 0x3e, 0x58, 0x5c, 0x66, 0x61, //
+        0x47, 0xc0, 0x22, 0x87, 0x68, 0x35, 0x7c, 0x93, //
+    ];
+
+    /// scrypt of the passphrase `bcachefs` with the parameters of `TOOLS_CRYPT`
+    const TOOLS_PASSPHRASE_KEY: [u8; KEY_BYTES] = [
+        0x57, 0x20, 0x4a, 0x53, 0x8b, 0x53, 0x23, 0x88, 0xd5, 0xc8, 0x46, 0xf5, 0xfa, 0xdf, 0xb9,
+        0x7c, 0x2e, 0xaf, 0x1c, 0x0b, 0x6e, 0x88, 0x1b, 0xb7, 0x51, 0x2c, 0xf2, 0x0a, 0x08, 0x67,
+        0xeb, 0x15,
+    ];
+
+    #[test]
+    fn kdf_salt() {
+        // scrypt of `bcachefs` salted with `bcache` and its NUL, N=2^10 r=8 p=1
+        let mut body = [0u8; CRYPT_BYTES];
+        let mut crypt = CryptField::from(&mut body[..]);
+        crypt.set_flag(CryptFlag::SCRYPT_N, 10).unwrap();
+        crypt.set_flag(CryptFlag::SCRYPT_R, 3).unwrap();
+        assert_eq!(
+            derive_key(&CryptField::from(&body[..]), "bcachefs").unwrap(),
+            [
+                0x2d, 0xe7, 0x45, 0x22, 0xaf, 0x23, 0xdb, 0x40, 0xaf, 0x2c, 0x80, 0xed, 0x30, 0xa1,
+                0x8c, 0x8c, 0xba, 0xb8, 0x01, 0x1c, 0xe1, 0xe1, 0x59, 0xd0, 0x9a, 0x74, 0x5d, 0x50,
+                0x4e, 0x86, 0xb8, 0x01,
+            ]
+        );
+    }
+
+    #[test]
+    fn key_magic() {
+        assert_eq!(&KEY_MAGIC.to_le_bytes(), b"bch**key");
+    }
+
+    #[test]
+    fn unlock_key() {
+        let mut sb = format_members(&["mem0"]).remove(0).sb;
+        assert!(crypt_field(&sb).is_err());
+
+        let key = [0x5a; KEY_BYTES];
+        encrypt(&mut sb, &key, "secret");
+
+        let crypt = crypt_field(&sb).unwrap();
+        assert!(crypt.is_encrypted().unwrap());
+        assert_eq!(crypt.flag(CryptFlag::SCRYPT_N).unwrap(), 10);
+
+        let passphrase_key = derive_key(&crypt, "secret").unwrap();
+        assert_eq!(decrypt_key(&sb, &passphrase_key).unwrap(), key);
+        let wrong_key = derive_key(&crypt, "wrong").unwrap();
+        assert!(decrypt_key(&sb, &wrong_key).is_err());
+    }
+
+    #[test]
+    fn tools_crypt_field() {
+        let crypt = CryptField::from(&TOOLS_CRYPT[..]);
+        assert_eq!(
+            crypt.flag(CryptFlag::KDF_TYPE).unwrap(),
+            KdfType::Scrypt as u64
+        );
+        assert_eq!(crypt.flag(CryptFlag::SCRYPT_N).unwrap(), 14);
+        assert_eq!(crypt.flag(CryptFlag::SCRYPT_R).unwrap(), 3);
+        assert_eq!(crypt.flag(CryptFlag::SCRYPT_P).unwrap(), 4);
+        assert!(crypt.is_encrypted().unwrap());
+
+        let mut sb = format_members(&["mem0"]).remove(0).sb;
+        let uuid = Uuid::parse_str("2f6a4b55-0cf0-4f4d-9d6e-8a3b1c0e7d21").unwrap();
+        SuperBlock::from(&mut sb[..]).set_uuid(uuid).unwrap();
+        set_field(&mut sb, Field::Crypt, &TOOLS_CRYPT).unwrap();
+        let key = decrypt_key(&sb, &TOOLS_PASSPHRASE_KEY).unwrap();
+        assert_eq!(key.to_vec(), (0..32).collect::<Vec<u8>>());
+
+        // the parameters are written to the kdf flags only
+        let mut body = TOOLS_CRYPT;
+        body[crypt_offsets::KDF_FLAGS].copy_from_slice(&[0u8; 8]);
+        let mut crypt = CryptField::from(&mut body[..]);
+        crypt.set_flag(CryptFlag::SCRYPT_N, 14).unwrap();
+        crypt.set_flag(CryptFlag::SCRYPT_R, 3).unwrap();
+        crypt.set_flag(CryptFlag::SCRYPT_P, 4).unwrap();
+        assert_eq!(body, TOOLS_CRYPT);
+    }
+
+    #[test]
+    fn change_passphrase() {
+        let mut members = format_members(&["mem0", "mem1"]);
+        let key = [0xa5; KEY_BYTES];
+        for member in members.iter_mut() {
+            encrypt(&mut member.sb, &key, "old");
+        }
+
+        rewrap_members(&mut members, &key, Some("new"), TEST_PARAMS).unwrap();
+        write_members(&mut members).unwrap();
+        let sb = read_super(members[1].dev.as_ref()).unwrap();
+        let crypt = crypt_field(&sb).unwrap();
+        assert!(decrypt_key(&sb, &derive_key(&crypt, "old").unwrap()).is_err());
+        assert_eq!(
+            decrypt_key(&sb, &derive_key(&crypt, "new").unwrap()).unwrap(),
+            key
+        );
+
+        rewrap_members(&mut members, &key, None, TEST_PARAMS).unwrap();
+        let crypt = crypt_field(&members[0].sb).unwrap();
+        assert!(!crypt.is_encrypted().unwrap());
+        assert_eq!(unwrap_key(&members, None).unwrap(), key);
+    }
+}
@@ -0,0 +1,815 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::block_dev::{is_mounted, open_device, BlockDevice};
+use crate::format::{check_device, default_layout, Device};
+use crate::super_block::{
+    Field, MemberField, MemberFlag, SuperBlock, SuperBlockFlag, SuperBlockFlags, SuperBlockLayout,
+    MEMBER_BYTES,
+};
+use crate::super_io::{
+    check_complete, member_uuids, open_members, open_members_partial, remove_field, set_field,
+    wipe_super, write_members, Member,
+};
+use crate::{BchError, Result};
+
+use log::{debug, info, warn};
+
+/// Maximum number of member devices of a filesystem
+const MAX_DEVICES: usize = 64;
+/// Largest durability that fits in the member flags
+const MAX_DURABILITY: u64 = 2;
+
+/// The state of a member device
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum MemberState {
+    /// Data may be read from and written to the device
+    ReadWrite = 0,
+    /// Data may only be read from the device
+    ReadOnly = 1,
+    /// The device has failed and is not used
+    Failed = 2,
+    /// The device is a spare and is not used
+    Spare = 3,
+}
+
+impl fmt::Display for MemberState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemberState::ReadWrite => write!(f, "rw"),
+            MemberState::ReadOnly => write!(f, "ro"),
+            MemberState::Failed => write!(f, "failed"),
+            MemberState::Spare => write!(f, "spare"),
+        }
+    }
+}
+
+impl FromStr for MemberState {
+    type Err = BchError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rw" | "read-write" => Ok(MemberState::ReadWrite),
+            "ro" | "read-only" => Ok(MemberState::ReadOnly),
+            "failed" => Ok(MemberState::Failed),
+            "spare" => Ok(MemberState::Spare),
+            _ => Err(BchError::Einval(format!("unknown member state: {}", s))),
+        }
+    }
+}
+
+impl TryFrom<u64> for MemberState {
+    type Error = BchError;
+
+    fn try_from(state: u64) -> Result<Self> {
+        match state {
+            0 => Ok(MemberState::ReadWrite),
+            1 => Ok(MemberState::ReadOnly),
+            2 => Ok(MemberState::Failed),
+            3 => Ok(MemberState::Spare),
+            _ => Err(BchError::Einval(format!("unknown member state: {}", state))),
+        }
+    }
+}
+
+/// Arguments that the device add subcommand may be provided.
+#[derive(Debug)]
+pub struct AddArgs {
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// The device to add to the filesystem
+    pub new_device: String,
+    /// The bucket size in bytes, picked for the device if not given
+    pub bucket_size: Option<u64>,
+    /// The durability of data stored on the device
+    pub durability: u64,
+    /// Add the device even if a filesystem or partition table exists on it
+    pub force: bool,
+    /// Add a host-managed zoned device, using only its conventional zones
+    pub host_managed: bool,
+}
+
+/// Add a device to the members of a filesystem and build its superblock
+///
+/// The new member takes the first unused slot of the members field. The
+/// superblocks are not written.
+fn add_member(
+    members: &mut Vec<Member>,
+    path: &str,
+    dev: Box<dyn BlockDevice>,
+    bucket_size: Option<u64>,
+    durability: u64,
+    host_managed: bool,
+) -> Result<u8> {
+    if durability > MAX_DURABILITY {
+        return Err(BchError::Einval(format!(
+            "durability {} > {}",
+            durability, MAX_DURABILITY
+        )));
+    }
+
+    let first = match members.first() {
+        Some(first) => first,
+        None => return Err(BchError::Str("no devices given".to_string())),
+    };
+    let view = first.sb();
+    let block_size = u64::from(view.block_size()?);
+    let sb_max_size = view.layout()?.sb_max_size()?;
+    let mut member_buf = view
+        .field(Field::Members)?
+        .map(|members| members.to_vec())
+        .unwrap_or_default();
+    let mut new_sb = first.sb.clone();
+
+    let idx = match member_uuids(&first.sb)?.iter().position(Option::is_none) {
+        Some(idx) => idx,
+        None => member_buf.len() / MEMBER_BYTES,
+    };
+    if idx >= MAX_DEVICES {
+        return Err(BchError::Str(format!(
+            "filesystem already has {} devices",
+            MAX_DEVICES
+        )));
+    }
+    if member_buf.len() < (idx + 1) * MEMBER_BYTES {
+        member_buf.resize((idx + 1) * MEMBER_BYTES, 0);
+    }
+    let nr_devices = (member_buf.len() / MEMBER_BYTES) as u8;
+
+    {
+        let mut device = Device::probe(path, dev.as_ref(), 1 << sb_max_size, host_managed)?;
+        if device.logical_block_size() > block_size << 9 {
+            return Err(BchError::Str(format!(
+                "{}: logical block size {} larger than filesystem block size {}",
+                path,
+                device.logical_block_size(),
+                block_size << 9
+            )));
+        }
+        device.pick_buckets(block_size, bucket_size.map(|size| size >> 9))?;
+        device.init_member(
+            &mut member_buf[(idx * MEMBER_BYTES)..((idx + 1) * MEMBER_BYTES)],
+            durability,
+        )?;
+        device.init_layout(&default_layout(1 << sb_max_size)?)?;
+    }
+    info!("{}: adding as member {}", path, idx);
+
+    for member in members.iter_mut() {
+        set_field(&mut member.sb, Field::Members, &member_buf)?;
+        member.sb_mut().set_nr_devices(nr_devices)?;
+    }
+
+    // the journal buckets of the copied superblock are on another device
+    remove_field(&mut new_sb, Field::Journal)?;
+    set_field(&mut new_sb, Field::Members, &member_buf)?;
+    let layout_buf = default_layout(1 << sb_max_size)?;
+    let mut sb = SuperBlock::from(&mut new_sb[..]);
+    sb.set_nr_devices(nr_devices)?;
+    sb.set_dev_idx(idx as u8)?;
+    sb.set_layout(&SuperBlockLayout::from(&layout_buf[..]))?;
+
+    members.push(Member {
+        path: path.to_string(),
+        dev,
+        sb: new_sb,
+    });
+
+    Ok(idx as u8)
+}
+
+/// Add a new device to an unmounted filesystem
+pub fn device_add(args: AddArgs) -> Result<()> {
+    let mut members = open_members(&args.devices, true)?;
+
+    if is_mounted(&args.new_device)? {
+        return Err(BchError::Str(format!(
+            "{}: device is mounted",
+            args.new_device
+        )));
+    }
+    if members.iter().any(|member| member.path == args.new_device) {
+        return Err(BchError::Str(format!(
+            "{} is already a member of the filesystem",
+            args.new_device
+        )));
+    }
+    if !args.force {
+        check_device(&args.new_device)?;
+    }
+
+    let dev = open_device(&args.new_device, true)?;
+    let idx = add_member(
+        &mut members,
+        &args.new_device,
+        dev,
+        args.bucket_size,
+        args.durability,
+        args.host_managed,
+    )?;
+    debug!("{}: writing superblocks", args.new_device);
+
+    write_members(&mut members)?;
+    println!("{}: added as device {}", args.new_device, idx);
+    Ok(())
+}
+
+/// Arguments that the device set-state subcommand may be provided.
+#[derive(Debug)]
+pub struct SetStateArgs {
+    /// The devices of the filesystem, the changed device may be missing
+    pub devices: Vec<String>,
+    /// The path or index of the member to change
+    pub device: String,
+    /// The new state of the member
+    pub state: MemberState,
+    /// Change the state even if the required replicas can no longer be written
+    pub force: bool,
+}
+
+/// Arguments that the device remove subcommand may be provided.
+#[derive(Debug)]
+pub struct RemoveArgs {
+    /// The devices of the filesystem, the removed device may be missing
+    pub devices: Vec<String>,
+    /// The path or index of the member to remove
+    pub device: String,
+    /// Remove the device even if the required replicas can no longer be written
+    pub force: bool,
+}
+
+/// Find the index of a member given its path or index
+fn find_member(members: &[Member], device: &str) -> Result<u8> {
+    if let Some(member) = members.iter().find(|member| member.path == device) {
+        return member.sb().device_index();
+    }
+
+    let first = match members.first() {
+        Some(first) => first,
+        None => return Err(BchError::Str("no devices given".to_string())),
+    };
+    match device.parse::<u8>() {
+        Ok(idx) if member_uuids(&first.sb)?.get(idx as usize) == Some(&None) => {
+            Err(BchError::Einval(format!("member {} has been removed", idx)))
+        }
+        Ok(idx) if (idx as usize) < member_uuids(&first.sb)?.len() => Ok(idx),
+        _ => Err(BchError::Einval(format!(
+            "{} is neither a given device nor a member index",
+            device
+        ))),
+    }
+}
+
+/// The members field shared by all members
+fn members_field(members: &[Member]) -> Result<Vec<u8>> {
+    match members.first() {
+        Some(first) => match first.sb().field(Field::Members)? {
+            Some(member_buf) => Ok(member_buf.to_vec()),
+            None => Err(BchError::Str("superblock has no members".to_string())),
+        },
+        None => Err(BchError::Str("no devices given".to_string())),
+    }
+}
+
+/// Drop a device from the entries of a replicas field
+///
+/// Entries are a data type, the number of devices, for the current field the
+/// number of devices required, and the device indexes. Entries left without a
+/// device are dropped, the number required is lowered for the kernel to accept
+/// the entry. Like the kernel's the entries are kept sorted and unique.
+fn replicas_without(replicas_buf: &[u8], header: usize, idx: u8) -> Result<Vec<u8>> {
+    let mut entries = Vec::new();
+    let mut rest = replicas_buf;
+
+    while rest.len() >= header && rest[0] > 0 {
+        let end = header + rest[1] as usize;
+        if rest.len() < end {
+            return Err(BchError::Einval(
+                "replicas entry past the end of the field".to_string(),
+            ));
+        }
+        let mut devs = rest[header..end]
+            .iter()
+            .copied()
+            .filter(|dev| *dev != idx)
+            .collect::<Vec<_>>();
+        if !devs.is_empty() {
+            devs.sort_unstable();
+            let mut entry = rest[..header].to_vec();
+            entry[1] = devs.len() as u8;
+            if header > 2 {
+                // more than one device required has to leave one to spare
+                entry[2] = cmp::min(entry[2], cmp::max(entry[1] - 1, 1));
+            }
+            entry.extend_from_slice(&devs);
+            entries.push(entry);
+        }
+        rest = &rest[end..];
+    }
+
+    entries.sort();
+    entries.dedup();
+    Ok(entries.concat())
+}
+
+/// Check that the read-write members, without `idx`, can hold the replicas required
+fn check_replicas(members: &[Member], member_buf: &[u8], idx: u8, force: bool) -> Result<()> {
+    let sb = members[0].sb();
+    let flags = sb.flags()?;
+    let required = cmp::max(
+        flags.flag(SuperBlockFlag::META_REPLICAS_REQ)?,
+        flags.flag(SuperBlockFlag::DATA_REPLICAS_REQ)?,
+    );
+
+    let mut rw = 0;
+    for (i, member) in member_buf.chunks_exact(MEMBER_BYTES).enumerate() {
+        let member = MemberField::from(member);
+        if i != idx as usize
+            && !member.uuid()?.is_nil()
+            && MemberState::try_from(member.flag(MemberFlag::STATE)?)? == MemberState::ReadWrite
+        {
+            rw += 1;
+        }
+    }
+
+    if rw >= required {
+        Ok(())
+    } else if force {
+        warn!(
+            "only {} read-write devices left for {} required replicas",
+            rw, required
+        );
+        Ok(())
+    } else {
+        Err(BchError::Str(format!(
+            "only {} read-write devices would be left for {} required replicas",
+            rw, required
+        )))
+    }
+}
+
+/// Change the state of a member in the superblock of every member
+fn set_member_state(
+    members: &mut [Member],
+    idx: u8,
+    state: MemberState,
+    force: bool,
+) -> Result<()> {
+    let mut member_buf = members_field(members)?;
+    if state != MemberState::ReadWrite {
+        check_replicas(members, &member_buf, idx, force)?;
+    }
+
+    let start = idx as usize * MEMBER_BYTES;
+    let mut member = MemberField::from(&mut member_buf[start..(start + MEMBER_BYTES)]);
+    member.set_flag(MemberFlag::STATE, state as u64)?;
+    info!("setting member {} to {}", idx, state);
+
+    for member in members.iter_mut() {
+        set_field(&mut member.sb, Field::Members, &member_buf)?;
+    }
+    Ok(())
+}
+
+/// Remove a member from the superblock of every member
+///
+/// Targets pointing at the member are reset. The removed member is returned if
+/// it was given.
+fn remove_member(members: &mut Vec<Member>, idx: u8, force: bool) -> Result<Option<Member>> {
+    let mut member_buf = members_field(members)?;
+    check_replicas(members, &member_buf, idx, force)?;
+
+    let start = idx as usize * MEMBER_BYTES;
+    member_buf[start..(start + MEMBER_BYTES)].copy_from_slice(&[0u8; MEMBER_BYTES]);
+    info!("removing member {}", idx);
+
+    let mut flags_buf = [0u8; 64];
+    flags_buf.copy_from_slice(members[0].sb().flags()?.as_ref());
+    let mut flags = SuperBlockFlags::from(&mut flags_buf[..]);
+    for target in [
+        SuperBlockFlag::FOREGROUND_TARGET,
+        SuperBlockFlag::BACKGROUND_TARGET,
+        SuperBlockFlag::PROMOTE_TARGET,
+        SuperBlockFlag::METADATA_TARGET,
+    ]
+    .iter()
+    {
+        if flags.flag(target.clone())? == u64::from(idx) + 1 {
+            info!("resetting target {:?} to the removed member", target);
+            flags.set_flag(target.clone(), 0)?;
+        }
+    }
+
+    let mut removed = None;
+    for i in (0..members.len()).rev() {
+        if members[i].sb().device_index()? == idx {
+            removed = Some(members.remove(i));
+        }
+    }
+
+    for member in members.iter_mut() {
+        set_field(&mut member.sb, Field::Members, &member_buf)?;
+        member.sb_mut().set_flags(&flags)?;
+        for (field, header) in [(Field::ReplicasV0, 2), (Field::Replicas, 3)].iter() {
+            let replicas_buf = match member.sb().field(*field)? {
+                Some(replicas_buf) => replicas_without(replicas_buf, *header, idx)?,
+                None => continue,
+            };
+            set_field(&mut member.sb, *field, &replicas_buf)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Change the state of a member of an unmounted filesystem
+pub fn device_set_state(args: SetStateArgs) -> Result<()> {
+    let mut members = open_members_partial(&args.devices, true)?;
+    let idx = find_member(&members, &args.device)?;
+    check_complete(&members, Some(idx))?;
+
+    set_member_state(&mut members, idx, args.state, args.force)?;
+    write_members(&mut members)
+}
+
+/// Remove a member from an unmounted filesystem
+///
+/// When the removed device is given its superblock is wiped.
+pub fn device_remove(args: RemoveArgs) -> Result<()> {
+    let mut members = open_members_partial(&args.devices, true)?;
+    let idx = find_member(&members, &args.device)?;
+    check_complete(&members, Some(idx))?;
+
+    if members.len() == 1 && members[0].sb().device_index()? == idx {
+        return Err(BchError::Str(
+            "cannot remove the last device of a filesystem".to_string(),
+        ));
+    }
+
+    let removed = remove_member(&mut members, idx, args.force)?;
+    write_members(&mut members)?;
+
+    if let Some(removed) = removed {
+        debug!("{}: wiping superblock", removed.path);
+        wipe_super(removed.dev.as_ref(), &removed.sb)?;
+    }
+    Ok(())
+}
+
+/// Arguments that the device resize subcommand may be provided.
+#[derive(Debug)]
+pub struct ResizeArgs {
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// The member to resize
+    pub device: String,
+    /// The new size in bytes, the size of the device if not given
+    pub size: Option<u64>,
+}
+
+/// Grow the buckets of a member to the given size in sectors
+///
+/// A superblock copy past the end of the buckets is moved to the new end of the
+/// device. Only the member fields and layout are updated; the allocation info of
+/// the new buckets is created by the kernel on the next mount. Returns whether
+/// the member changed.
+fn resize_member(members: &mut [Member], pos: usize, size: u64) -> Result<bool> {
+    let idx = members[pos].sb().device_index()? as usize;
+    let mut member_buf = members_field(members)?;
+    let member = MemberField::from(&member_buf[(idx * MEMBER_BYTES)..((idx + 1) * MEMBER_BYTES)]);
+    let bucket_size = u64::from(member.bucket_size()?);
+    let nbuckets = member.n_buckets()?;
+    let buckets_end = nbuckets * bucket_size;
+
+    let sb = members[pos].sb();
+    let mut layout_buf = [0u8; 512];
+    layout_buf.copy_from_slice(sb.layout()?.as_ref());
+    let mut layout = SuperBlockLayout::from(&mut layout_buf[..]);
+    let sb_sectors = 1u64 << layout.sb_max_size()?;
+    let block_size = u64::from(sb.block_size()?);
+
+    // superblock copies stored after the buckets live at the end of the device
+    let nr_superblocks = layout.nr_superblocks()? as usize;
+    let mut moving = Vec::new();
+    for i in 0..nr_superblocks {
+        if layout.sb_offset(i)? >= buckets_end {
+            moving.push(i);
+        }
+    }
+    let end = match size.checked_sub(moving.len() as u64 * sb_sectors) {
+        Some(end) => end / block_size * block_size,
+        None => {
+            return Err(BchError::Einval(format!(
+                "{}: {} sectors too small for {} superblocks",
+                members[pos].path,
+                size,
+                moving.len()
+            )))
+        }
+    };
+    for (n, i) in moving.iter().enumerate() {
+        let new_offset = end + n as u64 * sb_sectors;
+        debug!(
+            "{}: moving superblock at sector {} to {}",
+            members[pos].path,
+            layout.sb_offset(*i)?,
+            new_offset
+        );
+        layout.set_sb_offset(*i, new_offset)?;
+    }
+    let moved = !moving.is_empty();
+
+    let new_nbuckets = end / bucket_size;
+    if new_nbuckets < nbuckets {
+        return Err(BchError::Str(format!(
+            "{}: shrinking from {} to {} buckets is not supported",
+            members[pos].path, nbuckets, new_nbuckets
+        )));
+    } else if new_nbuckets == nbuckets {
+        info!(
+            "{}: already uses {} buckets of {} sectors",
+            members[pos].path, nbuckets, bucket_size
+        );
+        return Ok(false);
+    }
+    info!(
+        "{}: growing from {} to {} buckets",
+        members[pos].path, nbuckets, new_nbuckets
+    );
+
+    MemberField::from(&mut member_buf[(idx * MEMBER_BYTES)..((idx + 1) * MEMBER_BYTES)])
+        .set_n_buckets(new_nbuckets)?;
+    for member in members.iter_mut() {
+        set_field(&mut member.sb, Field::Members, &member_buf)?;
+    }
+    if moved {
+        members[pos].sb_mut().set_layout(&layout)?;
+    }
+    Ok(true)
+}
+
+/// Grow a member of an unmounted filesystem to the size of its device
+pub fn device_resize(args: ResizeArgs) -> Result<()> {
+    let mut members = open_members(&args.devices, true)?;
+    let pos = match members.iter().position(|member| member.path == args.device) {
+        Some(pos) => pos,
+        None => {
+            return Err(BchError::Einval(format!(
+                "{} is not one of the given devices",
+                args.device
+            )))
+        }
+    };
+
+    let sb_max_size = members[pos].sb().layout()?.sb_max_size()?;
+    let dev_size = Device::probe(
+        &args.device,
+        members[pos].dev.as_ref(),
+        1 << sb_max_size,
+        true,
+    )?
+    .size();
+    let size = match args.size {
+        Some(size) if size >> 9 > dev_size => {
+            return Err(BchError::Einval(format!(
+                "{}: size {} larger than the device",
+                args.device, size
+            )))
+        }
+        Some(size) => size >> 9,
+        None => dev_size,
+    };
+
+    if resize_member(&mut members, pos, size)? {
+        write_members(&mut members)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_device {
+    use super::*;
+    use crate::block_dev::MemoryDevice;
+    use crate::format::test_format::format_members as members;
+    use crate::super_block::{MemberField, MemberFlag};
+    use crate::super_io::read_super;
+
+    #[test]
+    fn add_and_reread() {
+        let mut members = members(&["mem0", "mem1"]);
+        let dev = Box::new(MemoryDevice::new(32 << 20));
+        let idx = add_member(&mut members, "mem2", dev, None, 2, false).unwrap();
+        assert_eq!(idx, 2);
+        write_members(&mut members).unwrap();
+
+        let sbs = members
+            .iter()
+            .map(|member| read_super(member.dev.as_ref()).unwrap())
+            .collect::<Vec<_>>();
+        for (i, sb) in sbs.iter().enumerate() {
+            let view = SuperBlock::from(&sb[..]);
+            assert_eq!(view.nr_devices().unwrap(), 3);
+            assert_eq!(view.device_index().unwrap() as usize, i);
+            assert_eq!(view.seq().unwrap(), 1);
+            assert_eq!(member_uuids(sb).unwrap(), member_uuids(&sbs[0]).unwrap());
+        }
+
+        let view = SuperBlock::from(&sbs[2][..]);
+        let member_buf = view.field(Field::Members).unwrap().unwrap();
+        let member = MemberField::from(&member_buf[(2 * MEMBER_BYTES)..]);
+        assert!(!member.uuid().unwrap().is_nil());
+        assert_eq!(member.flag(MemberFlag::DURABILITY).unwrap(), 3);
+        assert_eq!(
+            member.n_buckets().unwrap() * u64::from(member.bucket_size().unwrap()),
+            (32 << 20) >> 9
+        );
+    }
+
+    #[test]
+    fn add_without_journal() {
+        let mut members = members(&["mem0"]);
+        let journal = [8u8, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0];
+        set_field(&mut members[0].sb, Field::Journal, &journal).unwrap();
+
+        let dev = Box::new(MemoryDevice::new(32 << 20));
+        add_member(&mut members, "mem1", dev, None, 1, false).unwrap();
+        write_members(&mut members).unwrap();
+
+        let sb = read_super(members[0].dev.as_ref()).unwrap();
+        let view = SuperBlock::from(&sb[..]);
+        assert_eq!(view.field(Field::Journal).unwrap().unwrap(), &journal[..]);
+        let sb = read_super(members[1].dev.as_ref()).unwrap();
+        let view = SuperBlock::from(&sb[..]);
+        assert!(view.field(Field::Journal).unwrap().is_none());
+        assert!(view.field(Field::Members).unwrap().is_some());
+    }
+
+    fn state(member: &Member, idx: usize) -> MemberState {
+        let sb = member.sb();
+        let member_buf = sb.field(Field::Members).unwrap().unwrap();
+        let member = MemberField::from(&member_buf[(idx * MEMBER_BYTES)..]);
+        MemberState::try_from(member.flag(MemberFlag::STATE).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn set_state_and_remove() {
+        let mut members = members(&["mem0", "mem1", "mem2"]);
+        assert_eq!(find_member(&members, "mem1").unwrap(), 1);
+        assert_eq!(find_member(&members, "2").unwrap(), 2);
+        assert!(find_member(&members, "3").is_err());
+
+        set_member_state(&mut members, 1, MemberState::Failed, false).unwrap();
+        assert_eq!(state(&members[2], 1), MemberState::Failed);
+        assert_eq!(state(&members[2], 0), MemberState::ReadWrite);
+
+        let removed = remove_member(&mut members, 1, false).unwrap().unwrap();
+        assert_eq!(removed.path, "mem1");
+        write_members(&mut members).unwrap();
+
+        let sb = read_super(members[0].dev.as_ref()).unwrap();
+        let uuids = member_uuids(&sb).unwrap();
+        assert_eq!(uuids.len(), 3);
+        assert!(uuids[1].is_none() && uuids[2].is_some());
+        assert!(find_member(&members, "1").is_err());
+        check_complete(&members, None).unwrap();
+    }
+
+    #[test]
+    fn remove_from_replicas() {
+        let mut members = members(&["mem0", "mem1", "mem2"]);
+        // journal on 0 and 1, btree on 1 and 2 with both required, user on 1
+        let replicas = [2, 2, 1, 0, 1, 3, 2, 2, 1, 2, 4, 1, 1, 1];
+        let replicas_v0 = [4, 2, 1, 2, 3, 1, 1];
+        for member in members.iter_mut() {
+            set_field(&mut member.sb, Field::Replicas, &replicas).unwrap();
+            set_field(&mut member.sb, Field::ReplicasV0, &replicas_v0).unwrap();
+        }
+
+        remove_member(&mut members, 1, false).unwrap();
+        write_members(&mut members).unwrap();
+
+        for member in members.iter() {
+            let sb = read_super(member.dev.as_ref()).unwrap();
+            let view = SuperBlock::from(&sb[..]);
+            let replicas_buf = view.field(Field::Replicas).unwrap().unwrap();
+            assert_eq!(replicas_buf, &[2, 1, 1, 0, 3, 1, 1, 2]);
+            let replicas_buf = view.field(Field::ReplicasV0).unwrap().unwrap();
+            assert_eq!(replicas_buf, &[4, 1, 2, 0, 0, 0, 0, 0]);
+        }
+
+        // user data on 1, 2 and 3 with all of them required, on 0 and 1, and
+        // on 0 alone which the second entry becomes a duplicate of
+        let replicas = [4, 3, 3, 1, 2, 3, 4, 2, 1, 0, 1, 4, 1, 1, 0];
+        assert_eq!(
+            replicas_without(&replicas, 3, 1).unwrap(),
+            vec![4, 1, 1, 0, 4, 2, 1, 2, 3]
+        );
+        let replicas_v0 = [4, 2, 1, 2, 4, 1, 1, 3, 1, 0];
+        assert_eq!(
+            replicas_without(&replicas_v0, 2, 2).unwrap(),
+            vec![3, 1, 0, 4, 1, 1]
+        );
+    }
+
+    #[test]
+    fn replicas_required() {
+        let mut members = members(&["mem0", "mem1"]);
+        for member in members.iter_mut() {
+            let mut flags_buf = [0u8; 64];
+            flags_buf.copy_from_slice(member.sb().flags().unwrap().as_ref());
+            let mut flags = SuperBlockFlags::from(&mut flags_buf[..]);
+            flags
+                .set_flag(SuperBlockFlag::DATA_REPLICAS_REQ, 2)
+                .unwrap();
+            member.sb_mut().set_flags(&flags).unwrap();
+        }
+
+        assert!(remove_member(&mut members, 0, false).is_err());
+        assert!(set_member_state(&mut members, 1, MemberState::Spare, false).is_err());
+        set_member_state(&mut members, 1, MemberState::ReadOnly, true).unwrap();
+        assert_eq!(state(&members[0], 1), MemberState::ReadOnly);
+        assert_eq!(members.len(), 2);
+    }
+
+    /// Copy a member to a larger in-memory device
+    fn grow(member: &mut Member, size: usize) {
+        let mut buf = vec![0u8; member.dev.size().unwrap() as usize];
+        member.dev.read_at(&mut buf, 0).unwrap();
+        let dev = MemoryDevice::new(size);
+        dev.write_at(&buf, 0).unwrap();
+        member.dev = Box::new(dev);
+    }
+
+    #[test]
+    fn resize() {
+        let mut members = members(&["mem0", "mem1"]);
+        assert!(!resize_member(&mut members, 1, (16 << 20) >> 9).unwrap());
+        assert!(resize_member(&mut members, 1, (8 << 20) >> 9).is_err());
+
+        // add two backup superblocks at the end of the device
+        let mut layout_buf = [0u8; 512];
+        layout_buf.copy_from_slice(members[1].sb().layout().unwrap().as_ref());
+        let mut layout = SuperBlockLayout::from(&mut layout_buf[..]);
+        let sb_sectors = 1u64 << layout.sb_max_size().unwrap();
+        let nbuckets = {
+            let member_buf = members_field(&members).unwrap();
+            let member = MemberField::from(&member_buf[MEMBER_BYTES..]);
+            let end = ((16 << 20) >> 9) - 2 * sb_sectors;
+            let bucket_size = u64::from(member.bucket_size().unwrap());
+            layout.set_nr_superblocks(3).unwrap();
+            layout.set_sb_offset(1, end).unwrap();
+            layout.set_sb_offset(2, end + sb_sectors).unwrap();
+            end / bucket_size
+        };
+        members[1].sb_mut().set_layout(&layout).unwrap();
+        let mut member_buf = members_field(&members).unwrap();
+        MemberField::from(&mut member_buf[MEMBER_BYTES..])
+            .set_n_buckets(nbuckets)
+            .unwrap();
+        for member in members.iter_mut() {
+            set_field(&mut member.sb, Field::Members, &member_buf).unwrap();
+        }
+        write_members(&mut members).unwrap();
+
+        assert!(resize_member(&mut members, 1, sb_sectors).is_err());
+        assert!(resize_member(&mut members, 1, 2 * sb_sectors - 1).is_err());
+
+        grow(&mut members[1], 32 << 20);
+        assert!(resize_member(&mut members, 1, (32 << 20) >> 9).unwrap());
+        write_members(&mut members).unwrap();
+
+        // each backup gets its own place at the new end of the device
+        let end = ((32 << 20) >> 9) - 2 * sb_sectors;
+        let sb = members[1].sb();
+        let layout = sb.layout().unwrap();
+        assert_eq!(layout.nr_superblocks().unwrap(), 3);
+        assert_eq!(layout.sb_offset(0).unwrap(), 8);
+        assert_eq!(layout.sb_offset(1).unwrap(), end);
+        assert_eq!(layout.sb_offset(2).unwrap(), end + sb_sectors);
+
+        // the primary superblock is gone, the moved backup is found
+        members[1].dev.write_at(&[0u8; 512], 8 << 9).unwrap();
+        let sb = read_super(members[1].dev.as_ref()).unwrap();
+        let view = SuperBlock::from(&sb[..]);
+        assert_eq!(view.offset().unwrap(), end);
+        let member_buf = view.field(Field::Members).unwrap().unwrap();
+        let member = MemberField::from(&member_buf[MEMBER_BYTES..]);
+        assert!(member.n_buckets().unwrap() > 2 * nbuckets);
+        assert!(
+            member.n_buckets().unwrap() * u64::from(member.bucket_size().unwrap())
+                <= view.offset().unwrap()
+        );
+    }
+
+    #[test]
+    fn add_invalid() {
+        let mut members = members(&["mem0"]);
+        let small = Box::new(MemoryDevice::new(16 << 10));
+        assert!(add_member(&mut members, "small", small, None, 1, false).is_err());
+        let dev = Box::new(MemoryDevice::new(16 << 20));
+        assert!(add_member(&mut members, "mem1", dev, None, 3, false).is_err());
+        assert_eq!(members.len(), 1);
+    }
+}
@@ -0,0 +1,638 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::bkey::{Bpos, KeyType, OwnedKey};
+use crate::btree::BtreeId;
+use crate::btree_iter::Btrees;
+use crate::crypt::fs_key;
+use crate::inode::{Inode, TimeBase};
+use crate::str_hash::HashInfo;
+use crate::super_io::open_members_partial;
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::debug;
+
+/// The inode number of the root directory
+pub const BCACHEFS_ROOT_INO: u64 = 4096;
+/// The id of the root subvolume
+pub const BCACHEFS_ROOT_SUBVOL: u32 = 1;
+/// The type of a dirent pointing to a subvolume
+pub const DT_SUBVOL: u8 = 16;
+
+/// The size of the fixed part of a dirent value
+pub const DIRENT_BYTES: usize = 9;
+
+/// The offsets of the fixed part of a dirent value
+#[allow(missing_docs)]
+pub mod dirent_offsets {
+    use std::ops::Range;
+
+    pub const INUM: Range<usize> = 0..8;
+    pub const CHILD_SUBVOL: Range<usize> = 0..4;
+    pub const PARENT_SUBVOL: Range<usize> = 4..8;
+    pub const TYPE: usize = 8;
+}
+
+/// The offsets of a subvolume value
+#[allow(missing_docs)]
+pub mod subvolume_offsets {
+    use std::ops::Range;
+
+    pub const SNAPSHOT: Range<usize> = 4..8;
+    pub const INODE: Range<usize> = 8..16;
+}
+
+/// The offsets of a snapshot value
+#[allow(missing_docs)]
+pub mod snapshot_offsets {
+    use std::ops::Range;
+
+    pub const PARENT: Range<usize> = 4..8;
+}
+
+/// What a dirent points to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirentTarget {
+    /// An inode in the same subvolume
+    Inode(u64),
+    /// The root of a subvolume
+    Subvol {
+        /// The subvolume pointed to
+        child: u32,
+        /// The subvolume of the directory
+        parent: u32,
+    },
+}
+
+impl fmt::Display for DirentTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirentTarget::Inode(inum) => write!(f, "{}", inum),
+            DirentTarget::Subvol { child, parent } => {
+                write!(f, "subvol {} (parent {})", child, parent)
+            }
+        }
+    }
+}
+
+/// A directory entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dirent {
+    /// The position of the dirent, the directory and the hash of the name
+    pub p: Bpos,
+    /// The name
+    pub name: Vec<u8>,
+    /// What the dirent points to
+    pub target: DirentTarget,
+    /// The file type of the target, as in the mode bits 12-15
+    pub d_type: u8,
+}
+
+impl Dirent {
+    /// Decode a dirent key
+    pub fn decode(key: &OwnedKey) -> Result<Dirent> {
+        let ty = KeyType::try_from(key.ty())?;
+        if ty != KeyType::Dirent {
+            return Err(BchError::Einval(format!("{} key is not a dirent", ty)));
+        }
+        let val = &key.val[..];
+        if val.len() < DIRENT_BYTES {
+            return Err(BchError::Exhausted);
+        }
+
+        let d_type = val[dirent_offsets::TYPE];
+        let target = if d_type == DT_SUBVOL {
+            DirentTarget::Subvol {
+                child: LittleEndian::read_u32(&val[dirent_offsets::CHILD_SUBVOL]),
+                parent: LittleEndian::read_u32(&val[dirent_offsets::PARENT_SUBVOL]),
+            }
+        } else {
+            DirentTarget::Inode(LittleEndian::read_u64(&val[dirent_offsets::INUM]))
+        };
+        let name = &val[DIRENT_BYTES..];
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+
+        Ok(Dirent {
+            p: key.p(),
+            name: name[..len].to_vec(),
+            target,
+            d_type,
+        })
+    }
+}
+
+impl fmt::Display for Dirent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} type {}",
+            String::from_utf8_lossy(&self.name),
+            self.target,
+            self.d_type
+        )
+    }
+}
+
+/// The hash of a name in a directory, the offset the dirent is stored at
+///
+/// Offsets 0 and 1 are reserved for the dot entries.
+pub fn dirent_hash(info: &HashInfo, name: &[u8]) -> u64 {
+    info.hash(name).max(2)
+}
+
+/// An inode and the subvolume and snapshot it was found in
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedInode {
+    /// The subvolume of the inode, 0 on filesystems without subvolumes
+    pub subvol: u32,
+    /// The snapshot the inode was read from
+    pub snapshot: u32,
+    /// The inode
+    pub inode: Inode,
+}
+
+impl Btrees {
+    /// The key at the given position visible in a snapshot
+    ///
+    /// `ancestors` are the snapshot and those it descends from, which have
+    /// larger ids. The key of the nearest one is visible, unless it is a
+    /// whiteout.
+    fn lookup_slot(
+        &self,
+        id: BtreeId,
+        inode: u64,
+        offset: u64,
+        ancestors: &[u32],
+    ) -> Result<Option<OwnedKey>> {
+        let mut iter = self.iter(id).with_whiteouts();
+        iter.seek(Bpos::new(inode, offset, ancestors[0]));
+        for key in iter {
+            let key = key?;
+            let p = key.p();
+            if p.inode != inode || p.offset != offset {
+                break;
+            }
+            if ancestors.contains(&p.snapshot) {
+                return Ok(Some(key).filter(|key| key.ty() != KeyType::Discard as u8));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The given snapshot and those it descends from, nearest first
+    ///
+    /// Filesystems without snapshots only have snapshot 0.
+    pub(crate) fn snapshot_ancestors(&self, snapshot: u32) -> Result<Vec<u32>> {
+        let mut ancestors = vec![snapshot];
+        if snapshot == 0 || self.root(BtreeId::Snapshots).is_none() {
+            return Ok(ancestors);
+        }
+
+        let mut id = snapshot;
+        loop {
+            let key = self
+                .lookup_slot(BtreeId::Snapshots, 0, u64::from(id), &[0])?
+                .filter(|key| key.ty() == KeyType::Snapshot as u8)
+                .ok_or_else(|| BchError::Str(format!("snapshot {} not found", id)))?;
+            let parent = key
+                .val
+                .get(snapshot_offsets::PARENT)
+                .map(LittleEndian::read_u32)
+                .ok_or(BchError::Exhausted)?;
+            if parent == 0 {
+                return Ok(ancestors);
+            } else if parent <= id {
+                return Err(BchError::Einval(format!(
+                    "snapshot {} has parent {} with a smaller id",
+                    id, parent
+                )));
+            }
+            ancestors.push(parent);
+            id = parent;
+        }
+    }
+
+    /// Read the inode of the given number, as seen in the given snapshot
+    pub fn inode(&self, inum: u64, snapshot: u32) -> Result<Inode> {
+        let ancestors = self.snapshot_ancestors(snapshot)?;
+        match self.lookup_slot(BtreeId::Inodes, 0, inum, &ancestors)? {
+            Some(key) => Inode::decode(&key),
+            None => Err(BchError::Str(format!("inode {} not found", inum))),
+        }
+    }
+
+    /// The snapshot and root inode of the given subvolume
+    pub fn subvolume(&self, subvol: u32) -> Result<(u32, u64)> {
+        let key = self
+            .lookup_slot(BtreeId::Subvolumes, 0, u64::from(subvol), &[0])?
+            .filter(|key| key.ty() == KeyType::Subvolume as u8)
+            .ok_or_else(|| BchError::Str(format!("subvolume {} not found", subvol)))?;
+        if key.val.len() < subvolume_offsets::INODE.end {
+            return Err(BchError::Exhausted);
+        }
+        Ok((
+            LittleEndian::read_u32(&key.val[subvolume_offsets::SNAPSHOT]),
+            LittleEndian::read_u64(&key.val[subvolume_offsets::INODE]),
+        ))
+    }
+
+    /// Find the dirent of the given name in a directory
+    ///
+    /// The dirent is looked for from the offset the name hashes to, on
+    /// through the dirents and whiteouts of colliding names up to the first
+    /// empty offset.
+    pub fn lookup(&self, dir: &Inode, snapshot: u32, name: &[u8]) -> Result<Option<Dirent>> {
+        let info = HashInfo::new(dir)?;
+        let ancestors = self.snapshot_ancestors(snapshot)?;
+        let mut offset = dirent_hash(&info, name);
+        while let Some(key) = self.lookup_slot(BtreeId::Dirents, dir.inum, offset, &ancestors)? {
+            if key.ty() == KeyType::Dirent as u8 {
+                let dirent = Dirent::decode(&key)?;
+                if dirent.name == name {
+                    return Ok(Some(dirent));
+                }
+            } else if key.ty() != KeyType::HashWhiteout as u8 {
+                break;
+            }
+            offset = match offset.checked_add(1) {
+                Some(offset) => offset,
+                None => break,
+            };
+        }
+        Ok(None)
+    }
+
+    /// The root directory of the filesystem
+    pub fn root_dir(&self) -> Result<ResolvedInode> {
+        let (subvol, snapshot, inum) = if self.root(BtreeId::Subvolumes).is_some() {
+            let (snapshot, inum) = self.subvolume(BCACHEFS_ROOT_SUBVOL)?;
+            (BCACHEFS_ROOT_SUBVOL, snapshot, inum)
+        } else {
+            (0, 0, BCACHEFS_ROOT_INO)
+        };
+        Ok(ResolvedInode {
+            subvol,
+            snapshot,
+            inode: self.inode(inum, snapshot)?,
+        })
+    }
+
+    /// Resolve an absolute path to the inode it names
+    ///
+    /// Symbolic links are not followed.
+    pub fn resolve(&self, path: &str) -> Result<ResolvedInode> {
+        if !path.starts_with('/') {
+            return Err(BchError::Einval(format!("{}: path is not absolute", path)));
+        }
+
+        let mut parents = vec![self.root_dir()?];
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let dir = parents.last().expect("path always holds the root");
+            match name {
+                "." => continue,
+                ".." => {
+                    if parents.len() > 1 {
+                        parents.pop();
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+            if !dir.inode.is_dir() {
+                return Err(BchError::Str(format!(
+                    "{}: {} is not a directory",
+                    path, dir.inode.inum
+                )));
+            }
+
+            let dirent = self
+                .lookup(&dir.inode, dir.snapshot, name.as_bytes())?
+                .ok_or_else(|| BchError::Str(format!("{}: {} not found", path, name)))?;
+            debug!("{}: {}", path, dirent);
+            let next = self.dirent_inode(dir, &dirent)?;
+            parents.push(next);
+        }
+        Ok(parents.pop().expect("path always holds the root"))
+    }
+
+    /// The inode a dirent of the given directory points to
+    pub fn dirent_inode(&self, dir: &ResolvedInode, dirent: &Dirent) -> Result<ResolvedInode> {
+        match dirent.target {
+            DirentTarget::Inode(inum) => Ok(ResolvedInode {
+                subvol: dir.subvol,
+                snapshot: dir.snapshot,
+                inode: self.inode(inum, dir.snapshot)?,
+            }),
+            DirentTarget::Subvol { child, .. } => {
+                let (snapshot, inum) = self.subvolume(child)?;
+                Ok(ResolvedInode {
+                    subvol: child,
+                    snapshot,
+                    inode: self.inode(inum, snapshot)?,
+                })
+            }
+        }
+    }
+
+    /// The keys of an inode in a hashed btree, as seen in the given snapshot
+    ///
+    /// Whiteouts, and the keys of older snapshots they hide, are skipped.
+    pub(crate) fn hashed_keys(
+        &self,
+        id: BtreeId,
+        inum: u64,
+        snapshot: u32,
+    ) -> Result<Vec<OwnedKey>> {
+        let ancestors = self.snapshot_ancestors(snapshot)?;
+        let mut iter = self.iter(id).with_whiteouts();
+        iter.seek(Bpos::new(inum, 0, 0));
+
+        let mut keys = Vec::new();
+        let mut last = None;
+        for key in iter {
+            let key = key?;
+            let p = key.p();
+            if p.inode != inum {
+                break;
+            }
+            if !ancestors.contains(&p.snapshot) || last == Some(p.offset) {
+                continue;
+            }
+            last = Some(p.offset);
+            if key.ty() != KeyType::HashWhiteout as u8 && key.ty() != KeyType::Discard as u8 {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// The dirents of a directory, in hash order
+    pub fn readdir(&self, dir: &ResolvedInode) -> Result<Vec<Dirent>> {
+        self.hashed_keys(BtreeId::Dirents, dir.inode.inum, dir.snapshot)?
+            .iter()
+            .map(Dirent::decode)
+            .collect()
+    }
+}
+
+/// The file type and permissions of a mode, as printed by ls
+fn mode_string(mode: u16) -> String {
+    let ty = match u32::from(mode) & libc::S_IFMT {
+        libc::S_IFDIR => 'd',
+        libc::S_IFLNK => 'l',
+        libc::S_IFCHR => 'c',
+        libc::S_IFBLK => 'b',
+        libc::S_IFIFO => 'p',
+        libc::S_IFSOCK => 's',
+        _ => '-',
+    };
+    let mut s = ty.to_string();
+    for shift in [6, 3, 0].iter() {
+        let bits = mode >> *shift;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+/// Arguments that the stat subcommand may be provided.
+#[derive(Debug)]
+pub struct StatArgs {
+    /// The absolute path of the file within the filesystem
+    pub path: String,
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// Read the passphrase of an encrypted filesystem from a file
+    pub keyfile: Option<String>,
+}
+
+/// Print the inode of a file of an unmounted filesystem
+pub fn stat(args: StatArgs) -> Result<()> {
+    let members = open_members_partial(&args.devices, false)?;
+    let time_base = TimeBase::from_sb(&members[0].sb)?;
+    let key = fs_key(&members, args.keyfile.as_deref())?;
+    let btrees = Btrees::open(members, key)?;
+    let resolved = btrees.resolve(&args.path)?;
+    let inode = &resolved.inode;
+
+    println!("  File: {}", args.path);
+    println!(
+        "  Size: {:<12} Sectors: {:<10} Inode: {:<10} Links: {}",
+        inode.size,
+        inode.sectors,
+        inode.inum,
+        inode.links()
+    );
+    println!(
+        "Access: ({:04o}/{})  Uid: {:<6} Gid: {}",
+        inode.mode & 0o7777,
+        mode_string(inode.mode),
+        inode.uid,
+        inode.gid
+    );
+    println!(
+        "Subvol: {:<12} Snapshot: {:<9} Flags: {:?}",
+        resolved.subvol,
+        resolved.snapshot,
+        inode.inode_flags()
+    );
+    println!("Access: {}", time_base.to_timespec(inode.atime));
+    println!("Modify: {}", time_base.to_timespec(inode.mtime));
+    println!("Change: {}", time_base.to_timespec(inode.ctime));
+    println!(" Birth: {}", time_base.to_timespec(inode.otime));
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_dirent {
+    use super::*;
+    use crate::bkey::{bkey_format_current, BkeyFormat};
+    use crate::btree::test_btree::key;
+    use crate::btree_iter::test_btree_iter::btrees;
+    use crate::inode::test_inode::inode_val;
+    use crate::inode::INODE_NR_FIELDS;
+
+    /// An inode key of the given mode and size
+    pub(crate) fn inode(inum: u64, snapshot: u32, mode: u32, size: u64) -> Vec<u8> {
+        let mut fields = vec![0u64; INODE_NR_FIELDS];
+        fields[4] = size;
+        key(
+            KeyType::Inode,
+            Bpos::new(0, inum, snapshot),
+            &inode_val(mode as u16, &fields, true),
+        )
+    }
+
+    /// A dirent key in the given directory, at the offset its name hashes to
+    pub(crate) fn dirent(dir: u64, snapshot: u32, name: &str, target: u64, d_type: u8) -> Vec<u8> {
+        let offset = dirent_hash(&dir_hash(), name.as_bytes());
+        dirent_at(dir, offset, snapshot, name, target, d_type)
+    }
+
+    /// The hash info of the directories made by `inode`
+    pub(crate) fn dir_hash() -> HashInfo {
+        HashInfo::new(&Inode::unpack(0, &inode_val(0, &[], true)).unwrap()).unwrap()
+    }
+
+    /// A dirent key at the given offset
+    pub(crate) fn dirent_at(
+        dir: u64,
+        offset: u64,
+        snapshot: u32,
+        name: &str,
+        target: u64,
+        d_type: u8,
+    ) -> Vec<u8> {
+        let mut val = vec![0u8; DIRENT_BYTES];
+        LittleEndian::write_u64(&mut val[dirent_offsets::INUM], target);
+        val[dirent_offsets::TYPE] = d_type;
+        val.extend_from_slice(name.as_bytes());
+        val.resize(val.len() + (8 - val.len() % 8) % 8, 0);
+        key(KeyType::Dirent, Bpos::new(dir, offset, snapshot), &val)
+    }
+
+    #[test]
+    fn resolve_paths() {
+        let dir = libc::S_IFDIR | 0o755;
+        let reg = libc::S_IFREG | 0o644;
+        let b_hash = dirent_hash(&dir_hash(), b"b");
+
+        let mut dirents = vec![
+            dirent(BCACHEFS_ROOT_INO, 0, "a", 4097, 4),
+            dirent(4097, 0, "file", 4098, 8),
+            key(KeyType::HashWhiteout, Bpos::new(4097, b_hash, 0), &[]),
+            dirent_at(4097, b_hash + 1, 0, "b", 4099, 8),
+        ];
+        dirents.sort_by_key(|key| {
+            BkeyFormat::from(&bkey_format_current()[..])
+                .unpack_key(key)
+                .unwrap()
+                .p()
+        });
+        let btrees = btrees(&[
+            (
+                BtreeId::Inodes,
+                vec![
+                    inode(BCACHEFS_ROOT_INO, 0, dir, 0),
+                    inode(4097, 0, dir, 0),
+                    inode(4098, 0, reg, 42),
+                    inode(4099, 0, reg, 7),
+                ],
+            ),
+            (BtreeId::Dirents, dirents),
+        ]);
+
+        let file = btrees.resolve("/a/file").unwrap();
+        assert_eq!((file.inode.inum, file.inode.size), (4098, 42));
+        assert!(file.inode.is_reg());
+        assert_eq!(btrees.resolve("/a/./b").unwrap().inode.inum, 4099);
+        assert_eq!(btrees.resolve("/a/../a//").unwrap().inode.inum, 4097);
+        assert_eq!(btrees.resolve("/").unwrap().inode.inum, BCACHEFS_ROOT_INO);
+
+        assert!(btrees.resolve("/a/missing").is_err());
+        assert!(btrees.resolve("/a/file/x").is_err());
+        assert!(btrees.resolve("a").is_err());
+
+        let dirent = btrees
+            .lookup(&btrees.inode(4097, 0).unwrap(), 0, b"b")
+            .unwrap()
+            .unwrap();
+        assert_eq!(dirent.target, DirentTarget::Inode(4099));
+        assert_eq!(dirent.to_string(), "b -> 4099 type 8");
+
+        let dir = btrees.resolve("/a").unwrap();
+        let mut names: Vec<_> = btrees
+            .readdir(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|dirent| dirent.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec![b"b".to_vec(), b"file".to_vec()]);
+        assert_eq!(mode_string(reg as u16), "-rw-r--r--");
+    }
+
+    /// A node of the snapshot tree with the given parent
+    pub(crate) fn snapshot(id: u32, parent: u32) -> Vec<u8> {
+        let mut val = vec![0u8; 24];
+        LittleEndian::write_u32(&mut val[snapshot_offsets::PARENT], parent);
+        key(KeyType::Snapshot, Bpos::new(0, u64::from(id), 0), &val)
+    }
+
+    #[test]
+    fn snapshot_visibility() {
+        let dir = libc::S_IFDIR | 0o755;
+        let reg = libc::S_IFREG | 0o644;
+        let b_hash = dirent_hash(&dir_hash(), b"b");
+        let sort = |keys: &mut Vec<Vec<u8>>| {
+            keys.sort_by_key(|key| {
+                BkeyFormat::from(&bkey_format_current()[..])
+                    .unpack_key(key)
+                    .unwrap()
+                    .p()
+            })
+        };
+
+        // snapshots 80 and 90 are siblings below 100, "b" is deleted in 90
+        let mut dirents = vec![
+            dirent(BCACHEFS_ROOT_INO, 100, "a", 4097, 8),
+            dirent(BCACHEFS_ROOT_INO, 100, "b", 4097, 8),
+            key(
+                KeyType::Discard,
+                Bpos::new(BCACHEFS_ROOT_INO, b_hash, 90),
+                &[],
+            ),
+            dirent(BCACHEFS_ROOT_INO, 80, "c", 4097, 8),
+        ];
+        sort(&mut dirents);
+        let mut inodes = vec![
+            inode(BCACHEFS_ROOT_INO, 100, dir, 0),
+            inode(4097, 100, reg, 1),
+            inode(4097, 80, reg, 2),
+        ];
+        sort(&mut inodes);
+        let btrees = btrees(&[
+            (BtreeId::Inodes, inodes),
+            (BtreeId::Dirents, dirents),
+            (
+                BtreeId::Snapshots,
+                vec![snapshot(80, 100), snapshot(90, 100), snapshot(100, 0)],
+            ),
+        ]);
+
+        assert_eq!(btrees.snapshot_ancestors(90).unwrap(), vec![90, 100]);
+        assert!(btrees.snapshot_ancestors(70).is_err());
+        assert_eq!(btrees.inode(4097, 90).unwrap().size, 1);
+        assert_eq!(btrees.inode(4097, 80).unwrap().size, 2);
+        assert_eq!(btrees.inode(4097, 100).unwrap().size, 1);
+
+        let names = |snapshot: u32| {
+            let dir = ResolvedInode {
+                subvol: 0,
+                snapshot,
+                inode: btrees.inode(BCACHEFS_ROOT_INO, snapshot).unwrap(),
+            };
+            let mut names: Vec<_> = btrees
+                .readdir(&dir)
+                .unwrap()
+                .into_iter()
+                .map(|dirent| dirent.name)
+                .collect();
+            names.sort();
+            for name in names.iter() {
+                assert!(btrees.lookup(&dir.inode, snapshot, name).unwrap().is_some());
+            }
+            names
+        };
+        assert_eq!(names(100), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(names(90), vec![b"a".to_vec()]);
+        assert_eq!(names(80), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+        let root = btrees.inode(BCACHEFS_ROOT_INO, 90).unwrap();
+        assert!(btrees.lookup(&root, 90, b"b").unwrap().is_none());
+        assert!(btrees.lookup(&root, 90, b"c").unwrap().is_none());
+        assert!(btrees.lookup(&root, 80, b"b").unwrap().is_some());
+    }
+}
@@ -0,0 +1,415 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::bkey::Bversion;
+use crate::checksum::{CsumType, Nonce, NONCE_EXTENT};
+use crate::compress::CompressionType;
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The size of a pointer extent entry
+pub const EXTENT_PTR_BYTES: usize = 8;
+
+/// The types of the entries of an extent value
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ExtentEntryType {
+    /// A pointer to the data on a device
+    Ptr = 0,
+    /// Checksum and compression info of up to 128 sectors
+    Crc32 = 1,
+    /// Checksum and compression info of up to 512 sectors
+    Crc64 = 2,
+    /// Checksum and compression info of up to 8192 sectors
+    Crc128 = 3,
+    /// A pointer to an erasure coded stripe
+    StripePtr = 4,
+}
+
+impl ExtentEntryType {
+    /// The size of an entry of the type
+    pub fn bytes(self) -> usize {
+        match self {
+            ExtentEntryType::Ptr | ExtentEntryType::Crc32 | ExtentEntryType::StripePtr => 8,
+            ExtentEntryType::Crc64 => 16,
+            ExtentEntryType::Crc128 => 24,
+        }
+    }
+}
+
+impl TryFrom<u32> for ExtentEntryType {
+    type Error = BchError;
+
+    fn try_from(ty: u32) -> Result<Self> {
+        match ty {
+            0 => Ok(ExtentEntryType::Ptr),
+            1 => Ok(ExtentEntryType::Crc32),
+            2 => Ok(ExtentEntryType::Crc64),
+            3 => Ok(ExtentEntryType::Crc128),
+            4 => Ok(ExtentEntryType::StripePtr),
+            _ => Err(BchError::Einval(format!(
+                "unknown extent entry type {}",
+                ty
+            ))),
+        }
+    }
+}
+
+/// A pointer to data on a member device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtentPtr {
+    /// The index of the member device
+    pub dev: u8,
+    /// The offset on the device in sectors
+    pub offset: u64,
+    /// The generation of the bucket the data is in
+    pub gen: u8,
+    /// Whether the data is a cached copy
+    pub cached: bool,
+}
+
+impl ExtentPtr {
+    /// Decode a pointer extent entry
+    pub fn decode(buf: &[u8]) -> Result<ExtentPtr> {
+        if buf.len() < EXTENT_PTR_BYTES {
+            return Err(BchError::Exhausted);
+        }
+        let v = LittleEndian::read_u64(&buf[..EXTENT_PTR_BYTES]);
+        if v.trailing_zeros() != ExtentEntryType::Ptr as u32 {
+            return Err(BchError::Einval(format!(
+                "extent entry of type {} is not a pointer",
+                v.trailing_zeros()
+            )));
+        }
+
+        Ok(ExtentPtr {
+            cached: v & (1 << 1) != 0,
+            offset: (v >> 4) & ((1 << 44) - 1),
+            dev: (v >> 48) as u8,
+            gen: (v >> 56) as u8,
+        })
+    }
+
+    /// Encode the pointer as an extent entry
+    pub fn encode(&self) -> Result<[u8; EXTENT_PTR_BYTES]> {
+        if self.offset >= 1 << 44 {
+            return Err(BchError::Einval(format!(
+                "extent pointer offset {} too large",
+                self.offset
+            )));
+        }
+
+        let v = (1 << ExtentEntryType::Ptr as u64)
+            | (u64::from(self.cached) << 1)
+            | (self.offset << 4)
+            | (u64::from(self.dev) << 48)
+            | (u64::from(self.gen) << 56);
+        let mut buf = [0u8; EXTENT_PTR_BYTES];
+        LittleEndian::write_u64(&mut buf, v);
+        Ok(buf)
+    }
+}
+
+impl fmt::Display for ExtentPtr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ptr: {}:{} gen {}", self.dev, self.offset, self.gen)?;
+        if self.cached {
+            write!(f, " cached")?;
+        }
+        Ok(())
+    }
+}
+
+/// The checksum and compression info of the data pointers following it
+///
+/// The sizes and offset are in sectors. The checksum covers the data as
+/// stored, which is `compressed_size` sectors long.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtentCrc {
+    /// The size of the data as stored
+    pub compressed_size: u32,
+    /// The size of the data once decompressed
+    pub uncompressed_size: u32,
+    /// The offset of the live data of the key in the decompressed data
+    pub offset: u32,
+    /// The nonce of encrypted data
+    pub nonce: u32,
+    /// The checksum type
+    pub csum_type: CsumType,
+    /// The compression type
+    pub compression_type: u8,
+    /// The checksum, low u64 first
+    pub csum: [u64; 2],
+}
+
+/// A bitfield of an entry, the bits from `shift` on
+fn bits(v: u64, shift: u32, bits: u32) -> u64 {
+    (v >> shift) & ((1 << bits) - 1)
+}
+
+impl ExtentCrc {
+    /// The crc of the data of an extent without a crc entry
+    pub fn none(size: u32) -> ExtentCrc {
+        ExtentCrc {
+            compressed_size: size,
+            uncompressed_size: size,
+            offset: 0,
+            nonce: 0,
+            csum_type: CsumType::None,
+            compression_type: 0,
+            csum: [0, 0],
+        }
+    }
+
+    /// The nonce of the data, given the version of its key
+    ///
+    /// Compressed data is told apart by its compression type and size.
+    pub fn nonce(&self, version: Bversion) -> Nonce {
+        let compression_type = match self.compression_type {
+            ty if ty == CompressionType::Incompressible as u8 => 0,
+            ty => u32::from(ty),
+        };
+        let size = if compression_type != 0 {
+            self.uncompressed_size
+        } else {
+            0
+        };
+        Nonce([
+            size << 22,
+            version.lo as u32,
+            (version.lo >> 32) as u32,
+            (version.hi | compression_type << 24) ^ NONCE_EXTENT,
+        ])
+        .advance(u64::from(self.nonce) << 9)
+    }
+
+    /// Decode a crc entry of any of the three sizes
+    pub fn decode(buf: &[u8]) -> Result<ExtentCrc> {
+        let v = LittleEndian::read_u64(buf.get(..8).ok_or(BchError::Exhausted)?);
+        let ty = ExtentEntryType::try_from(v.trailing_zeros())?;
+        let buf = buf.get(..ty.bytes()).ok_or(BchError::Exhausted)?;
+
+        // the widths of the sizes, offset and nonce, and the checksum
+        let (size_bits, nonce_bits, shift, csum) = match ty {
+            ExtentEntryType::Crc32 => (7, 0, 2, [v >> 32, 0]),
+            ExtentEntryType::Crc64 => (9, 10, 3, [LittleEndian::read_u64(&buf[8..]), v >> 48]),
+            ExtentEntryType::Crc128 => (
+                13,
+                13,
+                4,
+                [
+                    LittleEndian::read_u64(&buf[8..]),
+                    LittleEndian::read_u64(&buf[16..]),
+                ],
+            ),
+            _ => {
+                return Err(BchError::Einval(format!(
+                    "extent entry of type {} is not a crc",
+                    v.trailing_zeros()
+                )))
+            }
+        };
+        let types = shift + size_bits * 3 + nonce_bits + u32::from(ty == ExtentEntryType::Crc32);
+        let csum_type = CsumType::try_from(bits(v, types, 4))?;
+
+        Ok(ExtentCrc {
+            compressed_size: bits(v, shift, size_bits) as u32 + 1,
+            uncompressed_size: bits(v, shift + size_bits, size_bits) as u32 + 1,
+            offset: bits(v, shift + size_bits * 2, size_bits) as u32,
+            nonce: bits(v, shift + size_bits * 3, nonce_bits) as u32,
+            csum_type,
+            compression_type: bits(v, types + 4, 4) as u8,
+            csum,
+        })
+    }
+
+    /// Encode the crc as the smallest entry it fits in
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let max_size = self.compressed_size.max(self.uncompressed_size);
+        let fits = |size_bits: u32, nonce_bits: u32| {
+            max_size <= 1 << size_bits
+                && self.offset < 1 << size_bits
+                && self.nonce < 1 << nonce_bits
+                && self.compressed_size > 0
+                && self.uncompressed_size > 0
+        };
+        let (ty, size_bits, nonce_bits, shift) =
+            if fits(7, 0) && self.csum[1] == 0 && self.csum[0] >> 32 == 0 {
+                (ExtentEntryType::Crc32, 7, 0, 2)
+            } else if fits(9, 10) && self.csum[1] >> 16 == 0 {
+                (ExtentEntryType::Crc64, 9, 10, 3)
+            } else if fits(13, 13) {
+                (ExtentEntryType::Crc128, 13, 13, 4)
+            } else {
+                return Err(BchError::Einval(format!(
+                    "crc of {} sectors too large",
+                    max_size
+                )));
+            };
+        let types = shift + size_bits * 3 + nonce_bits + u32::from(ty == ExtentEntryType::Crc32);
+
+        let mut v = (1 << ty as u64)
+            | (u64::from(self.compressed_size - 1) << shift)
+            | (u64::from(self.uncompressed_size - 1) << (shift + size_bits))
+            | (u64::from(self.offset) << (shift + size_bits * 2))
+            | (u64::from(self.nonce) << (shift + size_bits * 3))
+            | ((self.csum_type as u64) << types)
+            | (u64::from(self.compression_type & 0xf) << (types + 4));
+        let mut buf = vec![0u8; ty.bytes()];
+        match ty {
+            ExtentEntryType::Crc32 => v |= self.csum[0] << 32,
+            ExtentEntryType::Crc64 => {
+                v |= self.csum[1] << 48;
+                LittleEndian::write_u64(&mut buf[8..], self.csum[0]);
+            }
+            _ => {
+                LittleEndian::write_u64(&mut buf[8..16], self.csum[0]);
+                LittleEndian::write_u64(&mut buf[16..], self.csum[1]);
+            }
+        }
+        LittleEndian::write_u64(&mut buf[..8], v);
+        Ok(buf)
+    }
+}
+
+/// A pointer to the data of an extent and how the data is stored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtentData {
+    /// Where the data is
+    pub ptr: ExtentPtr,
+    /// How the data is checksummed and compressed
+    pub crc: ExtentCrc,
+}
+
+/// Decode the entries of an extent of the given size in sectors
+///
+/// Each pointer is returned with the crc entry preceding it, stripe
+/// pointers are skipped.
+pub fn decode_extent(val: &[u8], size: u32) -> Result<Vec<ExtentData>> {
+    let mut data = Vec::new();
+    let mut crc = ExtentCrc::none(size);
+    let mut val = val;
+    while !val.is_empty() {
+        let v = LittleEndian::read_u64(val.get(..8).ok_or(BchError::Exhausted)?);
+        let ty = ExtentEntryType::try_from(v.trailing_zeros())?;
+        match ty {
+            ExtentEntryType::Ptr => data.push(ExtentData {
+                ptr: ExtentPtr::decode(val)?,
+                crc,
+            }),
+            ExtentEntryType::StripePtr => (),
+            _ => crc = ExtentCrc::decode(val)?,
+        }
+        val = val.get(ty.bytes()..).ok_or(BchError::Exhausted)?;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test_extents {
+    use super::*;
+
+    #[test]
+    fn ptr_round_trip() {
+        let ptr = ExtentPtr {
+            dev: 3,
+            offset: (1 << 40) + 17,
+            gen: 200,
+            cached: true,
+        };
+        let buf = ptr.encode().unwrap();
+        assert_eq!(ExtentPtr::decode(&buf).unwrap(), ptr);
+        assert_eq!(
+            ptr.to_string(),
+            format!("ptr: 3:{} gen 200 cached", (1u64 << 40) + 17)
+        );
+
+        assert!(ExtentPtr::decode(&[0x02, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(ExtentPtr {
+            offset: 1 << 44,
+            ..ptr
+        }
+        .encode()
+        .is_err());
+    }
+
+    #[test]
+    fn crc_round_trip() {
+        let crc = ExtentCrc {
+            compressed_size: 8,
+            uncompressed_size: 128,
+            offset: 3,
+            nonce: 0,
+            csum_type: CsumType::Crc32c,
+            compression_type: 3,
+            csum: [0xdead_beef, 0],
+        };
+        let wide = ExtentCrc {
+            uncompressed_size: 512,
+            nonce: 1000,
+            csum: [u64::MAX, 0xffff],
+            ..crc
+        };
+        let widest = ExtentCrc {
+            uncompressed_size: 8192,
+            csum: [1, u64::MAX],
+            ..wide
+        };
+
+        for (crc, bytes) in [(crc, 8), (wide, 16), (widest, 24)].iter() {
+            let buf = crc.encode().unwrap();
+            assert_eq!(buf.len(), *bytes);
+            assert_eq!(ExtentCrc::decode(&buf).unwrap(), *crc);
+        }
+        assert!(ExtentCrc {
+            uncompressed_size: 8193,
+            ..crc
+        }
+        .encode()
+        .is_err());
+
+        let ptr = ExtentPtr {
+            dev: 1,
+            offset: 4096,
+            gen: 2,
+            cached: false,
+        };
+        let mut val = ptr.encode().unwrap().to_vec();
+        val.extend(wide.encode().unwrap());
+        val.extend(ptr.encode().unwrap().iter());
+        let data = decode_extent(&val, 16).unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].crc, ExtentCrc::none(16));
+        assert_eq!(data[1].crc, wide);
+        assert!(decode_extent(&val[..20], 16).is_err());
+    }
+
+    #[test]
+    fn crc_nonce() {
+        let version = Bversion {
+            hi: 5,
+            lo: 0x1_0000_0002,
+        };
+        let crc = ExtentCrc {
+            uncompressed_size: 128,
+            nonce: 3,
+            compression_type: CompressionType::Lz4 as u8,
+            ..ExtentCrc::none(8)
+        };
+        // the nonce offset is in sectors, 8 ChaCha20 blocks each
+        assert_eq!(
+            crc.nonce(version),
+            Nonce([(128 << 22) + 24, 2, 1, (5 | 3 << 24) ^ NONCE_EXTENT])
+        );
+
+        let incompressible = ExtentCrc {
+            compression_type: CompressionType::Incompressible as u8,
+            ..crc
+        };
+        assert_eq!(
+            incompressible.nonce(version),
+            Nonce([24, 2, 1, 5 ^ NONCE_EXTENT])
+        );
+    }
+}
@@ -0,0 +1,978 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::bkey::{Bpos, Bversion, KeyType, OwnedKey};
+use crate::btree::BtreeId;
+use crate::btree_iter::Btrees;
+use crate::checksum::checksum;
+use crate::compress::{self, CompressionType};
+use crate::crypt::{chacha20, fs_key};
+use crate::dirent::ResolvedInode;
+use crate::extents::{decode_extent, ExtentCrc, ExtentData};
+use crate::inode::TimeBase;
+use crate::super_io::open_members_partial;
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, info, warn};
+use nix::sys::stat::{makedev, mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{fchownat, geteuid, FchownatFlags, Gid, Uid};
+
+/// The size of the refcount preceding the value of an indirect extent
+const REFCOUNT_BYTES: usize = 8;
+
+/// Reads the data of files of an unmounted filesystem
+pub struct FileReader<'a> {
+    btrees: &'a Btrees,
+    ignore_csum: bool,
+}
+
+/// The data of an extent once decompressed
+fn decompress(crc: &ExtentCrc, buf: Vec<u8>) -> Result<Vec<u8>> {
+    match CompressionType::try_from(crc.compression_type)? {
+        CompressionType::None | CompressionType::Incompressible => Ok(buf),
+        ty => compress::decompress(ty, &buf, crc.uncompressed_size as usize * 512),
+    }
+}
+
+impl<'a> FileReader<'a> {
+    /// A reader of the files of the given btrees
+    ///
+    /// With `ignore_csum` the data of an extent is used even when none of
+    /// its copies has a valid checksum, to salvage what is left.
+    pub fn new(btrees: &'a Btrees, ignore_csum: bool) -> FileReader<'a> {
+        FileReader {
+            btrees,
+            ignore_csum,
+        }
+    }
+
+    /// The data of an extent once decrypted and decompressed
+    fn decode(&self, version: Bversion, crc: &ExtentCrc, mut buf: Vec<u8>) -> Result<Vec<u8>> {
+        if crc.csum_type.is_encryption() {
+            let key = self.btrees.key().ok_or_else(|| {
+                BchError::Str("encrypted data needs the filesystem key".to_string())
+            })?;
+            chacha20(key, crc.nonce(version), &mut buf);
+        }
+        decompress(crc, buf)
+    }
+
+    /// Read a copy of the data of an extent, decrypted and decompressed
+    ///
+    /// The copies are tried in turn, cached copies last. The crc of the copy
+    /// read is returned along with the data.
+    fn read_extent(&self, version: Bversion, data: &[ExtentData]) -> Result<(ExtentCrc, Vec<u8>)> {
+        let mut data = data.to_vec();
+        data.sort_by_key(|data| data.ptr.cached);
+
+        let mut errors = Vec::new();
+        let mut salvage = None;
+        for ExtentData { ptr, crc } in data.iter() {
+            let member = match self.btrees.member(ptr.dev) {
+                Some(member) => member,
+                None => {
+                    errors.push(format!("{}: device missing", ptr));
+                    continue;
+                }
+            };
+
+            let mut buf = vec![0u8; crc.compressed_size as usize * 512];
+            if let Err(e) = member.dev.read_at(&mut buf, ptr.offset << 9) {
+                errors.push(format!("{}: {}", ptr, e));
+                continue;
+            }
+            let csum = checksum(crc.csum_type, self.btrees.key(), crc.nonce(version), &buf)?;
+            if csum == crc.csum {
+                return Ok((*crc, self.decode(version, crc, buf)?));
+            }
+
+            errors.push(format!(
+                "{}: {} checksum {:x}:{:x} instead of {:x}:{:x}",
+                ptr, crc.csum_type, csum[1], csum[0], crc.csum[1], crc.csum[0]
+            ));
+            if salvage.is_none() {
+                salvage = Some((crc, buf));
+            }
+        }
+
+        match salvage {
+            Some((crc, buf)) if self.ignore_csum => {
+                warn!("using data with a bad checksum: {}", errors.join(", "));
+                Ok((*crc, self.decode(version, crc, buf)?))
+            }
+            _ => Err(BchError::Str(format!(
+                "no readable copy of extent: {}",
+                errors.join(", ")
+            ))),
+        }
+    }
+
+    /// The data of the sectors `from` to `to` of an extent key
+    ///
+    /// Sectors past the end of inline data read as zeros.
+    fn read_key(&self, key: &OwnedKey, ty: KeyType, from: u64, to: u64) -> Result<Vec<u8>> {
+        let bkey = key.bkey();
+        let skip = ((from - bkey.start()?.offset) << 9) as usize;
+        let len = ((to - from) << 9) as usize;
+
+        let (data, skip) = match ty {
+            KeyType::Extent | KeyType::ReflinkV => {
+                let val = match ty {
+                    KeyType::ReflinkV => {
+                        key.val.get(REFCOUNT_BYTES..).ok_or(BchError::Exhausted)?
+                    }
+                    _ => &key.val[..],
+                };
+                let (crc, data) =
+                    self.read_extent(bkey.version()?, &decode_extent(val, bkey.size()?)?)?;
+                (data, skip + ((crc.offset as usize) << 9))
+            }
+            KeyType::InlineData => (key.val.clone(), skip),
+            KeyType::IndirectInlineData => (
+                key.val
+                    .get(REFCOUNT_BYTES..)
+                    .ok_or(BchError::Exhausted)?
+                    .to_vec(),
+                skip,
+            ),
+            ty => return Err(BchError::Einval(format!("{} key has no data", ty))),
+        };
+
+        let mut buf = vec![0u8; len];
+        if skip < data.len() {
+            let n = len.min(data.len() - skip);
+            buf[..n].copy_from_slice(&data[skip..(skip + n)]);
+        } else if !matches!(ty, KeyType::InlineData | KeyType::IndirectInlineData) {
+            return Err(BchError::Str(format!(
+                "extent {} smaller than its key",
+                key.p()
+            )));
+        }
+        Ok(buf)
+    }
+
+    /// The visible parts of the keys in the given sectors of an inode
+    ///
+    /// Each part maps its first sector to its end and key. `ancestors` are
+    /// the snapshot read and those it descends from, nearest first. Where keys
+    /// of several of them overlap, the key of the nearest snapshot is visible,
+    /// even a whiteout.
+    fn visible_keys(
+        &self,
+        id: BtreeId,
+        inode: u64,
+        ancestors: &[u32],
+        range: &Range<u64>,
+    ) -> Result<BTreeMap<u64, (u64, OwnedKey)>> {
+        let mut iter = self.btrees.iter(id).with_whiteouts();
+        iter.seek(Bpos::new(inode, range.start + 1, 0));
+
+        // keys are sorted by their end, once a snapshot has a key reaching
+        // the end of the range its later keys are past it
+        let mut keys = Vec::new();
+        let mut finished = vec![false; ancestors.len()];
+        for key in iter {
+            let key = key?;
+            let p = key.p();
+            if p.inode != inode || finished.iter().all(|done| *done) {
+                break;
+            }
+            let nearest = match ancestors.iter().position(|id| *id == p.snapshot) {
+                Some(nearest) => nearest,
+                None => continue,
+            };
+            finished[nearest] |= p.offset >= range.end;
+            if key.bkey().start()?.offset < range.end {
+                keys.push((nearest, key));
+            }
+        }
+        keys.sort_by_key(|(nearest, _)| *nearest);
+
+        let mut visible: BTreeMap<u64, (u64, OwnedKey)> = BTreeMap::new();
+        for (_, key) in keys {
+            let from = key.bkey().start()?.offset.max(range.start);
+            let to = key.p().offset.min(range.end);
+
+            // the parts not covered by the keys of nearer snapshots
+            let mut parts = Vec::new();
+            let mut end = to;
+            for (start, (stop, _)) in visible.range(..to).rev() {
+                if *stop <= from {
+                    break;
+                }
+                if *stop < end {
+                    parts.push((*stop, end));
+                }
+                end = *start;
+            }
+            if from < end {
+                parts.push((from, end));
+            }
+            for (start, stop) in parts {
+                visible.insert(start, (stop, key.clone()));
+            }
+        }
+        Ok(visible)
+    }
+
+    /// Pass the data of the given sectors of an inode to `f`
+    ///
+    /// The data is passed in order along with its offset in bytes, which
+    /// starts at `at` sectors. Holes are skipped.
+    fn read_range(
+        &self,
+        id: BtreeId,
+        inode: u64,
+        ancestors: &[u32],
+        range: Range<u64>,
+        at: u64,
+        f: &mut dyn FnMut(u64, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        for (from, (to, key)) in self.visible_keys(id, inode, ancestors, &range)? {
+            let p = key.p();
+            let start = key.bkey().start()?.offset;
+            let offset = at + from - range.start;
+
+            match KeyType::try_from(key.ty())? {
+                KeyType::Discard | KeyType::Reservation => (),
+                KeyType::ReflinkP => {
+                    let idx = LittleEndian::read_u64(key.val.get(..8).ok_or(BchError::Exhausted)?);
+                    debug!("{}: reflinked to {}", p, idx);
+                    self.read_range(
+                        BtreeId::Reflink,
+                        0,
+                        &[0],
+                        (idx + from - start)..(idx + to - start),
+                        offset,
+                        f,
+                    )?;
+                }
+                KeyType::Error => {
+                    return Err(BchError::Str(format!("data of extent {} was lost", p)))
+                }
+                ty => f(offset << 9, &self.read_key(&key, ty, from, to)?)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Pass the data of a file to `f`, in order along with its offset
+    ///
+    /// Holes are skipped, the data passed never goes past the size of the
+    /// file.
+    pub fn read(
+        &self,
+        file: &ResolvedInode,
+        f: &mut dyn FnMut(u64, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let size = file.inode.size;
+        let sectors = size.saturating_add(511) >> 9;
+        self.read_range(
+            BtreeId::Extents,
+            file.inode.inum,
+            &self.btrees.snapshot_ancestors(file.snapshot)?,
+            0..sectors,
+            0,
+            &mut |offset, data| {
+                if offset >= size {
+                    return Ok(());
+                }
+                let len = data.len().min((size - offset) as usize);
+                f(offset, &data[..len])
+            },
+        )
+    }
+
+    /// Write the contents of a file to `out`, holes included
+    pub fn copy(&self, file: &ResolvedInode, out: &mut dyn Write) -> Result<()> {
+        let zeros = [0u8; 4096];
+        let mut pos = 0;
+        let fill = |out: &mut dyn Write, pos: &mut u64, to: u64| -> Result<()> {
+            while *pos < to {
+                let n = zeros.len().min((to - *pos) as usize);
+                out.write_all(&zeros[..n])?;
+                *pos += n as u64;
+            }
+            Ok(())
+        };
+
+        self.read(file, &mut |offset, data| {
+            fill(out, &mut pos, offset)?;
+            out.write_all(data)?;
+            pos += data.len() as u64;
+            Ok(())
+        })?;
+        fill(out, &mut pos, file.inode.size)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Arguments that the cat subcommand may be provided.
+#[derive(Debug)]
+pub struct CatArgs {
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// The absolute path of the file within the filesystem
+    pub path: String,
+    /// Use data even if its checksum is wrong
+    pub ignore_csum: bool,
+    /// Read the passphrase of an encrypted filesystem from a file
+    pub keyfile: Option<String>,
+}
+
+/// Write a file of an unmounted filesystem to stdout
+pub fn cat(args: CatArgs) -> Result<()> {
+    let members = open_members_partial(&args.devices, false)?;
+    let key = fs_key(&members, args.keyfile.as_deref())?;
+    let btrees = Btrees::open(members, key)?;
+    let file = btrees.resolve(&args.path)?;
+    if !file.inode.is_reg() {
+        return Err(BchError::Einval(format!(
+            "{} is not a regular file",
+            args.path
+        )));
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    FileReader::new(&btrees, args.ignore_csum).copy(&file, &mut out)
+}
+
+/// Restores a tree of files of an unmounted filesystem to a directory
+struct Restorer<'a> {
+    btrees: &'a Btrees,
+    reader: FileReader<'a>,
+    time_base: TimeBase,
+    /// Whether to restore ownership, only root may
+    chown: bool,
+    /// The first path each inode with several links was restored to
+    links: HashMap<(u32, u64), PathBuf>,
+    /// The number of files restored
+    restored: usize,
+    /// The files not, or not entirely, restored and why
+    errors: Vec<(PathBuf, BchError)>,
+}
+
+impl<'a> Restorer<'a> {
+    /// Restore a file, and the files below a directory, to `dest`
+    ///
+    /// Errors are recorded rather than returned.
+    fn restore_tree(&mut self, file: &ResolvedInode, dest: &Path) {
+        if let Err(e) = self.restore_file(file, dest) {
+            self.errors.push((dest.to_path_buf(), e));
+        }
+    }
+
+    /// Restore the contents of a directory to `dest`
+    fn restore_dir(&mut self, dir: &ResolvedInode, dest: &Path) -> Result<()> {
+        match fs::create_dir(dest) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && dest.is_dir() => (),
+            r => r?,
+        }
+
+        for dirent in self.btrees.readdir(dir)? {
+            let name = OsStr::from_bytes(&dirent.name);
+            let child_dest = dest.join(name);
+            if dirent.name.contains(&b'/') || Path::new(name).file_name() != Some(name) {
+                self.errors.push((
+                    child_dest,
+                    BchError::Einval(format!("invalid name {:?}", name)),
+                ));
+                continue;
+            }
+            match self.btrees.dirent_inode(dir, &dirent) {
+                Ok(child) => self.restore_tree(&child, &child_dest),
+                Err(e) => self.errors.push((child_dest, e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore a file to `dest`, hard linking it if it was restored already
+    fn restore_file(&mut self, file: &ResolvedInode, dest: &Path) -> Result<()> {
+        let inode = &file.inode;
+        let id = (file.subvol, inode.inum);
+        let linked = !inode.is_dir() && inode.links() > 1;
+        if let Some(first) = self.links.get(&id).filter(|_| linked) {
+            debug!("{}: linking to {}", dest.display(), first.display());
+            fs::hard_link(first, dest)?;
+            self.restored += 1;
+            return Ok(());
+        }
+
+        let ty = u32::from(inode.mode) & libc::S_IFMT;
+        match ty {
+            libc::S_IFDIR => self.restore_dir(file, dest)?,
+            libc::S_IFREG => {
+                let out = OpenOptions::new().write(true).create_new(true).open(dest)?;
+                self.reader.read(
+                    file,
+                    &mut |offset, data| Ok(out.write_all_at(data, offset)?),
+                )?;
+                out.set_len(inode.size)?;
+            }
+            libc::S_IFLNK => {
+                let mut target = Vec::new();
+                self.reader.read(file, &mut |offset, data| {
+                    let offset = offset as usize;
+                    target.resize(offset + data.len(), 0);
+                    target[offset..].copy_from_slice(data);
+                    Ok(())
+                })?;
+                target.truncate(inode.size as usize);
+                while target.last() == Some(&0) {
+                    target.pop();
+                }
+                symlink(OsStr::from_bytes(&target), dest)?;
+            }
+            libc::S_IFCHR | libc::S_IFBLK | libc::S_IFIFO | libc::S_IFSOCK => mknod(
+                dest,
+                SFlag::from_bits_truncate(ty),
+                Mode::from_bits_truncate(u32::from(inode.mode) & 0o7777),
+                makedev(u64::from(inode.dev >> 20), u64::from(inode.dev & 0xfffff)),
+            )?,
+            _ => {
+                return Err(BchError::Einval(format!(
+                    "inode {} of unknown type {:o}",
+                    inode.inum, inode.mode
+                )))
+            }
+        }
+
+        if linked {
+            self.links.insert(id, dest.to_path_buf());
+        }
+        self.restored += 1;
+        self.restore_metadata(file, dest);
+        Ok(())
+    }
+
+    /// Restore the xattrs, ownership, permissions and times of a file
+    ///
+    /// Each of them that fails is recorded on its own.
+    fn restore_metadata(&mut self, file: &ResolvedInode, dest: &Path) {
+        let inode = &file.inode;
+        let path = CString::new(dest.as_os_str().as_bytes()).expect("paths have no nul bytes");
+        let mut errors = Vec::new();
+
+        match self.btrees.xattrs(file) {
+            Ok(xattrs) => {
+                for xattr in xattrs {
+                    let name = CString::new(xattr.full_name());
+                    let value = xattr.system_value();
+                    let r = match (name, value) {
+                        (Ok(name), Ok(value)) => unsafe {
+                            libc::lsetxattr(
+                                path.as_ptr(),
+                                name.as_ptr(),
+                                value.as_ptr() as *const libc::c_void,
+                                value.len(),
+                                0,
+                            )
+                        },
+                        _ => -1,
+                    };
+                    if r != 0 {
+                        errors.push(format!("xattr {}: {}", xattr, io::Error::last_os_error()));
+                    }
+                }
+            }
+            Err(e) => errors.push(format!("xattrs: {}", e)),
+        }
+
+        if self.chown {
+            if let Err(e) = fchownat(
+                None,
+                dest,
+                Some(Uid::from_raw(inode.uid)),
+                Some(Gid::from_raw(inode.gid)),
+                FchownatFlags::NoFollowSymlink,
+            ) {
+                errors.push(format!("owner: {}", e));
+            }
+        }
+        if !inode.is_symlink() {
+            let perms = fs::Permissions::from_mode(u32::from(inode.mode) & 0o7777);
+            if let Err(e) = fs::set_permissions(dest, perms) {
+                errors.push(format!("permissions: {}", e));
+            }
+        }
+
+        let time = |time| {
+            let time = self.time_base.to_timespec(time);
+            TimeSpec::from(libc::timespec {
+                tv_sec: time.sec as libc::time_t,
+                tv_nsec: time.nsec as libc::c_long,
+            })
+        };
+        if let Err(e) = utimensat(
+            None,
+            dest,
+            &time(inode.atime),
+            &time(inode.mtime),
+            UtimensatFlags::NoFollowSymlink,
+        ) {
+            errors.push(format!("times: {}", e));
+        }
+
+        if !errors.is_empty() {
+            self.errors
+                .push((dest.to_path_buf(), BchError::Str(errors.join(", "))));
+        }
+    }
+}
+
+/// Arguments that the restore subcommand may be provided.
+#[derive(Debug)]
+pub struct RestoreArgs {
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// The absolute path of the file or directory to restore
+    pub path: String,
+    /// The directory to restore to
+    pub outdir: String,
+    /// Use data even if its checksum is wrong
+    pub ignore_csum: bool,
+    /// Read the passphrase of an encrypted filesystem from a file
+    pub keyfile: Option<String>,
+}
+
+/// Restore a file or directory tree of an unmounted filesystem
+///
+/// The file is restored in the output directory under its own name, the
+/// contents of the root directory straight to the output directory.
+/// Existing files are not overwritten, existing directories are restored
+/// into. Ownership is only restored when running as root. The files that could
+/// not be restored are reported once all others are.
+pub fn restore(args: RestoreArgs) -> Result<()> {
+    let members = open_members_partial(&args.devices, false)?;
+    let time_base = TimeBase::from_sb(&members[0].sb)?;
+    let key = fs_key(&members, args.keyfile.as_deref())?;
+    let btrees = Btrees::open(members, key)?;
+    let file = btrees.resolve(&args.path)?;
+
+    let outdir = Path::new(&args.outdir);
+    fs::create_dir_all(outdir)?;
+    let dest = match Path::new(&args.path).file_name() {
+        Some(name) => outdir.join(name),
+        None => outdir.to_path_buf(),
+    };
+
+    let mut restorer = Restorer {
+        btrees: &btrees,
+        reader: FileReader::new(&btrees, args.ignore_csum),
+        time_base,
+        chown: geteuid().is_root(),
+        links: HashMap::new(),
+        restored: 0,
+        errors: Vec::new(),
+    };
+    restorer.restore_tree(&file, &dest);
+
+    info!("restored {} files to {}", restorer.restored, dest.display());
+    for (path, e) in restorer.errors.iter() {
+        println!("{}: {}", path.display(), e);
+    }
+    if restorer.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(BchError::Str(format!(
+            "{} files could not be entirely restored",
+            restorer.errors.len()
+        )))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_extract {
+    use super::*;
+    use crate::bkey::Bkey;
+    use crate::bkey::{bkey_format_current, BkeyFormat};
+    use crate::btree::test_btree::key;
+    use crate::btree_iter::test_btree_iter::{btree_members, btrees};
+    use crate::checksum::{CsumType, Nonce};
+    use crate::crypt::KEY_BYTES;
+    use crate::dirent::test_dirent::{dirent, inode, snapshot};
+    use crate::dirent::BCACHEFS_ROOT_INO;
+    use crate::extents::ExtentPtr;
+    use crate::inode::test_inode::inode_val;
+    use crate::inode::INODE_NR_FIELDS;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    /// A key of a file of the given type, covering `size` sectors up to `end`
+    pub(crate) fn sized(ty: KeyType, inum: u64, end: u64, size: u32, val: &[u8]) -> Vec<u8> {
+        let mut buf = key(ty, Bpos::new(inum, end, 0), val);
+        Bkey::from(&mut buf[..]).set_size(size).unwrap();
+        buf
+    }
+
+    /// An extent key of a file, covering `size` sectors up to `end`
+    pub(crate) fn extent(inum: u64, end: u64, size: u32, entries: &[Vec<u8>]) -> Vec<u8> {
+        sized(KeyType::Extent, inum, end, size, &entries.concat())
+    }
+
+    /// A pointer entry to the given sector of device 0
+    pub(crate) fn ptr(offset: u64) -> Vec<u8> {
+        ExtentPtr {
+            dev: 0,
+            offset,
+            gen: 0,
+            cached: false,
+        }
+        .encode()
+        .unwrap()
+        .to_vec()
+    }
+
+    /// A crc entry of data stored as is
+    pub(crate) fn crc(size: u32, offset: u32, data: &[u8]) -> Vec<u8> {
+        ExtentCrc {
+            offset,
+            csum_type: CsumType::Crc32c,
+            csum: checksum(CsumType::Crc32c, None, Nonce::default(), data).unwrap(),
+            ..ExtentCrc::none(size)
+        }
+        .encode()
+        .unwrap()
+    }
+
+    #[test]
+    fn read_files() {
+        let reg = libc::S_IFREG | 0o644;
+        let stored: Vec<u8> = (0..(8 * 512)).map(|i| (i % 251) as u8).collect();
+        let mut inline = b"inline".to_vec();
+        inline.resize(8, 0);
+
+        let size = 14 * 512 + 100;
+        let leaves = [
+            (
+                BtreeId::Inodes,
+                vec![
+                    inode(BCACHEFS_ROOT_INO, 0, libc::S_IFDIR | 0o755, 0),
+                    inode(4097, 0, reg, size),
+                ],
+            ),
+            (
+                BtreeId::Dirents,
+                vec![dirent(BCACHEFS_ROOT_INO, 0, "file", 4097, 8)],
+            ),
+            (
+                BtreeId::Extents,
+                vec![
+                    // sectors 2..8 of the stored data, then a hole
+                    extent(4097, 6, 6, &[crc(8, 2, &stored), ptr(12288)]),
+                    extent(4097, 10, 2, &[ptr(12288 + 8)]),
+                    sized(KeyType::Reservation, 4097, 12, 2, &[]),
+                    sized(KeyType::InlineData, 4097, 15, 1, &inline),
+                ],
+            ),
+        ];
+        let btrees = btrees(&leaves);
+        let mut disk = stored.clone();
+        disk.extend((0..1024).map(|i| (i % 7) as u8));
+        btrees
+            .member(0)
+            .unwrap()
+            .dev
+            .write_at(&disk, 12288 << 9)
+            .unwrap();
+
+        let file = btrees.resolve("/file").unwrap();
+        let mut out = Vec::new();
+        FileReader::new(&btrees, false)
+            .copy(&file, &mut out)
+            .unwrap();
+        assert_eq!(out.len() as u64, size);
+        assert_eq!(&out[..(6 * 512)], &stored[1024..]);
+        assert!(out[(6 * 512)..(8 * 512)].iter().all(|b| *b == 0));
+        assert_eq!(&out[(8 * 512)..(10 * 512)], &disk[(8 * 512)..]);
+        assert!(out[(10 * 512)..(14 * 512)].iter().all(|b| *b == 0));
+        assert_eq!(&out[(14 * 512)..(14 * 512 + 6)], b"inline");
+
+        let mut chunks = Vec::new();
+        FileReader::new(&btrees, false)
+            .read(&file, &mut |offset, data| {
+                chunks.push((offset, data.len()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(chunks, vec![(0, 3072), (4096, 1024), (7168, 100)]);
+
+        btrees
+            .member(0)
+            .unwrap()
+            .dev
+            .write_at(&[0xff], 12290 << 9)
+            .unwrap();
+        assert!(FileReader::new(&btrees, false)
+            .copy(&file, &mut Vec::new())
+            .is_err());
+        let mut out = Vec::new();
+        FileReader::new(&btrees, true)
+            .copy(&file, &mut out)
+            .unwrap();
+        assert_eq!(out[0], 0xff);
+        assert_eq!(&out[1..(6 * 512)], &stored[1025..]);
+    }
+
+    #[test]
+    fn read_compressed() {
+        let data: Vec<u8> = b"compressed "
+            .iter()
+            .copied()
+            .cycle()
+            .take(8 * 512)
+            .collect();
+        let stored = compress::compress(CompressionType::Zstd, &data).unwrap();
+        assert_eq!(stored.len(), 512);
+        let crc = ExtentCrc {
+            compressed_size: 1,
+            offset: 2,
+            csum_type: CsumType::Crc32c,
+            compression_type: CompressionType::Zstd as u8,
+            csum: checksum(CsumType::Crc32c, None, Nonce::default(), &stored).unwrap(),
+            ..ExtentCrc::none(8)
+        };
+
+        let btrees = btrees(&[
+            (
+                BtreeId::Inodes,
+                vec![
+                    inode(BCACHEFS_ROOT_INO, 0, libc::S_IFDIR | 0o755, 0),
+                    inode(4097, 0, libc::S_IFREG | 0o644, 4 * 512),
+                ],
+            ),
+            (
+                BtreeId::Dirents,
+                vec![dirent(BCACHEFS_ROOT_INO, 0, "file", 4097, 8)],
+            ),
+            (
+                BtreeId::Extents,
+                vec![extent(4097, 4, 4, &[crc.encode().unwrap(), ptr(12288)])],
+            ),
+        ]);
+        btrees
+            .member(0)
+            .unwrap()
+            .dev
+            .write_at(&stored, 12288 << 9)
+            .unwrap();
+
+        let mut out = Vec::new();
+        FileReader::new(&btrees, false)
+            .copy(&btrees.resolve("/file").unwrap(), &mut out)
+            .unwrap();
+        assert_eq!(out, &data[1024..3072]);
+    }
+
+    #[test]
+    fn read_encrypted() {
+        let secret = [0x42; KEY_BYTES];
+        let data: Vec<u8> = (0..(4 * 512)).map(|i| (i % 251) as u8).collect();
+        let mut stored = data.clone();
+        let crc = ExtentCrc {
+            csum_type: CsumType::ChaCha20Poly1305_128,
+            ..ExtentCrc::none(4)
+        };
+        let nonce = crc.nonce(Bversion::default());
+        chacha20(&secret, nonce, &mut stored);
+        let crc = ExtentCrc {
+            csum: checksum(crc.csum_type, Some(&secret), nonce, &stored).unwrap(),
+            ..crc
+        };
+
+        let members = btree_members(&[
+            (
+                BtreeId::Inodes,
+                vec![
+                    inode(BCACHEFS_ROOT_INO, 0, libc::S_IFDIR | 0o755, 0),
+                    inode(4097, 0, libc::S_IFREG | 0o644, 4 * 512),
+                ],
+            ),
+            (
+                BtreeId::Dirents,
+                vec![dirent(BCACHEFS_ROOT_INO, 0, "file", 4097, 8)],
+            ),
+            (
+                BtreeId::Extents,
+                vec![extent(4097, 4, 4, &[crc.encode().unwrap(), ptr(12288)])],
+            ),
+        ]);
+        members[0].dev.write_at(&stored, 12288 << 9).unwrap();
+        let btrees = Btrees::open(members, Some(secret)).unwrap();
+        let file = btrees.resolve("/file").unwrap();
+
+        let mut out = Vec::new();
+        FileReader::new(&btrees, false)
+            .copy(&file, &mut out)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn snapshot_extents() {
+        let sized = |ty, end, size, snapshot, val: &[u8]| {
+            let mut buf = key(ty, Bpos::new(4097, end, snapshot), val);
+            Bkey::from(&mut buf[..]).set_size(size).unwrap();
+            buf
+        };
+
+        // snapshots 80 and 90 are siblings below 100: 90 overwrites sectors
+        // 8..16 and deletes 20..24 of the file, 80 overwrites 0..4
+        let btrees = btrees(&[
+            (
+                BtreeId::Inodes,
+                vec![
+                    inode(BCACHEFS_ROOT_INO, 100, libc::S_IFDIR | 0o755, 0),
+                    inode(4097, 100, libc::S_IFREG | 0o644, 24 * 512),
+                ],
+            ),
+            (
+                BtreeId::Extents,
+                vec![
+                    sized(KeyType::Extent, 4, 4, 80, &ptr(12288 + 32)),
+                    sized(KeyType::Extent, 16, 8, 90, &ptr(12288 + 24)),
+                    sized(KeyType::Extent, 16, 16, 100, &ptr(12288)),
+                    sized(KeyType::Discard, 24, 4, 90, &[]),
+                    sized(KeyType::Extent, 24, 8, 100, &ptr(12288 + 16)),
+                ],
+            ),
+            (
+                BtreeId::Snapshots,
+                vec![snapshot(80, 100), snapshot(90, 100), snapshot(100, 0)],
+            ),
+        ]);
+        // each sector of the disk holds its number past 12288, plus one
+        let disk: Vec<u8> = (0..(36 * 512)).map(|i| (i / 512 + 1) as u8).collect();
+        btrees
+            .member(0)
+            .unwrap()
+            .dev
+            .write_at(&disk, 12288 << 9)
+            .unwrap();
+
+        let read = |snapshot| {
+            let file = ResolvedInode {
+                subvol: 0,
+                snapshot,
+                inode: btrees.inode(4097, snapshot).unwrap(),
+            };
+            let mut out = Vec::new();
+            FileReader::new(&btrees, false)
+                .copy(&file, &mut out)
+                .unwrap();
+            out.chunks(512).map(|sector| sector[0]).collect::<Vec<_>>()
+        };
+        let sectors = |range: Range<u8>| range.collect::<Vec<_>>();
+        assert_eq!(read(100), sectors(1..25));
+        assert_eq!(
+            read(90),
+            [sectors(1..9), sectors(25..33), sectors(17..21), vec![0; 4]].concat()
+        );
+        assert_eq!(read(80), [sectors(33..37), sectors(5..25)].concat());
+    }
+
+    /// An inode key with the given number of links beyond the first
+    fn linked_inode(inum: u64, mode: u32, size: u64, nlink: u64) -> Vec<u8> {
+        let mut fields = vec![0u64; INODE_NR_FIELDS];
+        fields[2] = 1_000_000_000;
+        fields[4] = size;
+        fields[8] = nlink;
+        key(
+            KeyType::Inode,
+            Bpos::new(0, inum, 0),
+            &inode_val(mode as u16, &fields, true),
+        )
+    }
+
+    #[test]
+    fn restore_tree() {
+        let dir = libc::S_IFDIR | 0o750;
+        let mut dirents = vec![
+            dirent(BCACHEFS_ROOT_INO, 0, "d", 4097, 4),
+            dirent(BCACHEFS_ROOT_INO, 0, "l", 4099, 10),
+            dirent(BCACHEFS_ROOT_INO, 0, "p", 4100, 1),
+            dirent(4097, 0, "f", 4098, 8),
+            dirent(4097, 0, "g", 4098, 8),
+        ];
+        let format = bkey_format_current();
+        dirents.sort_by_key(|key| BkeyFormat::from(&format[..]).unpack_key(key).unwrap().p());
+        let btrees = btrees(&[
+            (
+                BtreeId::Inodes,
+                vec![
+                    linked_inode(BCACHEFS_ROOT_INO, dir, 0, 0),
+                    linked_inode(4097, dir, 0, 0),
+                    linked_inode(4098, libc::S_IFREG | 0o640, 5, 1),
+                    linked_inode(4099, libc::S_IFLNK | 0o777, 4, 0),
+                    linked_inode(4100, libc::S_IFIFO | 0o600, 0, 0),
+                ],
+            ),
+            (BtreeId::Dirents, dirents),
+            (
+                BtreeId::Extents,
+                vec![
+                    sized(KeyType::InlineData, 4098, 1, 1, b"hello\0\0\0"),
+                    sized(KeyType::InlineData, 4099, 1, 1, b"d/f\0\0\0\0\0"),
+                ],
+            ),
+        ]);
+
+        let out = std::env::temp_dir().join(format!("bcachefs-restore-{}", std::process::id()));
+        let time_base = TimeBase::from_sb(btrees.sb()).unwrap();
+        let mut restorer = Restorer {
+            btrees: &btrees,
+            reader: FileReader::new(&btrees, false),
+            time_base,
+            chown: false,
+            links: HashMap::new(),
+            restored: 0,
+            errors: Vec::new(),
+        };
+        fs::create_dir_all(&out).unwrap();
+        restorer.restore_tree(&btrees.resolve("/").unwrap(), &out);
+        let (restored, errors) = (restorer.restored, restorer.errors.len());
+
+        let f = fs::metadata(out.join("d/f")).unwrap();
+        let result = (
+            fs::read(out.join("d/f")).unwrap(),
+            f.ino() == fs::metadata(out.join("d/g")).unwrap().ino(),
+            f.permissions().mode() & 0o7777,
+            f.mtime(),
+            fs::read_link(out.join("l")).unwrap(),
+            fs::symlink_metadata(out.join("p"))
+                .unwrap()
+                .file_type()
+                .is_fifo(),
+            fs::metadata(out.join("d")).unwrap().permissions().mode() & 0o7777,
+        );
+
+        // restoring again leaves the existing files alone
+        fs::write(out.join("d/f"), b"local").unwrap();
+        restorer.links.clear();
+        restorer.errors.clear();
+        restorer.restore_tree(&btrees.resolve("/d/f").unwrap(), &out.join("d/f"));
+        let existing = (restorer.errors.len(), fs::read(out.join("d/f")).unwrap());
+        fs::remove_dir_all(&out).unwrap();
+
+        assert_eq!((restored, errors), (6, 0));
+        assert_eq!(result.0, b"hello");
+        assert!(result.1);
+        assert_eq!(result.2, 0o640);
+        assert_eq!(result.3, time_base.to_timespec(1_000_000_000).sec);
+        assert_eq!(result.4, Path::new("d/f"));
+        assert!(result.5);
+        assert_eq!(result.6, 0o750);
+        assert_eq!(existing, (1, b"local".to_vec()));
+    }
+}
@@ -1,68 +1,34 @@
 use std::cmp;
 use std::fmt;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
-use std::os::linux::fs::MetadataExt;
-use std::os::unix::fs::FileTypeExt;
-use std::os::unix::io::AsRawFd;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::str::FromStr;
 
+use crate::block_dev::{open_device, BlockDevice, Topology};
 use crate::super_block::{
     DataTypes, Features, Field, MemberField, MemberFlag, SuperBlock, SuperBlockFlag,
     SuperBlockFlags, SuperBlockLayout,
 };
+use crate::super_io::{LAYOUT_SECTOR, SB_SECTOR};
+use crate::version::METADATA_VERSION_CURRENT;
+use crate::zoned::{plan_zones, ZoneModel, ZonePlan};
 use crate::{BchError, Result};
 
 use libblkid_rs::BlkidProbe;
-use log::{debug, error};
-use nix::{ioctl_read, request_code_none};
+use log::{debug, error, warn};
 use uuid::Uuid;
 
-/// The maximum metadata version
-const METADATA_VERSION_MAX: u16 = 14;
-/// The current metadata version
-const METADATA_VERSION_CURRENT: u16 = METADATA_VERSION_MAX - 1;
-
 /// Minumum number of buckets on a device
 const MIN_NR_NBUCKETS: u64 = 1 << 6;
-/// Smallest supported block size
-const MIN_BLOCK_SIZE: u64 = 512;
-/// Default supported block size
-const DEFAULT_BLOCK_SIZE: u64 = MIN_BLOCK_SIZE;
+/// Smallest bucket size picked if the device is large enough (128k)
+const MIN_BUCKET_SIZE: u64 = 256;
+/// Largest bucket size picked (1M)
+const MAX_BUCKET_SIZE: u64 = 1 << 11;
 /// Default btree node size
 const DEFAULT_BTREE_NODE_SIZE: u64 = 512;
-
-/// The superblock sector
-const SB_SECTOR: u64 = 8;
-/// The sector of the default layout
-const LAYOUT_SECTOR: u64 = 7;
-
-const BLKPBSZGET_IOC_MAGIC: u8 = 0x12;
-const BLKPBSZGET_IOC_TYPE_MODE: u8 = 123;
-
-/// Thin wrapper around ioctl(BLKPBSZGET)
-unsafe fn blkpbszget(fd: libc::c_int, data: *mut u64) -> Result<libc::c_int> {
-    let res = libc::ioctl(
-        fd,
-        request_code_none!(BLKPBSZGET_IOC_MAGIC, BLKPBSZGET_IOC_TYPE_MODE),
-        data,
-    );
-    if res != 0 {
-        Err(io::Error::from_raw_os_error(res).into())
-    } else {
-        Ok(res)
-    }
-}
-
-const BLKGETSIZE64_IOC_MAGIC: u8 = 0x12;
-const BLKGETSIZE64_IOC_TYPE_MODE: u8 = 114;
-
-ioctl_read!(
-    blkgetsize64,
-    BLKGETSIZE64_IOC_MAGIC,
-    BLKGETSIZE64_IOC_TYPE_MODE,
-    u64
-);
+/// Largest block size picked from the device topology
+const MAX_BLOCK_SIZE: u64 = 1 << 15;
 
 /// Action to take on a FS error
 #[derive(Debug, PartialEq)]
@@ -121,12 +87,16 @@ pub struct Args {
     pub label: Option<String>,
     /// The disk uuid
     pub uuid: Uuid,
-    /// Force creation if a preexisting FS exists
+    /// Force creation if a preexisting FS or partition table exists
     pub force: bool,
     /// The size of the filesystem
     pub superblock_size: u64,
-    /// The block size of the new FS
-    pub block_size: u16,
+    /// Format host-managed zoned devices, using only their conventional zones
+    pub host_managed: bool,
+    /// The block size of the new FS, picked from the devices if not given
+    pub block_size: Option<u16>,
+    /// The bucket size in bytes, picked for each device if not given
+    pub bucket_size: Option<u64>,
     /// The foreground target of the device set
     pub foreground_target: Option<u64>,
     /// The background target of the device set
@@ -141,60 +111,375 @@ pub struct Args {
     pub devices: Vec<String>,
 }
 
+/// The granularity, in sectors, buckets on a device should be aligned to
+///
+/// Buckets start at multiples of the bucket size from the start of the
+/// device, so when the device is offset from its natural alignment only the
+/// part of the alignment that evenly divides the offset can be kept.
+fn bucket_alignment(topology: &Topology) -> u64 {
+    let align = [
+        topology.io_opt,
+        topology.io_min,
+        topology.physical_block_size,
+    ]
+    .iter()
+    .map(|size| size >> 9)
+    .find(|&size| size > 0 && size <= MAX_BUCKET_SIZE)
+    .unwrap_or(1);
+
+    match topology.alignment_offset {
+        0 => align,
+        offset if offset % 512 > 0 => 1,
+        offset => gcd(align, offset >> 9),
+    }
+}
+
 /// Parsed device
-#[derive(Debug)]
-struct Device {
+pub(crate) struct Device<'a> {
+    dev: &'a dyn BlockDevice,
     dev_name: String,
     size: u64,
-    block_size: u64,
+    topology: Topology,
+    zones: Option<ZonePlan>,
     bucket_size: u64,
     nbuckets: u64,
 }
 
-impl Device {
-    fn file(&self) -> Result<File> {
-        Ok(OpenOptions::new().write(true).open(self.dev_name.clone())?)
+impl<'a> fmt::Display for Device<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let topology = &self.topology;
+        writeln!(f, "{}:", self.dev_name)?;
+        writeln!(
+            f,
+            "\tsize:             {} ({} sectors)",
+            self.size << 9,
+            self.size
+        )?;
+        writeln!(f, "\tlogical sector:   {}", topology.logical_block_size)?;
+        writeln!(f, "\tphysical sector:  {}", topology.physical_block_size)?;
+        writeln!(f, "\tminimum io:       {}", topology.io_min)?;
+        writeln!(f, "\toptimal io:       {}", topology.io_opt)?;
+        if topology.alignment_offset != 0 {
+            writeln!(
+                f,
+                "\talignment offset: {} (buckets aligned to {} sectors)",
+                topology.alignment_offset,
+                bucket_alignment(topology)
+            )?;
+        } else {
+            writeln!(f, "\talignment offset: 0")?;
+        }
+        if let Some(ref zones) = self.zones {
+            writeln!(f, "\tzoned:            {}", zones)?;
+        }
+        writeln!(
+            f,
+            "\tbucket size:      {} ({} sectors)",
+            self.bucket_size << 9,
+            self.bucket_size
+        )?;
+        write!(f, "\tbuckets:          {}", self.nbuckets)
+    }
+}
+
+impl<'a> Device<'a> {
+    /// Probe the size, topology and zones of a device
+    ///
+    /// Host-managed zoned devices are refused unless `host_managed` is set,
+    /// as only their conventional zones can be used.
+    pub(crate) fn probe(
+        dev_name: &str,
+        dev: &'a dyn BlockDevice,
+        superblock_size: u64,
+        host_managed: bool,
+    ) -> Result<Device<'a>> {
+        let topology = dev.topology()?;
+        let zones = plan_zones(dev, dev_name, SB_SECTOR + superblock_size)?;
+        let mut size = dev.size()? >> 9;
+
+        debug!(
+            "\tdevice {}: size={} topology={:?}",
+            dev_name, size, topology
+        );
+
+        if let Some(ref zones) = zones {
+            if zones.model == ZoneModel::HostManaged && !host_managed {
+                return Err(BchError::Str(format!(
+                    "{}: host-managed zoned device, only the first {} of {} sectors are in \
+                     conventional zones and usable; pass --host-managed to format it anyway",
+                    dev_name, zones.usable, size
+                )));
+            }
+            if zones.usable < size {
+                println!(
+                    "{}: only the first {} of {} sectors are in conventional zones and usable",
+                    dev_name, zones.usable, size
+                );
+                size = zones.usable;
+            }
+        }
+
+        Ok(Device {
+            dev,
+            dev_name: dev_name.to_string(),
+            size,
+            topology,
+            zones,
+            bucket_size: 0,
+            nbuckets: 0,
+        })
+    }
+
+    /// The usable size of the device in sectors
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The smallest unit the device can address
+    pub(crate) fn logical_block_size(&self) -> u64 {
+        self.topology.logical_block_size
+    }
+
+    /// Pick the bucket size and count of the device, sizes in sectors
+    pub(crate) fn pick_buckets(&mut self, block_size: u64, bucket_size: Option<u64>) -> Result<()> {
+        let (bucket_size, nbuckets) = pick_bucket_size(
+            &self.dev_name,
+            self.size,
+            block_size,
+            bucket_alignment(&self.topology),
+            self.zones.map(|zones| zones.zone_size),
+            bucket_size,
+        )?;
+        debug!(
+            "\t{}: bucketsize={} nbuckets={}",
+            self.dev_name, bucket_size, nbuckets
+        );
+
+        self.bucket_size = bucket_size;
+        self.nbuckets = nbuckets;
+        Ok(())
+    }
+
+    /// Zero the start of the device and write the superblock layout
+    pub(crate) fn init_layout(&self, layout: &[u8]) -> Result<()> {
+        const ZEROS: [u8; (SB_SECTOR as usize) << 9] = [0x00; ((SB_SECTOR as usize) << 9)];
+        self.dev.write_at(&ZEROS[..], 0)?;
+        self.dev.write_at(layout, LAYOUT_SECTOR << 9)
+    }
+
+    /// Fill in the member field of the device with a new uuid
+    pub(crate) fn init_member(&self, buf: &mut [u8], durability: u64) -> Result<()> {
+        let mut member = MemberField::from(buf);
+        let uuid = Uuid::new_v4();
+        debug!("\t{}: uuid={}", self.dev_name, uuid);
+
+        member.set_uuid(uuid)?;
+        member.set_n_buckets(self.nbuckets)?;
+        member.set_first_bucket(0)?;
+        member.set_bucket_size(self.bucket_size as u16)?;
+
+        member.set_flag(MemberFlag::REPLACEMENT, 0)?;
+        member.set_flag(MemberFlag::DISCARD, 0)?;
+        member.set_flag(MemberFlag::DATA_ALLOWED, DataTypes::DEFAULT.bits())?;
+        // stored off by one, zero means the default durability of one
+        member.set_flag(MemberFlag::DURABILITY, durability + 1)
+    }
+}
+
+/// Build the default superblock layout of a single superblock
+pub(crate) fn default_layout(superblock_size: u64) -> Result<[u8; 512]> {
+    let mut layout_buf = [0u8; 512];
+    let mut layout = SuperBlockLayout::from(&mut layout_buf[..]);
+    // write out sb layout header
+    layout.set_magic()?;
+    layout.set_layout_type(0x00)?;
+    layout.set_nr_superblocks(0x01)?;
+    layout.set_sb_max_size((superblock_size as f64).log2() as u8)?;
+    // write out one superblock offset
+    layout.set_sb_offset(0, SB_SECTOR)?;
+    Ok(layout_buf)
+}
+
+/// The greatest common divisor of two numbers
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
     }
+    a
 }
 
-/// The minimum size a device may be given the number of buckets
-fn min_size(buckets: u64) -> u64 {
-    buckets * MIN_NR_NBUCKETS
+/// The minimum size a device may be given the bucket size
+fn min_size(bucket_size: u64) -> u64 {
+    bucket_size * MIN_NR_NBUCKETS
 }
 
-/// Get the device block size
-fn get_blocksize(f: &File) -> Result<u64> {
-    let meta = f.metadata()?;
-    let ft = meta.file_type();
+/// Pick the bucket size and number of buckets for a device.
+///
+/// All sizes are in sectors. An explicit bucket size must be a multiple of
+/// the block size. Unless an explicit bucket size is given, the
+/// bucket size starts at 128k and scales up with the device size to at most
+/// 1M, or is halved until the device holds the minimum number of buckets.
+/// The picked bucket size is then rounded up to the given alignment, as long
+/// as the device still holds the minimum number of buckets. On zoned devices
+/// the bucket size must evenly divide the zone size, so that no bucket
+/// crosses a zone boundary.
+fn pick_bucket_size(
+    dev: &str,
+    size: u64,
+    block_size: u64,
+    align: u64,
+    zone_size: Option<u64>,
+    bucket_size: Option<u64>,
+) -> Result<(u64, u64)> {
+    let bucket_size = match bucket_size {
+        Some(bucket_size) => {
+            if bucket_size < block_size || bucket_size % block_size > 0 {
+                return Err(BchError::Str(format!(
+                    "{}: bucket size {} must be a multiple of the block size {}",
+                    dev, bucket_size, block_size
+                )));
+            }
+            if let Some(zone_size) = zone_size {
+                if zone_size % bucket_size != 0 {
+                    return Err(BchError::Str(format!(
+                        "{}: bucket size {} does not evenly divide the zone size {}",
+                        dev, bucket_size, zone_size
+                    )));
+                }
+            }
+            bucket_size
+        }
+        None => {
+            if size < min_size(block_size) {
+                return Err(BchError::Str(format!(
+                    "cannot format {}, too small ({} sectors, min {})",
+                    dev,
+                    size,
+                    min_size(block_size)
+                )));
+            }
+
+            let mut bucket_size = cmp::max(block_size, MIN_BUCKET_SIZE);
+
+            if size >= min_size(bucket_size) {
+                let ratio = size / min_size(bucket_size);
+                let scale = cmp::max(1, u64::from(63 - ratio.leading_zeros()) / 4);
+                // round the scale down to a power of two
+                let scale = 1 << (63 - scale.leading_zeros());
+                bucket_size = cmp::min(bucket_size * scale, MAX_BUCKET_SIZE);
+            } else {
+                while size < min_size(bucket_size) {
+                    bucket_size /= 2;
+                }
+            }
+
+            let aligned = match bucket_size % align {
+                0 => bucket_size,
+                rem => bucket_size + align - rem,
+            };
+            if size >= min_size(aligned) {
+                bucket_size = aligned;
+            } else {
+                debug!("{}: too small to align buckets to {} sectors", dev, align);
+            }
+
+            match zone_size {
+                Some(zone_size) => gcd(bucket_size, zone_size),
+                None => bucket_size,
+            }
+        }
+    };
+
+    if bucket_size % align != 0 {
+        warn!(
+            "{}: bucket size {} is not aligned to the optimal io size ({} sectors)",
+            dev, bucket_size, align
+        );
+    }
+
+    if bucket_size < block_size {
+        return Err(BchError::Str(format!(
+            "{}: bucket size {} cannot be smaller than block size {}",
+            dev, bucket_size, block_size
+        )));
+    }
+
+    let nbuckets = size / bucket_size;
 
-    if !ft.is_block_device() {
-        Ok(meta.st_blksize() >> 9)
-    } else {
-        let mut data = 0u64;
-        unsafe { blkpbszget(f.as_raw_fd(), &mut data)? };
-        Ok(data >> 9)
+    if nbuckets < MIN_NR_NBUCKETS {
+        return Err(BchError::Str(format!(
+            "{}: not enough buckets: {}, need {} (bucket size {})",
+            dev, nbuckets, MIN_NR_NBUCKETS, bucket_size
+        )));
     }
+
+    Ok((bucket_size, nbuckets))
 }
 
-/// Get the device size
-fn get_size(f: &File) -> Result<u64> {
-    let meta = f.metadata()?;
-    let ft = meta.file_type();
+/// A partition found in the partition table of a device
+#[derive(Debug)]
+struct Partition {
+    /// The device path of the partition
+    path: String,
+    /// The filesystem type found on the partition
+    fs_type: Option<String>,
+    /// The filesystem label found on the partition
+    fs_label: Option<String>,
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.fs_type, &self.fs_label) {
+            (Some(ty), Some(label)) => write!(f, "{}: {} FS labelled `{}`", self.path, ty, label),
+            (Some(ty), None) => write!(f, "{}: {} FS", self.path, ty),
+            (None, _) => write!(f, "{}: no known FS", self.path),
+        }
+    }
+}
 
-    if !ft.is_block_device() {
-        Ok(meta.st_size())
-    } else {
-        let mut data = 0u64;
-        unsafe { blkgetsize64(f.as_raw_fd(), &mut data)? };
-        Ok(data)
+/// Get the device path of the partition with the given number
+///
+/// Follows the kernel naming scheme, where disks whose name ends in a digit
+/// (e.g. `nvme0n1`, `loop0`) separate the partition number with a `p`.
+fn partition_path(device: &str, partno: u32) -> String {
+    match device.chars().last() {
+        Some(c) if c.is_ascii_digit() => format!("{}p{}", device, partno),
+        _ => format!("{}{}", device, partno),
     }
 }
 
-/// Check if a filesystem exists on the given device
-fn check_device(device: &String) -> Result<()> {
+/// Look up the filesystem type and label in the given region of a device
+fn probe_region(raw_fd: RawFd, offset: i64, size: i64) -> Result<(Option<String>, Option<String>)> {
     let mut probe = BlkidProbe::new()?;
+
+    probe.set_device(raw_fd, offset, size)?;
+    probe.enable_superblocks(true)?;
+    probe.do_fullprobe()?;
+
+    Ok((
+        probe.lookup_value("TYPE").ok(),
+        probe.lookup_value("LABEL").ok(),
+    ))
+}
+
+/// Ask the user to confirm that we should proceed
+fn confirm(prompt: &str) -> Result<bool> {
     let mut input = String::new();
 
+    print!("{} ", prompt);
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut input)?;
+    let yn = input.trim();
+
+    Ok(yn == "y" || yn == "Y")
+}
+
+/// Check if a filesystem or partition table exists on the given device
+pub(crate) fn check_device(device: &String) -> Result<()> {
+    let mut probe = BlkidProbe::new()?;
+
     debug!("openning device: {}", device);
     let f = File::open(device.clone())?;
 
@@ -202,10 +487,50 @@ fn check_device(device: &String) -> Result<()> {
 
     probe.set_device(raw_fd, 0, 0)?;
 
+    probe.enable_superblocks(true)?;
     probe.enable_partitions(true)?;
 
     probe.do_fullprobe()?;
 
+    if let Ok(pt_type) = probe.lookup_value("PTTYPE") {
+        let mut partitions = Vec::new();
+
+        if let Ok(mut list) = probe.get_partitions() {
+            for i in 0..list.number_of_partitions()? {
+                let part = list.get_partition(i)?;
+                let start = *part.get_start().bytes().as_ref();
+                let size = *part.get_size().bytes().as_ref();
+                let (fs_type, fs_label) = probe_region(raw_fd, start, size)?;
+
+                partitions.push(Partition {
+                    path: partition_path(device, part.get_partno()?),
+                    fs_type,
+                    fs_label,
+                });
+            }
+        }
+
+        debug!("{} partitions={:?}", device, partitions);
+
+        if partitions.is_empty() {
+            println!("{} contains a {} partition table", device, pt_type);
+        } else {
+            println!(
+                "{} contains a {} partition table with {} partition(s):",
+                device,
+                pt_type,
+                partitions.len()
+            );
+            for part in partitions.iter() {
+                println!("\t{}", part);
+            }
+        }
+
+        if !confirm("Format the whole device and destroy all partitions?")? {
+            return Err(BchError::Str("Existing partition table found".to_string()));
+        }
+    }
+
     if let Ok(fs_type) = probe.lookup_value("TYPE") {
         if let Ok(fs_label) = probe.lookup_value("LABEL") {
             println!(
@@ -216,111 +541,64 @@ fn check_device(device: &String) -> Result<()> {
             println!("{} contains a {} FS", device, fs_type);
         }
 
-        print!("Proceed anyway? ");
-        io::stdout().flush()?;
-        io::stdin().read_line(&mut input)?;
-        let yn = input.trim();
-
-        if yn == "y" || yn == "Y" {
-            Ok(())
-        } else {
-            Err(BchError::Str("Existing filesystem found".to_string()))
+        if !confirm("Proceed anyway?")? {
+            return Err(BchError::Str("Existing filesystem found".to_string()));
         }
-    } else {
-        Ok(())
     }
+
+    Ok(())
 }
 
 /// Worker function that formats the given devices per the provided
 /// arguments.
-fn format(args: Args) -> Result<()> {
+fn format(args: Args, devices: &[Box<dyn BlockDevice>]) -> Result<()> {
     let mut devs = Vec::new();
 
     debug!("Gathering device info");
-    for dev in args.devices.iter() {
-        let file = OpenOptions::new().write(true).open(dev)?;
-        let block_size = get_blocksize(&file)?;
-        let size = get_size(&file)? >> 9;
-
-        debug!("\tdevice {}: size={} blocksize={}", dev, size, block_size);
-
-        let mut bucket_size = cmp::max(args.block_size as u64, block_size << 9);
-
-        if size < min_size(bucket_size) {
-            return Err(BchError::Str(format!(
-                "cannot format {}, too small ({} sectors, min {})",
-                dev,
-                size,
-                min_size(DEFAULT_BLOCK_SIZE)
-            )));
-        }
-
-        bucket_size = cmp::max(bucket_size, MIN_BLOCK_SIZE);
-
-        if size >= min_size(bucket_size) {
-            let scale = cmp::max(
-                1,
-                ((size / min_size(args.block_size as u64)) as f64).log2() as u64 / 4,
-            );
-            bucket_size = cmp::min(bucket_size * scale, 1 << 11);
-        } else {
-            while size < min_size(bucket_size) {
-                bucket_size /= 2;
-            }
-        }
-
-        let nbuckets = size / bucket_size;
-
-        if bucket_size < (args.block_size as u64) >> 9 {
-            return Err(BchError::Str(format!(
-                "{}: block size {} to small for bucket size {}",
-                dev, bucket_size, args.block_size
-            )));
-        }
+    for (dev, device) in args.devices.iter().zip(devices.iter()) {
+        devs.push(Device::probe(
+            dev,
+            device.as_ref(),
+            args.superblock_size,
+            args.host_managed,
+        )?);
+    }
 
-        if nbuckets < MIN_NR_NBUCKETS {
-            return Err(BchError::Str(format!(
-                "{}: too few buckets {}",
-                dev, nbuckets
-            )));
-        }
-        debug!("\t\tbucketsize={} nbuckets={}", bucket_size, nbuckets);
+    let max_logical = devs
+        .iter()
+        .map(|dev| dev.topology.logical_block_size)
+        .max()
+        .ok_or_else(|| BchError::Str("no devices given".to_string()))?;
+    let max_physical = devs
+        .iter()
+        .map(|dev| dev.topology.physical_block_size)
+        .max()
+        .unwrap_or(max_logical);
 
-        let device = Device {
-            dev_name: dev.clone(),
-            block_size,
-            size,
-            bucket_size,
-            nbuckets,
-        };
-        devs.push(device);
-    }
+    debug!(
+        "max logical sector={} max physical sector={}",
+        max_logical, max_physical
+    );
 
-    let max_dev_block_size = match devs.iter().map(|dev| dev.block_size).max() {
-        Some(blksize) => blksize,
-        None => {
-            return Err(BchError::Str(
-                "could not determine the max device block size".to_string(),
-            ));
-        }
+    let block_size = match args.block_size {
+        Some(block_size) => u64::from(block_size),
+        None => max_physical.clamp(512, MAX_BLOCK_SIZE),
     };
 
-    debug!("max blocksize={}", max_dev_block_size);
-
-    if u64::from(args.block_size >> 9) < max_dev_block_size {
+    if block_size < max_logical {
         return Err(BchError::Str(format!(
-            "block size {} too small for max device block size {}",
-            args.block_size, max_dev_block_size
+            "block size {} too small for max device logical sector size {}",
+            block_size, max_logical
         )));
+    } else if block_size < max_physical {
+        warn!(
+            "block size {} smaller than max device physical sector size {}",
+            block_size, max_physical
+        );
     }
 
-    debug!("Zeroing superblock:");
-    for (i, dev) in devs.iter().enumerate() {
-        let mut file = dev.file()?;
-
-        debug!("\tdevice #{}: {}", i, args.devices[i]);
-        const ZEROS: [u8; (SB_SECTOR as usize) << 9] = [0x00; ((SB_SECTOR as usize) << 9)];
-        file.write(&ZEROS[..])?;
+    for dev in devs.iter_mut() {
+        dev.pick_buckets(block_size >> 9, args.bucket_size.map(|size| size >> 9))?;
     }
 
     let btree_node_size = cmp::min(
@@ -331,27 +609,26 @@ fn format(args: Args) -> Result<()> {
         DEFAULT_BTREE_NODE_SIZE,
     );
 
-    let mut layout_buf = [0u8; 512];
-    let mut layout = SuperBlockLayout::from(&mut layout_buf[..]);
-    // write out sb layout header
-    layout.set_magic()?;
-    layout.set_layout_type(0x00)?;
-    layout.set_nr_superblocks(0x01)?;
-    layout.set_sb_max_size((args.superblock_size as f64).log2() as u8)?;
-    // write out one superblock offset
-    layout.set_sb_offset(0, SB_SECTOR)?;
+    println!("Format plan:");
+    println!("block size:      {}", block_size);
+    println!("btree node size: {}", btree_node_size << 9);
+    for dev in devs.iter() {
+        println!("{}", dev);
+    }
+
+    let layout_buf = default_layout(args.superblock_size)?;
+    let layout = SuperBlockLayout::from(&layout_buf[..]);
     debug!(
         "First superblock at offset={} with sb_size={} block_size={}",
         SB_SECTOR,
         args.superblock_size >> 9,
-        args.block_size
+        block_size
     );
 
-    for dev in devs.iter() {
-        let mut file = dev.file()?;
-
-        file.seek(SeekFrom::Start(LAYOUT_SECTOR << 9))?;
-        file.write(layout.as_ref())?;
+    debug!("Zeroing superblock:");
+    for (i, dev) in devs.iter().enumerate() {
+        debug!("\tdevice #{}: {}", i, args.devices[i]);
+        dev.init_layout(&layout_buf)?;
     }
 
     let mut sb_buf = [0u8; 1024];
@@ -360,7 +637,7 @@ fn format(args: Args) -> Result<()> {
     sb.set_version(METADATA_VERSION_CURRENT)?;
     sb.set_version_min(METADATA_VERSION_CURRENT)?;
     sb.set_magic()?;
-    sb.set_block_size(args.block_size >> 9)?;
+    sb.set_block_size((block_size >> 9) as u16)?;
     sb.set_nr_devices(args.devices.len() as u8)?;
 
     debug!("Superblock ID info");
@@ -437,19 +714,8 @@ fn format(args: Args) -> Result<()> {
     debug!("Building member fields:");
     let mut member_buf = vec![0u8; 56 * devs.len()];
     for (i, dev) in devs.iter().enumerate() {
-        let mut member = MemberField::from(&mut member_buf[(56 * i)..]);
-        let uuid = Uuid::new_v4();
-        debug!("\tdevice #{}: {} uuid={}", i, dev.dev_name, uuid);
-
-        member.set_uuid(uuid)?;
-        member.set_n_buckets(dev.nbuckets)?;
-        member.set_first_bucket(0)?;
-        member.set_bucket_size(dev.bucket_size as u16)?;
-
-        member.set_flag(MemberFlag::REPLACEMENT, 0)?;
-        member.set_flag(MemberFlag::DISCARD, 0)?;
-        member.set_flag(MemberFlag::DATA_ALLOWED, DataTypes::DEFAULT.bits())?;
-        member.set_flag(MemberFlag::DURABILITY, 2)?;
+        debug!("\tdevice #{}: {}", i, dev.dev_name);
+        dev.init_member(&mut member_buf[(56 * i)..(56 * (i + 1))], 1)?;
     }
     sb.add_field(Field::Members, &member_buf)?;
 
@@ -459,9 +725,8 @@ fn format(args: Args) -> Result<()> {
         sb.set_dev_idx(i as u8)?;
         sb.set_offset(SB_SECTOR)?;
 
-        let mut file = dev.file()?;
-        file.seek(SeekFrom::Start(SB_SECTOR << 9))?;
-        file.write(sb.as_ref())?;
+        dev.dev.write_at(sb.as_ref(), SB_SECTOR << 9)?;
+        dev.dev.flush()?;
     }
 
     Ok(())
@@ -480,10 +745,370 @@ pub fn format_device(args: Args) {
                 std::process::exit(1);
             }
         }
+    }
 
-        if let Err(e) = format(args) {
-            error!("Failed to format devices: {}", e);
+    let devices = match args
+        .devices
+        .iter()
+        .map(|dev| open_device(dev, true))
+        .collect::<Result<Vec<_>>>()
+    {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("Failed to open devices: {}", e);
             std::process::exit(1);
         }
+    };
+
+    if let Err(e) = format(args, &devices) {
+        error!("Failed to format devices: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test_bucket_size {
+    use super::*;
+
+    const MIB: u64 = 1 << 11;
+    const GIB: u64 = 1 << 21;
+    const TIB: u64 = 1 << 31;
+
+    #[test]
+    fn pick_by_device_size() {
+        // (device size, block size, bucket size, nbuckets), all in sectors
+        let table = [
+            (64 * MIB, 1, 256, 512),
+            (256 * MIB, 1, 256, 2048),
+            (GIB, 1, 256, 8192),
+            (16 * GIB, 1, 512, 65536),
+            (256 * GIB, 1, 512, 1 << 20),
+            (TIB, 1, 1024, 1 << 21),
+            (16 * TIB, 1, 1024, 1 << 25),
+            (100 * TIB, 1, 1024, 100 << 21),
+            (64 * MIB, 8, 256, 512),
+            (16 * GIB, 8, 512, 65536),
+            (100 * TIB, 8, 1024, 100 << 21),
+            (64 * MIB, 512, 512, 256),
+            (100 * TIB, 512, 2048, 100 << 20),
+        ];
+
+        for &(size, block_size, bucket_size, nbuckets) in table.iter() {
+            assert_eq!(
+                pick_bucket_size("dev", size, block_size, 1, None, None).unwrap(),
+                (bucket_size, nbuckets),
+                "size={} block_size={}",
+                size,
+                block_size
+            );
+        }
+    }
+
+    #[test]
+    fn pick_small_device() {
+        assert_eq!(
+            pick_bucket_size("dev", 4 * MIB, 1, 1, None, None).unwrap(),
+            (128, 64)
+        );
+        assert_eq!(
+            pick_bucket_size("dev", MIB, 1, 1, None, None).unwrap(),
+            (32, 64)
+        );
+        assert!(pick_bucket_size("dev", 63, 1, 1, None, None).is_err());
+        assert_eq!(
+            pick_bucket_size("dev", MIB, 8, 1, None, None).unwrap(),
+            (32, 64)
+        );
+        assert!(pick_bucket_size("dev", 511, 8, 1, None, None).is_err());
+    }
+
+    #[test]
+    fn explicit_bucket_size() {
+        assert_eq!(
+            pick_bucket_size("dev", GIB, 1, 1, None, Some(2048)).unwrap(),
+            (2048, 1024)
+        );
+        assert_eq!(
+            pick_bucket_size("dev", 100 * TIB, 8, 1, None, Some(8)).unwrap(),
+            (8, 100 << 28)
+        );
+        assert!(pick_bucket_size("dev", GIB, 8, 1, None, Some(4)).is_err());
+        assert!(pick_bucket_size("dev", GIB, 8, 1, None, Some(12)).is_err());
+        assert!(pick_bucket_size("dev", GIB, 8, 1, None, Some(0)).is_err());
+        assert!(pick_bucket_size("dev", 64 * MIB, 1, 1, None, Some(4096)).is_err());
+    }
+
+    #[test]
+    fn aligned_bucket_size() {
+        // 4Kn drive
+        assert_eq!(
+            pick_bucket_size("dev", GIB, 8, 8, None, None).unwrap(),
+            (256, 8192)
+        );
+        // RAID5 with three data disks and a 128k chunk size
+        assert_eq!(
+            pick_bucket_size("dev", GIB, 1, 768, None, None).unwrap(),
+            (768, 2730)
+        );
+        assert_eq!(
+            pick_bucket_size("dev", TIB, 1, 768, None, None).unwrap(),
+            (1536, 1398101)
+        );
+        // too small to align, fall back to the unaligned bucket size
+        assert_eq!(
+            pick_bucket_size("dev", 8 * MIB, 1, 768, None, None).unwrap(),
+            (256, 64)
+        );
+        // an explicit bucket size is never changed
+        assert_eq!(
+            pick_bucket_size("dev", GIB, 1, 768, None, Some(512)).unwrap(),
+            (512, 4096)
+        );
+    }
+
+    #[test]
+    fn zoned_bucket_size() {
+        // 256M zones
+        assert_eq!(
+            pick_bucket_size("dev", TIB, 8, 8, Some(1 << 19), None).unwrap(),
+            (1024, 1 << 21)
+        );
+        // buckets aligned to a stripe are shrunk to fit in the zones
+        assert_eq!(
+            pick_bucket_size("dev", GIB, 1, 768, Some(1 << 19), None).unwrap(),
+            (256, 8192)
+        );
+        // zones smaller than the picked bucket size
+        assert_eq!(
+            pick_bucket_size("dev", TIB, 1, 1, Some(512), None).unwrap(),
+            (512, 1 << 22)
+        );
+        assert_eq!(
+            pick_bucket_size("dev", TIB, 8, 8, Some(1 << 19), Some(2048)).unwrap(),
+            (2048, 1 << 20)
+        );
+        assert!(pick_bucket_size("dev", TIB, 8, 8, Some(1 << 19), Some(768)).is_err());
+    }
+
+    #[test]
+    fn topology_alignment() {
+        let mut topology = Topology {
+            logical_block_size: 512,
+            physical_block_size: 4096,
+            io_min: 4096,
+            io_opt: 0,
+            alignment_offset: 0,
+        };
+        assert_eq!(bucket_alignment(&topology), 8);
+        topology.io_opt = 3 << 17;
+        assert_eq!(bucket_alignment(&topology), 768);
+        // stripes larger than the largest bucket are ignored
+        topology.io_opt = 4 << 20;
+        topology.io_min = 1 << 17;
+        assert_eq!(bucket_alignment(&topology), 256);
+        topology.physical_block_size = 512;
+        topology.io_min = 512;
+        topology.io_opt = 0;
+        assert_eq!(bucket_alignment(&topology), 1);
+    }
+
+    #[test]
+    fn alignment_offset() {
+        // RAID5 with three data disks and a 128k chunk size, on a partition
+        // starting 128k into a stripe
+        let mut topology = Topology {
+            logical_block_size: 512,
+            physical_block_size: 4096,
+            io_min: 1 << 17,
+            io_opt: 3 << 17,
+            alignment_offset: 1 << 17,
+        };
+        assert_eq!(bucket_alignment(&topology), 256);
+        assert_eq!(
+            pick_bucket_size("dev", GIB, 8, bucket_alignment(&topology), None, None).unwrap(),
+            (256, 8192)
+        );
+
+        // a 512e drive partitioned at sector 63 is off by a sector
+        topology.io_min = 4096;
+        topology.io_opt = 0;
+        topology.alignment_offset = 512;
+        assert_eq!(bucket_alignment(&topology), 1);
+        topology.alignment_offset = 100;
+        assert_eq!(bucket_alignment(&topology), 1);
+        topology.alignment_offset = 8192;
+        assert_eq!(bucket_alignment(&topology), 8);
+    }
+}
+
+#[cfg(test)]
+mod test_check {
+    use super::*;
+
+    #[test]
+    fn partition_paths() {
+        assert_eq!(partition_path("/dev/sdb", 1), "/dev/sdb1");
+        assert_eq!(partition_path("/dev/vdaa", 12), "/dev/vdaa12");
+        assert_eq!(partition_path("/dev/nvme0n1", 2), "/dev/nvme0n1p2");
+        assert_eq!(partition_path("/dev/loop0", 1), "/dev/loop0p1");
+        assert_eq!(partition_path("/dev/mmcblk0", 3), "/dev/mmcblk0p3");
+    }
+
+    #[test]
+    fn partition_display() {
+        let mut part = Partition {
+            path: "/dev/sdb1".to_string(),
+            fs_type: Some("ext4".to_string()),
+            fs_label: Some("root".to_string()),
+        };
+        assert_eq!(part.to_string(), "/dev/sdb1: ext4 FS labelled `root`");
+        part.fs_label = None;
+        assert_eq!(part.to_string(), "/dev/sdb1: ext4 FS");
+        part.fs_type = None;
+        assert_eq!(part.to_string(), "/dev/sdb1: no known FS");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_format {
+    use super::*;
+    use crate::block_dev::MemoryDevice;
+    use crate::super_io::{read_super, Member};
+    use crate::zoned::{Zone, ZoneType};
+
+    /// Format arguments for the given devices
+    pub(crate) fn args(devices: &[&str]) -> Args {
+        Args {
+            metadata_replicas: 1,
+            metadata_replicas_req: 1,
+            data_replicas: 1,
+            data_replicas_req: 1,
+            encrypted: false,
+            no_passphrase: false,
+            no_initialize: false,
+            label: Some("test".to_string()),
+            uuid: Uuid::new_v4(),
+            force: true,
+            superblock_size: 2048,
+            host_managed: false,
+            block_size: None,
+            bucket_size: None,
+            foreground_target: None,
+            background_target: None,
+            promote_target: None,
+            metadata_target: None,
+            error_action: ErrorAction::ReadOnly,
+            devices: devices.iter().map(|dev| dev.to_string()).collect(),
+        }
+    }
+
+    /// Format a 16M in-memory device for each device in the arguments
+    pub(crate) fn format_memory(args: Args) -> Vec<Box<dyn BlockDevice>> {
+        let devices: Vec<Box<dyn BlockDevice>> = args
+            .devices
+            .iter()
+            .map(|_| Box::new(MemoryDevice::new(16 << 20)) as Box<dyn BlockDevice>)
+            .collect();
+        format(args, &devices).unwrap();
+        devices
+    }
+
+    /// Format 16M image files at the given paths
+    pub(crate) fn format_files(paths: &[&str]) {
+        let devices = paths
+            .iter()
+            .map(|path| {
+                File::create(path).unwrap().set_len(16 << 20).unwrap();
+                open_device(path, true).unwrap()
+            })
+            .collect::<Vec<_>>();
+        format(args(paths), &devices).unwrap();
+    }
+
+    /// Format in-memory devices with the given names and open them as members
+    pub(crate) fn format_members(names: &[&str]) -> Vec<Member> {
+        format_memory(args(names))
+            .into_iter()
+            .zip(names.iter())
+            .map(|(dev, name)| Member {
+                path: name.to_string(),
+                sb: read_super(dev.as_ref()).unwrap(),
+                dev,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn format_memory_devices() {
+        let devices: Vec<Box<dyn BlockDevice>> = vec![
+            Box::new(MemoryDevice::new(16 << 20)),
+            Box::new(MemoryDevice::new(32 << 20).with_topology(Topology::new(512, 4096))),
+        ];
+        let args = args(&["mem0", "mem1"]);
+        let user_uuid = args.uuid;
+        format(args, &devices).unwrap();
+
+        let mut uuids = Vec::new();
+        for (i, dev) in devices.iter().enumerate() {
+            let mut layout_buf = [0u8; 512];
+            dev.read_at(&mut layout_buf, LAYOUT_SECTOR << 9).unwrap();
+            let layout = SuperBlockLayout::from(&layout_buf[..]);
+            assert_eq!(layout.nr_superblocks().unwrap(), 1);
+            assert_eq!(layout.sb_offset(0).unwrap(), SB_SECTOR);
+
+            let mut sb_buf = [0u8; 1024];
+            dev.read_at(&mut sb_buf, SB_SECTOR << 9).unwrap();
+            let sb = SuperBlock::from(&sb_buf[..]);
+            assert_eq!(sb.magic().unwrap(), layout.magic().unwrap());
+            assert_eq!(sb.user_uuid().unwrap(), user_uuid);
+            assert_eq!(&sb.label().unwrap()[..5], b"test\0");
+            assert_eq!(sb.nr_devices().unwrap(), 2);
+            assert_eq!(sb.device_index().unwrap(), i as u8);
+            // picked from the largest physical sector size
+            assert_eq!(sb.block_size().unwrap(), 8);
+            uuids.push(sb.uuid().unwrap());
+        }
+        assert_eq!(uuids[0], uuids[1]);
+    }
+
+    #[test]
+    fn format_host_managed() {
+        // four conventional zones of 2M, then four sequential ones
+        let zones = (0..8)
+            .map(|i| Zone {
+                start: i << 12,
+                len: 1 << 12,
+                capacity: 1 << 12,
+                zone_type: if i < 4 {
+                    ZoneType::Conventional
+                } else {
+                    ZoneType::SeqWriteRequired
+                },
+            })
+            .collect::<Vec<_>>();
+        let devices: Vec<Box<dyn BlockDevice>> = vec![Box::new(
+            MemoryDevice::new(16 << 20).with_zones(ZoneModel::HostManaged, zones),
+        )];
+        assert!(format(args(&["mem0"]), &devices).is_err());
+
+        let args = Args {
+            host_managed: true,
+            ..args(&["mem0"])
+        };
+        format(args, &devices).unwrap();
+        let sb = read_super(devices[0].as_ref()).unwrap();
+        let sb = SuperBlock::from(&sb[..]);
+        let members = sb.field(Field::Members).unwrap().unwrap();
+        let member = MemberField::from(&members[..56]);
+        assert_eq!(
+            member.n_buckets().unwrap() * u64::from(member.bucket_size().unwrap()),
+            4 << 12
+        );
+    }
+
+    #[test]
+    fn format_too_small() {
+        let devices: Vec<Box<dyn BlockDevice>> = vec![Box::new(MemoryDevice::new(16 << 10))];
+        assert!(format(args(&["mem0"]), &devices).is_err());
     }
 }
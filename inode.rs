@@ -0,0 +1,539 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::bkey::{KeyType, OwnedKey};
+use crate::super_block::SuperBlock;
+use crate::{BchError, Result};
+
+use bitflags::bitflags;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The size of the fixed part of an inode value
+pub const INODE_BYTES: usize = 14;
+
+/// The offsets of the fixed part of an inode value
+#[allow(missing_docs)]
+pub mod inode_offsets {
+    use std::ops::Range;
+
+    pub const HASH_SEED: Range<usize> = 0..8;
+    pub const FLAGS: Range<usize> = 8..12;
+    pub const MODE: Range<usize> = 12..14;
+}
+
+/// The number of packed fields of an inode, in the order they are packed
+pub const INODE_NR_FIELDS: usize = 25;
+
+/// The maximum number of bits of each packed field of an inode
+const INODE_FIELD_BITS: [u32; INODE_NR_FIELDS] = [
+    96, 96, 96, 96, 64, 64, 32, 32, 32, 32, 32, 8, 8, 32, 8, 8, 16, 16, 16, 16, 16, 64, 64, 32, 32,
+];
+
+/// The number of bytes of a field of the old encoding by the number of
+/// leading zeros of its first byte
+const FIELD_BYTES: [usize; 8] = [1, 2, 3, 4, 6, 8, 10, 13];
+
+/// The bits of the inode flags holding the string hash type
+const STR_HASH_SHIFT: u32 = 20;
+/// The bits of the inode flags holding the number of packed fields
+const NR_FIELDS_SHIFT: u32 = 24;
+/// The bit of the inode flags set when the fields are varint encoded
+const NEW_VARINT_SHIFT: u32 = 31;
+
+bitflags! {
+    /// The flags of an inode
+    pub struct InodeFlags: u32 {
+        /// Writes are synchronous
+        const SYNC = 1 << 0;
+        /// The file may not be changed
+        const IMMUTABLE = 1 << 1;
+        /// The file may only be appended to
+        const APPEND = 1 << 2;
+        /// The file is not backed up by dump
+        const NODUMP = 1 << 3;
+        /// The access time is not updated
+        const NOATIME = 1 << 4;
+        /// The size is being updated
+        const I_SIZE_DIRTY = 1 << 5;
+        /// The sector count is being updated
+        const I_SECTORS_DIRTY = 1 << 6;
+        /// The inode is unlinked but still open
+        const UNLINKED = 1 << 7;
+        /// The backpointer to the dirent may be wrong
+        const BACKPTR_UNTRUSTED = 1 << 8;
+    }
+}
+
+/// The hash functions names in directories and xattrs may be hashed with
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum StrHashType {
+    /// crc32c
+    Crc32c = 0,
+    /// crc64
+    Crc64 = 1,
+    /// The original, full length siphash
+    SiphashOld = 2,
+    /// The shortened siphash
+    Siphash = 3,
+}
+
+impl TryFrom<u8> for StrHashType {
+    type Error = BchError;
+
+    fn try_from(ty: u8) -> Result<StrHashType> {
+        match ty {
+            0 => Ok(StrHashType::Crc32c),
+            1 => Ok(StrHashType::Crc64),
+            2 => Ok(StrHashType::SiphashOld),
+            3 => Ok(StrHashType::Siphash),
+            _ => Err(BchError::Einval(format!("unknown string hash type {}", ty))),
+        }
+    }
+}
+
+/// The options an inode may override the filesystem options with
+///
+/// The options not set on the inode are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InodeOpts {
+    /// The checksum type of data
+    pub data_checksum: Option<u8>,
+    /// The compression type of data
+    pub compression: Option<u8>,
+    /// The project the inode belongs to, for quotas
+    pub project: Option<u32>,
+    /// The compression type data is recompressed with in the background
+    pub background_compression: Option<u8>,
+    /// The number of replicas of data
+    pub data_replicas: Option<u8>,
+    /// The target data is cached on when read
+    pub promote_target: Option<u16>,
+    /// The target data is written to
+    pub foreground_target: Option<u16>,
+    /// The target data is moved to in the background
+    pub background_target: Option<u16>,
+    /// Whether data is erasure coded
+    pub erasure_code: Option<u16>,
+}
+
+/// An inode, unpacked
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inode {
+    /// The inode number
+    pub inum: u64,
+    /// The seed of the hash of the names in a directory
+    pub hash_seed: u64,
+    /// The flags of the inode
+    pub flags: u32,
+    /// The file type and permissions
+    pub mode: u16,
+    /// The access time, in filesystem time units
+    pub atime: u64,
+    /// The change time, in filesystem time units
+    pub ctime: u64,
+    /// The modification time, in filesystem time units
+    pub mtime: u64,
+    /// The creation time, in filesystem time units
+    pub otime: u64,
+    /// The size in bytes
+    pub size: u64,
+    /// The number of sectors allocated
+    pub sectors: u64,
+    /// The owner
+    pub uid: u32,
+    /// The group
+    pub gid: u32,
+    /// The number of links, not counting the first
+    pub nlink: u32,
+    /// The generation of the inode number
+    pub generation: u32,
+    /// The device number of a device file
+    pub dev: u32,
+    /// The options set on the inode
+    pub opts: InodeOpts,
+    /// The options explicitly set, rather than inherited from the parent
+    pub fields_set: u16,
+    /// The directory of the dirent pointing to the inode
+    pub dir: u64,
+    /// The offset of the dirent pointing to the inode
+    pub dir_offset: u64,
+    /// The subvolume a subvolume root is the root of
+    pub subvol: u32,
+    /// The subvolume the subvolume containing the inode is in
+    pub parent_subvol: u32,
+}
+
+/// A time, in seconds and nanoseconds since the epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timespec {
+    /// The seconds since the epoch
+    pub sec: i64,
+    /// The nanoseconds within the second
+    pub nsec: u32,
+}
+
+impl fmt::Display for Timespec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:09}", self.sec, self.nsec)
+    }
+}
+
+/// The time base of a filesystem, to convert inode times with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeBase {
+    /// The time of time unit 0, in time units
+    pub base: i128,
+    /// The length of a time unit in nanoseconds
+    pub precision: u32,
+}
+
+impl TimeBase {
+    /// The time base of the given superblock
+    ///
+    /// As in the kernel, the low bits of the base are in nanoseconds and the
+    /// high bits are not used.
+    pub fn from_sb(sb: &[u8]) -> Result<TimeBase> {
+        let sb = SuperBlock::from(sb);
+        let precision = sb.time_base_p()?;
+        if precision == 0 || 1_000_000_000 % precision != 0 {
+            return Err(BchError::Einval(format!(
+                "invalid time precision {}",
+                precision
+            )));
+        }
+        Ok(TimeBase {
+            base: i128::from(sb.time_base_lo()? / u64::from(precision)),
+            precision,
+        })
+    }
+
+    /// Convert an inode time to the time since the epoch
+    pub fn to_timespec(&self, time: u64) -> Timespec {
+        let units_per_sec = i128::from(1_000_000_000 / self.precision);
+        let time = self.base + i128::from(time as i64);
+        Timespec {
+            sec: time.div_euclid(units_per_sec) as i64,
+            nsec: time.rem_euclid(units_per_sec) as u32 * self.precision,
+        }
+    }
+}
+
+/// Decode a field of the old encoding, returning its value and length
+///
+/// The number of leading zeros of the first byte gives the length of the
+/// field, the value is the big endian number following the first set bit.
+fn decode_field(buf: &[u8]) -> Result<(u128, usize)> {
+    let first = *buf.first().ok_or(BchError::Exhausted)?;
+    if first == 0 {
+        return Err(BchError::Einval("invalid inode field".to_string()));
+    }
+    let bytes = FIELD_BYTES[first.leading_zeros() as usize];
+    let field = buf.get(..bytes).ok_or(BchError::Exhausted)?;
+
+    let mut v = u128::from(first ^ (0x80 >> first.leading_zeros()));
+    for byte in field[1..].iter() {
+        v = (v << 8) | u128::from(*byte);
+    }
+    Ok((v, bytes))
+}
+
+/// Decode a varint, returning its value and length
+///
+/// The number of trailing ones of the first byte gives the length of the
+/// varint, the value is the little endian number above them.
+fn decode_varint(buf: &[u8]) -> Result<(u64, usize)> {
+    let first = *buf.first().ok_or(BchError::Exhausted)?;
+    let bytes = first.trailing_ones() as usize + 1;
+    let varint = buf.get(..bytes).ok_or(BchError::Exhausted)?;
+
+    if bytes == 9 {
+        return Ok((LittleEndian::read_u64(&varint[1..]), bytes));
+    }
+    let mut v = [0u8; 8];
+    v[..bytes].copy_from_slice(varint);
+    Ok((LittleEndian::read_u64(&v) >> bytes, bytes))
+}
+
+/// Decode the packed fields of an inode
+fn decode_fields(buf: &[u8], nr_fields: usize, varint: bool) -> Result<[u64; INODE_NR_FIELDS]> {
+    let mut fields = [0u64; INODE_NR_FIELDS];
+    let mut buf = buf;
+    for (i, bits) in INODE_FIELD_BITS
+        .iter()
+        .enumerate()
+        .take(nr_fields.min(INODE_NR_FIELDS))
+    {
+        let v = if varint {
+            let (lo, len) = decode_varint(buf)?;
+            buf = &buf[len..];
+            if *bits > 64 {
+                let (hi, len) = decode_varint(buf)?;
+                buf = &buf[len..];
+                (u128::from(hi) << 64) | u128::from(lo)
+            } else {
+                u128::from(lo)
+            }
+        } else {
+            let (v, len) = decode_field(buf)?;
+            buf = &buf[len..];
+            v
+        };
+
+        let max = if *bits >= 64 {
+            u64::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        if v > u128::from(max) {
+            return Err(BchError::Einval(format!(
+                "inode field {} too large: {}",
+                i, v
+            )));
+        }
+        fields[i] = v as u64;
+    }
+    Ok(fields)
+}
+
+/// The value of an inode option, which is stored plus one
+fn opt<T: TryFrom<u64>>(v: u64) -> Option<T> {
+    v.checked_sub(1).and_then(|v| T::try_from(v).ok())
+}
+
+impl Inode {
+    /// Decode the value of the inode of the given number
+    pub fn unpack(inum: u64, val: &[u8]) -> Result<Inode> {
+        if val.len() < INODE_BYTES {
+            return Err(BchError::Exhausted);
+        }
+        let flags = LittleEndian::read_u32(&val[inode_offsets::FLAGS]);
+        let nr_fields = ((flags >> NR_FIELDS_SHIFT) & 0x7f) as usize;
+        let varint = flags >> NEW_VARINT_SHIFT != 0;
+        let f = decode_fields(&val[INODE_BYTES..], nr_fields, varint)?;
+
+        Ok(Inode {
+            inum,
+            hash_seed: LittleEndian::read_u64(&val[inode_offsets::HASH_SEED]),
+            flags,
+            mode: LittleEndian::read_u16(&val[inode_offsets::MODE]),
+            atime: f[0],
+            ctime: f[1],
+            mtime: f[2],
+            otime: f[3],
+            size: f[4],
+            sectors: f[5],
+            uid: f[6] as u32,
+            gid: f[7] as u32,
+            nlink: f[8] as u32,
+            generation: f[9] as u32,
+            dev: f[10] as u32,
+            opts: InodeOpts {
+                data_checksum: opt(f[11]),
+                compression: opt(f[12]),
+                project: opt(f[13]),
+                background_compression: opt(f[14]),
+                data_replicas: opt(f[15]),
+                promote_target: opt(f[16]),
+                foreground_target: opt(f[17]),
+                background_target: opt(f[18]),
+                erasure_code: opt(f[19]),
+            },
+            fields_set: f[20] as u16,
+            dir: f[21],
+            dir_offset: f[22],
+            subvol: f[23] as u32,
+            parent_subvol: f[24] as u32,
+        })
+    }
+
+    /// Decode an inode key
+    pub fn decode(key: &OwnedKey) -> Result<Inode> {
+        match KeyType::try_from(key.ty())? {
+            KeyType::Inode => Inode::unpack(key.p().offset, &key.val),
+            ty => Err(BchError::Einval(format!("{} key is not an inode", ty))),
+        }
+    }
+
+    /// The flags of the inode known to the crate
+    pub fn inode_flags(&self) -> InodeFlags {
+        InodeFlags::from_bits_truncate(self.flags)
+    }
+
+    /// The hash function of the names in a directory
+    pub fn str_hash(&self) -> Result<StrHashType> {
+        StrHashType::try_from(((self.flags >> STR_HASH_SHIFT) & 0xf) as u8)
+    }
+
+    /// The number of links to the inode
+    pub fn links(&self) -> u32 {
+        if self.inode_flags().contains(InodeFlags::UNLINKED) {
+            0
+        } else if self.is_dir() {
+            self.nlink + 2
+        } else {
+            self.nlink + 1
+        }
+    }
+
+    /// Whether the inode is a directory
+    pub fn is_dir(&self) -> bool {
+        u32::from(self.mode) & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    /// Whether the inode is a regular file
+    pub fn is_reg(&self) -> bool {
+        u32::from(self.mode) & libc::S_IFMT == libc::S_IFREG
+    }
+
+    /// Whether the inode is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        u32::from(self.mode) & libc::S_IFMT == libc::S_IFLNK
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_inode {
+    use super::*;
+    use crate::bkey::{bkey_format_current, BkeyFormat, Bpos};
+    use crate::btree::test_btree::key;
+    use crate::format::test_format::format_members;
+
+    /// Encode a field in the old encoding
+    fn encode_field(v: u128) -> Vec<u8> {
+        let bits = 128 - v.leading_zeros() as usize;
+        let (zeros, bytes) = FIELD_BYTES
+            .iter()
+            .enumerate()
+            .find(|(zeros, bytes)| bits < *bytes * 8 - zeros)
+            .unwrap();
+        let mut buf = v.to_be_bytes()[(16 - bytes)..].to_vec();
+        buf[0] |= 0x80 >> zeros;
+        buf
+    }
+
+    /// Encode a varint
+    fn encode_varint(v: u64) -> Vec<u8> {
+        let bits = 64 - v.leading_zeros() as usize;
+        let bytes = (1..9).find(|bytes| bits <= bytes * 7).unwrap_or(9);
+        if bytes == 9 {
+            let mut buf = vec![0xff];
+            buf.extend_from_slice(&v.to_le_bytes());
+            return buf;
+        }
+        let v = (v << bytes) | ((1 << (bytes - 1)) - 1);
+        v.to_le_bytes()[..bytes].to_vec()
+    }
+
+    /// The value of an inode with the given fields
+    pub(crate) fn inode_val(mode: u16, fields: &[u64], varint: bool) -> Vec<u8> {
+        let mut val = vec![0u8; INODE_BYTES];
+        LittleEndian::write_u64(&mut val[inode_offsets::HASH_SEED], 0x1234);
+        let flags = (u32::from(varint) << NEW_VARINT_SHIFT)
+            | ((fields.len() as u32) << NR_FIELDS_SHIFT)
+            | ((StrHashType::Siphash as u32) << STR_HASH_SHIFT)
+            | InodeFlags::NOATIME.bits();
+        LittleEndian::write_u32(&mut val[inode_offsets::FLAGS], flags);
+        LittleEndian::write_u16(&mut val[inode_offsets::MODE], mode);
+
+        for (v, bits) in fields.iter().zip(INODE_FIELD_BITS.iter()) {
+            if !varint {
+                val.extend(encode_field(u128::from(*v)));
+                continue;
+            }
+            val.extend(encode_varint(*v));
+            if *bits > 64 {
+                val.extend(encode_varint(0));
+            }
+        }
+        val.resize(val.len() + (8 - val.len() % 8) % 8, 0);
+        val
+    }
+
+    #[test]
+    fn decode_encodings() {
+        let mut fields = vec![0u64; INODE_NR_FIELDS];
+        fields[0] = 1 << 40;
+        fields[4] = 123_456_789;
+        fields[5] = u64::MAX;
+        fields[6] = 1000;
+        fields[8] = 2;
+        fields[12] = 3;
+        fields[21] = 4096;
+
+        for varint in [false, true].iter() {
+            let val = inode_val(libc::S_IFDIR as u16 | 0o755, &fields, *varint);
+            let key = key(KeyType::Inode, Bpos::new(0, 4096, 0), &val);
+            let key = BkeyFormat::from(&bkey_format_current()[..])
+                .unpack_key(&key)
+                .unwrap();
+            let inode = Inode::decode(&key).unwrap();
+
+            assert_eq!(inode.inum, 4096);
+            assert_eq!(inode.hash_seed, 0x1234);
+            assert!(inode.is_dir());
+            assert_eq!(inode.mode & 0o777, 0o755);
+            assert_eq!(inode.atime, 1 << 40);
+            assert_eq!(inode.size, 123_456_789);
+            assert_eq!(inode.sectors, u64::MAX);
+            assert_eq!((inode.uid, inode.gid, inode.nlink), (1000, 0, 2));
+            assert_eq!(inode.opts.compression, Some(2));
+            assert_eq!(inode.opts.data_checksum, None);
+            assert_eq!(inode.dir, 4096);
+            assert_eq!(inode.inode_flags(), InodeFlags::NOATIME);
+            assert_eq!(inode.str_hash().unwrap(), StrHashType::Siphash);
+        }
+
+        let short = inode_val(libc::S_IFREG as u16, &fields[..5], true);
+        let inode = Inode::unpack(1, &short).unwrap();
+        assert!(inode.is_reg());
+        assert_eq!((inode.size, inode.uid), (123_456_789, 0));
+
+        let mut val = inode_val(0, &fields, false);
+        val.truncate(INODE_BYTES + 3);
+        assert!(Inode::unpack(1, &val).is_err());
+    }
+
+    #[test]
+    fn convert_times() {
+        let sb = format_members(&["mem0"]).remove(0).sb;
+        let mut time_base = TimeBase::from_sb(&sb).unwrap();
+        assert_eq!(time_base.precision, 1);
+
+        time_base.base = 1_600_000_000_000_000_000;
+        assert_eq!(
+            time_base.to_timespec(1_500_000_001),
+            Timespec {
+                sec: 1_600_000_001,
+                nsec: 500_000_001
+            }
+        );
+        assert_eq!(
+            time_base.to_timespec(-1i64 as u64).to_string(),
+            "1599999999.999999999"
+        );
+
+        time_base.precision = 1000;
+        time_base.base = 0;
+        assert_eq!(
+            time_base.to_timespec(1_000_001),
+            Timespec { sec: 1, nsec: 1000 }
+        );
+
+        // the base is stored in nanoseconds, its high bits are ignored
+        let mut sb = sb;
+        let mut view = SuperBlock::from(&mut sb[..]);
+        view.set_time_base_lo(1_600_000_000_000_000_000).unwrap();
+        view.set_time_base_hi(5).unwrap();
+        view.set_time_base_p(1000).unwrap();
+        let time_base = TimeBase::from_sb(&sb).unwrap();
+        assert_eq!(time_base.base, 1_600_000_000_000_000);
+        assert_eq!(
+            time_base.to_timespec(1_500_001),
+            Timespec {
+                sec: 1_600_000_001,
+                nsec: 500_001_000
+            }
+        );
+    }
+}
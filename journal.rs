@@ -0,0 +1,969 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::bkey::{bkey_format_current, Bkey, BkeyFormat, OwnedKey};
+use crate::btree::BtreeId;
+use crate::checksum::{checksum, CsumType, Nonce, NONCE_JOURNAL};
+use crate::crypt::{chacha20, fs_key, KEY_BYTES};
+use crate::super_block::{Field, MemberField, SuperBlock, MEMBER_BYTES};
+use crate::super_io::{open_members_partial, Member};
+use crate::version::version_name;
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::{debug, warn};
+
+/// The magic of journal entries, xored with the start of the filesystem uuid
+const JSET_MAGIC: u64 = 0x2452_35c1_a362_5032;
+/// The size of the header of a journal entry
+pub const JSET_BYTES: usize = jset_offsets::LAST_SEQ.end;
+/// The size of the header of an item of a journal entry
+pub const JSET_ENTRY_BYTES: usize = 8;
+
+mod jset_offsets {
+    use std::ops::Range;
+
+    pub const CSUM: Range<usize> = 0..16;
+    pub const MAGIC: Range<usize> = 16..24;
+    pub const SEQ: Range<usize> = 24..32;
+    pub const VERSION: Range<usize> = 32..36;
+    pub const FLAGS: Range<usize> = 36..40;
+    pub const U64S: Range<usize> = 40..44;
+    // the rest of an entry is encrypted
+    pub const ENCRYPTED_START: usize = 44;
+    // 4 bytes of obsolete clocks
+    pub const LAST_SEQ: Range<usize> = 48..56;
+}
+
+mod entry_offsets {
+    use std::ops::Range;
+
+    pub const U64S: Range<usize> = 0..2;
+    pub const BTREE_ID: usize = 2;
+    pub const LEVEL: usize = 3;
+    pub const TYPE: usize = 4;
+}
+
+/// The magic of the journal entries of a filesystem
+pub fn jset_magic(sb: &[u8]) -> Result<u64> {
+    let uuid = SuperBlock::from(sb).uuid()?;
+    Ok(LittleEndian::read_u64(&uuid.as_bytes()[..8]) ^ JSET_MAGIC)
+}
+
+/// The types of the items of a journal entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum JsetEntryType {
+    /// Keys inserted into a btree
+    BtreeKeys = 0,
+    /// The root of a btree
+    BtreeRoot = 1,
+    /// Obsolete bucket priorities
+    PrioPtrs = 2,
+    /// Journal sequence numbers that may not be used
+    Blacklist = 3,
+    /// A range of journal sequence numbers that may not be used
+    BlacklistV2 = 4,
+    /// Filesystem usage counters
+    Usage = 5,
+    /// Usage of a replicas entry
+    DataUsage = 6,
+    /// IO clocks
+    Clock = 7,
+    /// Usage of a member device
+    DevUsage = 8,
+}
+
+impl TryFrom<u8> for JsetEntryType {
+    type Error = BchError;
+
+    fn try_from(ty: u8) -> Result<Self> {
+        match ty {
+            0 => Ok(JsetEntryType::BtreeKeys),
+            1 => Ok(JsetEntryType::BtreeRoot),
+            2 => Ok(JsetEntryType::PrioPtrs),
+            3 => Ok(JsetEntryType::Blacklist),
+            4 => Ok(JsetEntryType::BlacklistV2),
+            5 => Ok(JsetEntryType::Usage),
+            6 => Ok(JsetEntryType::DataUsage),
+            7 => Ok(JsetEntryType::Clock),
+            8 => Ok(JsetEntryType::DevUsage),
+            _ => Err(BchError::Einval(format!(
+                "unknown journal entry type {}",
+                ty
+            ))),
+        }
+    }
+}
+
+/// The data types of replicas entries
+const DATA_TYPE_NAMES: [&str; 7] = ["none", "sb", "journal", "btree", "user", "cached", "parity"];
+/// The filesystem usage counters
+const USAGE_NAMES: [&str; 3] = ["reserved", "inodes", "key_version"];
+
+/// A decoded item of a journal entry
+#[derive(Debug, Clone, PartialEq)]
+pub enum JournalItem {
+    /// Keys inserted into a btree
+    BtreeKeys {
+        /// The btree the keys were inserted into
+        btree_id: BtreeId,
+        /// The level of the btree the keys were inserted at
+        level: u8,
+        /// The keys
+        keys: Vec<OwnedKey>,
+    },
+    /// The root of a btree
+    BtreeRoot {
+        /// The btree
+        btree_id: BtreeId,
+        /// The level of the root node
+        level: u8,
+        /// The pointer to the root node
+        key: OwnedKey,
+    },
+    /// Journal sequence numbers that may not be used, as start and end
+    Blacklist(Vec<(u64, u64)>),
+    /// A filesystem usage counter
+    Usage {
+        /// The counter, see `USAGE_NAMES`
+        ty: u8,
+        /// The index of a reserved counter
+        idx: u8,
+        /// The value of the counter
+        v: u64,
+    },
+    /// The usage of a replicas entry
+    DataUsage {
+        /// The sectors used
+        v: u64,
+        /// The type of data
+        data_type: u8,
+        /// The number of replicas needed to read the data
+        nr_required: u8,
+        /// The member devices the data is replicated on
+        devs: Vec<u8>,
+    },
+    /// An IO clock
+    Clock {
+        /// 0 for the read clock, 1 for the write clock
+        rw: u8,
+        /// The time of the clock
+        time: u64,
+    },
+    /// The usage of a member device
+    DevUsage {
+        /// The member device
+        dev: u32,
+        /// Buckets used for erasure coding
+        buckets_ec: u64,
+        /// Buckets not available for allocation
+        buckets_unavailable: u64,
+        /// Buckets, sectors and fragmented sectors of each data type
+        usage: Vec<(u64, u64, u64)>,
+    },
+    /// An item this library does not decode
+    Unknown {
+        /// The raw type of the item
+        ty: u8,
+        /// The size of the item data
+        bytes: usize,
+    },
+}
+
+impl JournalItem {
+    /// The btree the item applies to, if any
+    pub fn btree_id(&self) -> Option<BtreeId> {
+        match self {
+            JournalItem::BtreeKeys { btree_id, .. } | JournalItem::BtreeRoot { btree_id, .. } => {
+                Some(*btree_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JournalItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name =
+            |names: &[&'static str], idx: u8| names.get(idx as usize).copied().unwrap_or("unknown");
+
+        match self {
+            JournalItem::BtreeKeys {
+                btree_id,
+                level,
+                keys,
+            } => {
+                write!(f, "btree_keys: {} level {}", btree_id, level)?;
+                for key in keys.iter() {
+                    write!(f, "\n  {}", key)?;
+                }
+                Ok(())
+            }
+            JournalItem::BtreeRoot {
+                btree_id,
+                level,
+                key,
+            } => write!(f, "btree_root: {} level {}: {}", btree_id, level, key),
+            JournalItem::Blacklist(ranges) => {
+                write!(f, "blacklist:")?;
+                for (start, end) in ranges.iter() {
+                    write!(f, " {}-{}", start, end)?;
+                }
+                Ok(())
+            }
+            JournalItem::Usage { ty, idx, v } => {
+                write!(f, "usage: {}[{}] {}", name(&USAGE_NAMES, *ty), idx, v)
+            }
+            JournalItem::DataUsage {
+                v,
+                data_type,
+                nr_required,
+                devs,
+            } => write!(
+                f,
+                "data_usage: {} {}/{} {:?}: {}",
+                name(&DATA_TYPE_NAMES, *data_type),
+                nr_required,
+                devs.len(),
+                devs,
+                v
+            ),
+            JournalItem::Clock { rw, time } => {
+                write!(
+                    f,
+                    "clock: {} {}",
+                    if *rw == 0 { "read" } else { "write" },
+                    time
+                )
+            }
+            JournalItem::DevUsage {
+                dev,
+                buckets_ec,
+                buckets_unavailable,
+                usage,
+            } => {
+                write!(
+                    f,
+                    "dev_usage: dev {} buckets_ec {} buckets_unavailable {}",
+                    dev, buckets_ec, buckets_unavailable
+                )?;
+                for (i, (buckets, sectors, fragmented)) in usage.iter().enumerate() {
+                    write!(
+                        f,
+                        "\n  {}: buckets {} sectors {} fragmented {}",
+                        name(&DATA_TYPE_NAMES, i as u8),
+                        buckets,
+                        sectors,
+                        fragmented
+                    )?;
+                }
+                Ok(())
+            }
+            JournalItem::Unknown { ty, bytes } => write!(f, "type {}: {} bytes", ty, bytes),
+        }
+    }
+}
+
+/// Decode the unpacked keys of a journal item
+fn decode_keys(data: &[u8]) -> Result<Vec<OwnedKey>> {
+    let current = bkey_format_current();
+    let format = BkeyFormat::from(&current[..]);
+    let mut keys = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let bytes = Bkey::from(&data[pos..]).u64s()? as usize * 8;
+        if bytes == 0 || pos + bytes > data.len() {
+            return Err(BchError::Einval(format!(
+                "key of {} bytes overruns journal entry",
+                bytes
+            )));
+        }
+        if Bkey::from(&data[pos..]).is_packed()? {
+            return Err(BchError::Einval("packed key in journal entry".to_string()));
+        }
+        keys.push(format.unpack_key(&data[pos..(pos + bytes)])?);
+        pos += bytes;
+    }
+    Ok(keys)
+}
+
+/// Decode an item of a journal entry
+pub fn decode_item(entry: &[u8]) -> Result<JournalItem> {
+    if entry.len() < JSET_ENTRY_BYTES {
+        return Err(BchError::Exhausted);
+    }
+    let end = JSET_ENTRY_BYTES + LittleEndian::read_u16(&entry[entry_offsets::U64S]) as usize * 8;
+    if entry.len() < end {
+        return Err(BchError::Exhausted);
+    }
+    let data = &entry[JSET_ENTRY_BYTES..end];
+    let u64_at = |i: usize| -> Result<u64> {
+        data.get((i * 8)..((i + 1) * 8))
+            .map(LittleEndian::read_u64)
+            .ok_or(BchError::Exhausted)
+    };
+    let btree_id = entry[entry_offsets::BTREE_ID];
+    let level = entry[entry_offsets::LEVEL];
+    let ty = entry[entry_offsets::TYPE];
+
+    let ty = match JsetEntryType::try_from(ty) {
+        Ok(ty) => ty,
+        Err(_) => {
+            return Ok(JournalItem::Unknown {
+                ty,
+                bytes: data.len(),
+            })
+        }
+    };
+
+    // items of btrees added by newer versions are skipped like unknown items
+    let unknown = JournalItem::Unknown {
+        ty: ty as u8,
+        bytes: data.len(),
+    };
+    let btree = BtreeId::try_from(u64::from(btree_id));
+
+    Ok(match ty {
+        JsetEntryType::BtreeKeys | JsetEntryType::BtreeRoot if btree.is_err() => unknown,
+        JsetEntryType::BtreeKeys => JournalItem::BtreeKeys {
+            btree_id: btree?,
+            level,
+            keys: decode_keys(data)?,
+        },
+        JsetEntryType::BtreeRoot => JournalItem::BtreeRoot {
+            btree_id: btree?,
+            level,
+            key: decode_keys(data)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| BchError::Einval("empty btree root".to_string()))?,
+        },
+        JsetEntryType::Blacklist => JournalItem::Blacklist(
+            (0..(data.len() / 8))
+                .map(|i| u64_at(i).map(|seq| (seq, seq.saturating_add(1))))
+                .collect::<Result<_>>()?,
+        ),
+        JsetEntryType::BlacklistV2 => {
+            JournalItem::Blacklist(vec![(u64_at(0)?, u64_at(1)?.saturating_add(1))])
+        }
+        JsetEntryType::Usage => JournalItem::Usage {
+            ty: btree_id,
+            idx: level,
+            v: u64_at(0)?,
+        },
+        JsetEntryType::DataUsage => {
+            let r = data.get(8..11).ok_or(BchError::Exhausted)?;
+            let devs = data
+                .get(11..(11 + r[1] as usize))
+                .ok_or(BchError::Exhausted)?;
+            JournalItem::DataUsage {
+                v: u64_at(0)?,
+                data_type: r[0],
+                nr_required: r[2],
+                devs: devs.to_vec(),
+            }
+        }
+        JsetEntryType::Clock => JournalItem::Clock {
+            rw: *data.first().ok_or(BchError::Exhausted)?,
+            time: u64_at(1)?,
+        },
+        JsetEntryType::DevUsage => JournalItem::DevUsage {
+            dev: LittleEndian::read_u32(data.get(0..4).ok_or(BchError::Exhausted)?),
+            buckets_ec: u64_at(1)?,
+            buckets_unavailable: u64_at(2)?,
+            usage: (0..((data.len() / 8).saturating_sub(3) / 3))
+                .map(|i| Ok((u64_at(3 + i * 3)?, u64_at(4 + i * 3)?, u64_at(5 + i * 3)?)))
+                .collect::<Result<_>>()?,
+        },
+        JsetEntryType::PrioPtrs => unknown,
+    })
+}
+
+/// Decode a run of journal items, as found in journal entries
+pub(crate) fn decode_items(data: &[u8]) -> Result<Vec<JournalItem>> {
+    let mut items = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let entry = &data[pos..];
+        if entry.len() < JSET_ENTRY_BYTES {
+            return Err(BchError::Exhausted);
+        }
+        let end =
+            JSET_ENTRY_BYTES + LittleEndian::read_u16(&entry[entry_offsets::U64S]) as usize * 8;
+        items.push(decode_item(entry)?);
+        pos += end;
+    }
+    Ok(items)
+}
+
+/// Where a journal entry was found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JournalLocation {
+    /// The member device
+    pub dev: u8,
+    /// The journal bucket
+    pub bucket: u64,
+    /// The offset in the bucket in sectors
+    pub offset: u64,
+}
+
+/// A journal entry read from disk
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    /// The sequence number of the entry
+    pub seq: u64,
+    /// The oldest sequence number still dirty when the entry was written
+    pub last_seq: u64,
+    /// The metadata version the entry was written with
+    pub version: u32,
+    /// Whether the entry was written without a flush
+    pub no_flush: bool,
+    /// The decoded items of the entry
+    pub items: Vec<JournalItem>,
+    /// The copies of the entry found
+    pub locations: Vec<JournalLocation>,
+}
+
+impl fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "journal entry {} last_seq {} version {}{}",
+            self.seq,
+            self.last_seq,
+            self.version,
+            if self.no_flush { " no_flush" } else { "" }
+        )?;
+        for loc in self.locations.iter() {
+            write!(
+                f,
+                " [dev {} bucket {} offset {}]",
+                loc.dev, loc.bucket, loc.offset
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The journal entries read from every member of a filesystem
+#[derive(Debug, Default)]
+pub struct Journal {
+    /// The valid entries found by sequence number
+    pub entries: BTreeMap<u64, JournalEntry>,
+    /// The sequence numbers that may not be used, as start and end
+    pub blacklist: Vec<(u64, u64)>,
+    /// The problems found reading the journal
+    pub errors: Vec<String>,
+}
+
+impl Journal {
+    /// The newest valid entry, the one the filesystem would be replayed from
+    pub fn newest(&self) -> Option<&JournalEntry> {
+        self.entries
+            .values()
+            .rev()
+            .find(|entry| !entry.no_flush && !self.is_blacklisted(entry.seq))
+    }
+
+    /// Whether the given sequence number may not be used
+    pub fn is_blacklisted(&self, seq: u64) -> bool {
+        self.blacklist
+            .iter()
+            .any(|(start, end)| seq >= *start && seq < *end)
+    }
+
+    /// The entries that would be replayed, from the newest entry's last_seq on
+    pub fn dirty(&self) -> impl Iterator<Item = &JournalEntry> {
+        let newest = self.newest();
+        self.entries.values().filter(move |entry| match newest {
+            Some(newest) => {
+                (newest.last_seq..=newest.seq).contains(&entry.seq)
+                    && !self.is_blacklisted(entry.seq)
+            }
+            None => false,
+        })
+    }
+
+    /// The newest root of each btree and its level
+    pub fn btree_roots(&self) -> BTreeMap<BtreeId, (u8, OwnedKey)> {
+        let mut roots = BTreeMap::new();
+        for entry in self.dirty() {
+            for item in entry.items.iter() {
+                if let JournalItem::BtreeRoot {
+                    btree_id,
+                    level,
+                    key,
+                } = item
+                {
+                    roots.insert(*btree_id, (*level, key.clone()));
+                }
+            }
+        }
+        roots
+    }
+
+    /// Parse the journal entries of a bucket
+    fn read_bucket(
+        &mut self,
+        sb: &[u8],
+        key: Option<&[u8; KEY_BYTES]>,
+        buf: &[u8],
+        dev: u8,
+        bucket: u64,
+    ) -> Result<()> {
+        let block_bytes = SuperBlock::from(sb).block_size()? as usize * 512;
+        let magic = jset_magic(sb)?;
+        let mut offset = 0;
+        let mut last = 0;
+
+        while offset + JSET_BYTES <= buf.len() {
+            let jset = &buf[offset..];
+            if LittleEndian::read_u64(&jset[jset_offsets::MAGIC]) != magic {
+                break;
+            }
+
+            let seq = LittleEndian::read_u64(&jset[jset_offsets::SEQ]);
+            let location = JournalLocation {
+                dev,
+                bucket,
+                offset: (offset >> 9) as u64,
+            };
+            if seq < last {
+                debug!(
+                    "dev {} bucket {}: stale entry {} after {}",
+                    dev, bucket, seq, last
+                );
+                break;
+            }
+            last = seq;
+
+            let end = JSET_BYTES + LittleEndian::read_u32(&jset[jset_offsets::U64S]) as usize * 8;
+            if end > jset.len() {
+                self.errors.push(format!(
+                    "journal entry {} at dev {} bucket {} overruns the bucket",
+                    seq, dev, bucket
+                ));
+                break;
+            }
+            offset += end + (block_bytes - end % block_bytes) % block_bytes;
+
+            if let Some(entry) = self.entries.get_mut(&seq) {
+                entry.locations.push(location);
+                continue;
+            }
+            match parse_jset(&jset[..end], key, location) {
+                Ok(entry) => {
+                    for item in entry.items.iter() {
+                        if let JournalItem::Blacklist(ranges) = item {
+                            self.blacklist.extend_from_slice(ranges);
+                        }
+                    }
+                    self.entries.insert(seq, entry);
+                }
+                Err(e) => self.errors.push(format!(
+                    "journal entry {} at dev {} bucket {}: {}",
+                    seq, dev, bucket, e
+                )),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The nonce of the MAC and encryption of a journal entry
+fn journal_nonce(jset: &[u8]) -> Nonce {
+    let seq = &jset[jset_offsets::SEQ];
+    Nonce([
+        0,
+        LittleEndian::read_u32(&seq[..4]),
+        LittleEndian::read_u32(&seq[4..]),
+        NONCE_JOURNAL,
+    ])
+}
+
+/// Parse a journal entry whose magic was checked, verifying its checksum
+///
+/// Entries with a MAC are decrypted with the filesystem key once their
+/// checksum, which covers the encrypted data, is verified.
+fn parse_jset(
+    jset: &[u8],
+    key: Option<&[u8; KEY_BYTES]>,
+    location: JournalLocation,
+) -> Result<JournalEntry> {
+    let version = LittleEndian::read_u32(&jset[jset_offsets::VERSION]);
+    version_name(version as u16)?;
+
+    let flags = LittleEndian::read_u32(&jset[jset_offsets::FLAGS]);
+    if flags & (1 << 4) != 0 {
+        return Err(BchError::Str(
+            "big endian journal entries are not supported".to_string(),
+        ));
+    }
+
+    let ty = CsumType::try_from(u64::from(flags & 0xf))?;
+    let nonce = journal_nonce(jset);
+    let csum = checksum(ty, key, nonce, &jset[jset_offsets::MAGIC.start..])?;
+    let stored = [
+        LittleEndian::read_u64(&jset[jset_offsets::CSUM.start..(jset_offsets::CSUM.start + 8)]),
+        LittleEndian::read_u64(&jset[(jset_offsets::CSUM.start + 8)..jset_offsets::CSUM.end]),
+    ];
+    if csum != stored {
+        return Err(BchError::Str(format!("bad {} checksum", ty)));
+    }
+
+    let mut jset = jset.to_vec();
+    if let Some(key) = key.filter(|_| ty.is_encryption()) {
+        chacha20(key, nonce, &mut jset[jset_offsets::ENCRYPTED_START..]);
+    }
+    let items = decode_items(&jset[JSET_BYTES..])?;
+
+    Ok(JournalEntry {
+        seq: LittleEndian::read_u64(&jset[jset_offsets::SEQ]),
+        last_seq: LittleEndian::read_u64(&jset[jset_offsets::LAST_SEQ]),
+        version,
+        no_flush: flags & (1 << 5) != 0,
+        items,
+        locations: vec![location],
+    })
+}
+
+/// The journal buckets of a member and their size in sectors
+fn journal_buckets(sb: &[u8]) -> Result<(Vec<u64>, u64)> {
+    let view = SuperBlock::from(sb);
+    let idx = view.device_index()? as usize;
+    let members = view
+        .field(Field::Members)?
+        .ok_or_else(|| BchError::Str("superblock has no members".to_string()))?;
+    let member = members
+        .get((idx * MEMBER_BYTES)..((idx + 1) * MEMBER_BYTES))
+        .ok_or(BchError::Exhausted)?;
+    let bucket_size = u64::from(MemberField::from(member).bucket_size()?);
+
+    let buckets = match view.field(Field::Journal)? {
+        Some(field) => field.chunks_exact(8).map(LittleEndian::read_u64).collect(),
+        None => Vec::new(),
+    };
+    Ok((buckets, bucket_size))
+}
+
+/// Read the journal of every given member of a filesystem
+///
+/// Entries written to several members are read once, their copies recorded.
+/// Encrypted entries are read with the given filesystem key.
+pub fn read_journal(members: &[Member], key: Option<&[u8; KEY_BYTES]>) -> Result<Journal> {
+    let mut journal = Journal::default();
+
+    for member in members.iter() {
+        let (buckets, bucket_size) = journal_buckets(&member.sb)?;
+        let dev = member.sb().device_index()?;
+        if let Some(denylist) = member.sb().field(Field::JournalSeqDenylist)? {
+            journal
+                .blacklist
+                .extend(denylist.chunks_exact(16).map(|entry| {
+                    (
+                        LittleEndian::read_u64(&entry[..8]),
+                        LittleEndian::read_u64(&entry[8..]),
+                    )
+                }));
+        }
+
+        let mut buf = vec![0u8; bucket_size as usize * 512];
+        for bucket in buckets {
+            debug!("{}: reading journal bucket {}", member.path, bucket);
+            member.dev.read_at(&mut buf, bucket * bucket_size * 512)?;
+            journal.read_bucket(&member.sb, key, &buf, dev, bucket)?;
+        }
+    }
+
+    for e in journal.errors.iter() {
+        warn!("{}", e);
+    }
+    Ok(journal)
+}
+
+/// Arguments that the list-journal subcommand may be provided.
+#[derive(Debug)]
+pub struct ListJournalArgs {
+    /// The first sequence number to show, the oldest dirty one if not given
+    pub from: Option<u64>,
+    /// The last sequence number to show
+    pub to: Option<u64>,
+    /// Only show the items of the given btree
+    pub btree: Option<BtreeId>,
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+    /// Read the passphrase of an encrypted filesystem from a file
+    pub keyfile: Option<String>,
+}
+
+/// Print the entries of the journal of an unmounted filesystem
+pub fn list_journal(args: ListJournalArgs) -> Result<()> {
+    let members = open_members_partial(&args.devices, false)?;
+    let key = fs_key(&members, args.keyfile.as_deref())?;
+    let journal = read_journal(&members, key.as_ref())?;
+
+    let newest = match journal.newest() {
+        Some(newest) => newest,
+        None => return Err(BchError::Str("no valid journal entries found".to_string())),
+    };
+    let from = args.from.unwrap_or(newest.last_seq);
+    let to = args.to.unwrap_or(u64::MAX);
+    println!(
+        "newest journal entry {} last_seq {}",
+        newest.seq, newest.last_seq
+    );
+
+    for entry in journal.entries.range(from..=to).map(|(_, entry)| entry) {
+        let items = entry
+            .items
+            .iter()
+            .filter(|item| args.btree.is_none() || item.btree_id() == args.btree)
+            .collect::<Vec<_>>();
+        if args.btree.is_some() && items.is_empty() {
+            continue;
+        }
+
+        print!("{}", entry);
+        if journal.is_blacklisted(entry.seq) {
+            print!(" (blacklisted)");
+        }
+        println!();
+        for item in items {
+            println!("  {}", item.to_string().replace('\n', "\n  "));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_journal {
+    use super::*;
+    use crate::bkey::{Bpos, KeyType};
+    use crate::btree::test_btree::key;
+    use crate::format::test_format::format_members;
+    use crate::super_io::set_field;
+
+    /// A journal item of the given type
+    pub(crate) fn item(ty: JsetEntryType, btree_id: BtreeId, level: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; JSET_ENTRY_BYTES];
+        LittleEndian::write_u16(&mut buf[entry_offsets::U64S], (data.len() / 8) as u16);
+        buf[entry_offsets::BTREE_ID] = btree_id as u8;
+        buf[entry_offsets::LEVEL] = level;
+        buf[entry_offsets::TYPE] = ty as u8;
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// A journal entry holding the given items
+    pub(crate) fn jset(sb: &[u8], seq: u64, last_seq: u64, items: &[Vec<u8>]) -> Vec<u8> {
+        jset_with(sb, seq, last_seq, items, CsumType::Crc32cNonzero, None)
+    }
+
+    /// A journal entry of the given checksum type, encrypted with the key if any
+    fn jset_with(
+        sb: &[u8],
+        seq: u64,
+        last_seq: u64,
+        items: &[Vec<u8>],
+        ty: CsumType,
+        key: Option<&[u8; KEY_BYTES]>,
+    ) -> Vec<u8> {
+        let items = items.concat();
+        let mut buf = vec![0u8; JSET_BYTES];
+        LittleEndian::write_u64(&mut buf[jset_offsets::MAGIC], jset_magic(sb).unwrap());
+        LittleEndian::write_u64(&mut buf[jset_offsets::SEQ], seq);
+        LittleEndian::write_u32(&mut buf[jset_offsets::VERSION], 13);
+        LittleEndian::write_u32(&mut buf[jset_offsets::FLAGS], ty as u32);
+        LittleEndian::write_u32(&mut buf[jset_offsets::U64S], (items.len() / 8) as u32);
+        LittleEndian::write_u64(&mut buf[jset_offsets::LAST_SEQ], last_seq);
+        buf.extend_from_slice(&items);
+
+        let nonce = journal_nonce(&buf);
+        if let Some(key) = key {
+            chacha20(key, nonce, &mut buf[jset_offsets::ENCRYPTED_START..]);
+        }
+        let csum = checksum(ty, key, nonce, &buf[jset_offsets::MAGIC.start..]).unwrap();
+        LittleEndian::write_u64(&mut buf[jset_offsets::CSUM.start..8], csum[0]);
+        LittleEndian::write_u64(&mut buf[8..jset_offsets::CSUM.end], csum[1]);
+        buf
+    }
+
+    /// Give the members journal buckets and write the given entries to them
+    ///
+    /// Each entry is written to the given bucket of every member, in the
+    /// order given.
+    pub(crate) fn write_journal(
+        members: &mut [Member],
+        buckets: &[u64],
+        entries: &[(u64, Vec<u8>)],
+    ) {
+        let mut field = vec![0u8; buckets.len() * 8];
+        for (i, bucket) in buckets.iter().enumerate() {
+            LittleEndian::write_u64(&mut field[(i * 8)..((i + 1) * 8)], *bucket);
+        }
+
+        for member in members.iter_mut() {
+            set_field(&mut member.sb, Field::Journal, &field).unwrap();
+            let (_, bucket_size) = journal_buckets(&member.sb).unwrap();
+            let mut offsets = BTreeMap::new();
+            for (bucket, jset) in entries.iter() {
+                let offset = offsets.entry(*bucket).or_insert(0);
+                let padded = jset.len() + (512 - jset.len() % 512) % 512;
+                member
+                    .dev
+                    .write_at(jset, bucket * bucket_size * 512 + *offset)
+                    .unwrap();
+                *offset += padded as u64;
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_btree() {
+        let root = key(KeyType::BtreePtrV2, Bpos::MAX, &[0u8; 48]);
+        let mut unknown = item(JsetEntryType::BtreeRoot, BtreeId::Inodes, 0, &root);
+        unknown[entry_offsets::BTREE_ID] = 42;
+        let snapshots = item(JsetEntryType::BtreeRoot, BtreeId::Snapshots, 1, &root);
+        let mut blacklist = vec![0xff; 16];
+        LittleEndian::write_u64(&mut blacklist[..8], 7);
+        let blacklist = item(JsetEntryType::BlacklistV2, BtreeId::Extents, 0, &blacklist);
+
+        let items = decode_items(&[unknown, snapshots, blacklist].concat()).unwrap();
+        assert_eq!(items.len(), 3);
+        match &items[0] {
+            JournalItem::Unknown { ty, bytes } => {
+                assert_eq!(*ty, JsetEntryType::BtreeRoot as u8);
+                assert_eq!(*bytes, root.len());
+            }
+            item => panic!("unexpected item {}", item),
+        }
+        assert_eq!(items[1].btree_id(), Some(BtreeId::Snapshots));
+        match &items[2] {
+            JournalItem::Blacklist(ranges) => assert_eq!(ranges, &vec![(7, u64::MAX)]),
+            item => panic!("unexpected item {}", item),
+        }
+    }
+
+    #[test]
+    fn read_entries() {
+        let mut members = format_members(&["mem0", "mem1"]);
+        let sb = members[0].sb.clone();
+        let root = key(KeyType::BtreePtrV2, Bpos::MAX, &[0u8; 48]);
+        let inode = key(KeyType::Inode, Bpos::new(4096, 0, 0), &[0u8; 16]);
+
+        let mut usage = vec![0u8; 8];
+        LittleEndian::write_u64(&mut usage, 42);
+        let mut blacklist = vec![0u8; 16];
+        LittleEndian::write_u64(&mut blacklist[..8], 9);
+        LittleEndian::write_u64(&mut blacklist[8..], 9);
+
+        let mut bad = jset(&sb, 13, 10, &[]);
+        bad[jset_offsets::LAST_SEQ.start] ^= 1;
+        write_journal(
+            &mut members,
+            &[20, 21],
+            &[
+                (20, jset(&sb, 9, 9, &[])),
+                (
+                    20,
+                    jset(
+                        &sb,
+                        10,
+                        10,
+                        &[item(JsetEntryType::BtreeRoot, BtreeId::Inodes, 1, &root)],
+                    ),
+                ),
+                (
+                    20,
+                    jset(
+                        &sb,
+                        11,
+                        10,
+                        &[
+                            item(JsetEntryType::BtreeKeys, BtreeId::Inodes, 0, &inode),
+                            item(JsetEntryType::Usage, BtreeId::Inodes, 0, &usage),
+                        ],
+                    ),
+                ),
+                (
+                    21,
+                    jset(
+                        &sb,
+                        12,
+                        10,
+                        &[item(
+                            JsetEntryType::BlacklistV2,
+                            BtreeId::Extents,
+                            0,
+                            &blacklist,
+                        )],
+                    ),
+                ),
+                (21, bad),
+            ],
+        );
+
+        let journal = read_journal(&members, None).unwrap();
+        assert_eq!(journal.entries.len(), 4);
+        assert_eq!(journal.errors.len(), 2);
+        assert_eq!(journal.entries[&11].locations.len(), 2);
+        assert!(journal.is_blacklisted(9));
+        assert!(!journal.is_blacklisted(10));
+
+        let newest = journal.newest().unwrap();
+        assert_eq!(newest.seq, 12);
+        assert_eq!(
+            journal.dirty().map(|entry| entry.seq).collect::<Vec<_>>(),
+            vec![10, 11, 12]
+        );
+        let roots = journal.btree_roots();
+        assert_eq!(roots[&BtreeId::Inodes].0, 1);
+        assert_eq!(roots[&BtreeId::Inodes].1.p(), Bpos::MAX);
+
+        match &journal.entries[&11].items[..] {
+            [JournalItem::BtreeKeys { btree_id, keys, .. }, JournalItem::Usage { v, .. }] => {
+                assert_eq!(*btree_id, BtreeId::Inodes);
+                assert_eq!(keys[0].p(), Bpos::new(4096, 0, 0));
+                assert_eq!(keys[0].val.len(), 16);
+                assert_eq!(*v, 42);
+            }
+            items => panic!("unexpected items {:?}", items),
+        }
+    }
+
+    #[test]
+    fn read_encrypted() {
+        let mut members = format_members(&["mem0"]);
+        let sb = members[0].sb.clone();
+        let secret = [0x42; KEY_BYTES];
+        let root = key(KeyType::BtreePtrV2, Bpos::MAX, &[0x17; 48]);
+        let entry = jset_with(
+            &sb,
+            10,
+            9,
+            &[item(JsetEntryType::BtreeRoot, BtreeId::Inodes, 1, &root)],
+            CsumType::ChaCha20Poly1305_80,
+            Some(&secret),
+        );
+        assert!(!entry.windows(8).any(|bytes| bytes == [0x17; 8]));
+        write_journal(&mut members, &[20], &[(20, entry)]);
+
+        let journal = read_journal(&members, Some(&secret)).unwrap();
+        assert!(journal.errors.is_empty());
+        let newest = journal.newest().unwrap();
+        assert_eq!((newest.seq, newest.last_seq), (10, 9));
+        assert_eq!(
+            journal.btree_roots()[&BtreeId::Inodes].1.val,
+            vec![0x17; 48]
+        );
+
+        let journal = read_journal(&members, None).unwrap();
+        assert!(journal.entries.is_empty());
+        assert_eq!(journal.errors.len(), 1);
+        let journal = read_journal(&members, Some(&[0x24; KEY_BYTES])).unwrap();
+        assert!(journal.entries.is_empty());
+    }
+}
@@ -7,15 +7,86 @@ use std::io;
 
 use libblkid_rs::BlkidErr;
 
+mod bkey;
+mod block_dev;
+mod btree;
+mod btree_iter;
+mod checksum;
+mod compress;
+mod crypt;
+mod device;
+mod dirent;
+mod extents;
+mod extract;
 mod format;
+mod inode;
+mod journal;
+mod mount;
+mod set_option;
+mod str_hash;
 mod super_block;
+mod super_io;
+mod version;
+mod xattr;
+mod zoned;
 
+pub use bkey::{
+    bkey_format_current, Bkey, BkeyField, BkeyFormat, Bpos, Bversion, KeyType, OwnedKey,
+    BKEY_BYTES, BKEY_FORMAT_BYTES, BKEY_NR_FIELDS, BKEY_U64S, KEY_FORMAT_CURRENT,
+    KEY_FORMAT_LOCAL_BTREE,
+};
+pub use block_dev::{
+    is_mounted, open_device, BlockDev, BlockDevice, ImageFile, MemoryDevice, Topology,
+};
+pub use btree::{
+    bset_magic, parse_btree_node, read_btree_node, Bset, BtreeId, BtreeNode, BtreeNodeHeader,
+};
+pub use btree_iter::{list_keys, BtreeIter, Btrees, ListKeysArgs};
+pub use checksum::{
+    checksum, crc32c, crc64_be, xxhash64, CsumType, Nonce, NONCE_BTREE, NONCE_EXTENT, NONCE_JOURNAL,
+};
+pub use compress::{compress, decompress, CompressionType};
+pub use crypt::{
+    crypt_field, decrypt_key, derive_key, remove_passphrase, set_passphrase, unlock, CryptField,
+    CryptFlag, KdfType, Keyring, PassphraseArgs, UnlockArgs, KEY_BYTES,
+};
+pub use device::{
+    device_add, device_remove, device_resize, device_set_state, AddArgs as DeviceAddArgs,
+    MemberState, RemoveArgs as DeviceRemoveArgs, ResizeArgs as DeviceResizeArgs,
+    SetStateArgs as DeviceSetStateArgs,
+};
+pub use dirent::{
+    dirent_hash, stat, Dirent, DirentTarget, ResolvedInode, StatArgs, BCACHEFS_ROOT_INO,
+    BCACHEFS_ROOT_SUBVOL, DT_SUBVOL,
+};
+pub use extents::{decode_extent, ExtentCrc, ExtentData, ExtentEntryType, ExtentPtr};
+pub use extract::{cat, restore, CatArgs, FileReader, RestoreArgs};
 pub use format::{format_device, Args as FormatArgs, ErrorAction};
+pub use inode::{Inode, InodeFlags, InodeOpts, StrHashType, TimeBase, Timespec};
+pub use journal::{
+    decode_item, jset_magic, list_journal, read_journal, Journal, JournalEntry, JournalItem,
+    JournalLocation, JsetEntryType, ListJournalArgs,
+};
+pub use mount::{
+    block_devices, list_devices, mount, probe_filesystems, scan_filesystems, Filesystem, ListArgs,
+    MemberInfo, MountArgs,
+};
+pub use set_option::{
+    set_label, set_options, set_user_uuid, Args as SetOptionArgs, LabelArgs as SetLabelArgs,
+    UuidArgs as SetUuidArgs,
+};
+pub use str_hash::{str_hash_type, HashInfo};
+pub use version::{upgrade, version_name, UpgradeArgs, VersionPlan};
+pub use xattr::{Xattr, XattrType};
+pub use zoned::{Zone, ZoneModel, ZoneType};
 
 pub use super_block::{
     DataTypes, Features, Field, MemberField, MemberFlag, SuperBlock, SuperBlockFlag,
     SuperBlockFlags, SuperBlockLayout,
 };
+pub use super_io::{
+    open_members, read_super, sb_csum, set_field, write_members, write_super, Member,
+};
 
 /// Core error type for the bcachefs tooling implementations
 #[derive(Debug)]
@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+
+use crate::block_dev::open_device;
+use crate::crypt::{unlock_sb, Keyring};
+use crate::device::MemberState;
+use crate::super_block::{Field, MemberField, MemberFlag, SuperBlock, MEMBER_BYTES};
+use crate::super_io::{member_uuids, read_super};
+use crate::{BchError, Result};
+
+use log::{debug, info, warn};
+use nix::mount::{mount as mount_fs, MsFlags};
+use uuid::Uuid;
+
+/// A member of a filesystem as recorded in its superblock
+#[derive(Debug)]
+pub struct MemberInfo {
+    /// The index of the member
+    pub index: u8,
+    /// The uuid of the member
+    pub uuid: Uuid,
+    /// The state of the member
+    pub state: MemberState,
+    /// The path of the member, `None` if it was not found
+    pub path: Option<String>,
+}
+
+/// A filesystem assembled from the devices found with its superblock
+#[derive(Debug)]
+pub struct Filesystem {
+    /// The newest superblock found of the filesystem
+    pub sb: Vec<u8>,
+    /// The paths of the members found by member index
+    pub devices: BTreeMap<u8, String>,
+}
+
+impl Filesystem {
+    /// The internal uuid of the filesystem
+    pub fn uuid(&self) -> Result<Uuid> {
+        SuperBlock::from(&self.sb[..]).uuid()
+    }
+
+    /// The user uuid of the filesystem
+    pub fn user_uuid(&self) -> Result<Uuid> {
+        SuperBlock::from(&self.sb[..]).user_uuid()
+    }
+
+    /// The label of the filesystem
+    pub fn label(&self) -> Result<String> {
+        let sb = SuperBlock::from(&self.sb[..]);
+        let label = sb.label()?;
+        let len = label.iter().position(|c| *c == 0).unwrap_or(label.len());
+        Ok(String::from_utf8_lossy(&label[..len]).into_owned())
+    }
+
+    /// The index and uuid of the members that were not found
+    pub fn missing(&self) -> Result<Vec<(u8, Uuid)>> {
+        Ok(member_uuids(&self.sb)?
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, uuid)| uuid.map(|uuid| (idx as u8, uuid)))
+            .filter(|(idx, _)| !self.devices.contains_key(idx))
+            .collect())
+    }
+
+    /// The members in use of the filesystem by member index
+    pub fn members(&self) -> Result<Vec<MemberInfo>> {
+        let sb = SuperBlock::from(&self.sb[..]);
+        let member_buf = match sb.field(Field::Members)? {
+            Some(members) => members,
+            None => return Err(BchError::Str("superblock has no members".to_string())),
+        };
+
+        let mut members = Vec::new();
+        for (idx, buf) in member_buf.chunks_exact(MEMBER_BYTES).enumerate() {
+            let member = MemberField::from(buf);
+            let uuid = member.uuid()?;
+            if uuid.is_nil() {
+                continue;
+            }
+            let index = idx as u8;
+            members.push(MemberInfo {
+                index,
+                uuid,
+                state: MemberState::try_from(member.flag(MemberFlag::STATE)?)?,
+                path: self.devices.get(&index).cloned(),
+            });
+        }
+        Ok(members)
+    }
+
+    /// A listing of the filesystem and its members
+    pub fn describe(&self) -> Result<String> {
+        let mut out = self.user_uuid()?.to_string();
+        let label = self.label()?;
+        if !label.is_empty() {
+            out.push_str(&format!(" ({})", label));
+        }
+        out.push('\n');
+        for member in self.members()? {
+            let path = match member.path {
+                Some(path) => path,
+                None => format!("missing ({})", member.uuid),
+            };
+            out.push_str(&format!(
+                "  {:<3} {:<7} {}\n",
+                member.index,
+                member.state.to_string(),
+                path
+            ));
+        }
+        Ok(out)
+    }
+
+    /// The device string to mount, the member paths joined by colons
+    pub fn device_string(&self) -> String {
+        self.devices
+            .values()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+/// The member uuid a superblock claims for its own device
+fn own_member_uuid(sb: &[u8]) -> Result<Option<Uuid>> {
+    let idx = SuperBlock::from(sb).device_index()? as usize;
+    Ok(member_uuids(sb)?.get(idx).copied().flatten())
+}
+
+/// Group superblocks read from devices into filesystems
+///
+/// The newest superblock of each filesystem decides its members; devices
+/// whose member slot has been removed or reused are left out.
+pub(crate) fn assemble(probed: Vec<(String, Vec<u8>)>) -> Result<Vec<Filesystem>> {
+    let mut by_uuid: BTreeMap<Uuid, Vec<(String, Vec<u8>)>> = BTreeMap::new();
+    for (path, sb) in probed {
+        by_uuid
+            .entry(SuperBlock::from(&sb[..]).uuid()?)
+            .or_default()
+            .push((path, sb));
+    }
+
+    let mut filesystems = Vec::new();
+    for (uuid, devices) in by_uuid {
+        let mut newest = 0;
+        for (i, (_, sb)) in devices.iter().enumerate() {
+            if SuperBlock::from(&sb[..]).seq()? > SuperBlock::from(&devices[newest].1[..]).seq()? {
+                newest = i;
+            }
+        }
+
+        let sb = devices[newest].1.clone();
+        let members = member_uuids(&sb)?;
+        let mut found = BTreeMap::new();
+        for (path, dev_sb) in devices {
+            let idx = SuperBlock::from(&dev_sb[..]).device_index()?;
+            let own = own_member_uuid(&dev_sb)?;
+            if own.is_none() || members.get(idx as usize).copied().flatten() != own {
+                warn!("{}: no longer a member of filesystem {}", path, uuid);
+            } else if let Some(other) = found.insert(idx, path.clone()) {
+                warn!(
+                    "{} and {} both claim to be member {} of {}",
+                    other, path, idx, uuid
+                );
+            }
+        }
+
+        filesystems.push(Filesystem { sb, devices: found });
+    }
+
+    Ok(filesystems)
+}
+
+/// Read the bcachefs superblocks of the given devices, skipping the others
+pub fn probe_filesystems(paths: &[String]) -> Result<Vec<Filesystem>> {
+    let mut probed = Vec::new();
+    for path in paths.iter() {
+        let sb = open_device(path, false).and_then(|dev| read_super(dev.as_ref()));
+        match sb {
+            Ok(sb) => probed.push((path.clone(), sb)),
+            Err(e) => debug!("{}: {}", path, e),
+        }
+    }
+    assemble(probed)
+}
+
+/// The block devices and partitions of the system
+///
+/// libblkid-rs does not expose the blkid device cache, so the devices are
+/// listed from sysfs instead.
+pub fn block_devices() -> Result<Vec<String>> {
+    let mut devices = fs::read_dir("/sys/class/block")?
+        .map(|entry| Ok(format!("/dev/{}", entry?.file_name().to_string_lossy())))
+        .collect::<Result<Vec<_>>>()?;
+    devices.sort();
+    Ok(devices)
+}
+
+/// Scan every block device of the system for bcachefs filesystems
+pub fn scan_filesystems() -> Result<Vec<Filesystem>> {
+    probe_filesystems(&block_devices()?)
+}
+
+/// Arguments that the list-devices subcommand may be provided.
+#[derive(Debug)]
+pub struct ListArgs {
+    /// The devices to probe, every block device of the system if empty
+    pub devices: Vec<String>,
+}
+
+/// Print the bcachefs filesystems found and their members
+pub fn list_devices(args: ListArgs) -> Result<()> {
+    let filesystems = if args.devices.is_empty() {
+        scan_filesystems()?
+    } else {
+        probe_filesystems(&args.devices)?
+    };
+
+    for filesystem in filesystems.iter() {
+        print!("{}", filesystem.describe()?);
+    }
+    Ok(())
+}
+
+/// Find the filesystem given as `UUID=<uuid>` or one of its devices
+///
+/// A uuid may be either the user or the internal uuid of the filesystem.
+pub(crate) fn find_filesystem(spec: &str, candidates: &[String]) -> Result<Filesystem> {
+    let uuid = match spec.strip_prefix("UUID=") {
+        Some(uuid) => Uuid::parse_str(uuid)?,
+        None => {
+            let dev = open_device(spec, false)?;
+            let sb =
+                read_super(dev.as_ref()).map_err(|e| BchError::Str(format!("{}: {}", spec, e)))?;
+            SuperBlock::from(&sb[..]).uuid()?
+        }
+    };
+
+    let mut paths = candidates.to_vec();
+    if !spec.starts_with("UUID=") && !paths.iter().any(|path| path == spec) {
+        paths.push(spec.to_string());
+    }
+
+    for filesystem in probe_filesystems(&paths)? {
+        if filesystem.uuid()? == uuid || filesystem.user_uuid()? == uuid {
+            return Ok(filesystem);
+        }
+    }
+    Err(BchError::Str(format!(
+        "no bcachefs filesystem found for {}",
+        spec
+    )))
+}
+
+/// Split mount options into mount flags and filesystem options
+fn parse_options(options: &str) -> (MsFlags, Vec<&str>) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+
+    for opt in options.split(',').filter(|opt| !opt.is_empty()) {
+        match opt {
+            "ro" => flags.insert(MsFlags::MS_RDONLY),
+            "rw" => flags.remove(MsFlags::MS_RDONLY),
+            "nosuid" => flags.insert(MsFlags::MS_NOSUID),
+            "nodev" => flags.insert(MsFlags::MS_NODEV),
+            "noexec" => flags.insert(MsFlags::MS_NOEXEC),
+            "noatime" => flags.insert(MsFlags::MS_NOATIME),
+            "nodiratime" => flags.insert(MsFlags::MS_NODIRATIME),
+            "relatime" => flags.insert(MsFlags::MS_RELATIME),
+            "sync" => flags.insert(MsFlags::MS_SYNCHRONOUS),
+            _ => data.push(opt),
+        }
+    }
+
+    (flags, data)
+}
+
+/// Arguments that the mount subcommand may be provided.
+#[derive(Debug)]
+pub struct MountArgs {
+    /// `UUID=<uuid>` or a device of the filesystem
+    pub device: String,
+    /// The directory to mount on
+    pub dir: String,
+    /// Comma separated mount options
+    pub options: String,
+    /// Mount even if members are missing
+    pub degraded: bool,
+    /// Read the passphrase from a file instead of prompting for it
+    pub keyfile: Option<String>,
+}
+
+/// Assemble the members of a filesystem and mount it
+pub fn mount(args: MountArgs) -> Result<()> {
+    let filesystem = find_filesystem(&args.device, &block_devices()?)?;
+    let uuid = filesystem.uuid()?;
+    info!(
+        "found filesystem {} with {} devices",
+        uuid,
+        filesystem.devices.len()
+    );
+
+    let missing = filesystem.missing()?;
+    for (idx, member_uuid) in missing.iter() {
+        warn!("member {} ({}) of {} not found", idx, member_uuid, uuid);
+    }
+
+    let (flags, mut data) = parse_options(&args.options);
+    if !missing.is_empty() {
+        if !args.degraded {
+            return Err(BchError::Str(format!(
+                "{} of the members of {} not found, mount with --degraded to mount anyway",
+                missing.len(),
+                uuid
+            )));
+        }
+        if !data.contains(&"degraded") {
+            data.push("degraded");
+        }
+    }
+
+    if SuperBlock::from(&filesystem.sb[..])
+        .field(Field::Crypt)?
+        .is_some()
+    {
+        unlock_sb(&filesystem.sb, args.keyfile.as_deref(), Keyring::User)?;
+    }
+
+    let devices = filesystem.device_string();
+    let data = data.join(",");
+    debug!("mounting {} on {} with {}", devices, args.dir, data);
+    mount_fs(
+        Some(devices.as_str()),
+        args.dir.as_str(),
+        Some("bcachefs"),
+        flags,
+        Some(data.as_str()),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_mount {
+    use super::*;
+    use crate::format::test_format::{format_files, format_members};
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn assemble_members() {
+        let first = format_members(&["a0", "a1"]);
+        let second = format_members(&["b0"]);
+        let probed = first
+            .iter()
+            .chain(second.iter())
+            .map(|member| (member.path.clone(), member.sb.clone()))
+            .collect::<Vec<_>>();
+
+        let filesystems = assemble(probed[1..].to_vec()).unwrap();
+        assert_eq!(filesystems.len(), 2);
+        let filesystem = filesystems
+            .iter()
+            .find(|filesystem| filesystem.uuid().unwrap() == first[0].sb().uuid().unwrap())
+            .unwrap();
+        assert_eq!(filesystem.device_string(), "a1");
+        assert_eq!(filesystem.missing().unwrap().len(), 1);
+        assert_eq!(filesystem.missing().unwrap()[0].0, 0);
+        assert_eq!(filesystem.label().unwrap(), "test");
+        let members = filesystem.members().unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].path, None);
+        assert_eq!(members[1].path.as_deref(), Some("a1"));
+        assert_eq!(members[1].state, MemberState::ReadWrite);
+        let listing = filesystem.describe().unwrap();
+        assert!(listing.contains(&format!("missing ({})", members[0].uuid)));
+
+        let filesystem = assemble(probed[..2].to_vec()).unwrap().remove(0);
+        assert_eq!(filesystem.device_string(), "a0:a1");
+        assert!(filesystem.missing().unwrap().is_empty());
+    }
+
+    #[test]
+    fn find_on_image_files() {
+        let dir = env::temp_dir();
+        let paths = (0..3)
+            .map(|i| {
+                dir.join(format!("bcachefs-rs-mount-{}-{}.img", process::id(), i))
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        let names = paths.iter().map(String::as_str).collect::<Vec<_>>();
+        format_files(&names[..2]);
+        fs::write(&paths[2], vec![0u8; 1 << 20]).unwrap();
+
+        let filesystem = find_filesystem(&paths[1], &paths).unwrap();
+        assert_eq!(
+            filesystem.device_string(),
+            format!("{}:{}", paths[0], paths[1])
+        );
+
+        let spec = format!("UUID={}", filesystem.user_uuid().unwrap());
+        let filesystem = find_filesystem(&spec, &paths[1..]).unwrap();
+        assert_eq!(filesystem.missing().unwrap().len(), 1);
+        assert!(find_filesystem(&paths[2], &paths).is_err());
+
+        for path in paths.iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn mount_options() {
+        let (flags, data) = parse_options("ro,noatime,degraded,,fsck");
+        assert_eq!(flags, MsFlags::MS_RDONLY | MsFlags::MS_NOATIME);
+        assert_eq!(data, vec!["degraded", "fsck"]);
+    }
+}
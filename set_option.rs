@@ -0,0 +1,425 @@
+use crate::super_block::{SuperBlockFlag, SuperBlockFlags};
+use crate::super_io::{open_members, write_members, Member};
+use crate::{BchError, Result};
+
+use log::info;
+use uuid::Uuid;
+
+/// Maximum length of a filesystem label
+const LABEL_MAX: usize = 32;
+
+/// Maximum number of replicas
+const REPLICAS_MAX: u64 = 4;
+
+/// How the value of an option is parsed
+enum OptType {
+    /// A boolean, stored as 0 or 1
+    Bool,
+    /// An integer in the given inclusive range
+    Uint(u64, u64),
+    /// One of the given names, stored as its index
+    Str(&'static [&'static str]),
+    /// The number of replicas, at most the number of members
+    Replicas,
+    /// A member device stored as its index + 1, or `none`
+    Target,
+}
+
+/// An option stored in the superblock flags
+struct SbOpt {
+    name: &'static str,
+    flag: SuperBlockFlag,
+    ty: OptType,
+}
+
+const CSUM_OPTS: &[&str] = &["none", "crc32c", "crc64", "xxhash"];
+const COMPRESSION_OPTS: &[&str] = &["none", "lz4", "gzip", "zstd"];
+
+/// The options that may be changed on an unmounted filesystem
+const SB_OPTS: &[SbOpt] = &[
+    SbOpt {
+        name: "errors",
+        flag: SuperBlockFlag::ERROR_ACTION,
+        ty: OptType::Str(&["continue", "ro", "panic"]),
+    },
+    SbOpt {
+        name: "metadata_replicas",
+        flag: SuperBlockFlag::META_REPLICAS_WANT,
+        ty: OptType::Replicas,
+    },
+    SbOpt {
+        name: "data_replicas",
+        flag: SuperBlockFlag::DATA_REPLICAS_WANT,
+        ty: OptType::Replicas,
+    },
+    SbOpt {
+        name: "metadata_replicas_required",
+        flag: SuperBlockFlag::META_REPLICAS_REQ,
+        ty: OptType::Replicas,
+    },
+    SbOpt {
+        name: "data_replicas_required",
+        flag: SuperBlockFlag::DATA_REPLICAS_REQ,
+        ty: OptType::Replicas,
+    },
+    SbOpt {
+        name: "metadata_checksum",
+        flag: SuperBlockFlag::META_CSUM_TYPE,
+        ty: OptType::Str(CSUM_OPTS),
+    },
+    SbOpt {
+        name: "data_checksum",
+        flag: SuperBlockFlag::DATA_CSUM_TYPE,
+        ty: OptType::Str(CSUM_OPTS),
+    },
+    SbOpt {
+        name: "compression",
+        flag: SuperBlockFlag::COMPRESSION_TYPE,
+        ty: OptType::Str(COMPRESSION_OPTS),
+    },
+    SbOpt {
+        name: "background_compression",
+        flag: SuperBlockFlag::BACKGROUND_COMPRESSION_TYPE,
+        ty: OptType::Str(COMPRESSION_OPTS),
+    },
+    SbOpt {
+        name: "str_hash",
+        flag: SuperBlockFlag::STR_HASH_TYPE,
+        ty: OptType::Str(&["crc32c", "crc64", "siphash"]),
+    },
+    SbOpt {
+        name: "foreground_target",
+        flag: SuperBlockFlag::FOREGROUND_TARGET,
+        ty: OptType::Target,
+    },
+    SbOpt {
+        name: "background_target",
+        flag: SuperBlockFlag::BACKGROUND_TARGET,
+        ty: OptType::Target,
+    },
+    SbOpt {
+        name: "promote_target",
+        flag: SuperBlockFlag::PROMOTE_TARGET,
+        ty: OptType::Target,
+    },
+    SbOpt {
+        name: "metadata_target",
+        flag: SuperBlockFlag::METADATA_TARGET,
+        ty: OptType::Target,
+    },
+    SbOpt {
+        name: "gc_reserve_percent",
+        flag: SuperBlockFlag::GC_RESERVE,
+        ty: OptType::Uint(5, 21),
+    },
+    SbOpt {
+        name: "root_reserve_percent",
+        flag: SuperBlockFlag::ROOT_RESERVE,
+        ty: OptType::Uint(0, 100),
+    },
+    SbOpt {
+        name: "acl",
+        flag: SuperBlockFlag::POSIX_ACL,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "usrquota",
+        flag: SuperBlockFlag::USRQUOTA,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "grpquota",
+        flag: SuperBlockFlag::GRPQUOTA,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "prjquota",
+        flag: SuperBlockFlag::PRJQUOTA,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "inodes_32bit",
+        flag: SuperBlockFlag::INODE_32BIT,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "shard_inode_numbers",
+        flag: SuperBlockFlag::SHARD_INODES,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "erasure_code",
+        flag: SuperBlockFlag::ERASURE_CODE,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "journal_flush_delay",
+        flag: SuperBlockFlag::JOURNAL_FLUSH_DELAY,
+        ty: OptType::Uint(1, u32::MAX as u64),
+    },
+    SbOpt {
+        name: "journal_flush_disabled",
+        flag: SuperBlockFlag::JOURNAL_FLUSH_DISABLED,
+        ty: OptType::Bool,
+    },
+    SbOpt {
+        name: "journal_reclaim_delay",
+        flag: SuperBlockFlag::JOURNAL_RECLAIM_DELAY,
+        ty: OptType::Uint(0, u32::MAX as u64),
+    },
+];
+
+/// Arguments that the set-option subcommand may be provided.
+#[derive(Debug)]
+pub struct Args {
+    /// The options to set as name and value
+    pub options: Vec<(String, String)>,
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+}
+
+/// Parse the value of an option into the value stored in the superblock
+fn parse_value(opt: &SbOpt, value: &str, members: &[Member]) -> Result<u64> {
+    let invalid = || BchError::Einval(format!("invalid value for {}: `{}`", opt.name, value));
+
+    match opt.ty {
+        OptType::Bool => match value {
+            "1" | "true" | "yes" => Ok(1),
+            "0" | "false" | "no" => Ok(0),
+            _ => Err(invalid()),
+        },
+        OptType::Uint(min, max) => match value.parse::<u64>() {
+            Ok(val) if (min..=max).contains(&val) => Ok(val),
+            _ => Err(invalid()),
+        },
+        OptType::Str(names) => names
+            .iter()
+            .position(|name| *name == value)
+            .map(|idx| idx as u64)
+            .ok_or_else(invalid),
+        OptType::Replicas => match value.parse::<u64>() {
+            Ok(val) if (1..=REPLICAS_MAX).contains(&val) && val <= members.len() as u64 => Ok(val),
+            _ => Err(invalid()),
+        },
+        OptType::Target if value == "none" => Ok(0),
+        OptType::Target => match members.iter().find(|member| member.path == value) {
+            Some(member) => Ok(u64::from(member.sb().device_index()?) + 1),
+            None => Err(BchError::Einval(format!(
+                "{} `{}` is not one of the given devices",
+                opt.name, value
+            ))),
+        },
+    }
+}
+
+/// Change the given options in the superblock of every member
+fn apply_options(members: &mut [Member], options: &[(String, String)]) -> Result<()> {
+    let mut changes = Vec::new();
+
+    for (name, value) in options.iter() {
+        let opt = SB_OPTS
+            .iter()
+            .find(|opt| opt.name == name.replace('-', "_"))
+            .ok_or_else(|| BchError::Einval(format!("unknown option: {}", name)))?;
+        let val = parse_value(opt, value, members)?;
+
+        info!("setting {}={} ({})", opt.name, value, val);
+        changes.push((opt.flag.clone(), val));
+    }
+
+    for member in members.iter_mut() {
+        let mut flags_buf = [0u8; 64];
+        flags_buf.copy_from_slice(member.sb().flags()?.as_ref());
+
+        let mut flags = SuperBlockFlags::from(&mut flags_buf[..]);
+        for (flag, val) in changes.iter() {
+            flags.set_flag(flag.clone(), *val)?;
+        }
+        member.sb_mut().set_flags(&flags)?;
+    }
+
+    Ok(())
+}
+
+/// Change options in the superblocks of an unmounted filesystem
+pub fn set_options(args: Args) -> Result<()> {
+    if args.options.is_empty() {
+        return Err(BchError::Str("no options given".to_string()));
+    }
+
+    let mut members = open_members(&args.devices, true)?;
+    apply_options(&mut members, &args.options)?;
+    write_members(&mut members)
+}
+
+/// Arguments that the set-label subcommand may be provided.
+#[derive(Debug)]
+pub struct LabelArgs {
+    /// The new label, empty to clear it
+    pub label: String,
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+}
+
+/// Arguments that the set-uuid subcommand may be provided.
+#[derive(Debug)]
+pub struct UuidArgs {
+    /// The new user uuid, a random one if not given
+    pub uuid: Option<Uuid>,
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+}
+
+/// Change the label in the superblock of every member
+fn apply_label(members: &mut [Member], label: &str) -> Result<()> {
+    if label.len() > LABEL_MAX {
+        return Err(BchError::Einval(format!(
+            "label of length {} longer than {}",
+            label.len(),
+            LABEL_MAX
+        )));
+    }
+
+    let mut label_buf = [0u8; LABEL_MAX];
+    label_buf[..label.len()].copy_from_slice(label.as_bytes());
+    for member in members.iter_mut() {
+        member.sb_mut().set_label(&label_buf)?;
+    }
+    Ok(())
+}
+
+/// Change the label of an unmounted filesystem
+pub fn set_label(args: LabelArgs) -> Result<()> {
+    let mut members = open_members(&args.devices, true)?;
+    apply_label(&mut members, &args.label)?;
+    write_members(&mut members)?;
+    info!("label set to `{}`", args.label);
+    Ok(())
+}
+
+/// Change the user uuid in the superblock of every member
+fn apply_user_uuid(members: &mut [Member], uuid: Uuid) -> Result<()> {
+    if uuid.is_nil() {
+        return Err(BchError::Einval("the user uuid may not be nil".to_string()));
+    }
+
+    for member in members.iter_mut() {
+        member.sb_mut().set_user_uuid(uuid)?;
+    }
+    Ok(())
+}
+
+/// Change the user uuid of an unmounted filesystem
+///
+/// The user uuid is the one reported by blkid and used to mount by uuid, the
+/// internal uuid is left alone.
+pub fn set_user_uuid(args: UuidArgs) -> Result<()> {
+    let uuid = args.uuid.unwrap_or_else(Uuid::new_v4);
+    let mut members = open_members(&args.devices, true)?;
+    apply_user_uuid(&mut members, uuid)?;
+    write_members(&mut members)?;
+    println!("{}", uuid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_set_option {
+    use super::*;
+    use crate::block_dev::MemoryDevice;
+    use crate::format::test_format::format_members as members;
+    use crate::super_block::SuperBlock;
+    use crate::super_io::read_super;
+
+    fn opts(opts: &[(&str, &str)]) -> Vec<(String, String)> {
+        opts.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn flag(member: &Member, flag: SuperBlockFlag) -> u64 {
+        member.sb().flags().unwrap().flag(flag).unwrap()
+    }
+
+    #[test]
+    fn set_and_write() {
+        let mut members = members(&["mem0", "mem1"]);
+        let options = opts(&[
+            ("compression", "zstd"),
+            ("background-compression", "lz4"),
+            ("data_replicas", "2"),
+            ("acl", "true"),
+            ("promote_target", "mem1"),
+        ]);
+        apply_options(&mut members, &options).unwrap();
+        write_members(&mut members).unwrap();
+
+        for member in members.iter() {
+            let sb = read_super(member.dev.as_ref()).unwrap();
+            let reread = Member {
+                path: member.path.clone(),
+                dev: Box::new(MemoryDevice::new(0)),
+                sb,
+            };
+            assert_eq!(reread.sb().seq().unwrap(), 1);
+            assert_eq!(flag(&reread, SuperBlockFlag::COMPRESSION_TYPE), 3);
+            assert_eq!(
+                flag(&reread, SuperBlockFlag::BACKGROUND_COMPRESSION_TYPE),
+                1
+            );
+            assert_eq!(flag(&reread, SuperBlockFlag::DATA_REPLICAS_WANT), 2);
+            assert_eq!(flag(&reread, SuperBlockFlag::META_REPLICAS_WANT), 1);
+            assert_eq!(flag(&reread, SuperBlockFlag::POSIX_ACL), 1);
+            assert_eq!(flag(&reread, SuperBlockFlag::PROMOTE_TARGET), 2);
+        }
+    }
+
+    #[test]
+    fn label() {
+        let mut members = members(&["mem0", "mem1"]);
+        apply_label(&mut members, "new label").unwrap();
+        write_members(&mut members).unwrap();
+        let sb = read_super(members[1].dev.as_ref()).unwrap();
+        let label = SuperBlock::from(&sb[..]).label().unwrap().to_vec();
+        assert_eq!(&label[..10], b"new label\0");
+        assert!(label[9..].iter().all(|byte| *byte == 0));
+
+        assert!(apply_label(&mut members, &"x".repeat(33)).is_err());
+    }
+
+    #[test]
+    fn user_uuid() {
+        let mut members = members(&["mem0", "mem1"]);
+        let sb = read_super(members[0].dev.as_ref()).unwrap();
+        let internal = SuperBlock::from(&sb[..]).uuid().unwrap();
+
+        let uuid = Uuid::new_v4();
+        apply_user_uuid(&mut members, uuid).unwrap();
+        write_members(&mut members).unwrap();
+        for member in members.iter() {
+            let sb = read_super(member.dev.as_ref()).unwrap();
+            let view = SuperBlock::from(&sb[..]);
+            assert_eq!(view.user_uuid().unwrap(), uuid);
+            assert_eq!(view.uuid().unwrap(), internal);
+        }
+
+        assert!(apply_user_uuid(&mut members, Uuid::nil()).is_err());
+    }
+
+    #[test]
+    fn invalid_options() {
+        let mut members = members(&["mem0"]);
+        for (name, value) in [
+            ("compression", "brotli"),
+            ("data_replicas", "2"),
+            ("gc_reserve_percent", "50"),
+            ("acl", "maybe"),
+            ("foreground_target", "mem1"),
+            ("no_such_option", "1"),
+        ]
+        .iter()
+        {
+            assert!(apply_options(&mut members, &opts(&[(name, value)])).is_err());
+        }
+        assert_eq!(flag(&members[0], SuperBlockFlag::COMPRESSION_TYPE), 0);
+    }
+}
@@ -0,0 +1,189 @@
+use std::hash::Hasher;
+
+use crate::checksum::{crc32c, crc64_be};
+use crate::inode::{Inode, StrHashType};
+use crate::super_block::{Features, SuperBlock, SuperBlockFlag};
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use sha2::{Digest, Sha256};
+use siphasher::sip::SipHasher24;
+
+/// The values of the str_hash option
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+enum StrHashOpt {
+    Crc32c = 0,
+    Crc64 = 1,
+    Siphash = 2,
+}
+
+/// The hash type new directories and xattrs are given on a filesystem
+///
+/// This follows the str_hash option, filesystems without the new siphash
+/// feature use the original siphash.
+pub fn str_hash_type(sb: &[u8]) -> Result<StrHashType> {
+    let sb = SuperBlock::from(sb);
+    let opt = sb.flags()?.flag(SuperBlockFlag::STR_HASH_TYPE)?;
+    let features = Features::from_bits_truncate(sb.feature(0)?);
+    match opt {
+        o if o == StrHashOpt::Crc32c as u64 => Ok(StrHashType::Crc32c),
+        o if o == StrHashOpt::Crc64 as u64 => Ok(StrHashType::Crc64),
+        o if o == StrHashOpt::Siphash as u64 && features.contains(Features::NEW_SIPHASH) => {
+            Ok(StrHashType::Siphash)
+        }
+        o if o == StrHashOpt::Siphash as u64 => Ok(StrHashType::SiphashOld),
+        o => Err(BchError::Einval(format!("unknown str_hash option {}", o))),
+    }
+}
+
+/// What the names in a directory, or the xattrs of an inode, are hashed with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HashInfo {
+    /// The hash function
+    pub ty: StrHashType,
+    /// The key of the hash function, the hash seed of the inode
+    pub key: [u64; 2],
+}
+
+impl HashInfo {
+    /// The hash info of the given directory or inode
+    pub fn new(inode: &Inode) -> Result<HashInfo> {
+        let ty = inode.str_hash()?;
+        let key = if ty == StrHashType::SiphashOld {
+            let digest = Sha256::digest(&inode.hash_seed.to_le_bytes());
+            [
+                LittleEndian::read_u64(&digest[..8]),
+                LittleEndian::read_u64(&digest[8..16]),
+            ]
+        } else {
+            [inode.hash_seed, 0]
+        };
+        Ok(HashInfo { ty, key })
+    }
+
+    /// Hash the given name
+    pub fn hash(&self, name: &[u8]) -> u64 {
+        let seed = self.key[0].to_le_bytes();
+        match self.ty {
+            StrHashType::Crc32c => u64::from(crc32c(crc32c(!0, &seed), name)),
+            StrHashType::Crc64 => crc64_be(crc64_be(!0, &seed), name) >> 1,
+            StrHashType::SiphashOld | StrHashType::Siphash => {
+                let mut hasher = SipHasher24::new_with_keys(self.key[0], self.key[1]);
+                hasher.write(name);
+                hasher.finish() >> 1
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_str_hash {
+    use super::*;
+    use crate::format::test_format::format_members;
+    use crate::super_block::SuperBlockFlags;
+
+    #[test]
+    fn hash_types() {
+        let mut member = format_members(&["mem0"]).remove(0);
+        assert_eq!(str_hash_type(&member.sb).unwrap(), StrHashType::Crc32c);
+
+        let mut set_opt = |opt: StrHashOpt, features: Features| {
+            let mut flags_buf = [0u8; 64];
+            flags_buf.copy_from_slice(member.sb().flags().unwrap().as_ref());
+            let mut flags = SuperBlockFlags::from(&mut flags_buf[..]);
+            flags
+                .set_flag(SuperBlockFlag::STR_HASH_TYPE, opt as u64)
+                .unwrap();
+            let mut sb = member.sb_mut();
+            sb.set_flags(&flags).unwrap();
+            sb.set_feature(0, features).unwrap();
+            str_hash_type(&member.sb).unwrap()
+        };
+        assert_eq!(
+            set_opt(StrHashOpt::Crc64, Features::ALL),
+            StrHashType::Crc64
+        );
+        assert_eq!(
+            set_opt(StrHashOpt::Siphash, Features::ALL),
+            StrHashType::Siphash
+        );
+        assert_eq!(
+            set_opt(StrHashOpt::Siphash, Features::ALWAYS),
+            StrHashType::SiphashOld
+        );
+
+        let mut inode = Inode {
+            hash_seed: 0x0123_4567_89ab_cdef,
+            ..Inode::default()
+        };
+        let mut hashes = Vec::new();
+        for ty in 0..4u32 {
+            inode.flags = ty << 20;
+            let info = HashInfo::new(&inode).unwrap();
+            assert_eq!(info.ty as u32, ty);
+            assert_eq!(info.hash(b"name"), info.hash(b"name"));
+            assert_ne!(info.hash(b"name"), info.hash(b"other"));
+            hashes.push(info.hash(b"name"));
+        }
+        assert!(hashes[0] <= u64::from(u32::MAX));
+        assert!(hashes.iter().all(|hash| hash >> 63 == 0));
+        assert_ne!(hashes[2], hashes[3]);
+
+        inode.flags = 4 << 20;
+        assert!(HashInfo::new(&inode).is_err());
+    }
+
+    #[test]
+    fn known_hashes() {
+        // crc32c, crc64, siphash_old and siphash as hashed by
+        // bch2_str_hash_init() and bch2_str_hash_end() of the kernel
+        let vectors: [(&[u8], [u64; 4]); 3] = [
+            (
+                b"",
+                [
+                    0x9a4f_27dc,
+                    0x3e5c_5f4f_cc9c_716d,
+                    0x6837_faf8_2f37_51fb,
+                    0x5343_a7f2_ecf7_9c57,
+                ],
+            ),
+            (
+                b"name",
+                [
+                    0x30e1_b97b,
+                    0x22ab_c45f_a89b_53bf,
+                    0x0d36_2035_78d2_5439,
+                    0x31e1_d9c7_b0fd_b1c7,
+                ],
+            ),
+            (
+                b"lost+found",
+                [
+                    0xa295_c59b,
+                    0x6166_454a_27b5_b88b,
+                    0x548d_ec06_e1db_d4f9,
+                    0x0f2e_ef37_82c4_236f,
+                ],
+            ),
+        ];
+
+        let mut inode = Inode {
+            hash_seed: 0x0123_4567_89ab_cdef,
+            ..Inode::default()
+        };
+        for ty in 0..4u32 {
+            inode.flags = ty << 20;
+            let info = HashInfo::new(&inode).unwrap();
+            for (name, hashes) in vectors.iter() {
+                assert_eq!(
+                    info.hash(name),
+                    hashes[ty as usize],
+                    "{:?} {:?}",
+                    info.ty,
+                    name
+                );
+            }
+        }
+    }
+}
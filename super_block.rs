@@ -87,6 +87,7 @@ bitflags! {
 }
 
 /// Superblock field types
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u64)]
 pub enum Field {
     /// Journal field
@@ -110,17 +111,20 @@ pub enum Field {
 }
 
 /// Returns the superblock magic for bcachefs
-fn magic() -> Uuid {
+pub(crate) fn magic() -> Uuid {
     const MAGIC_D4: [u8; 8] = [0x82, 0x65, 0xf5, 0x7f, 0x48, 0xba, 0x6d, 0x81];
     Uuid::from_fields_le(0xf67385c6, 0x1a4e, 0xca45, &MAGIC_D4)
         .expect("Incorrect Bcachefs Magic specified")
 }
 
+/// The size of the fixed part of the superblock preceding the fields
+pub(crate) const SB_HEADER_BYTES: usize = sb_offsets::FIELDS;
+
 mod sb_offsets {
     use super::layout_offsets;
     use std::ops::Range;
 
-    // FIXME: add csum hi/lo from 0..8/8..16
+    pub const CSUM: Range<usize> = 0..16;
     pub const VERSION: Range<usize> = 16..18;
     pub const VERSION_MIN: Range<usize> = 18..20;
     // reserved four bytes
@@ -156,6 +160,9 @@ mod layout_offsets {
     pub const SB_OFFSET: Range<usize> = 24..512;
 }
 
+/// The size of a single member in the members field
+pub(crate) const MEMBER_BYTES: usize = member_offsets::FLAGS.end;
+
 mod member_offsets {
     use std::ops::Range;
 
@@ -190,6 +197,20 @@ impl<T: AsRef<[u8]>> AsRef<[u8]> for SuperBlock<T> {
 }
 
 impl<T: AsRef<[u8]>> SuperBlock<T> {
+    /// The checksum of this superblock, low and high u64
+    pub fn csum(&self) -> Result<[u64; 2]> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < sb_offsets::CSUM.end {
+            Err(BchError::Exhausted)
+        } else {
+            let csum = &buf[sb_offsets::CSUM];
+            Ok([
+                LittleEndian::read_u64(&csum[..8]),
+                LittleEndian::read_u64(&csum[8..]),
+            ])
+        }
+    }
+
     /// The current version supported
     pub fn version(&self) -> Result<u16> {
         let buf = self.buffer.as_ref();
@@ -253,6 +274,26 @@ impl<T: AsRef<[u8]>> SuperBlock<T> {
         }
     }
 
+    /// The sector this copy of the superblock is stored at
+    pub fn offset(&self) -> Result<u64> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < sb_offsets::OFFSET.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u64(&buf[sb_offsets::OFFSET]))
+        }
+    }
+
+    /// The sequence number of this superblock
+    pub fn seq(&self) -> Result<u64> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < sb_offsets::SEQ.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u64(&buf[sb_offsets::SEQ]))
+        }
+    }
+
     /// The block size of this superblock
     pub fn block_size(&self) -> Result<u16> {
         let buf = self.buffer.as_ref();
@@ -293,6 +334,11 @@ impl<T: AsRef<[u8]>> SuperBlock<T> {
         }
     }
 
+    /// The size of the superblock including the variable TLV of fields
+    pub fn bytes(&self) -> Result<usize> {
+        Ok(sb_offsets::FIELDS + self.u64s()? as usize * 8)
+    }
+
     /// The low bits of the time base
     pub fn time_base_lo(&self) -> Result<u64> {
         let buf = self.buffer.as_ref();
@@ -337,6 +383,16 @@ impl<T: AsRef<[u8]>> SuperBlock<T> {
         }
     }
 
+    /// The superblock flags
+    pub fn flags(&self) -> Result<SuperBlockFlags<&[u8]>> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < sb_offsets::FLAGS.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(SuperBlockFlags::from(&buf[sb_offsets::FLAGS]))
+        }
+    }
+
     /// The feature set at the given index
     pub fn feature(&self, idx: usize) -> Result<u64> {
         let buf = self.buffer.as_ref();
@@ -376,6 +432,57 @@ impl<T: AsRef<[u8]>> SuperBlock<T> {
             Ok(data)
         }
     }
+
+    /// The copy of the superblock layout stored in the superblock
+    pub fn layout(&self) -> Result<SuperBlockLayout<&[u8]>> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < sb_offsets::LAYOUT.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(SuperBlockLayout::from(&buf[sb_offsets::LAYOUT]))
+        }
+    }
+
+    /// The body of the first field of the given type, if there is one
+    pub fn field(&self, ty: Field) -> Result<Option<&[u8]>> {
+        let buf = self.buffer.as_ref();
+        Ok(self
+            .field_range(ty)?
+            .map(|range| &buf[(range.start + 8)..range.end]))
+    }
+
+    /// The range of the first field of the given type including its header
+    pub(crate) fn field_range(&self, ty: Field) -> Result<Option<Range<usize>>> {
+        let buf = self.buffer.as_ref();
+        let end = self.bytes()?;
+        let mut start = sb_offsets::FIELDS;
+
+        if buf.len() < end {
+            return Err(BchError::Exhausted);
+        }
+
+        while start < end {
+            if end - start < 8 {
+                return Err(BchError::Exhausted);
+            }
+            let u64s = LittleEndian::read_u32(&buf[start..(start + 4)]) as usize;
+            let field_ty = LittleEndian::read_u32(&buf[(start + 4)..(start + 8)]);
+            let field_end = start + u64s * 8;
+
+            if u64s == 0 || field_end > end {
+                return Err(BchError::Einval(format!(
+                    "invalid superblock field at offset {}",
+                    start
+                )));
+            }
+            if u64::from(field_ty) == ty as u64 {
+                return Ok(Some(start..field_end));
+            }
+            start = field_end;
+        }
+
+        Ok(None)
+    }
 }
 
 impl<T: AsMut<[u8]>> AsMut<[u8]> for SuperBlock<T> {
@@ -385,6 +492,18 @@ impl<T: AsMut<[u8]>> AsMut<[u8]> for SuperBlock<T> {
 }
 
 impl<T: AsMut<[u8]>> SuperBlock<T> {
+    /// Set the checksum of this superblock from the low and high u64
+    pub fn set_csum(&mut self, csum: [u64; 2]) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < sb_offsets::CSUM.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u64(&mut buf[0..8], csum[0]);
+            LittleEndian::write_u64(&mut buf[8..16], csum[1]);
+            Ok(())
+        }
+    }
+
     /// Set the version used
     pub fn set_version(&mut self, version: u16) -> Result<()> {
         let buf = self.buffer.as_mut();
@@ -510,6 +629,20 @@ impl<T: AsMut<[u8]>> SuperBlock<T> {
         }
     }
 
+    /// Set the number of u64s in the variable TLV of fields directly
+    ///
+    /// Unlike `set_u64s` this does not depend on the fields added through this
+    /// view, for superblocks read back from a device.
+    pub(crate) fn set_fields_u64s(&mut self, u64s: u32) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < sb_offsets::U64S.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u32(&mut buf[sb_offsets::U64S], u64s);
+            Ok(())
+        }
+    }
+
     /// Set the number of devices
     pub fn set_nr_devices(&mut self, val: u8) -> Result<()> {
         let buf = self.buffer.as_mut();
@@ -521,6 +654,28 @@ impl<T: AsMut<[u8]>> SuperBlock<T> {
         }
     }
 
+    /// Set the low bits of the time base
+    pub fn set_time_base_lo(&mut self, val: u64) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < sb_offsets::TIME_BASE_LO.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u64(&mut buf[sb_offsets::TIME_BASE_LO], val);
+            Ok(())
+        }
+    }
+
+    /// Set the high bits of the time base
+    pub fn set_time_base_hi(&mut self, val: u32) -> Result<()> {
+        let buf = self.buffer.as_mut();
+        if buf.len() < sb_offsets::TIME_BASE_HI.end {
+            Err(BchError::Exhausted)
+        } else {
+            LittleEndian::write_u32(&mut buf[sb_offsets::TIME_BASE_HI], val);
+            Ok(())
+        }
+    }
+
     /// Set the time precision
     pub fn set_time_base_p(&mut self, val: u32) -> Result<()> {
         let buf = self.buffer.as_mut();
@@ -746,6 +901,8 @@ impl<T: AsMut<[u8]>> SuperBlockLayout<T> {
 pub struct MemberFlag(usize, Range<u64>);
 
 impl MemberFlag {
+    /// Bitmask for the state of the member device
+    pub const STATE: MemberFlag = MemberFlag(0, 0..4);
     /// Bitmask for replacement type for member device
     pub const REPLACEMENT: MemberFlag = MemberFlag(0, 10..14);
     // FIXME: fix this doc
@@ -783,6 +940,64 @@ impl<T: AsMut<[u8]>> AsMut<[u8]> for MemberField<T> {
     }
 }
 
+impl<T: AsRef<[u8]>> MemberField<T> {
+    /// The uuid of this member device, nil for an unused slot
+    pub fn uuid(&self) -> Result<Uuid> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < member_offsets::UUID.end {
+            Err(BchError::Exhausted)
+        } else {
+            let uuid = LittleEndian::read_u128(&buf[member_offsets::UUID]);
+            Ok(Uuid::from_u128_le(uuid))
+        }
+    }
+
+    /// The number of buckets of this member device
+    pub fn n_buckets(&self) -> Result<u64> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < member_offsets::N_BUCKETS.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u64(&buf[member_offsets::N_BUCKETS]))
+        }
+    }
+
+    /// The first usable bucket of this member device
+    pub fn first_bucket(&self) -> Result<u16> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < member_offsets::FIRST_BUCKET.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u16(&buf[member_offsets::FIRST_BUCKET]))
+        }
+    }
+
+    /// The bucket size of this member device in sectors
+    pub fn bucket_size(&self) -> Result<u16> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < member_offsets::BUCKET_SIZE.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok(LittleEndian::read_u16(&buf[member_offsets::BUCKET_SIZE]))
+        }
+    }
+
+    /// The value of the given member flag
+    pub fn flag(&self, flag: MemberFlag) -> Result<u64> {
+        let max = (1 << (flag.1.end - flag.1.start)) - 1;
+
+        let buf = self.buffer.as_ref();
+        let start = member_offsets::FLAGS.start + (flag.0 * 8);
+        let range = start..(start + 8);
+
+        if buf.len() < range.end || member_offsets::FLAGS.end < range.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok((LittleEndian::read_u64(&buf[range]) >> flag.1.start) & max)
+        }
+    }
+}
+
 impl<T: AsMut<[u8]>> MemberField<T> {
     /// Set the uuid for this member device
     pub fn set_uuid(&mut self, uuid: Uuid) -> Result<()> {
@@ -851,16 +1066,29 @@ impl<T: AsMut<[u8]>> MemberField<T> {
 }
 
 /// A superblock flag bitmask
+#[derive(Debug, Clone)]
 pub struct SuperBlockFlag(usize, Range<u64>);
 
 impl SuperBlockFlag {
     // index 0
+    /// Bitmask for the filesystem having been initialized
+    pub const INITIALIZED: SuperBlockFlag = SuperBlockFlag(0, 0..1);
+    /// Bitmask for the filesystem having been cleanly shut down
+    pub const CLEAN: SuperBlockFlag = SuperBlockFlag(0, 1..2);
+    /// Bitmask for the checksum type of the superblock
+    pub const CSUM_TYPE: SuperBlockFlag = SuperBlockFlag(0, 2..8);
     /// Bitmask for action to take on error
     pub const ERROR_ACTION: SuperBlockFlag = SuperBlockFlag(0, 8..12);
     /// Bitmask for btree node size
     pub const BTREE_NODE_SIZE: SuperBlockFlag = SuperBlockFlag(0, 12..28);
     /// Bitmask for percentage of gc reserve
     pub const GC_RESERVE: SuperBlockFlag = SuperBlockFlag(0, 28..33);
+    /// Bitmask for percentage of the root reserve
+    pub const ROOT_RESERVE: SuperBlockFlag = SuperBlockFlag(0, 33..40);
+    /// Bitmask for the checksum type of metadata
+    pub const META_CSUM_TYPE: SuperBlockFlag = SuperBlockFlag(0, 40..44);
+    /// Bitmask for the checksum type of data
+    pub const DATA_CSUM_TYPE: SuperBlockFlag = SuperBlockFlag(0, 44..48);
     /// Bitmask for number of metadata replicas wanted
     pub const META_REPLICAS_WANT: SuperBlockFlag = SuperBlockFlag(0, 48..52);
     /// Bitmask for number of data replicas wanted
@@ -870,10 +1098,18 @@ impl SuperBlockFlag {
     /// Bitmask for user quota flag
     pub const USRQUOTA: SuperBlockFlag = SuperBlockFlag(0, 57..58);
     /// Bitmask for group quota flag
-    pub const GRPQUOTA: SuperBlockFlag = SuperBlockFlag(0, 57..58);
+    pub const GRPQUOTA: SuperBlockFlag = SuperBlockFlag(0, 58..59);
     /// Bitmask for project quota flag
-    pub const PRJQUOTA: SuperBlockFlag = SuperBlockFlag(0, 57..58);
+    pub const PRJQUOTA: SuperBlockFlag = SuperBlockFlag(0, 59..60);
     // index 1
+    /// Bitmask for the hash type of directory entries and xattrs
+    pub const STR_HASH_TYPE: SuperBlockFlag = SuperBlockFlag(1, 0..4);
+    /// Bitmask for the foreground compression type
+    pub const COMPRESSION_TYPE: SuperBlockFlag = SuperBlockFlag(1, 4..8);
+    /// Bitmask for limiting inode numbers to 32 bits
+    pub const INODE_32BIT: SuperBlockFlag = SuperBlockFlag(1, 8..9);
+    /// Bitmask for the encryption type
+    pub const ENCRYPTION_TYPE: SuperBlockFlag = SuperBlockFlag(1, 10..14);
     /// Bitmask for number of metadata replicas required
     pub const META_REPLICAS_REQ: SuperBlockFlag = SuperBlockFlag(1, 20..24);
     /// Bitmask for number of data replicas required
@@ -885,8 +1121,22 @@ impl SuperBlockFlag {
     /// Bitmask for the background target device index
     pub const BACKGROUND_TARGET: SuperBlockFlag = SuperBlockFlag(1, 52..64);
     // index 2
-    /// Bitmask for the background target device index
+    /// Bitmask for the background compression type
+    pub const BACKGROUND_COMPRESSION_TYPE: SuperBlockFlag = SuperBlockFlag(2, 0..4);
+    // index 3
+    /// Bitmask for erasure coding
+    pub const ERASURE_CODE: SuperBlockFlag = SuperBlockFlag(3, 0..16);
+    /// Bitmask for the metadata target device index
     pub const METADATA_TARGET: SuperBlockFlag = SuperBlockFlag(3, 16..28);
+    /// Bitmask for sharding inode numbers by CPU
+    pub const SHARD_INODES: SuperBlockFlag = SuperBlockFlag(3, 28..29);
+    /// Bitmask for the journal flush delay in milliseconds
+    pub const JOURNAL_FLUSH_DELAY: SuperBlockFlag = SuperBlockFlag(3, 30..62);
+    /// Bitmask for disabling journal flushes
+    pub const JOURNAL_FLUSH_DISABLED: SuperBlockFlag = SuperBlockFlag(3, 62..63);
+    // index 4
+    /// Bitmask for the journal reclaim delay in milliseconds
+    pub const JOURNAL_RECLAIM_DELAY: SuperBlockFlag = SuperBlockFlag(4, 0..32);
 }
 
 /// A set of superblock flags
@@ -913,6 +1163,23 @@ impl<T: AsMut<[u8]>> AsMut<[u8]> for SuperBlockFlags<T> {
     }
 }
 
+impl<T: AsRef<[u8]>> SuperBlockFlags<T> {
+    /// Get the value of the given superblock flag
+    pub fn flag(&self, flag: SuperBlockFlag) -> Result<u64> {
+        let max = (1 << (flag.1.end - flag.1.start)) - 1;
+
+        let buf = self.buffer.as_ref();
+        let start = flag.0 * 8;
+        let range = start..(start + 8);
+
+        if buf.len() < range.end {
+            Err(BchError::Exhausted)
+        } else {
+            Ok((LittleEndian::read_u64(&buf[range]) >> flag.1.start) & max)
+        }
+    }
+}
+
 impl<T: AsMut<[u8]>> SuperBlockFlags<T> {
     /// Set the given superblock flag with the given value
     pub fn set_flag(&mut self, flag: SuperBlockFlag, val: u64) -> Result<()> {
@@ -0,0 +1,436 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+use crate::block_dev::{is_mounted, open_device, BlockDevice};
+use crate::checksum::{checksum, CsumType, Nonce};
+use crate::super_block::{
+    magic, Field, MemberField, SuperBlock, SuperBlockFlag, SuperBlockLayout, MEMBER_BYTES,
+    SB_HEADER_BYTES,
+};
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::debug;
+use uuid::Uuid;
+
+/// The sector of the first superblock
+pub(crate) const SB_SECTOR: u64 = 8;
+/// The sector of the backup superblock layout
+pub(crate) const LAYOUT_SECTOR: u64 = 7;
+/// The size of the superblock layout
+const LAYOUT_BYTES: usize = 512;
+
+/// Compute the checksum of a superblock with the checksum type it names
+pub fn sb_csum(sb: &[u8]) -> Result<[u64; 2]> {
+    let view = SuperBlock::from(sb);
+    let ty = CsumType::try_from(view.flags()?.flag(SuperBlockFlag::CSUM_TYPE)?)?;
+    let end = view.bytes()?;
+
+    if ty.is_encryption() {
+        return Err(BchError::Str(format!(
+            "superblock has an encryption checksum type: {}",
+            ty
+        )));
+    } else if sb.len() < end {
+        return Err(BchError::Exhausted);
+    }
+
+    // everything after the checksum itself is covered
+    checksum(ty, None, Nonce::default(), &sb[16..end])
+}
+
+/// Read and verify the superblock at the given sector
+fn read_super_at(dev: &dyn BlockDevice, sector: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; SB_HEADER_BYTES];
+    dev.read_at(&mut buf, sector << 9)?;
+
+    let sb = SuperBlock::from(&buf[..]);
+    if sb.magic()? != magic() {
+        return Err(BchError::Str(format!(
+            "no bcachefs superblock at sector {}",
+            sector
+        )));
+    }
+
+    let bytes = sb.bytes()?;
+    let max_bytes = 512usize << sb.layout()?.sb_max_size()?;
+    if bytes > max_bytes {
+        return Err(BchError::Str(format!(
+            "superblock at sector {} too big: {} > {}",
+            sector, bytes, max_bytes
+        )));
+    }
+
+    buf.resize(bytes, 0);
+    dev.read_at(
+        &mut buf[SB_HEADER_BYTES..],
+        (sector << 9) + SB_HEADER_BYTES as u64,
+    )?;
+
+    let sb = SuperBlock::from(&buf[..]);
+    if sb.csum()? != sb_csum(&buf)? {
+        return Err(BchError::Str(format!(
+            "bad checksum for superblock at sector {}",
+            sector
+        )));
+    }
+
+    Ok(buf)
+}
+
+/// Read the superblock of a device
+///
+/// The default superblock is tried first, falling back to the copies listed in
+/// the backup layout if it is missing or corrupt.
+pub fn read_super(dev: &dyn BlockDevice) -> Result<Vec<u8>> {
+    let err = match read_super_at(dev, SB_SECTOR) {
+        Ok(sb) => return Ok(sb),
+        Err(e) => e,
+    };
+    debug!("superblock at sector {}: {}", SB_SECTOR, err);
+
+    let mut layout_buf = [0u8; LAYOUT_BYTES];
+    dev.read_at(&mut layout_buf, LAYOUT_SECTOR << 9)?;
+    let layout = SuperBlockLayout::from(&layout_buf[..]);
+    if layout.magic()? != magic() {
+        return Err(err);
+    }
+
+    for i in 0..layout.nr_superblocks()? as usize {
+        let offset = layout.sb_offset(i)?;
+        if offset == SB_SECTOR {
+            continue;
+        }
+        match read_super_at(dev, offset) {
+            Ok(sb) => return Ok(sb),
+            Err(e) => debug!("superblock at sector {}: {}", offset, e),
+        }
+    }
+
+    Err(err)
+}
+
+/// Write every copy of the superblock listed in its layout
+///
+/// The offset and checksum of each copy are updated before it is written.
+pub fn write_super(dev: &dyn BlockDevice, sb: &mut [u8]) -> Result<()> {
+    let view = SuperBlock::from(&*sb);
+    let bytes = view.bytes()?;
+    let layout = view.layout()?;
+    let max_bytes = 512usize << layout.sb_max_size()?;
+    let offsets = (0..layout.nr_superblocks()? as usize)
+        .map(|i| layout.sb_offset(i))
+        .collect::<Result<Vec<_>>>()?;
+
+    if bytes > max_bytes {
+        return Err(BchError::Str(format!(
+            "superblock too big: {} > {}",
+            bytes, max_bytes
+        )));
+    }
+
+    for offset in offsets {
+        SuperBlock::from(&mut *sb).set_offset(offset)?;
+        let csum = sb_csum(sb)?;
+        SuperBlock::from(&mut *sb).set_csum(csum)?;
+
+        debug!("writing superblock at sector {}", offset);
+        dev.write_at(&sb[..bytes], offset << 9)?;
+
+        if offset == SB_SECTOR {
+            let view = SuperBlock::from(&*sb);
+            let layout = view.layout()?;
+            dev.write_at(&layout.as_ref()[..LAYOUT_BYTES], LAYOUT_SECTOR << 9)?;
+        }
+    }
+
+    dev.flush()
+}
+
+/// The uuids of the members in use in the members field of a superblock
+pub fn member_uuids(sb: &[u8]) -> Result<Vec<Option<Uuid>>> {
+    let view = SuperBlock::from(sb);
+    let members = match view.field(Field::Members)? {
+        Some(members) => members,
+        None => return Err(BchError::Str("superblock has no members".to_string())),
+    };
+
+    members
+        .chunks_exact(MEMBER_BYTES)
+        .map(|member| {
+            let uuid = MemberField::from(member).uuid()?;
+            Ok(if uuid.is_nil() { None } else { Some(uuid) })
+        })
+        .collect()
+}
+
+/// Replace the body of a field of a superblock, appending it if missing
+///
+/// The body is zero padded to a multiple of 8 bytes and the superblock buffer
+/// is resized to the new size of the superblock.
+pub fn set_field(sb: &mut Vec<u8>, ty: Field, body: &[u8]) -> Result<()> {
+    let view = SuperBlock::from(&sb[..]);
+    let end = view.bytes()?;
+    let range = view.field_range(ty)?.unwrap_or(end..end);
+
+    let u64s = body.chunks(8).count() + 1;
+    let mut field = vec![0u8; 8];
+    LittleEndian::write_u32(&mut field[0..4], u64s as u32);
+    LittleEndian::write_u32(&mut field[4..8], ty as u32);
+    field.extend_from_slice(body);
+    field.resize(u64s * 8, 0);
+
+    sb.truncate(end);
+    sb.splice(range, field);
+
+    let u64s = (sb.len() - SB_HEADER_BYTES) / 8;
+    SuperBlock::from(&mut sb[..]).set_fields_u64s(u64s as u32)
+}
+
+/// Remove a field from a superblock, if present
+///
+/// The superblock buffer is resized to the new size of the superblock.
+pub fn remove_field(sb: &mut Vec<u8>, ty: Field) -> Result<()> {
+    let view = SuperBlock::from(&sb[..]);
+    let end = view.bytes()?;
+    let range = match view.field_range(ty)? {
+        Some(range) => range,
+        None => return Ok(()),
+    };
+
+    sb.truncate(end);
+    sb.drain(range);
+
+    let u64s = (sb.len() - SB_HEADER_BYTES) / 8;
+    SuperBlock::from(&mut sb[..]).set_fields_u64s(u64s as u32)
+}
+
+/// A member device of an unmounted filesystem
+pub struct Member {
+    /// The path the device was opened from
+    pub path: String,
+    /// The device
+    pub dev: Box<dyn BlockDevice>,
+    /// The superblock read from the device
+    pub sb: Vec<u8>,
+}
+
+impl Member {
+    /// A view of the superblock of the member
+    pub fn sb(&self) -> SuperBlock<&[u8]> {
+        SuperBlock::from(&self.sb[..])
+    }
+
+    /// A mutable view of the superblock of the member
+    pub fn sb_mut(&mut self) -> SuperBlock<&mut [u8]> {
+        SuperBlock::from(&mut self.sb[..])
+    }
+}
+
+/// Open every member of an unmounted filesystem
+///
+/// All members of the filesystem must be given. When opened for writing,
+/// mounted devices are refused.
+pub fn open_members(paths: &[String], writable: bool) -> Result<Vec<Member>> {
+    let members = open_members_partial(paths, writable)?;
+    check_complete(&members, None)?;
+    Ok(members)
+}
+
+/// Open the given members of an unmounted filesystem
+///
+/// Members of the filesystem may be missing, the caller is expected to check
+/// for them with `check_complete`.
+pub(crate) fn open_members_partial(paths: &[String], writable: bool) -> Result<Vec<Member>> {
+    let mut members = Vec::new();
+
+    for path in paths.iter() {
+        if writable && is_mounted(path)? {
+            return Err(BchError::Str(format!("{}: filesystem is mounted", path)));
+        }
+
+        let dev = open_device(path, writable)?;
+        let sb = read_super(dev.as_ref()).map_err(|e| BchError::Str(format!("{}: {}", path, e)))?;
+
+        members.push(Member {
+            path: path.clone(),
+            dev,
+            sb,
+        });
+    }
+
+    check_members(&members)?;
+    Ok(members)
+}
+
+/// Check that the given devices are distinct members of the same filesystem
+fn check_members(members: &[Member]) -> Result<()> {
+    let first = match members.first() {
+        Some(first) => first,
+        None => return Err(BchError::Str("no devices given".to_string())),
+    };
+    let uuid = first.sb().uuid()?;
+    let mut seen = HashSet::new();
+
+    for member in members.iter() {
+        let sb = member.sb();
+        let idx = sb.device_index()?;
+
+        if sb.uuid()? != uuid {
+            return Err(BchError::Str(format!(
+                "{} is not a member of filesystem {}",
+                member.path, uuid
+            )));
+        }
+        if !seen.insert(idx) {
+            return Err(BchError::Str(format!(
+                "{}: member {} given more than once",
+                member.path, idx
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that every member of the filesystem, except `missing`, is given
+pub(crate) fn check_complete(members: &[Member], missing: Option<u8>) -> Result<()> {
+    let first = match members.first() {
+        Some(first) => first,
+        None => return Err(BchError::Str("no devices given".to_string())),
+    };
+    let uuid = first.sb().uuid()?;
+    let seen = members
+        .iter()
+        .map(|member| member.sb().device_index())
+        .collect::<Result<HashSet<_>>>()?;
+
+    for (idx, member) in member_uuids(&first.sb)?.iter().enumerate() {
+        if let Some(member_uuid) = member {
+            if !seen.contains(&(idx as u8)) && missing != Some(idx as u8) {
+                return Err(BchError::Str(format!(
+                    "member {} ({}) of filesystem {} not given",
+                    idx, member_uuid, uuid
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Wipe every copy of the superblock of a device along with its layout
+pub(crate) fn wipe_super(dev: &dyn BlockDevice, sb: &[u8]) -> Result<()> {
+    let view = SuperBlock::from(sb);
+    let layout = view.layout()?;
+    let zeros = vec![0u8; cmp::max(view.bytes()?, LAYOUT_BYTES)];
+
+    for i in 0..layout.nr_superblocks()? as usize {
+        let offset = layout.sb_offset(i)?;
+        debug!("wiping superblock at sector {}", offset);
+        dev.write_at(&zeros, offset << 9)?;
+    }
+    dev.write_at(&zeros[..LAYOUT_BYTES], LAYOUT_SECTOR << 9)?;
+
+    dev.flush()
+}
+
+/// Write back the superblocks of all members
+///
+/// The sequence number is bumped past the newest superblock so the kernel
+/// picks up the new copies.
+pub fn write_members(members: &mut [Member]) -> Result<()> {
+    let seq = members
+        .iter()
+        .map(|member| member.sb().seq())
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    for member in members.iter_mut() {
+        debug!("{}: writing superblock seq={}", member.path, seq);
+        member.sb_mut().set_seq(seq)?;
+        write_super(member.dev.as_ref(), &mut member.sb)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_super_io {
+    use super::*;
+    use crate::block_dev::MemoryDevice;
+    use crate::super_block::SuperBlockFlags;
+
+    /// Build a minimal superblock with two copies
+    fn build_sb(csum_type: CsumType) -> Vec<u8> {
+        let mut layout_buf = [0u8; LAYOUT_BYTES];
+        let mut layout = SuperBlockLayout::from(&mut layout_buf[..]);
+        layout.set_magic().unwrap();
+        layout.set_sb_max_size(3).unwrap();
+        layout.set_nr_superblocks(2).unwrap();
+        layout.set_sb_offset(0, SB_SECTOR).unwrap();
+        layout.set_sb_offset(1, SB_SECTOR + 8).unwrap();
+
+        let mut flags_buf = [0u8; 64];
+        let mut flags = SuperBlockFlags::from(&mut flags_buf[..]);
+        flags
+            .set_flag(SuperBlockFlag::CSUM_TYPE, csum_type as u64)
+            .unwrap();
+
+        let mut buf = vec![0u8; SB_HEADER_BYTES + 8 + MEMBER_BYTES];
+        let mut sb = SuperBlock::from(&mut buf[..]);
+        sb.set_magic().unwrap();
+        sb.set_uuid(Uuid::new_v4()).unwrap();
+        sb.set_flags(&flags).unwrap();
+        sb.set_layout(&layout).unwrap();
+        sb.add_field(Field::Members, &[0x01; MEMBER_BYTES][..])
+            .unwrap();
+        sb.set_u64s().unwrap();
+        buf
+    }
+
+    #[test]
+    fn write_read_roundtrip() {
+        let dev = MemoryDevice::new(1 << 16);
+        let mut sb = build_sb(CsumType::Crc32cNonzero);
+        write_super(&dev, &mut sb).unwrap();
+
+        let read = read_super(&dev).unwrap();
+        let read_sb = SuperBlock::from(&read[..]);
+        assert_eq!(read_sb.offset().unwrap(), SB_SECTOR);
+        assert_eq!(
+            read_sb.uuid().unwrap(),
+            SuperBlock::from(&sb[..]).uuid().unwrap()
+        );
+        assert_ne!(read_sb.csum().unwrap(), [0, 0]);
+        assert_eq!(member_uuids(&read).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn corrupt_primary() {
+        let dev = MemoryDevice::new(1 << 16);
+        let mut sb = build_sb(CsumType::Crc64);
+        write_super(&dev, &mut sb).unwrap();
+
+        // flip a byte of the label in the primary superblock
+        dev.write_at(b"x", (SB_SECTOR << 9) + 80).unwrap();
+
+        let read = read_super(&dev).unwrap();
+        assert_eq!(SuperBlock::from(&read[..]).offset().unwrap(), SB_SECTOR + 8);
+
+        // without a layout there is nothing to fall back to
+        dev.write_at(&[0u8; LAYOUT_BYTES], LAYOUT_SECTOR << 9)
+            .unwrap();
+        assert!(read_super(&dev).is_err());
+    }
+
+    #[test]
+    fn no_superblock() {
+        let dev = MemoryDevice::new(1 << 16);
+        assert!(read_super(&dev).is_err());
+    }
+}
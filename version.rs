@@ -0,0 +1,211 @@
+use std::fmt;
+
+use crate::super_block::{Features, SuperBlock};
+use crate::super_io::{open_members, write_members, Member};
+use crate::{BchError, Result};
+
+use log::info;
+
+/// The oldest metadata version understood
+pub(crate) const METADATA_VERSION_MIN: u16 = 9;
+/// The maximum metadata version
+pub(crate) const METADATA_VERSION_MAX: u16 = 14;
+/// The current metadata version
+pub(crate) const METADATA_VERSION_CURRENT: u16 = METADATA_VERSION_MAX - 1;
+
+/// The metadata versions understood, their names and whether the kernel
+/// converts the metadata of older filesystems when mounting them
+const VERSIONS: [(u16, &str, bool); 5] = [
+    (METADATA_VERSION_MIN, "min", false),
+    (10, "bkey_renumber", false),
+    (11, "inode_btree_change", true),
+    (12, "snapshot", true),
+    (13, "inode_backpointers", true),
+];
+
+/// The name of a metadata version understood
+pub fn version_name(version: u16) -> Result<&'static str> {
+    VERSIONS
+        .iter()
+        .find(|(v, _, _)| *v == version)
+        .map(|(_, name, _)| *name)
+        .ok_or_else(|| BchError::Einval(format!("unknown metadata version {}", version)))
+}
+
+/// The changes to make to the superblock to move it to another version
+#[derive(Debug, PartialEq)]
+pub struct VersionPlan {
+    /// The current version and minimum version
+    pub from: (u16, u16),
+    /// The new version and minimum version
+    pub to: (u16, u16),
+    /// The features to add
+    pub features: Features,
+}
+
+impl fmt::Display for VersionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "version:     {} ({}) -> {} ({})",
+            self.from.0,
+            version_name(self.from.0).unwrap_or("unknown"),
+            self.to.0,
+            version_name(self.to.0).unwrap_or("unknown")
+        )?;
+        writeln!(f, "version_min: {} -> {}", self.from.1, self.to.1)?;
+        if self.features.is_empty() {
+            writeln!(f, "features:    unchanged")
+        } else {
+            writeln!(f, "features:    +{:?}", self.features)
+        }
+    }
+}
+
+/// Plan moving a superblock to the given metadata version
+///
+/// Versions whose metadata the kernel converts when mounting, along with an
+/// fsck, cannot be reached offline: those upgrades are left to the kernel's
+/// `version_upgrade` mount option. As the kernel does, moving to the current
+/// version adds every feature bit. The minimum version is never raised since
+/// the metadata already written is left as it is, versions may only be
+/// lowered down to it, and feature bits are never cleared.
+pub(crate) fn plan_version(sb: &[u8], to: u16) -> Result<VersionPlan> {
+    let view = SuperBlock::from(sb);
+    let from = (view.version()?, view.version_min()?);
+    version_name(from.0)?;
+    version_name(from.1)?;
+    version_name(to)?;
+
+    let bits = view.features()?[0];
+    let features = Features::from_bits(bits).ok_or_else(|| {
+        BchError::Str(format!(
+            "unknown features 0x{:x}",
+            bits & !Features::all().bits()
+        ))
+    })?;
+
+    if to < from.1 {
+        return Err(BchError::Einval(format!(
+            "cannot downgrade to version {} below the minimum version {}",
+            to, from.1
+        )));
+    }
+    if let Some((_, name, _)) = VERSIONS
+        .iter()
+        .find(|(v, _, converts)| *converts && *v > from.0 && *v <= to)
+    {
+        return Err(BchError::Einval(format!(
+            "upgrading past version {} needs the kernel to convert the metadata, \
+             mount with -o version_upgrade instead",
+            name
+        )));
+    }
+
+    Ok(VersionPlan {
+        from,
+        to: (to, from.1),
+        features: if to == METADATA_VERSION_CURRENT {
+            Features::ALL - features
+        } else {
+            Features::empty()
+        },
+    })
+}
+
+/// Apply a version plan to the superblock of every member
+fn apply_version(members: &mut [Member], plan: &VersionPlan) -> Result<()> {
+    for member in members.iter_mut() {
+        let features = Features::from_bits_truncate(member.sb().features()?[0]);
+        let mut sb = member.sb_mut();
+        sb.set_version(plan.to.0)?;
+        sb.set_version_min(plan.to.1)?;
+        sb.set_feature(0, features | plan.features)?;
+    }
+    Ok(())
+}
+
+/// Arguments that the upgrade subcommand may be provided.
+#[derive(Debug)]
+pub struct UpgradeArgs {
+    /// The version to move to, the current version if not given
+    pub to: Option<u16>,
+    /// Only show the changes that would be made
+    pub dry_run: bool,
+    /// The devices of the filesystem
+    pub devices: Vec<String>,
+}
+
+/// Upgrade or downgrade the metadata version of an unmounted filesystem
+pub fn upgrade(args: UpgradeArgs) -> Result<()> {
+    let mut members = open_members(&args.devices, !args.dry_run)?;
+    let plan = plan_version(&members[0].sb, args.to.unwrap_or(METADATA_VERSION_CURRENT))?;
+    print!("{}", plan);
+
+    if args.dry_run || (plan.from == plan.to && plan.features.is_empty()) {
+        return Ok(());
+    }
+
+    apply_version(&mut members, &plan)?;
+    write_members(&mut members)?;
+    info!("metadata version set to {}", plan.to.0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_version {
+    use super::*;
+    use crate::format::test_format::format_members;
+
+    fn downgraded(version: u16) -> Vec<Member> {
+        let mut members = format_members(&["mem0", "mem1"]);
+        for member in members.iter_mut() {
+            let mut sb = member.sb_mut();
+            sb.set_version(version).unwrap();
+            sb.set_version_min(version).unwrap();
+            sb.set_feature(0, Features::empty()).unwrap();
+        }
+        members
+    }
+
+    #[test]
+    fn upgrade_without_conversion() {
+        let mut members = downgraded(METADATA_VERSION_MIN);
+        let plan = plan_version(&members[0].sb, 10).unwrap();
+        assert_eq!(plan.to, (10, METADATA_VERSION_MIN));
+        assert!(plan.features.is_empty());
+
+        apply_version(&mut members, &plan).unwrap();
+        for member in members.iter() {
+            let sb = member.sb();
+            assert_eq!(sb.version().unwrap(), 10);
+            assert_eq!(sb.version_min().unwrap(), METADATA_VERSION_MIN);
+            assert_eq!(sb.features().unwrap()[0], 0);
+        }
+        assert_eq!(
+            plan_version(&members[0].sb, METADATA_VERSION_MIN)
+                .unwrap()
+                .to,
+            (METADATA_VERSION_MIN, METADATA_VERSION_MIN)
+        );
+        assert!(plan_version(&members[0].sb, METADATA_VERSION_MIN - 1).is_err());
+        assert!(plan_version(&members[0].sb, METADATA_VERSION_MAX).is_err());
+    }
+
+    #[test]
+    fn upgrade_needing_conversion() {
+        let members = downgraded(10);
+        for to in 11..=METADATA_VERSION_CURRENT {
+            assert!(plan_version(&members[0].sb, to).is_err());
+        }
+
+        // at the current version only the feature bits are added
+        let members = downgraded(METADATA_VERSION_CURRENT);
+        let plan = plan_version(&members[0].sb, METADATA_VERSION_CURRENT).unwrap();
+        assert_eq!(
+            plan.to,
+            (METADATA_VERSION_CURRENT, METADATA_VERSION_CURRENT)
+        );
+        assert_eq!(plan.features, Features::ALL);
+    }
+}
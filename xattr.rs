@@ -0,0 +1,242 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::bkey::{KeyType, OwnedKey};
+use crate::btree::BtreeId;
+use crate::btree_iter::Btrees;
+use crate::dirent::ResolvedInode;
+use crate::{BchError, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The size of the fixed part of an xattr value
+pub const XATTR_BYTES: usize = 4;
+
+/// The offsets of the fixed part of an xattr value
+#[allow(missing_docs)]
+pub mod xattr_offsets {
+    use std::ops::Range;
+
+    pub const TYPE: usize = 0;
+    pub const NAME_LEN: usize = 1;
+    pub const VAL_LEN: Range<usize> = 2..4;
+}
+
+/// The version of the ACLs stored by bcachefs
+const BCH_ACL_VERSION: u32 = 1;
+/// The version of the ACLs of the system.posix_acl_* xattrs
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+/// The id of the ACL entries without one
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+/// The tag of the ACL entries of named users
+const ACL_USER: u16 = 0x02;
+/// The tag of the ACL entries of named groups
+const ACL_GROUP: u16 = 0x08;
+
+/// The namespaces an xattr may be in
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum XattrType {
+    /// user.
+    User = 0,
+    /// system.posix_acl_access
+    PosixAclAccess = 1,
+    /// system.posix_acl_default
+    PosixAclDefault = 2,
+    /// trusted.
+    Trusted = 3,
+    /// security.
+    Security = 4,
+}
+
+impl XattrType {
+    /// The prefix of the names of the namespace
+    pub fn prefix(self) -> &'static str {
+        match self {
+            XattrType::User => "user.",
+            XattrType::PosixAclAccess => "system.posix_acl_access",
+            XattrType::PosixAclDefault => "system.posix_acl_default",
+            XattrType::Trusted => "trusted.",
+            XattrType::Security => "security.",
+        }
+    }
+}
+
+impl TryFrom<u8> for XattrType {
+    type Error = BchError;
+
+    fn try_from(ty: u8) -> Result<XattrType> {
+        match ty {
+            0 => Ok(XattrType::User),
+            1 => Ok(XattrType::PosixAclAccess),
+            2 => Ok(XattrType::PosixAclDefault),
+            3 => Ok(XattrType::Trusted),
+            4 => Ok(XattrType::Security),
+            _ => Err(BchError::Einval(format!("unknown xattr type {}", ty))),
+        }
+    }
+}
+
+/// An extended attribute
+#[derive(Debug, Clone, PartialEq)]
+pub struct Xattr {
+    /// The namespace
+    pub ty: XattrType,
+    /// The name within the namespace
+    pub name: Vec<u8>,
+    /// The value, as stored
+    pub value: Vec<u8>,
+}
+
+/// Convert an ACL as stored by bcachefs to the posix ACL xattr format
+///
+/// bcachefs leaves out the ids of the entries that have none.
+fn acl_to_xattr(acl: &[u8]) -> Result<Vec<u8>> {
+    if acl.len() < 4 || LittleEndian::read_u32(&acl[..4]) != BCH_ACL_VERSION {
+        return Err(BchError::Einval("invalid ACL header".to_string()));
+    }
+
+    let mut xattr = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    let mut acl = &acl[4..];
+    while !acl.is_empty() {
+        let entry = acl.get(..4).ok_or(BchError::Exhausted)?;
+        let tag = LittleEndian::read_u16(&entry[..2]);
+        let (id, len) = match tag {
+            ACL_USER | ACL_GROUP => (
+                LittleEndian::read_u32(acl.get(4..8).ok_or(BchError::Exhausted)?),
+                8,
+            ),
+            _ => (ACL_UNDEFINED_ID, 4),
+        };
+        xattr.extend_from_slice(entry);
+        xattr.extend_from_slice(&id.to_le_bytes());
+        acl = &acl[len..];
+    }
+    Ok(xattr)
+}
+
+impl Xattr {
+    /// Decode an xattr key
+    pub fn decode(key: &OwnedKey) -> Result<Xattr> {
+        let ty = KeyType::try_from(key.ty())?;
+        if ty != KeyType::Xattr {
+            return Err(BchError::Einval(format!("{} key is not an xattr", ty)));
+        }
+        let val = &key.val[..];
+        if val.len() < XATTR_BYTES {
+            return Err(BchError::Exhausted);
+        }
+
+        let name_end = XATTR_BYTES + val[xattr_offsets::NAME_LEN] as usize;
+        let val_end = name_end + LittleEndian::read_u16(&val[xattr_offsets::VAL_LEN]) as usize;
+        if val.len() < val_end {
+            return Err(BchError::Exhausted);
+        }
+        Ok(Xattr {
+            ty: XattrType::try_from(val[xattr_offsets::TYPE])?,
+            name: val[XATTR_BYTES..name_end].to_vec(),
+            value: val[name_end..val_end].to_vec(),
+        })
+    }
+
+    /// The full name of the xattr, namespace included
+    pub fn full_name(&self) -> Vec<u8> {
+        let mut name = self.ty.prefix().as_bytes().to_vec();
+        name.extend_from_slice(&self.name);
+        name
+    }
+
+    /// The value of the xattr as the system call would return it
+    ///
+    /// ACLs are converted from the format bcachefs stores them in.
+    pub fn system_value(&self) -> Result<Vec<u8>> {
+        match self.ty {
+            XattrType::PosixAclAccess | XattrType::PosixAclDefault => acl_to_xattr(&self.value),
+            _ => Ok(self.value.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Xattr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} bytes",
+            String::from_utf8_lossy(&self.full_name()),
+            self.value.len()
+        )
+    }
+}
+
+impl Btrees {
+    /// The xattrs of an inode
+    pub fn xattrs(&self, inode: &ResolvedInode) -> Result<Vec<Xattr>> {
+        self.hashed_keys(BtreeId::Xattrs, inode.inode.inum, inode.snapshot)?
+            .iter()
+            .map(Xattr::decode)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_xattr {
+    use super::*;
+    use crate::bkey::{bkey_format_current, BkeyFormat, Bpos};
+    use crate::btree::test_btree::key;
+
+    /// An xattr key of the given inode, at the given offset
+    pub(crate) fn xattr(
+        inum: u64,
+        offset: u64,
+        ty: XattrType,
+        name: &str,
+        value: &[u8],
+    ) -> Vec<u8> {
+        let mut val = vec![ty as u8, name.len() as u8, 0, 0];
+        LittleEndian::write_u16(&mut val[xattr_offsets::VAL_LEN], value.len() as u16);
+        val.extend_from_slice(name.as_bytes());
+        val.extend_from_slice(value);
+        val.resize(val.len() + (8 - val.len() % 8) % 8, 0);
+        key(KeyType::Xattr, Bpos::new(inum, offset, 0), &val)
+    }
+
+    #[test]
+    fn decode_xattrs() {
+        let format = bkey_format_current();
+        let unpack = |key: Vec<u8>| BkeyFormat::from(&format[..]).unpack_key(&key).unwrap();
+
+        let user =
+            Xattr::decode(&unpack(xattr(4096, 7, XattrType::User, "mime", b"text"))).unwrap();
+        assert_eq!(user.full_name(), b"user.mime");
+        assert_eq!(user.system_value().unwrap(), b"text");
+        assert_eq!(user.to_string(), "user.mime: 4 bytes");
+
+        // user::rw-, user:1000:r--, other::---
+        let acl = [
+            &1u32.to_le_bytes()[..],
+            &[0x01, 0, 6, 0],
+            &[0x02, 0, 4, 0, 0xe8, 0x03, 0, 0],
+            &[0x20, 0, 0, 0],
+        ]
+        .concat();
+        let access =
+            Xattr::decode(&unpack(xattr(4096, 8, XattrType::PosixAclAccess, "", &acl))).unwrap();
+        assert_eq!(access.full_name(), b"system.posix_acl_access");
+        assert_eq!(
+            access.system_value().unwrap(),
+            [
+                &2u32.to_le_bytes()[..],
+                &[0x01, 0, 6, 0, 0xff, 0xff, 0xff, 0xff],
+                &[0x02, 0, 4, 0, 0xe8, 0x03, 0, 0],
+                &[0x20, 0, 0, 0, 0xff, 0xff, 0xff, 0xff],
+            ]
+            .concat()
+        );
+
+        let truncated = Xattr {
+            value: acl[..10].to_vec(),
+            ..access
+        };
+        assert!(truncated.system_value().is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+
+use crate::block_dev::BlockDevice;
+use crate::{BchError, Result};
+
+use log::debug;
+use nix::ioctl_readwrite;
+
+const BLK_IOC_MAGIC: u8 = 0x12;
+const BLKREPORTZONE_IOC_TYPE_MODE: u8 = 130;
+
+/// Number of zones requested from the kernel per BLKREPORTZONE call
+const REPORT_NR_ZONES: usize = 4096;
+
+/// The header of a zone report (`struct blk_zone_report`)
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct BlkZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+/// A zone descriptor as reported by the kernel (`struct blk_zone`)
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    zone_type: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+/// The report sets `capacity` in the zone descriptors
+const BLK_ZONE_REP_CAPACITY: u32 = 1 << 0;
+
+ioctl_readwrite!(
+    blkreportzone,
+    BLK_IOC_MAGIC,
+    BLKREPORTZONE_IOC_TYPE_MODE,
+    BlkZoneReport
+);
+
+/// The zone model of a block device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneModel {
+    /// A regular block device
+    None,
+    /// Zoned, but random writes are still allowed
+    HostAware,
+    /// Zoned, writes to sequential zones must be sequential
+    HostManaged,
+}
+
+impl fmt::Display for ZoneModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoneModel::None => write!(f, "none"),
+            ZoneModel::HostAware => write!(f, "host-aware"),
+            ZoneModel::HostManaged => write!(f, "host-managed"),
+        }
+    }
+}
+
+/// The type of a zone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneType {
+    /// Random writes are allowed
+    Conventional,
+    /// Writes must be sequential at the write pointer
+    SeqWriteRequired,
+    /// Sequential writes are preferred, random writes are allowed
+    SeqWritePreferred,
+}
+
+/// A zone of a zoned block device, all values in sectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Zone {
+    /// The first sector of the zone
+    pub start: u64,
+    /// The length of the zone
+    pub len: u64,
+    /// The number of sectors that may be written in the zone
+    pub capacity: u64,
+    /// The zone type
+    pub zone_type: ZoneType,
+}
+
+/// Read the zone model of a block device from sysfs
+pub(crate) fn zone_model(file: &File) -> Result<ZoneModel> {
+    let meta = file.metadata()?;
+
+    if !meta.file_type().is_block_device() {
+        return Ok(ZoneModel::None);
+    }
+
+    let rdev = meta.st_rdev();
+    let sysfs = format!(
+        "/sys/dev/block/{}:{}",
+        nix::sys::stat::major(rdev),
+        nix::sys::stat::minor(rdev)
+    );
+
+    // partitions share the queue of the whole disk
+    let model = fs::read_to_string(format!("{}/queue/zoned", sysfs))
+        .or_else(|_| fs::read_to_string(format!("{}/../queue/zoned", sysfs)));
+
+    match model.as_ref().map(|s| s.trim()) {
+        Ok("host-aware") => Ok(ZoneModel::HostAware),
+        Ok("host-managed") => Ok(ZoneModel::HostManaged),
+        Ok(_) | Err(_) => Ok(ZoneModel::None),
+    }
+}
+
+/// Report every zone of a zoned block device, in order
+pub(crate) fn report_zones(file: &File) -> Result<Vec<Zone>> {
+    let header_size = std::mem::size_of::<BlkZoneReport>();
+    let zone_size = std::mem::size_of::<BlkZone>();
+    let mut zones = Vec::new();
+    let mut sector = 0;
+
+    loop {
+        // u64 backing store keeps the report suitably aligned
+        let mut buf = vec![0u64; (header_size + REPORT_NR_ZONES * zone_size) / 8];
+        let report = buf.as_mut_ptr() as *mut BlkZoneReport;
+
+        let header = unsafe {
+            *report = BlkZoneReport {
+                sector,
+                nr_zones: REPORT_NR_ZONES as u32,
+                flags: 0,
+            };
+            blkreportzone(file.as_raw_fd(), report)?;
+            *report
+        };
+
+        if header.nr_zones == 0 {
+            break;
+        }
+
+        let descs = unsafe {
+            std::slice::from_raw_parts(
+                (report as *const u8).add(header_size) as *const BlkZone,
+                header.nr_zones as usize,
+            )
+        };
+
+        for desc in descs {
+            let zone_type = match desc.zone_type {
+                1 => ZoneType::Conventional,
+                2 => ZoneType::SeqWriteRequired,
+                3 => ZoneType::SeqWritePreferred,
+                ty => {
+                    return Err(BchError::Str(format!(
+                        "unknown type {} for zone at sector {}",
+                        ty, desc.start
+                    )))
+                }
+            };
+            let capacity = if header.flags & BLK_ZONE_REP_CAPACITY != 0 {
+                desc.capacity
+            } else {
+                desc.len
+            };
+
+            zones.push(Zone {
+                start: desc.start,
+                len: desc.len,
+                capacity,
+                zone_type,
+            });
+            sector = desc.start + desc.len;
+        }
+    }
+
+    Ok(zones)
+}
+
+/// How a filesystem has to be laid out on a zoned device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ZonePlan {
+    /// The zone model of the device
+    pub model: ZoneModel,
+    /// The size of each zone in sectors
+    pub zone_size: u64,
+    /// The number of zones on the device
+    pub nr_zones: u64,
+    /// The number of sectors, from the start of the device, usable by the filesystem
+    pub usable: u64,
+}
+
+impl fmt::Display for ZonePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} zones of {} sectors, {} sectors usable",
+            self.model, self.nr_zones, self.zone_size, self.usable
+        )
+    }
+}
+
+/// Work out how the filesystem has to be laid out on a zoned device.
+///
+/// Returns `None` for regular devices. Host-aware devices may be used as a
+/// whole. On host-managed devices the superblock, the journal and every
+/// bucket are rewritten in place, which sequential write required zones do
+/// not allow. The filesystem is therefore limited to the conventional zones
+/// at the start of the device, which must at least hold the superblock
+/// (`sb_end` sectors).
+pub fn plan_zones<D: BlockDevice + ?Sized>(
+    dev: &D,
+    dev_name: &str,
+    sb_end: u64,
+) -> Result<Option<ZonePlan>> {
+    let model = dev.zone_model()?;
+
+    if model == ZoneModel::None {
+        return Ok(None);
+    }
+
+    let zones = dev.report_zones()?;
+    let zone_size = match zones.first() {
+        Some(zone) => zone.len,
+        None => {
+            return Err(BchError::Str(format!(
+                "{}: {} zoned device reported no zones",
+                dev_name, model
+            )))
+        }
+    };
+
+    // only the last zone may be smaller than the others
+    if zone_size == 0
+        || zones[..zones.len() - 1]
+            .iter()
+            .any(|zone| zone.len != zone_size)
+    {
+        return Err(BchError::Str(format!(
+            "{}: zones of different sizes are not supported",
+            dev_name
+        )));
+    }
+
+    let usable = match model {
+        ZoneModel::HostManaged => zones
+            .iter()
+            .take_while(|zone| zone.zone_type == ZoneType::Conventional)
+            .map(|zone| zone.capacity)
+            .sum(),
+        _ => zones.iter().map(|zone| zone.len).sum(),
+    };
+
+    debug!(
+        "{}: zoned={} zone_size={} nr_zones={} usable={}",
+        dev_name,
+        model,
+        zone_size,
+        zones.len(),
+        usable
+    );
+
+    if usable < sb_end {
+        return Err(BchError::Str(format!(
+            "{}: host-managed zoned device without enough conventional zones at the start \
+             of the device for the superblock ({} sectors needed, {} available); \
+             bcachefs cannot use sequential write required zones",
+            dev_name, sb_end, usable
+        )));
+    }
+
+    Ok(Some(ZonePlan {
+        model,
+        zone_size,
+        nr_zones: zones.len() as u64,
+        usable,
+    }))
+}
+
+#[cfg(test)]
+mod test_zones {
+    use super::*;
+    use crate::block_dev::MemoryDevice;
+
+    /// Emulate a device with `nr_conv` conventional zones followed by
+    /// `nr_seq` sequential zones of the given type
+    fn emulated_zones(zone_size: u64, nr_conv: u64, nr_seq: u64, seq: ZoneType) -> Vec<Zone> {
+        (0..nr_conv + nr_seq)
+            .map(|i| Zone {
+                start: i * zone_size,
+                len: zone_size,
+                capacity: zone_size,
+                zone_type: if i < nr_conv {
+                    ZoneType::Conventional
+                } else {
+                    seq
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn not_zoned() {
+        let dev = MemoryDevice::new(1 << 20);
+        assert_eq!(plan_zones(&dev, "dev", 2056).unwrap(), None);
+    }
+
+    #[test]
+    fn host_aware() {
+        let zones = emulated_zones(1 << 19, 0, 64, ZoneType::SeqWritePreferred);
+        let dev = MemoryDevice::new(0).with_zones(ZoneModel::HostAware, zones);
+        let plan = plan_zones(&dev, "dev", 2056).unwrap().unwrap();
+        assert_eq!(plan.zone_size, 1 << 19);
+        assert_eq!(plan.nr_zones, 64);
+        assert_eq!(plan.usable, 64 << 19);
+    }
+
+    #[test]
+    fn host_managed_conventional_start() {
+        let zones = emulated_zones(1 << 19, 4, 60, ZoneType::SeqWriteRequired);
+        let dev = MemoryDevice::new(0).with_zones(ZoneModel::HostManaged, zones);
+        let plan = plan_zones(&dev, "dev", 2056).unwrap().unwrap();
+        assert_eq!(plan.nr_zones, 64);
+        assert_eq!(plan.usable, 4 << 19);
+    }
+
+    #[test]
+    fn host_managed_without_conventional_zones() {
+        let zones = emulated_zones(1 << 19, 0, 64, ZoneType::SeqWriteRequired);
+        let dev = MemoryDevice::new(0).with_zones(ZoneModel::HostManaged, zones);
+        assert!(plan_zones(&dev, "dev", 2056).is_err());
+    }
+
+    #[test]
+    fn irregular_zones() {
+        let mut zones = emulated_zones(1 << 19, 0, 4, ZoneType::SeqWritePreferred);
+        // a smaller last zone is fine
+        zones[3].len = 1 << 10;
+        let dev = MemoryDevice::new(0).with_zones(ZoneModel::HostAware, zones.clone());
+        assert_eq!(
+            plan_zones(&dev, "dev", 2056).unwrap().unwrap().usable,
+            3 << 19 | 1 << 10
+        );
+        zones[1].len = 1 << 10;
+        let dev = MemoryDevice::new(0).with_zones(ZoneModel::HostAware, zones);
+        assert!(plan_zones(&dev, "dev", 2056).is_err());
+    }
+}